@@ -0,0 +1,385 @@
+// Command-line flags, they take precedence over the configuration file and the environment
+
+use std::{env, net::SocketAddr, path::PathBuf};
+
+use crate::{
+    config::{Config, ConfigError, parse_upstream},
+    log::Level,
+    server::ListenerConfig,
+};
+
+pub const USAGE: &str = "\
+Usage: do-not-sneeze [OPTIONS]
+
+Options:
+  -c, --config <PATH>          Configuration file, DNS_CONFIG when unset
+  -l, --listen <LISTENER>      Listen on [udp/|tcp/|tls/|http/|https/]address:port[@interface],
+                               repeatable, replaces the configured listeners
+  -p, --port <PORT>            Port of every listener
+  -u, --upstream <ADDRESS>     Upstream server, port 53 by default, repeatable,
+                               replaces the configured upstreams
+  -z, --zone <ORIGIN>=<PATH>   Serve the zone file for ORIGIN, repeatable
+      --log-level <LEVEL>      error, warn, info or debug
+      --check-config           Validate the configuration and exit
+      --reload                 Make the server whose --pidfile is given reload its configuration
+                               and zones, as SIGHUP does, then exit
+  -d, --daemon                 Detach from the terminal and run in the background
+  -f, --foreground             Stay in the foreground (default), overrides --daemon
+      --pidfile <PATH>         Write the process id to this file
+      --tls-certificate <PATH> Certificate chain of the tls/ and https/ listeners, PEM encoded
+      --tls-key <PATH>         Private key of the tls/ and https/ listeners, PEM encoded
+      --user <USER>            Switch to this user once the listeners are bound
+      --group <GROUP>          Switch to this group, the user's primary group by default
+      --chroot <PATH>          Change the root directory once the listeners are bound
+  -h, --help                   Print this help and exit
+  -V, --version                Print the version and exit";
+
+pub const VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+// parsed once at startup, boxing the options would not save anything
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub config_path: Option<PathBuf>,
+    pub listeners: Vec<ListenerConfig>,
+    pub port: Option<u16>,
+    pub upstreams: Vec<SocketAddr>,
+    /// Zone files with their origin
+    pub zones: Vec<(String, PathBuf)>,
+    pub log_level: Option<Level>,
+    pub check_config: bool,
+    pub reload: bool,
+    pub daemon: bool,
+    pub pidfile: Option<PathBuf>,
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<PathBuf>,
+}
+
+impl Command {
+    /// Parses the arguments without the program name, values go after a space or an `=`
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} expects a value", flag))
+            };
+
+            if inline_value.is_some() && !takes_value(flag) {
+                return Err(format!("{} does not take a value", flag));
+            }
+
+            match flag {
+                "-h" | "--help" => return Ok(Self::Help),
+                "-V" | "--version" => return Ok(Self::Version),
+                "-c" | "--config" => options.config_path = Some(PathBuf::from(value()?)),
+                "-l" | "--listen" => options.listeners.push(value()?.parse()?),
+                "-p" | "--port" => {
+                    let port = value()?;
+                    options.port =
+                        Some(port.parse().map_err(|_| format!("invalid port {}", port))?);
+                }
+                "-u" | "--upstream" => options.upstreams.push(parse_upstream(&value()?)?),
+                "-z" | "--zone" => {
+                    let zone = value()?;
+                    let (origin, path) = zone
+                        .split_once('=')
+                        .filter(|(origin, path)| !origin.is_empty() && !path.is_empty())
+                        .ok_or_else(|| format!("expected --zone <ORIGIN>=<PATH>, got {}", zone))?;
+                    options
+                        .zones
+                        .push((origin.to_string(), PathBuf::from(path)));
+                }
+                "--log-level" => options.log_level = Some(value()?.parse()?),
+                "--check-config" => options.check_config = true,
+                "--reload" => options.reload = true,
+                "-d" | "--daemon" => options.daemon = true,
+                "-f" | "--foreground" => options.daemon = false,
+                "--pidfile" => options.pidfile = Some(PathBuf::from(value()?)),
+                "--tls-certificate" => options.tls_certificate = Some(PathBuf::from(value()?)),
+                "--tls-key" => options.tls_key = Some(PathBuf::from(value()?)),
+                "--user" => options.user = Some(value()?),
+                "--group" => options.group = Some(value()?),
+                "--chroot" => options.chroot = Some(PathBuf::from(value()?)),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+
+        Ok(Self::Run(options))
+    }
+}
+
+fn takes_value(flag: &str) -> bool {
+    !matches!(
+        flag,
+        "--check-config" | "--reload" | "--daemon" | "--foreground" | "--help" | "--version"
+    )
+}
+
+impl Options {
+    /// Configuration from --config, DNS_CONFIG or the environment, with the flags applied on top
+    pub fn load_config(&self) -> Result<Config, ConfigError> {
+        let path = self
+            .config_path
+            .clone()
+            .or_else(|| env::var_os("DNS_CONFIG").map(PathBuf::from));
+
+        let mut config = match path {
+            Some(path) => Config::load(&path)?,
+            None => Config::from_env()?,
+        };
+
+        self.apply(&mut config).map_err(|message| ConfigError {
+            path: None,
+            line: None,
+            message,
+        })?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply(&self, config: &mut Config) -> Result<(), String> {
+        if !self.listeners.is_empty() {
+            // keep the configured amount of sockets per listener
+            let sockets = config.listeners.first().map_or(1, |l| l.sockets);
+            config.listeners = self
+                .listeners
+                .iter()
+                .map(|listener| ListenerConfig {
+                    sockets,
+                    ..listener.clone()
+                })
+                .collect();
+        }
+
+        if let Some(port) = self.port {
+            config
+                .listeners
+                .iter_mut()
+                .for_each(|listener| listener.address.set_port(port));
+        }
+
+        if !self.upstreams.is_empty() {
+            config.upstreams = self.upstreams.clone();
+        }
+
+        for (origin, path) in &self.zones {
+            config.add_zone_file(origin, path)?;
+        }
+
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+
+        if let Some(tls_certificate) = &self.tls_certificate {
+            config.tls_certificate = Some(tls_certificate.clone());
+        }
+        if let Some(tls_key) = &self.tls_key {
+            config.tls_key = Some(tls_key.clone());
+        }
+
+        if let Some(user) = &self.user {
+            config.privileges.user = Some(user.clone());
+        }
+        if let Some(group) = &self.group {
+            config.privileges.group = Some(group.clone());
+        }
+        if let Some(chroot) = &self.chroot {
+            config.privileges.chroot = Some(chroot.clone());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn options(args: &[&str]) -> Options {
+        match parse(args).unwrap() {
+            Command::Run(options) => options,
+            command => panic!("expected options, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parses_flags() {
+        let options = options(&[
+            "--config",
+            "/etc/dns.conf",
+            "-l",
+            "udp/[::1]:5353",
+            "--listen=0.0.0.0:5353",
+            "-p",
+            "5300",
+            "--upstream=1.1.1.1",
+            "-u",
+            "9.9.9.9:5353",
+            "-z",
+            "example.com=/etc/zones/example.com",
+            "--log-level",
+            "debug",
+            "--check-config",
+            "--reload",
+            "-d",
+            "--pidfile",
+            "/run/dns.pid",
+            "--tls-certificate",
+            "/etc/dns/cert.pem",
+            "--tls-key=/etc/dns/key.pem",
+            "--user=dns",
+            "--group",
+            "dns",
+            "--chroot",
+            "/var/empty",
+        ]);
+
+        assert_eq!(options.config_path, Some(PathBuf::from("/etc/dns.conf")));
+        assert_eq!(options.listeners.len(), 2);
+        assert!(!options.listeners[0].tcp);
+        assert_eq!(options.port, Some(5300));
+        assert_eq!(
+            options.upstreams,
+            vec![
+                "1.1.1.1:53".parse().unwrap(),
+                "9.9.9.9:5353".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            options.zones,
+            vec![(
+                "example.com".to_string(),
+                PathBuf::from("/etc/zones/example.com")
+            )]
+        );
+        assert_eq!(options.log_level, Some(Level::Debug));
+        assert!(options.check_config);
+        assert!(options.reload);
+        assert!(options.daemon);
+        assert_eq!(options.pidfile, Some(PathBuf::from("/run/dns.pid")));
+        assert_eq!(
+            options.tls_certificate,
+            Some(PathBuf::from("/etc/dns/cert.pem"))
+        );
+        assert_eq!(options.tls_key, Some(PathBuf::from("/etc/dns/key.pem")));
+        assert_eq!(options.user.as_deref(), Some("dns"));
+        assert_eq!(options.group.as_deref(), Some("dns"));
+        assert_eq!(options.chroot, Some(PathBuf::from("/var/empty")));
+    }
+
+    #[test]
+    fn foreground_overrides_daemon() {
+        assert!(!options(&["--daemon", "--foreground"]).daemon);
+        assert_eq!(options(&[]), Options::default());
+    }
+
+    #[test]
+    fn stops_at_help_and_version() {
+        assert_eq!(parse(&["-p", "53", "--help"]), Ok(Command::Help));
+        assert_eq!(parse(&["-V", "--bogus"]), Ok(Command::Version));
+    }
+
+    #[test]
+    fn rejects_invalid_flags() {
+        assert_eq!(
+            parse(&["--bogus"]),
+            Err("unknown option --bogus".to_string())
+        );
+        assert_eq!(
+            parse(&["--port"]),
+            Err("--port expects a value".to_string())
+        );
+        assert_eq!(
+            parse(&["-p", "70000"]),
+            Err("invalid port 70000".to_string())
+        );
+        assert_eq!(
+            parse(&["--daemon=yes"]),
+            Err("--daemon does not take a value".to_string())
+        );
+        assert!(parse(&["--zone", "example.com"]).is_err());
+        assert!(parse(&["--log-level", "verbose"]).is_err());
+        assert!(parse(&["--listen", "0.0.0.0"]).is_err());
+    }
+
+    #[test]
+    fn overrides_configuration_file() {
+        let dir = env::temp_dir();
+        let config_path = dir.join(format!("dns-cli-test-{}.conf", std::process::id()));
+        let zone_path = dir.join(format!("dns-cli-test-{}.zone", std::process::id()));
+        fs::write(
+            &config_path,
+            "[listeners]\nlisten = 0.0.0.0:53\nlisten = [::]:53\nsockets = 2\n\n\
+             [upstreams]\nserver = 8.8.4.4\n\n[logging]\nlevel = error\n",
+        )
+        .unwrap();
+        fs::write(&zone_path, "www 60 A 192.0.2.1\n").unwrap();
+
+        let config = options(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "--port",
+            "5353",
+            "--upstream",
+            "1.1.1.1",
+            "--zone",
+            &format!("example.com={}", zone_path.display()),
+            "--log-level",
+            "info",
+            "--user",
+            "nobody",
+        ])
+        .load_config();
+        fs::remove_file(&config_path).unwrap();
+        fs::remove_file(&zone_path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.listeners.len(), 2);
+        assert!(config.listeners.iter().all(|l| l.address.port() == 5353));
+        assert!(config.listeners.iter().all(|l| l.sockets == 2));
+        assert_eq!(config.upstreams, vec!["1.1.1.1:53".parse().unwrap()]);
+        assert_eq!(config.zones[0].origin, "example.com");
+        assert_eq!(config.zones[0].records.len(), 1);
+        assert_eq!(config.log_level, Level::Info);
+        assert_eq!(config.privileges.user.as_deref(), Some("nobody"));
+    }
+
+    #[test]
+    fn reports_missing_zone_file() {
+        let error = options(&["--zone", "example.com=/nonexistent/example.com.zone"])
+            .load_config()
+            .unwrap_err();
+
+        assert!(
+            error
+                .message
+                .starts_with("could not read zone file /nonexistent/example.com.zone")
+        );
+    }
+}
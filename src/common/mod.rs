@@ -3,9 +3,9 @@ use opt_record::OptRecord;
 use question::Question;
 use resource_record::ResourceRecord;
 
-use crate::transport::UDP_MAX_MESSAGE_SIZE;
+use crate::transport::{TCP_MAX_MESSAGE_SIZE, Transport, UDP_MAX_MESSAGE_SIZE};
 
-use self::header::MessageType;
+use self::header::{MessageId, MessageType, QueryType, ResponseCode};
 
 pub mod domain_name;
 pub mod header;
@@ -65,19 +65,67 @@ impl Message {
         }
     }
 
+    /// Shortest TTL of the answer and authority records, the time the response may be cached for
+    /// as a whole
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.answers
+            .iter()
+            .chain(self.authorities.iter())
+            .map(|record| record.ttl)
+            .min()
+    }
+
     pub fn into_response(mut self) -> Self {
         self.header.qr = MessageType::Response;
 
         self
     }
 
+    /// Builds an empty response for a query that could not be decoded
+    /// Only the id of the query is known, so the response carries no question
+    pub fn error_response(id: MessageId, response_code: ResponseCode) -> Self {
+        Self::new(
+            Header {
+                id,
+                qr: MessageType::Response,
+                opcode: QueryType::Standard,
+                authoritative_answer: false,
+                truncated: false,
+                recursion_desired: false,
+                recursion_available: false,
+                reserved: false,
+                response_code,
+                questions_count: 0,
+                answers_count: 0,
+                authority_count: 0,
+                additional_count: 0,
+            },
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            None,
+        )
+    }
+
+    pub fn into_error_response(self, response_code: ResponseCode) -> Self {
+        let mut response = self.into_response();
+        response.set_answers(vec![]);
+        response.set_authorities(vec![]);
+        response.set_additionnals(vec![]);
+
+        response.header.response_code = response_code;
+
+        response
+    }
+
     pub fn truncate(mut self) -> Self {
         self.header.truncated = true;
         self.set_answers(vec![]);
         self.set_authorities(vec![]);
         self.set_additionnals(vec![]);
 
-        self.header.response_code = header::ResponseCode::NoError;
+        self.header.response_code = ResponseCode::NoError;
 
         self
     }
@@ -97,11 +145,12 @@ impl Message {
         self.additionnals = additionnals;
     }
 
-    pub fn max_message_size(&self) -> usize {
-        if let Some(opt_record) = &self.opt_record {
-            opt_record.udp_payload_size as usize
-        } else {
-            UDP_MAX_MESSAGE_SIZE
+    pub fn max_message_size(&self, transport: Transport) -> usize {
+        match (transport, &self.opt_record) {
+            // the EDNS payload size only applies to UDP, see https://datatracker.ietf.org/doc/html/rfc6891#section-6.2.5
+            (Transport::Tcp | Transport::Http, _) => TCP_MAX_MESSAGE_SIZE,
+            (Transport::Udp, Some(opt_record)) => opt_record.udp_payload_size as usize,
+            (Transport::Udp, None) => UDP_MAX_MESSAGE_SIZE,
         }
     }
 }
@@ -109,7 +158,6 @@ impl Message {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use header::{MessageType, QueryType, ResponseCode};
 
     #[test]
     fn truncate() {
@@ -197,4 +245,70 @@ mod tests {
 
         assert_eq!(truncated_message, expected_truncated_message);
     }
+
+    #[test]
+    fn into_error_response() {
+        let questions = vec![Question {
+            name: domain_name::DomainName::from("example.com."),
+            type_: question::Type::RRType(resource_record::Type::A),
+            class: question::Class::IN,
+        }];
+
+        let message = Message::new(
+            Header {
+                id: 1234,
+                qr: MessageType::Query,
+                opcode: QueryType::Inverse,
+                authoritative_answer: false,
+                truncated: false,
+                recursion_desired: true,
+                recursion_available: false,
+                reserved: false,
+                response_code: ResponseCode::NoError,
+                questions_count: 1,
+                answers_count: 1,
+                authority_count: 0,
+                additional_count: 0,
+            },
+            questions.clone(),
+            vec![ResourceRecord::new(
+                domain_name::DomainName::from("example.com."),
+                resource_record::Type::A,
+                question::Class::IN,
+                300,
+                vec![192, 0, 2, 1],
+            )],
+            vec![],
+            vec![],
+            None,
+        );
+
+        let expected_response = Message::new(
+            Header {
+                id: 1234,
+                qr: MessageType::Response,
+                opcode: QueryType::Inverse,
+                authoritative_answer: false,
+                truncated: false,
+                recursion_desired: true,
+                recursion_available: false,
+                reserved: false,
+                response_code: ResponseCode::NotImplemented,
+                questions_count: 1,
+                answers_count: 0,
+                authority_count: 0,
+                additional_count: 0,
+            },
+            questions,
+            vec![],
+            vec![],
+            vec![],
+            None,
+        );
+
+        assert_eq!(
+            message.into_error_response(ResponseCode::NotImplemented),
+            expected_response
+        );
+    }
 }
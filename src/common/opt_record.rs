@@ -1,3 +1,11 @@
+use std::time::Duration;
+
+// see: https://datatracker.ietf.org/doc/html/rfc7828
+pub const EDNS_TCP_KEEPALIVE_OPTION_CODE: u16 = 11;
+// see: https://datatracker.ietf.org/doc/html/rfc8914
+pub const EDNS_EXTENDED_ERROR_OPTION_CODE: u16 = 15;
+pub const EXTENDED_ERROR_STALE_ANSWER: u16 = 3;
+
 /// EDNS(0) OPT pseudo-RR for DNS extension mechanism
 /// OPT is a special record type (41) that carries control information
 /// and does not represent actual DNS data.
@@ -38,4 +46,49 @@ impl EdnsOption {
     pub fn new(code: u16, data: Vec<u8>) -> Self {
         Self { code, data }
     }
+
+    /// edns-tcp-keepalive option announcing the idle timeout, expressed in units of 100 milliseconds
+    pub fn tcp_keepalive(idle_timeout: Duration) -> Self {
+        let timeout = (idle_timeout.as_millis() / 100).min(u16::MAX as u128) as u16;
+        Self::new(
+            EDNS_TCP_KEEPALIVE_OPTION_CODE,
+            timeout.to_be_bytes().to_vec(),
+        )
+    }
+
+    /// Extended DNS Error option with an info code and no extra text
+    pub fn extended_error(info_code: u16) -> Self {
+        Self::new(
+            EDNS_EXTENDED_ERROR_OPTION_CODE,
+            info_code.to_be_bytes().to_vec(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_keepalive() {
+        let option = EdnsOption::tcp_keepalive(Duration::from_secs(10));
+
+        assert_eq!(option.code, EDNS_TCP_KEEPALIVE_OPTION_CODE);
+        assert_eq!(option.data, vec![0, 100]);
+    }
+
+    #[test]
+    fn extended_error() {
+        let option = EdnsOption::extended_error(EXTENDED_ERROR_STALE_ANSWER);
+
+        assert_eq!(option.code, EDNS_EXTENDED_ERROR_OPTION_CODE);
+        assert_eq!(option.data, vec![0, 3]);
+    }
+
+    #[test]
+    fn tcp_keepalive_saturates() {
+        let option = EdnsOption::tcp_keepalive(Duration::from_secs(24 * 3600));
+
+        assert_eq!(option.data, vec![0xFF, 0xFF]);
+    }
 }
@@ -7,6 +7,7 @@ pub struct Question {
     pub class: Class,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum Type {
     RRType(RRType),
@@ -45,6 +46,7 @@ impl From<Type> for u16 {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum Class {
     IN, // the Internet
@@ -34,6 +34,7 @@ impl ResourceRecord {
 
 // *_OBS: obsolete
 // *_EXP: experimental
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Type {
     A,        // IPv4 host address
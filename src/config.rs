@@ -0,0 +1,908 @@
+// Server configuration, read from an INI-like file:
+//
+//   # comment
+//   [section]
+//   key = value
+//
+// Keys marked as repeatable below can appear several times, zones are sections with an argument:
+// `[zone example.com]`. See dns.conf.example for every supported key.
+
+use std::{
+    collections::HashSet,
+    env,
+    fmt::Display,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    common::resource_record::ResourceRecord,
+    log::Level,
+    privileges::Privileges,
+    server::{
+        Acl, AclAction, DEFAULT_SHUTDOWN_TIMEOUT, ListenerConfig, Network, ProxyProtocol,
+        ServerMode, TcpConfig, TlsConfig, WorkerPoolConfig,
+    },
+    storage::{
+        Prefetch,
+        combined::{
+            DEFAULT_CACHE_SAVE_INTERVAL, DEFAULT_CACHE_SWEEP_INTERVAL, DEFAULT_PREFETCH,
+            DEFAULT_STALE_WINDOW,
+        },
+        fallback::DEFAULT_UPSTREAM_TIMEOUT,
+        zone::{DEFAULT_TTL, load_zone_file, parse_record},
+    },
+    transport::DNS_PORT,
+};
+
+const DEFAULT_UPSTREAM: &str = "8.8.8.8:53";
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub path: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(line: Option<usize>, message: String) -> Self {
+        Self {
+            path: None,
+            line,
+            message,
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "{}:", line)?;
+        }
+        if self.path.is_some() || self.line.is_some() {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Records the server is authoritative for, they never leave the in-memory store
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    pub origin: String,
+    pub records: Vec<ResourceRecord>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub mode: ServerMode,
+    pub worker_pool: WorkerPoolConfig,
+    pub tcp: TcpConfig,
+    /// PEM files of the DNS over TLS listeners
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Time given to in-flight queries when stopping
+    pub shutdown_timeout: Duration,
+    /// Switched to once the listeners are bound
+    pub privileges: Privileges,
+    /// Servers unknown names are forwarded to, tried in order, none means authoritative only
+    pub upstreams: Vec<SocketAddr>,
+    pub upstream_timeout: Duration,
+    /// Least recently used names are evicted beyond these many cached entries or bytes
+    pub max_cached_records: Option<usize>,
+    pub max_cache_bytes: Option<usize>,
+    /// How often the expired records are removed from the cache
+    pub cache_sweep_interval: Duration,
+    /// How long expired records are kept to be served when the upstreams can not be reached
+    pub stale_window: Duration,
+    /// When popular records are refreshed before they expire, a percent of 0 disables it
+    pub prefetch: Prefetch,
+    /// Where the cache is saved on shutdown and every save interval, and loaded from on startup
+    pub cache_file: Option<PathBuf>,
+    pub cache_save_interval: Duration,
+    pub zones: Vec<Zone>,
+    pub acl: Acl,
+    /// Load balancers allowed to pass the client address on through a PROXY v2 header
+    pub proxy_protocol: ProxyProtocol,
+    pub log_level: Level,
+    /// Logs go to stdout when unset
+    pub log_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listeners: vec![ListenerConfig::new(SocketAddr::from((
+                [0, 0, 0, 0],
+                DNS_PORT,
+            )))],
+            mode: ServerMode::Threaded,
+            worker_pool: WorkerPoolConfig::default(),
+            tcp: TcpConfig::default(),
+            tls_certificate: None,
+            tls_key: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            privileges: Privileges::default(),
+            upstreams: vec![DEFAULT_UPSTREAM.parse().unwrap()],
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            max_cached_records: None,
+            max_cache_bytes: None,
+            cache_sweep_interval: DEFAULT_CACHE_SWEEP_INTERVAL,
+            stale_window: DEFAULT_STALE_WINDOW,
+            prefetch: DEFAULT_PREFETCH,
+            cache_file: None,
+            cache_save_interval: DEFAULT_CACHE_SAVE_INTERVAL,
+            zones: vec![],
+            acl: Acl::default(),
+            proxy_protocol: ProxyProtocol::default(),
+            log_level: Level::Info,
+            log_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Configuration without file: defaults overridden by the DNS_* environment variables
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let default = Self::default();
+
+        Ok(Self {
+            listeners: ListenerConfig::from_env()
+                .map_err(|e| ConfigError::new(None, e.to_string()))?,
+            mode: ServerMode::from_env(),
+            worker_pool: WorkerPoolConfig::from_env(),
+            tcp: TcpConfig::from_env(),
+            tls_certificate: env::var_os("DNS_TLS_CERTIFICATE").map(PathBuf::from),
+            tls_key: env::var_os("DNS_TLS_KEY").map(PathBuf::from),
+            shutdown_timeout: env::var("DNS_SHUTDOWN_TIMEOUT")
+                .ok()
+                .and_then(|t| t.parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default.shutdown_timeout),
+            privileges: Privileges::from_env(),
+            upstreams: match env::var("DNS_UPSTREAMS") {
+                Ok(upstreams) => upstreams
+                    .split(',')
+                    .map(|u| parse_upstream(u.trim()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| ConfigError::new(None, e))?,
+                Err(_) => default.upstreams,
+            },
+            log_level: match env::var("DNS_LOG_LEVEL") {
+                Ok(level) => level.parse().map_err(|e| ConfigError::new(None, e))?,
+                Err(_) => default.log_level,
+            },
+            ..default
+        })
+    }
+
+    /// Reads and validates a configuration file, zone files are loaded as well
+    /// Anything the file does not set keeps its default value, environment variables are ignored
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let with_path = |e: ConfigError| ConfigError {
+            path: Some(path.to_path_buf()),
+            ..e
+        };
+
+        let content = fs::read_to_string(path).map_err(|e| {
+            with_path(ConfigError::new(
+                None,
+                format!("could not read configuration file: {}", e),
+            ))
+        })?;
+
+        content.parse::<Self>().map_err(with_path)
+    }
+
+    fn set(&mut self, section: &Section, entry: &Entry) -> Result<(), String> {
+        let value = entry.value.as_str();
+
+        match (section.name.as_str(), entry.key.as_str()) {
+            ("server", "mode") => {
+                self.mode = match value {
+                    "threaded" => ServerMode::Threaded,
+                    "event-loop" => ServerMode::EventLoop { reactors: 1 },
+                    _ => return Err(format!("unknown server mode {}", value)),
+                }
+            }
+            ("server", "reactors") => match self.mode {
+                ServerMode::EventLoop { .. } => {
+                    self.mode = ServerMode::EventLoop {
+                        reactors: parse_positive(value)?,
+                    }
+                }
+                ServerMode::Threaded => {
+                    return Err("reactors requires `mode = event-loop` set before it".to_string());
+                }
+            },
+            ("server", "workers") => self.worker_pool.workers = parse_positive(value)?,
+            ("server", "queue_capacity") => {
+                self.worker_pool.queue_capacity = parse_positive(value)?
+            }
+            ("server", "overflow_policy") => self.worker_pool.overflow_policy = value.parse()?,
+            ("server", "shutdown_timeout") => {
+                self.shutdown_timeout = Duration::from_secs(parse(value)?)
+            }
+            ("server", "user") => self.privileges.user = Some(value.to_string()),
+            ("server", "group") => self.privileges.group = Some(value.to_string()),
+            ("server", "chroot") => self.privileges.chroot = Some(PathBuf::from(value)),
+
+            ("listeners", "listen") => {
+                let listener = value.parse::<ListenerConfig>()?;
+                // the first listen entry replaces the default listener
+                if !section.seen_keys.contains("listen") {
+                    self.listeners.clear();
+                }
+                self.listeners.push(listener);
+            }
+            ("listeners", "sockets") => {
+                let sockets = parse_positive(value)?;
+                // applied again once every listener is known, see from_str
+                self.listeners
+                    .iter_mut()
+                    .for_each(|listener| listener.sockets = sockets);
+            }
+
+            ("tcp", "idle_timeout") => {
+                self.tcp.idle_timeout = Duration::from_secs(parse_positive(value)? as u64)
+            }
+            ("tcp", "max_queries") => self.tcp.max_queries_per_connection = parse_positive(value)?,
+            ("tcp", "max_connections") => self.tcp.max_connections = parse_positive(value)?,
+
+            ("tls", "certificate") => self.tls_certificate = Some(PathBuf::from(value)),
+            ("tls", "key") => self.tls_key = Some(PathBuf::from(value)),
+
+            ("upstreams", "server") => self.upstreams.push(parse_upstream(value)?),
+            ("upstreams", "timeout") => {
+                self.upstream_timeout = Duration::from_millis(parse_positive(value)? as u64)
+            }
+
+            ("cache", "max_records") => self.max_cached_records = Some(parse(value)?),
+            ("cache", "max_bytes") => self.max_cache_bytes = Some(parse_size(value)?),
+            ("cache", "sweep_interval") => {
+                self.cache_sweep_interval = Duration::from_secs(parse_positive(value)? as u64)
+            }
+            ("cache", "stale_window") => self.stale_window = Duration::from_secs(parse(value)?),
+            ("cache", "prefetch_hits") => self.prefetch.min_hits = parse(value)?,
+            ("cache", "prefetch_percent") => {
+                self.prefetch.percent = parse::<u8>(value)
+                    .ok()
+                    .filter(|percent| *percent <= 100)
+                    .ok_or_else(|| format!("expected a percentage, got {}", value))?
+            }
+            ("cache", "file") => self.cache_file = Some(PathBuf::from(value)),
+            ("cache", "save_interval") => {
+                self.cache_save_interval = Duration::from_secs(parse_positive(value)? as u64)
+            }
+
+            ("zone", "file") => self.add_zone_file(zone_origin(section)?, Path::new(value))?,
+            ("zone", "record") => {
+                let origin = zone_origin(section)?;
+                let record = parse_record(value, origin, DEFAULT_TTL)?;
+                self.zone(origin).records.push(record);
+            }
+
+            ("acl", "default") => self.acl.set_default_action(value.parse()?),
+            ("acl", action @ ("allow" | "deny")) => self
+                .acl
+                .add_rule(action.parse::<AclAction>()?, value.parse::<Network>()?),
+
+            ("proxy_protocol", "trusted") => self
+                .proxy_protocol
+                .add_trusted_proxy(value.parse::<Network>()?),
+
+            ("logging", "level") => self.log_level = value.parse()?,
+            ("logging", "file") => self.log_file = Some(PathBuf::from(value)),
+
+            (_, key) => return Err(format!("unknown key `{}` in [{}]", key, section.title())),
+        }
+
+        Ok(())
+    }
+
+    /// Loads the records of a zone file, merged with the zone's other records if already known
+    pub fn add_zone_file(&mut self, origin: &str, path: &Path) -> Result<(), String> {
+        let records = load_zone_file(path, origin)?;
+        self.zone(origin).records.extend(records);
+        Ok(())
+    }
+
+    fn zone(&mut self, origin: &str) -> &mut Zone {
+        if let Some(i) = self.zones.iter().position(|zone| zone.origin == origin) {
+            return &mut self.zones[i];
+        }
+
+        self.zones.push(Zone {
+            origin: origin.to_string(),
+            records: vec![],
+        });
+        self.zones.last_mut().unwrap()
+    }
+
+    /// Certificate and key of the TLS listeners, once both are known
+    pub fn tls(&self) -> Option<TlsConfig> {
+        Some(TlsConfig {
+            certificate: self.tls_certificate.clone()?,
+            key: self.tls_key.clone()?,
+        })
+    }
+
+    /// Sections of the other configuration which differ from this one but are only read at startup,
+    /// a reload only applies the zones and the logging settings
+    pub fn changes_requiring_restart(&self, other: &Config) -> Vec<&'static str> {
+        [
+            ("listeners", self.listeners != other.listeners),
+            (
+                "server",
+                self.mode != other.mode
+                    || self.worker_pool != other.worker_pool
+                    || self.shutdown_timeout != other.shutdown_timeout
+                    || self.privileges != other.privileges,
+            ),
+            ("tcp", self.tcp != other.tcp),
+            (
+                "tls",
+                self.tls_certificate != other.tls_certificate || self.tls_key != other.tls_key,
+            ),
+            (
+                "upstreams",
+                self.upstreams != other.upstreams
+                    || self.upstream_timeout != other.upstream_timeout,
+            ),
+            (
+                "cache",
+                self.max_cached_records != other.max_cached_records
+                    || self.max_cache_bytes != other.max_cache_bytes
+                    || self.cache_sweep_interval != other.cache_sweep_interval
+                    || self.stale_window != other.stale_window
+                    || self.prefetch != other.prefetch
+                    || self.cache_file != other.cache_file
+                    || self.cache_save_interval != other.cache_save_interval,
+            ),
+            ("acl", self.acl != other.acl),
+            (
+                "proxy_protocol",
+                self.proxy_protocol != other.proxy_protocol,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(section, _)| section)
+        .collect()
+    }
+
+    /// Checks what can only be checked once the whole configuration is known
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.check().map_err(|e| ConfigError::new(None, e))
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.listeners.iter().any(|l| !l.udp && !l.tcp) {
+            return Err("listeners must use UDP, TCP or both".to_string());
+        }
+
+        if self.tls_certificate.is_some() != self.tls_key.is_some() {
+            return Err("[tls] needs both a certificate and a key".to_string());
+        }
+        if self.listeners.iter().any(|l| l.tls) && self.tls().is_none() {
+            return Err("TLS listeners need a certificate and a key in [tls]".to_string());
+        }
+
+        if self.upstreams.is_empty() && self.zones.iter().all(|z| z.records.is_empty()) {
+            return Err("nothing to serve: configure an upstream server or a zone".to_string());
+        }
+
+        if let Some(chroot) = &self.privileges.chroot
+            && !chroot.is_dir()
+        {
+            return Err(format!("chroot {} is not a directory", chroot.display()));
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        if matches!(self.mode, ServerMode::EventLoop { .. }) {
+            return Err("the event-loop mode is only supported on Linux".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        let mut sections: Vec<Section> = vec![];
+        let mut listener_sockets = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let line_number = i + 1;
+            let error = |message: String| ConfigError::new(Some(line_number), message);
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(title) = line.strip_prefix('[') {
+                let title = title
+                    .strip_suffix(']')
+                    .ok_or_else(|| error(format!("missing `]` in section header {}", line)))?;
+                let section = Section::parse(title).map_err(error)?;
+
+                if section.name != "zone" && sections.iter().any(|s| s.name == section.name) {
+                    return Err(error(format!("duplicate section [{}]", section.title())));
+                }
+
+                // an [upstreams] section lists every upstream, without any the server is authoritative only
+                if section.name == "upstreams" {
+                    config.upstreams.clear();
+                }
+
+                sections.push(section);
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected `key = value`, got `{}`", line)))?;
+            let entry = Entry {
+                key: key.trim().to_ascii_lowercase(),
+                value: value.trim().to_string(),
+            };
+
+            let section = sections
+                .last_mut()
+                .ok_or_else(|| error(format!("`{}` is not in a section", entry.key)))?;
+
+            if entry.value.is_empty() {
+                return Err(error(format!("missing value for `{}`", entry.key)));
+            }
+
+            if !REPEATABLE_KEYS.contains(&entry.key.as_str())
+                && section.seen_keys.contains(&entry.key)
+            {
+                return Err(error(format!(
+                    "duplicate key `{}` in [{}]",
+                    entry.key,
+                    section.title()
+                )));
+            }
+
+            config.set(section, &entry).map_err(error)?;
+
+            if (section.name.as_str(), entry.key.as_str()) == ("listeners", "sockets") {
+                listener_sockets = config.listeners.first().map(|l| l.sockets);
+            }
+            section.seen_keys.insert(entry.key);
+        }
+
+        // `sockets` may come before the `listen` entries
+        if let Some(sockets) = listener_sockets {
+            config
+                .listeners
+                .iter_mut()
+                .for_each(|listener| listener.sockets = sockets);
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+/// Keys which can appear several times in their section
+const REPEATABLE_KEYS: [&str; 7] = [
+    "listen", "server", "file", "record", "allow", "deny", "trusted",
+];
+
+struct Section {
+    name: String,
+    argument: Option<String>,
+    seen_keys: HashSet<String>,
+}
+
+impl Section {
+    fn parse(title: &str) -> Result<Self, String> {
+        let mut parts = title.split_whitespace();
+        let name = parts
+            .next()
+            .ok_or_else(|| "empty section name".to_string())?
+            .to_ascii_lowercase();
+        let argument = parts.next().map(str::to_string);
+
+        if parts.next().is_some() {
+            return Err(format!("unexpected section header [{}]", title));
+        }
+
+        if !SECTIONS.contains(&name.as_str()) {
+            return Err(format!("unknown section [{}]", title));
+        }
+
+        match (name.as_str(), &argument) {
+            ("zone", None) => Err("zone sections need an origin: [zone example.com]".to_string()),
+            ("zone", Some(_)) | (_, None) => Ok(Self {
+                name,
+                argument,
+                seen_keys: HashSet::new(),
+            }),
+            (_, Some(_)) => Err(format!("[{}] does not take an argument", name)),
+        }
+    }
+
+    fn title(&self) -> String {
+        match &self.argument {
+            Some(argument) => format!("{} {}", self.name, argument),
+            None => self.name.clone(),
+        }
+    }
+}
+
+const SECTIONS: [&str; 10] = [
+    "server",
+    "listeners",
+    "tcp",
+    "tls",
+    "upstreams",
+    "cache",
+    "zone",
+    "acl",
+    "proxy_protocol",
+    "logging",
+];
+
+struct Entry {
+    key: String,
+    value: String,
+}
+
+fn zone_origin(section: &Section) -> Result<&str, String> {
+    section
+        .argument
+        .as_deref()
+        .ok_or_else(|| "zone sections need an origin".to_string())
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("invalid number {}", value))
+}
+
+fn parse_positive(value: &str) -> Result<usize, String> {
+    parse::<usize>(value)
+        .ok()
+        .filter(|v| *v > 0)
+        .ok_or_else(|| format!("expected a positive number, got {}", value))
+}
+
+/// A number of bytes with an optional K, M or G suffix, in powers of 1024
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+
+    parse::<usize>(number.trim())?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("size too large {}", value))
+}
+
+/// An address with an optional port, 53 by default
+pub fn parse_upstream(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            value
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        })
+        .map_err(|_| format!("invalid upstream server address {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::common::{domain_name::DomainName, resource_record::Type};
+
+    use super::*;
+
+    fn error_of(config: &str) -> ConfigError {
+        config.parse::<Config>().unwrap_err()
+    }
+
+    #[test]
+    fn parses_full_config() {
+        let config = r#"
+# resolver for the office network
+[server]
+mode = event-loop
+reactors = 2
+workers = 4
+queue_capacity = 256
+overflow_policy = refused
+shutdown_timeout = 2
+user = nobody
+group = nogroup
+chroot = /
+
+[listeners]
+sockets = 2
+listen = 0.0.0.0:5353
+listen = udp/[::1]:5353
+listen = tls/0.0.0.0:853
+listen = https/[::]:443
+
+[tcp]
+idle_timeout = 5
+max_queries = 10
+max_connections = 20
+
+[tls]
+certificate = /etc/dns/cert.pem
+key = /etc/dns/key.pem
+
+[upstreams]
+server = 1.1.1.1
+server = [2606:4700:4700::1111]:53
+timeout = 500
+
+[cache]
+max_records = 1000
+max_bytes = 64M
+sweep_interval = 30
+stale_window = 0
+prefetch_hits = 10
+prefetch_percent = 20
+file = /var/cache/dns/cache.bin
+save_interval = 600
+
+[zone example.com]
+record = www 60 IN A 192.0.2.1
+record = @ MX 10 mail
+
+[acl]
+allow = 192.168.0.0/16
+default = deny
+
+[proxy_protocol]
+trusted = 10.0.0.1
+trusted = fd00::/8
+
+[logging]
+level = warn
+file = /tmp/dns.log
+"#
+        .parse::<Config>()
+        .unwrap();
+
+        assert_eq!(config.mode, ServerMode::EventLoop { reactors: 2 });
+        assert_eq!(config.worker_pool.workers, 4);
+        assert_eq!(config.worker_pool.queue_capacity, 256);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(2));
+        assert_eq!(
+            config.privileges,
+            Privileges {
+                user: Some("nobody".to_string()),
+                group: Some("nogroup".to_string()),
+                chroot: Some(PathBuf::from("/")),
+            }
+        );
+        assert_eq!(config.listeners.len(), 4);
+        assert!(config.listeners.iter().all(|l| l.sockets == 2));
+        assert!(!config.listeners[1].tcp);
+        assert!(config.listeners[2].tls);
+        assert!(config.listeners[3].tls && config.listeners[3].http);
+        assert_eq!(config.tcp.idle_timeout, Duration::from_secs(5));
+        assert_eq!(config.tcp.max_queries_per_connection, 10);
+        assert_eq!(config.tcp.max_connections, 20);
+        assert_eq!(
+            config.tls(),
+            Some(TlsConfig {
+                certificate: PathBuf::from("/etc/dns/cert.pem"),
+                key: PathBuf::from("/etc/dns/key.pem"),
+            })
+        );
+        assert_eq!(
+            config.upstreams,
+            vec![
+                "1.1.1.1:53".parse().unwrap(),
+                "[2606:4700:4700::1111]:53".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.upstream_timeout, Duration::from_millis(500));
+        assert_eq!(config.max_cached_records, Some(1000));
+        assert_eq!(config.max_cache_bytes, Some(64 << 20));
+        assert_eq!(config.cache_sweep_interval, Duration::from_secs(30));
+        assert_eq!(config.stale_window, Duration::ZERO);
+        assert_eq!(
+            config.prefetch,
+            Prefetch {
+                min_hits: 10,
+                percent: 20
+            }
+        );
+        assert_eq!(
+            config.cache_file,
+            Some(PathBuf::from("/var/cache/dns/cache.bin"))
+        );
+        assert_eq!(config.cache_save_interval, Duration::from_secs(600));
+        assert_eq!(config.zones.len(), 1);
+        assert_eq!(config.zones[0].records.len(), 2);
+        assert_eq!(
+            config.zones[0].records[0].name,
+            DomainName::from("www.example.com.")
+        );
+        assert_eq!(config.zones[0].records[1].type_, Type::MX);
+        assert!(config.acl.is_allowed("192.168.1.1".parse().unwrap()));
+        assert!(!config.acl.is_allowed("10.0.0.1".parse().unwrap()));
+        assert!(
+            config
+                .proxy_protocol
+                .is_trusted("10.0.0.1".parse().unwrap())
+        );
+        assert!(config.proxy_protocol.is_trusted("fd00::1".parse().unwrap()));
+        assert!(
+            !config
+                .proxy_protocol
+                .is_trusted("10.0.0.2".parse().unwrap())
+        );
+        assert_eq!(config.log_level, Level::Warn);
+        assert_eq!(config.log_file, Some(PathBuf::from("/tmp/dns.log")));
+    }
+
+    #[test]
+    fn keeps_defaults_for_missing_keys() {
+        let config = "[logging]\nlevel = debug\n".parse::<Config>().unwrap();
+        let default = Config::default();
+
+        assert_eq!(config.listeners, default.listeners);
+        assert_eq!(config.upstreams, default.upstreams);
+        assert_eq!(config.mode, ServerMode::Threaded);
+        assert_eq!(config.log_level, Level::Debug);
+    }
+
+    #[test]
+    fn loads_zone_files() {
+        let path = env::temp_dir().join(format!("dns-config-test-{}.zone", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "$TTL 300\n@ NS ns1\nns1 A 192.0.2.53").unwrap();
+
+        let config = format!(
+            "[upstreams]\n[zone example.org]\nfile = {}\nrecord = www A 192.0.2.80\n",
+            path.display()
+        )
+        .parse::<Config>()
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.zones[0].origin, "example.org");
+        assert_eq!(config.zones[0].records.len(), 3);
+        assert_eq!(config.zones[0].records[0].ttl, 300);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(
+            error_of("[server]\nworkers = 0\n"),
+            ConfigError::new(Some(2), "expected a positive number, got 0".to_string())
+        );
+        assert_eq!(
+            error_of("[server]\nthreads = 4\n"),
+            ConfigError::new(Some(2), "unknown key `threads` in [server]".to_string())
+        );
+        assert_eq!(
+            error_of("\n\n[cluster]\n"),
+            ConfigError::new(Some(3), "unknown section [cluster]".to_string())
+        );
+        assert_eq!(
+            error_of("[tcp]\nmax_queries = 1\nmax_queries = 2\n"),
+            ConfigError::new(Some(3), "duplicate key `max_queries` in [tcp]".to_string())
+        );
+        assert_eq!(
+            error_of("workers = 4\n"),
+            ConfigError::new(Some(1), "`workers` is not in a section".to_string())
+        );
+        assert_eq!(
+            error_of("[zone example.com]\nrecord = www A 300.0.0.1\n"),
+            ConfigError::new(Some(2), "invalid IPv4 address: 300.0.0.1".to_string())
+        );
+        assert_eq!(
+            error_of("[zone]\n").message,
+            "zone sections need an origin: [zone example.com]"
+        );
+        assert!(error_of("[listeners]\nlisten = 0.0.0.0\n").line == Some(2));
+        assert!(error_of("[acl]\nallow = 10.0.0.0/40\n").line == Some(2));
+        assert!(error_of("[server]\nreactors = 2\n").line == Some(2));
+        assert_eq!(
+            error_of("[cache]\nprefetch_percent = 120\n").message,
+            "expected a percentage, got 120"
+        );
+        assert_eq!(
+            error_of("[cache]\nmax_bytes = 64X\n").message,
+            "invalid number 64X"
+        );
+        assert_eq!(
+            error_of("[server]\nchroot = /nonexistent\n").message,
+            "chroot /nonexistent is not a directory"
+        );
+        assert_eq!(
+            error_of("[listeners]\nlisten = tls/0.0.0.0:853\n").message,
+            "TLS listeners need a certificate and a key in [tls]"
+        );
+        assert_eq!(
+            error_of("[tls]\ncertificate = /etc/dns/cert.pem\n").message,
+            "[tls] needs both a certificate and a key"
+        );
+    }
+
+    #[test]
+    fn rejects_config_with_nothing_to_serve() {
+        assert_eq!(
+            error_of("[upstreams]\ntimeout = 100\n"),
+            ConfigError::new(
+                None,
+                "nothing to serve: configure an upstream server or a zone".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn lists_changes_requiring_restart() {
+        let config = "[zone example.com]\nrecord = www A 192.0.2.1\n"
+            .parse::<Config>()
+            .unwrap();
+        let reloaded = "[zone example.com]\nrecord = www A 192.0.2.2\n\
+                        [logging]\nlevel = debug\n\
+                        [tcp]\nmax_queries = 5\n\
+                        [acl]\ndeny = 10.0.0.0/8\n"
+            .parse::<Config>()
+            .unwrap();
+
+        assert!(config.changes_requiring_restart(&config).is_empty());
+        assert_eq!(
+            config.changes_requiring_restart(&reloaded),
+            vec!["tcp", "acl"]
+        );
+    }
+
+    #[test]
+    fn displays_error_location() {
+        let error = ConfigError {
+            path: Some(PathBuf::from("/etc/dns.conf")),
+            line: Some(12),
+            message: "unknown section [cluster]".to_string(),
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "/etc/dns.conf:12: unknown section [cluster]"
+        );
+        assert_eq!(
+            ConfigError::new(None, "oops".to_string()).to_string(),
+            "oops"
+        );
+    }
+
+    #[test]
+    fn reports_missing_file() {
+        let error = Config::load(Path::new("/nonexistent/dns.conf")).unwrap_err();
+
+        assert_eq!(error.path, Some(PathBuf::from("/nonexistent/dns.conf")));
+        assert!(
+            error
+                .message
+                .starts_with("could not read configuration file")
+        );
+    }
+}
@@ -0,0 +1,103 @@
+// Running in the background, the classic way: fork, new session, fork again
+
+use std::{
+    fs,
+    io::{Error, Result},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Detaches the process from its terminal, only the grandchild returns
+/// stdin, stdout and stderr are redirected to /dev/null, so logs should go to a file
+pub fn daemonize() -> Result<()> {
+    fork_and_exit_parent()?;
+
+    // SAFETY: no pointer involved, fails only when already a process group leader,
+    // which the child of a fork never is
+    if unsafe { libc::setsid() } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // the session leader exits so that the daemon can never acquire a terminal again
+    fork_and_exit_parent()?;
+
+    redirect_standard_streams()
+}
+
+fn fork_and_exit_parent() -> Result<()> {
+    // SAFETY: called before any thread is spawned, the child only keeps running this thread
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+fn redirect_standard_streams() -> Result<()> {
+    let dev_null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: both file descriptors are open, dup2 closes fd before reusing it
+        if unsafe { libc::dup2(dev_null.as_raw_fd(), fd) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Holds the process id in a file for as long as it lives
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    pub fn create(path: &Path) -> Result<Self> {
+        fs::write(path, format!("{}\n", process::id()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Process id written by a running server
+    pub fn read(path: &Path) -> Result<libc::pid_t> {
+        fs::read_to_string(path)?
+            .trim()
+            .parse()
+            .map_err(|_| Error::other(format!("no process id in {}", path.display())))
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn writes_and_removes_pidfile() {
+        let path = env::temp_dir().join(format!("dns-daemon-test-{}.pid", process::id()));
+
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        assert_eq!(Pidfile::read(&path).unwrap(), process::id() as libc::pid_t);
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -1,39 +1,76 @@
 use crate::{
     common::domain_name::{DomainName, Label},
+    decoder::DecodingError,
     utils::concat_two_u8s,
 };
 
 pub const ALIAS_FLAG: u8 = 0b11000000;
 
-pub fn decode<'a>(bytes: &'a [u8], source: &'a [u8]) -> (DomainName, &'a [u8]) {
+// guards against compression pointers looping on each other
+const MAX_ALIAS_DEPTH: usize = 32;
+
+pub fn decode<'a>(
+    bytes: &'a [u8],
+    source: &'a [u8],
+) -> Result<(DomainName, &'a [u8]), DecodingError> {
+    decode_with_depth(bytes, source, 0)
+}
+
+fn decode_with_depth<'a>(
+    bytes: &'a [u8],
+    source: &'a [u8],
+    depth: usize,
+) -> Result<(DomainName, &'a [u8]), DecodingError> {
+    if depth > MAX_ALIAS_DEPTH {
+        return Err(DecodingError::InvalidDomainName(
+            "Too many compression pointers".to_string(),
+        ));
+    }
+
     let mut buffer = bytes.iter();
     let mut labels = vec![];
 
     while let Some(x) = buffer.next() {
         if *x == 0 {
             labels.push(Label::new()); // this allows us to add the ending . in the name
-            break;
+            return Ok((DomainName { labels }, buffer.as_slice()));
         }
 
         if is_alias_flag(*x) {
+            let next = buffer.next().ok_or(DecodingError::UnexpectedEndOfBuffer)?;
             // take next byte, concat with current and counter detection flag on result to get 14th last bits
-            let alias_position_in_source =
-                concat_two_u8s(*x, *buffer.next().unwrap()) & !((ALIAS_FLAG as u16) << 8);
+            let alias_position_in_source = concat_two_u8s(*x, *next) & !((ALIAS_FLAG as u16) << 8);
 
             // read the alias from the source buffer and add its labels to the current label list
-            labels.append(&mut from_source(source, alias_position_in_source as usize).labels);
-            return (DomainName { labels }, buffer.as_slice());
+            labels.append(
+                &mut from_source(source, alias_position_in_source as usize, depth + 1)?.labels,
+            );
+            return Ok((DomainName { labels }, buffer.as_slice()));
         }
 
-        let label = (0..*x).map(|_| *buffer.next().unwrap() as char).collect();
+        let label = (0..*x)
+            .map(|_| {
+                buffer
+                    .next()
+                    .map(|c| *c as char)
+                    .ok_or(DecodingError::UnexpectedEndOfBuffer)
+            })
+            .collect::<Result<Label, DecodingError>>()?;
         labels.push(label);
     }
-    (DomainName { labels }, buffer.as_slice())
+
+    // a domain name must end with the root label or a compression pointer
+    Err(DecodingError::UnexpectedEndOfBuffer)
 }
 
-fn from_source(source: &[u8], position: usize) -> DomainName {
-    let trimmed_source = &<&[u8]>::clone(&source).to_vec()[position..];
-    decode(trimmed_source, source).0
+fn from_source(source: &[u8], position: usize, depth: usize) -> Result<DomainName, DecodingError> {
+    let trimmed_source = source.get(position..).ok_or_else(|| {
+        DecodingError::InvalidDomainName(format!(
+            "Compression pointer {} out of message bounds",
+            position
+        ))
+    })?;
+    decode_with_depth(trimmed_source, source, depth).map(|(name, _)| name)
 }
 
 pub fn is_alias_flag(byte: u8) -> bool {
@@ -47,7 +84,7 @@ mod tests {
     #[test]
     fn test_domain_name_from_buffer() {
         const BUFFER: &[u8] = &[3, b'a', b'b', b'c', 3, b'd', b'e', b'f', 2, b'g', b'h', 0];
-        let (domain_name, rest) = decode(BUFFER, BUFFER);
+        let (domain_name, rest) = decode(BUFFER, BUFFER).unwrap();
         assert_eq!("abc.def.gh.", domain_name.to_string(),);
         assert_eq!(rest, &[]);
     }
@@ -61,7 +98,7 @@ mod tests {
             0, 1, // question class
         ];
 
-        let (domain_name, rest) = decode(BUFFER, BUFFER);
+        let (domain_name, rest) = decode(BUFFER, BUFFER).unwrap();
 
         assert_eq!("wpad.numericable.fr.", domain_name.to_string());
         assert_eq!(
@@ -92,12 +129,14 @@ mod tests {
         let (original_name, _) = decode(
             &buffer_containing_alias[12..], // 12 is the position at which the original name is defined in the buffer
             buffer_containing_alias,
-        );
+        )
+        .unwrap();
 
         let (aliased_name, _) = decode(
             &buffer_containing_alias[28..], // 28 is the position at which the alias to the original name is
             buffer_containing_alias,
-        );
+        )
+        .unwrap();
 
         assert_eq!(original_name, aliased_name);
         assert_eq!(DomainName::from("google.com"), aliased_name);
@@ -123,7 +162,8 @@ mod tests {
         let (aliased_name, _) = decode(
             &buffer_containing_alias[28..], // 28 is the position at which the alias to the original name is
             buffer_containing_alias,
-        );
+        )
+        .unwrap();
 
         assert_eq!(DomainName::from("www.google.com"), aliased_name);
     }
@@ -148,7 +188,8 @@ mod tests {
         let (aliased_name, _) = decode(
             &buffer_containing_alias[28..], // 28 is the position at which the alias to the original name is
             buffer_containing_alias,
-        );
+        )
+        .unwrap();
 
         assert_eq!(DomainName::from("info.google.com"), aliased_name);
     }
@@ -180,17 +221,50 @@ mod tests {
         let (first_alias, _) = decode(
             &buffer_containing_alias[28..], // 28 is the position at which the alias to the original name is
             buffer_containing_alias,
-        );
+        )
+        .unwrap();
 
         let (second_alias, _) = decode(
             &buffer_containing_alias[49..], // 28 is the position at which the alias to the original name is
             buffer_containing_alias,
-        );
+        )
+        .unwrap();
 
         assert_eq!(DomainName::from("info.google.com"), first_alias);
         assert_eq!(DomainName::from("www.info.google.com"), second_alias);
     }
 
+    #[test]
+    fn test_truncated_domain_name() {
+        const BUFFER: &[u8] = &[3, b'a', b'b', b'c', 3, b'd', b'e'];
+
+        assert_eq!(
+            DecodingError::UnexpectedEndOfBuffer,
+            decode(BUFFER, BUFFER).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_alias_out_of_bounds() {
+        const BUFFER: &[u8] = &[3, b'w', b'w', b'w', 192, 200];
+
+        assert!(matches!(
+            decode(BUFFER, BUFFER).unwrap_err(),
+            DecodingError::InvalidDomainName(_)
+        ));
+    }
+
+    #[test]
+    fn test_alias_loop() {
+        // pointer to itself
+        const BUFFER: &[u8] = &[192, 0];
+
+        assert!(matches!(
+            decode(BUFFER, BUFFER).unwrap_err(),
+            DecodingError::InvalidDomainName(_)
+        ));
+    }
+
     #[test]
     fn test_to_string() {
         assert_eq!(
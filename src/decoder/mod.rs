@@ -1,4 +1,4 @@
-mod domain_name;
+pub mod domain_name;
 mod header;
 mod opt_record;
 mod question;
@@ -28,8 +28,11 @@ pub enum DecodingError {
     InvalidQuestionType(String),
     InvalidQuestionClass(String),
     InvalidOptRecord(String),
+    InvalidDomainName(String),
+    InvalidResourceData(String),
     MultipleOptRecords,
     ResourceDataLengthMismatch { expected: usize, actual: usize },
+    UnexpectedEndOfBuffer,
 }
 
 pub struct MessageDecoder {}
@@ -38,6 +41,10 @@ impl Decoder for MessageDecoder {
     fn decode(&self, buffer: &[u8]) -> Result<Message, DecodingError> {
         let source = buffer;
 
+        if buffer.len() < HEADER_BIT_SIZE / 8 {
+            return Err(DecodingError::InvalidHeaderSize);
+        }
+
         let (header_bits, mut buffer) = extract_header_bits_from_buffer(buffer);
         let header_bits: &[u8; HEADER_BIT_SIZE / 8] = header_bits
             .try_into()
@@ -98,6 +105,15 @@ impl Decoder for MessageDecoder {
     }
 }
 
+/// Makes sure the buffer holds at least `length` more bytes before reading them
+pub fn ensure_remaining(buffer: &[u8], length: usize) -> Result<(), DecodingError> {
+    if buffer.len() < length {
+        return Err(DecodingError::UnexpectedEndOfBuffer);
+    }
+
+    Ok(())
+}
+
 fn is_opt_record(buffer: &[u8]) -> Result<bool, DecodingError> {
     let type_value = peek_rr_type(buffer)?;
 
@@ -408,6 +424,41 @@ mod tests {
         assert_eq!(expected_message, message);
     }
 
+    #[test]
+    fn test_decode_message_shorter_than_header() {
+        let result = MessageDecoder {}.decode(&[226, 44, 1, 0]);
+
+        assert_eq!(Err(DecodingError::InvalidHeaderSize), result);
+    }
+
+    #[test]
+    fn test_decode_message_with_truncated_question() {
+        let buffer = [
+            226, 44, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, // header
+            6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0, // google.com
+            0, 1, // type: A, class is missing
+        ];
+
+        let result = MessageDecoder {}.decode(&buffer);
+
+        assert_eq!(Err(DecodingError::UnexpectedEndOfBuffer), result);
+    }
+
+    #[test]
+    fn test_decode_message_with_missing_question() {
+        // header announces 2 questions but only one is present
+        let buffer = [
+            226, 44, 1, 0, 0, 2, 0, 0, 0, 0, 0, 0, // header
+            6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0, // google.com
+            0, 1, // type: A
+            0, 1, // class: IN
+        ];
+
+        let result = MessageDecoder {}.decode(&buffer);
+
+        assert_eq!(Err(DecodingError::UnexpectedEndOfBuffer), result);
+    }
+
     #[test]
     fn test_decode_message_with_two_questions_using_compression() {
         // Message with two questions where the second references the first's domain name
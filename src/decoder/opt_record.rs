@@ -1,10 +1,13 @@
 use crate::{
     common::opt_record::{EdnsOption, OptRecord},
-    decoder::DecodingError,
+    decoder::{DecodingError, ensure_remaining},
     utils::extract_next_sixteen_bits_from_buffer,
 };
 
 pub fn decode(buffer: &[u8]) -> Result<(OptRecord, &[u8]), DecodingError> {
+    // NAME (1) + TYPE (2) + CLASS (2) + TTL (4) + RDLEN (2)
+    ensure_remaining(buffer, 11)?;
+
     // record name must always be 0 (root domain) for OPT
     if buffer[0] != 0 {
         return Err(DecodingError::InvalidOptRecord(
@@ -30,6 +33,7 @@ pub fn decode(buffer: &[u8]) -> Result<(OptRecord, &[u8]), DecodingError> {
     let (rdlen, buffer) = extract_next_sixteen_bits_from_buffer(buffer);
 
     // RDATA contains options
+    ensure_remaining(buffer, rdlen as usize)?;
     let (rdata, buffer) = buffer.split_at(rdlen as usize);
 
     let options = decode_options(rdata)?;
@@ -103,7 +107,7 @@ mod tests {
         assert_eq!(opt.udp_payload_size, 4096);
         assert_eq!(opt.extended_rcode, 0);
         assert_eq!(opt.version, 0);
-        assert_eq!(opt.dnssec_ok, false);
+        assert!(!opt.dnssec_ok);
         assert!(opt.options.is_empty());
         assert!(remaining.is_empty());
     }
@@ -121,7 +125,7 @@ mod tests {
 
         let (opt, _) = decode(&buffer).unwrap();
 
-        assert_eq!(opt.dnssec_ok, true);
+        assert!(opt.dnssec_ok);
     }
 
     #[test]
@@ -160,12 +164,27 @@ mod tests {
         assert!(matches!(result, Err(DecodingError::InvalidOptRecord(_))));
     }
 
+    #[test]
+    fn decode_opt_record_rdata_exceeding_buffer() {
+        let buffer = [
+            0, // NAME
+            0, 41, // TYPE
+            0x10, 0x00, // CLASS
+            0x00, 0x00, 0x00, 0x00, // TTL
+            0x00, 0x08, // RDLEN: 8 bytes announced
+            0x00, 0x0a, // only 2 bytes present
+        ];
+
+        let result = decode(&buffer);
+        assert_eq!(result, Err(DecodingError::UnexpectedEndOfBuffer));
+    }
+
     #[test]
     fn test_opt_record_decode_ttl() {
         let (extended_rcode, version, dnssec_ok) = decode_ttl(0xAB008000);
         assert_eq!(extended_rcode, 0xAB);
         assert_eq!(version, 0);
-        assert_eq!(dnssec_ok, true);
+        assert!(dnssec_ok);
     }
 
     #[test]
@@ -173,6 +192,6 @@ mod tests {
         let (extended_rcode, version, dnssec_ok) = decode_ttl(0x12000000);
         assert_eq!(extended_rcode, 0x12);
         assert_eq!(version, 0);
-        assert_eq!(dnssec_ok, false);
+        assert!(!dnssec_ok);
     }
 }
@@ -1,6 +1,6 @@
 use crate::{
     common::question::{Class, Question, Type},
-    decoder::DecodingError,
+    decoder::{DecodingError, ensure_remaining},
     utils::extract_next_sixteen_bits_from_buffer,
 };
 
@@ -10,7 +10,8 @@ pub fn decode<'a>(
     buffer: &'a [u8],
     source: &'a [u8],
 ) -> Result<(Question, &'a [u8]), DecodingError> {
-    let (name, buffer) = decode_domain_name(buffer, source);
+    let (name, buffer) = decode_domain_name(buffer, source)?;
+    ensure_remaining(buffer, 4)?;
     let (type_bytes, buffer) = extract_next_sixteen_bits_from_buffer(buffer);
     let (class_bytes, buffer) = extract_next_sixteen_bits_from_buffer(buffer);
 
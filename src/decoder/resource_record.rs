@@ -3,7 +3,8 @@ use crate::{
         question::Class,
         resource_record::{ResourceRecord, Type},
     },
-    decoder::DecodingError,
+    decoder::{DecodingError, ensure_remaining},
+    encoder::domain_name::encode as encode_domain_name,
     utils::{extract_next_sixteen_bits_from_buffer, extract_next_thirty_two_bits_from_buffer},
 };
 use std::net::{Ipv4Addr, Ipv6Addr};
@@ -14,7 +15,10 @@ pub fn decode<'a>(
     buffer: &'a [u8],
     source: &'a [u8],
 ) -> Result<(ResourceRecord, &'a [u8]), DecodingError> {
-    let (name, buffer) = decode_domain_name(buffer, source);
+    let (name, buffer) = decode_domain_name(buffer, source)?;
+
+    // type (2) + class (2) + ttl (4) + resource data length (2)
+    ensure_remaining(buffer, 10)?;
 
     let (type_, buffer) = extract_next_sixteen_bits_from_buffer(buffer);
     let type_ = Type::try_from(type_).map_err(DecodingError::InvalidResourceRecordType)?;
@@ -34,7 +38,7 @@ pub fn decode<'a>(
     }
     let (data, buffer) = buffer.split_at(resource_data_length as usize);
 
-    let resource_data = decode_data_from_type_and_buffer(type_, data, source);
+    let resource_data = decode_data_from_type_and_buffer(type_, data, source)?;
 
     Ok((
         ResourceRecord::new(name, type_, class, ttl, resource_data),
@@ -42,76 +46,111 @@ pub fn decode<'a>(
     ))
 }
 
-fn decode_data_from_type_and_buffer(type_: Type, buffer: &[u8], source: &[u8]) -> Vec<u8> {
+fn decode_data_from_type_and_buffer(
+    type_: Type,
+    buffer: &[u8],
+    source: &[u8],
+) -> Result<Vec<u8>, DecodingError> {
     match type_ {
         Type::A => decode_type_a_data(buffer),
         Type::AAAA => decode_type_aaaa_data(buffer),
         Type::TXT => decode_type_txt_data(buffer),
         Type::MX => decode_type_mx_data(buffer),
         Type::CNAME | Type::NS | Type::PTR => decode_record_type_as_domain_name(buffer, source),
-        _ => buffer.to_vec(), // Pass through opaque data
+        Type::SOA => decode_type_soa_data(buffer, source),
+        _ => Ok(buffer.to_vec()), // Pass through opaque data
     }
 }
 
-fn decode_type_a_data(buffer: &[u8]) -> Vec<u8> {
-    assert!(buffer.len() == 4);
-    Ipv4Addr::new(buffer[0], buffer[1], buffer[2], buffer[3])
-        .octets()
-        .to_vec()
+fn decode_type_a_data(buffer: &[u8]) -> Result<Vec<u8>, DecodingError> {
+    let octets: [u8; 4] = buffer.try_into().map_err(|_| {
+        DecodingError::InvalidResourceData("A record must be exactly 4 bytes".to_string())
+    })?;
+    Ok(Ipv4Addr::from(octets).octets().to_vec())
 }
 
-fn decode_type_aaaa_data(buffer: &[u8]) -> Vec<u8> {
-    assert_eq!(buffer.len(), 16, "AAAA record must be exactly 16 bytes");
-    let octets: [u8; 16] = buffer.try_into().expect("Invalid AAAA record length");
-    Ipv6Addr::from(octets).octets().to_vec()
+fn decode_type_aaaa_data(buffer: &[u8]) -> Result<Vec<u8>, DecodingError> {
+    let octets: [u8; 16] = buffer.try_into().map_err(|_| {
+        DecodingError::InvalidResourceData("AAAA record must be exactly 16 bytes".to_string())
+    })?;
+    Ok(Ipv6Addr::from(octets).octets().to_vec())
 }
 
-fn decode_type_txt_data(buffer: &[u8]) -> Vec<u8> {
-    let expected_length = buffer[0] as usize;
-    String::from_utf8_lossy(&buffer[1..=expected_length])
-        .as_bytes()
-        .to_vec()
+fn decode_type_txt_data(buffer: &[u8]) -> Result<Vec<u8>, DecodingError> {
+    let expected_length = *buffer.first().ok_or_else(|| {
+        DecodingError::InvalidResourceData("TXT record must have a length byte".to_string())
+    })? as usize;
+    let text = buffer.get(1..=expected_length).ok_or_else(|| {
+        DecodingError::InvalidResourceData("TXT record is shorter than its length".to_string())
+    })?;
+    Ok(String::from_utf8_lossy(text).as_bytes().to_vec())
 }
 
-fn decode_type_mx_data(buffer: &[u8]) -> Vec<u8> {
+fn decode_type_mx_data(buffer: &[u8]) -> Result<Vec<u8>, DecodingError> {
     // MX record format: preference (2 bytes) + domain name
-    assert!(
-        buffer.len() >= 2,
-        "MX record must have at least 2 bytes for preference"
-    );
+    if buffer.len() < 2 {
+        return Err(DecodingError::InvalidResourceData(
+            "MX record must have at least 2 bytes for preference".to_string(),
+        ));
+    }
 
     let (_, domain_buffer) = extract_next_sixteen_bits_from_buffer(buffer);
 
     // Verify domain name is present and properly formatted
-    assert!(
-        !domain_buffer.is_empty(),
-        "MX record must contain a domain name after preference"
-    );
+    if domain_buffer.is_empty() {
+        return Err(DecodingError::InvalidResourceData(
+            "MX record must contain a domain name after preference".to_string(),
+        ));
+    }
 
     // Decode the domain name to verify it's valid and buffer is fully consumed
-    let (_, remaining) = decode_domain_name(domain_buffer, domain_buffer);
+    let (_, remaining) = decode_domain_name(domain_buffer, domain_buffer)?;
 
-    // Assert that the entire buffer has been consumed
-    assert!(
-        remaining.is_empty(),
-        "Buffer must be empty after decoding MX record"
-    );
+    // Make sure that the entire buffer has been consumed
+    if !remaining.is_empty() {
+        return Err(DecodingError::InvalidResourceData(
+            "Buffer must be empty after decoding MX record".to_string(),
+        ));
+    }
 
     // Return the original buffer as-is
-    buffer.to_vec()
+    Ok(buffer.to_vec())
 }
 
-fn decode_record_type_as_domain_name(buffer: &[u8], source: &[u8]) -> Vec<u8> {
-    let (_, remaining) = decode_domain_name(buffer, source);
-
-    // Assert that the entire buffer has been consumed
-    assert!(
-        remaining.is_empty(),
-        "Buffer must be empty after decoding MX record"
-    );
+fn decode_record_type_as_domain_name(
+    buffer: &[u8],
+    source: &[u8],
+) -> Result<Vec<u8>, DecodingError> {
+    let (_, remaining) = decode_domain_name(buffer, source)?;
+
+    // Make sure that the entire buffer has been consumed
+    if !remaining.is_empty() {
+        return Err(DecodingError::InvalidResourceData(
+            "Buffer must be empty after decoding domain name record".to_string(),
+        ));
+    }
 
     // Domain names are stored as-is in the resource data
-    buffer.to_vec()
+    Ok(buffer.to_vec())
+}
+
+/// SOA record format: primary name server + mailbox + serial, refresh, retry, expire and minimum
+/// The names are expanded, negative answers are served from the cache long after the message
+/// their compression pointers point into is gone
+fn decode_type_soa_data(buffer: &[u8], source: &[u8]) -> Result<Vec<u8>, DecodingError> {
+    let (primary_name_server, remaining) = decode_domain_name(buffer, source)?;
+    let (mailbox, remaining) = decode_domain_name(remaining, source)?;
+
+    if remaining.len() != 20 {
+        return Err(DecodingError::InvalidResourceData(
+            "SOA record must end with exactly 20 bytes of timers".to_string(),
+        ));
+    }
+
+    let mut data = encode_domain_name(primary_name_server);
+    data.extend(encode_domain_name(mailbox));
+    data.extend_from_slice(remaining);
+    Ok(data)
 }
 
 #[cfg(test)]
@@ -182,7 +221,7 @@ mod tests {
     fn decode_aaaa_loopback() {
         let buffer = "::1".parse::<std::net::Ipv6Addr>().unwrap().octets();
 
-        let result = decode_type_aaaa_data(&buffer);
+        let result = decode_type_aaaa_data(&buffer).unwrap();
         assert_eq!(
             result,
             vec![
@@ -199,7 +238,7 @@ mod tests {
             .unwrap()
             .octets();
 
-        let result = decode_type_aaaa_data(&buffer);
+        let result = decode_type_aaaa_data(&buffer).unwrap();
         assert_eq!(
             result,
             vec![
@@ -210,9 +249,31 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "AAAA record must be exactly 16 bytes")]
     fn decode_aaaa_invalid_length() {
-        decode_type_aaaa_data(&[1, 2, 3, 4]);
+        assert_eq!(
+            decode_type_aaaa_data(&[1, 2, 3, 4]),
+            Err(DecodingError::InvalidResourceData(
+                "AAAA record must be exactly 16 bytes".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn decode_a_invalid_length() {
+        assert_eq!(
+            decode_type_a_data(&[1, 2, 3]),
+            Err(DecodingError::InvalidResourceData(
+                "A record must be exactly 4 bytes".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn decode_txt_shorter_than_its_length() {
+        assert!(matches!(
+            decode_type_txt_data(&[5, b'f', b'o', b'o']),
+            Err(DecodingError::InvalidResourceData(_))
+        ));
     }
 
     #[test]
@@ -318,7 +379,7 @@ mod tests {
             b'm', 0, // exchange: "mail.example.com"
         ];
 
-        let result = decode_type_mx_data(&buffer);
+        let result = decode_type_mx_data(&buffer).unwrap();
 
         // Should return the entire buffer
         assert_eq!(result, buffer.to_vec());
@@ -326,20 +387,27 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "MX record must have at least 2 bytes for preference")]
     fn decode_type_mx_data_empty_buffer() {
-        decode_type_mx_data(&[]);
+        assert_eq!(
+            decode_type_mx_data(&[]),
+            Err(DecodingError::InvalidResourceData(
+                "MX record must have at least 2 bytes for preference".to_string()
+            ))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "MX record must contain a domain name after preference")]
     fn decode_type_mx_data_missing_domain() {
         // Only preference, no domain name
-        decode_type_mx_data(&[0, 10]);
+        assert_eq!(
+            decode_type_mx_data(&[0, 10]),
+            Err(DecodingError::InvalidResourceData(
+                "MX record must contain a domain name after preference".to_string()
+            ))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Buffer must be empty after decoding MX record")]
     fn decode_type_mx_data_extra_bytes_after_domain() {
         // Valid MX record but with extra bytes at the end
         let buffer = [
@@ -348,7 +416,12 @@ mod tests {
             1, 2, 3, // extra bytes that shouldn't be here
         ];
 
-        decode_type_mx_data(&buffer);
+        assert_eq!(
+            decode_type_mx_data(&buffer),
+            Err(DecodingError::InvalidResourceData(
+                "Buffer must be empty after decoding MX record".to_string()
+            ))
+        );
     }
 
     #[test]
@@ -382,26 +455,70 @@ mod tests {
         assert!(buffer.is_empty(), "Buffer should be empty after decoding");
     }
 
+    #[test]
+    fn decode_soa_resource_record_expanding_compressed_names() {
+        let buffer = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, // name: "example.com"
+            0, 6, // type: SOA (6)
+            0, 1, // class: IN (1)
+            0, 0, 14, 16, // ttl: 3600 seconds
+            0, 33, // resource data length: 33 bytes
+            3, b'n', b's', b'1', 0xc0,
+            0, // primary name server: "ns1" + pointer to "example.com"
+            4, b'h', b'o', b's', b't', 0xc0, 0, // mailbox: "host" + pointer to "example.com"
+            0, 0, 0, 1, // serial
+            0, 0, 28, 32, // refresh: 7200
+            0, 0, 14, 16, // retry: 3600
+            0, 18, 117, 0, // expire: 1209600
+            0, 0, 1, 44, // minimum: 300
+        ];
+
+        let (rr, buffer) = decode(&buffer, &buffer).unwrap();
+
+        let mut expected_data = encode_domain_name(DomainName::from("ns1.example.com"));
+        expected_data.extend(encode_domain_name(DomainName::from("host.example.com")));
+        expected_data.extend([
+            0, 0, 0, 1, 0, 0, 28, 32, 0, 0, 14, 16, 0, 18, 117, 0, 0, 0, 1, 44,
+        ]);
+        assert_eq!(rr.type_, Type::SOA);
+        assert_eq!(rr.resource_data, expected_data);
+        assert!(buffer.is_empty(), "Buffer should be empty after decoding");
+    }
+
+    #[test]
+    fn decode_soa_resource_record_without_timers() {
+        let buffer = [
+            3, b'n', b's', b'1', 0, 4, b'h', b'o', b's', b't', 0, 0, 0, 0, 1,
+        ];
+
+        assert!(decode_type_soa_data(&buffer, &buffer).is_err());
+    }
+
     #[test]
     fn decode_record_type_as_domain_name_validates_buffer() {
         let buffer = [
             7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
         ];
 
-        let result = decode_record_type_as_domain_name(&buffer, &buffer);
+        let result = decode_record_type_as_domain_name(&buffer, &buffer).unwrap();
 
         assert_eq!(result, buffer.to_vec());
     }
 
     #[test]
-    #[should_panic(expected = "Buffer must be empty after decoding MX record")]
     fn decode_record_type_as_domain_name_extra_bytes() {
         let buffer = [
             7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 1, 2,
             3, // extra bytes
         ];
 
-        decode_record_type_as_domain_name(&buffer, &buffer);
+        assert_eq!(
+            decode_record_type_as_domain_name(&buffer, &buffer),
+            Err(DecodingError::InvalidResourceData(
+                "Buffer must be empty after decoding domain name record".to_string()
+            ))
+        );
     }
 
     #[test]
@@ -5,7 +5,7 @@ use self::{
     resource_record::encode as encode_resource_record,
 };
 
-mod domain_name;
+pub mod domain_name;
 mod header;
 mod opt_record;
 mod question;
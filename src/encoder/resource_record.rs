@@ -3,6 +3,7 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 use crate::{
     common::resource_record::{ResourceRecord, Type},
     encoder::domain_name::encode as encode_domain_name,
+    log::debug,
     utils::{push_u16_to_u8_vec, push_u32_to_u8_vec},
 };
 
@@ -38,7 +39,7 @@ fn encode_resource_data_from_type_and_string(type_: Type, value: Vec<u8>) -> Vec
         Type::TXT => encode_type_txt_string(value),
         Type::CNAME | Type::NS | Type::MX | Type::PTR => value,
         t => {
-            println!("⏭️ Pass through record data type encoding {:?}", t);
+            debug!("⏭️ Pass through record data type encoding {:?}", t);
             value
         }
     }
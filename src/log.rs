@@ -0,0 +1,134 @@
+// Minimal leveled logger, writes to stdout or to a file configured at startup
+
+use std::{
+    fmt::{Arguments, Display},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU8, Ordering},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(format!("Unknown log level: {}", s)),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        };
+        write!(f, "{}", level)
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+static FILE: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+
+/// Sets the most verbose level written, and the file to append to instead of stdout
+pub fn init(level: Level, file: Option<&Path>) -> std::io::Result<()> {
+    let writer = file
+        .map(|path| OpenOptions::new().create(true).append(true).open(path))
+        .transpose()?
+        .map(BufWriter::new);
+
+    flush();
+    *FILE.lock().unwrap_or_else(|p| p.into_inner()) = writer;
+    LEVEL.store(level as u8, Ordering::Relaxed);
+
+    Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn write(level: Level, args: Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let mut file = FILE.lock().unwrap_or_else(|p| p.into_inner());
+    match file.as_mut() {
+        Some(file) => {
+            let _ = writeln!(file, "{}", args);
+        }
+        None => println!("{}", args),
+    }
+}
+
+/// Writes the buffered lines out, to be called before exiting
+pub fn flush() {
+    if let Some(file) = FILE.lock().unwrap_or_else(|p| p.into_inner()).as_mut() {
+        let _ = file.flush();
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+// `warn` would clash with the builtin attribute
+pub(crate) use {debug, error, info, warning};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!("WARN".parse::<Level>(), Ok(Level::Warn));
+        assert_eq!("debug".parse::<Level>(), Ok(Level::Debug));
+        assert!("verbose".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn orders_levels_by_verbosity() {
+        assert!(Level::Error < Level::Warn);
+        assert!(Level::Info < Level::Debug);
+    }
+}
@@ -1,64 +1,319 @@
 // https://datatracker.ietf.org/doc/html/rfc1035
 
+use std::{env, io, process, thread};
+
+use cli::{Command, Options};
+use config::{Config, Zone};
 use decoder::MessageDecoder;
 use encoder::MessageEncoder;
-use server::Server;
+use server::{ActivatedSockets, Server, ServerHandle};
 
 use crate::{
-    common::{
-        domain_name::DomainName,
-        resource_record::{ResourceRecord, Type},
+    log::{error, info, warning},
+    storage::{
+        AuthoritativeRecords, InMemoryResourceRecordRepository,
+        combined::CombinedRepository,
+        fallback::FallbackRepository,
+        persistence::{self, CacheFileError},
     },
-    storage::{InMemoryResourceRecordRepository, fallback::FallbackRepository},
 };
 
+mod cli;
 mod common;
+mod config;
+mod daemon;
 mod decoder;
 mod encoder;
+mod log;
+mod privileges;
 mod server;
+mod signals;
 mod storage;
 mod transport;
 mod utils;
+mod worker_pool;
 
 fn main() {
-    let mut in_memory_repository = InMemoryResourceRecordRepository::new();
-
-    in_memory_repository.save(ResourceRecord::new(
-        DomainName::from("google.com"),
-        Type::A,
-        common::question::Class::IN,
-        3600,
-        vec![74, 125, 193, 101], // Google's IPv4 address
-    ));
-
-    let text_content = "some content for google.com".as_bytes().to_vec();
-    in_memory_repository.save(ResourceRecord::new(
-        DomainName::from("google.com"),
-        Type::TXT,
-        common::question::Class::IN,
-        3600,
-        text_content,
-    ));
-
-    in_memory_repository.save(ResourceRecord::new(
-        DomainName::from("google.com"),
-        Type::AAAA,
-        common::question::Class::IN,
-        3600,
-        "2607:f8b0:4004:c07::71"
-            .parse::<std::net::Ipv6Addr>()
-            .unwrap()
-            .octets()
-            .to_vec(), // Google IPv6 address
-    ));
-
-    let fallback_repository = FallbackRepository {
-        fallback_server_address: "8.8.8.8:53",
+    let options = match Command::parse(env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("{}", cli::VERSION);
+            return;
+        }
+        Err(e) => {
+            eprintln!("💣🔥 {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    if options.reload {
+        request_reload(&options);
+        return;
+    }
+
+    // a configuration file replaces the DNS_* environment variables altogether,
+    // flags override both
+    let config = options.load_config().unwrap_or_else(|e| {
+        eprintln!("💣🔥 Invalid configuration: {}", e);
+        process::exit(1);
+    });
+
+    if options.check_config {
+        println!("✅ Configuration is valid");
+        return;
+    }
+
+    if let Err(e) = log::init(config.log_level, config.log_file.as_deref()) {
+        eprintln!("💣🔥 Could not open the log file: {}", e);
+        process::exit(1);
+    }
+
+    // LISTEN_PID names this process, it has to be checked before daemonizing
+    let activated_sockets = ActivatedSockets::from_env().unwrap_or_else(|e| {
+        error!("💣🔥 Could not use the sockets passed by systemd: {}", e);
+        process::exit(1);
+    });
+
+    if options.daemon
+        && let Err(e) = daemon::daemonize()
+    {
+        eprintln!("💣🔥 Could not run in the background: {}", e);
+        process::exit(1);
+    }
+
+    // removed when main returns
+    let _pidfile = options.pidfile.as_deref().map(|path| {
+        daemon::Pidfile::create(path).unwrap_or_else(|e| {
+            error!("💣🔥 Could not write the pidfile {}: {}", path.display(), e);
+            process::exit(1);
+        })
+    });
+
+    // blocked before any thread is spawned, the cache ones too, so that they all inherit the mask
+    if let Err(e) = signals::block(&HANDLED_SIGNALS) {
+        error!("💣🔥 Could not block signals: {}", e);
+        process::exit(1);
+    }
+
+    let authoritative_records = AuthoritativeRecords::new(load_zones(&config.zones));
+
+    let fallback_repository = (!config.upstreams.is_empty()).then(|| FallbackRepository {
+        fallback_server_addresses: config.upstreams.clone(),
+        timeout: config.upstream_timeout,
         decoder: MessageDecoder {},
         encoder: MessageEncoder {},
+    });
+
+    let mut storage = CombinedRepository::new(authoritative_records.clone(), fallback_repository)
+        .with_stale_window(config.stale_window)
+        .with_prefetch(config.prefetch);
+    if let Some(max_cached_records) = config.max_cached_records {
+        storage = storage.with_max_cached_records(max_cached_records);
+    }
+    if let Some(max_cache_bytes) = config.max_cache_bytes {
+        storage = storage.with_max_cache_bytes(max_cache_bytes);
+    }
+    if let Err(e) = storage.spawn_cache_sweeper(config.cache_sweep_interval) {
+        warning!(
+            "🧹 Could not start the cache sweeper, expired records are only removed when looked up: {}",
+            e
+        );
+    }
+
+    // saved again on shutdown, once the server is done with the repository
+    let cache_file = config.cache_file.clone().map(|path| {
+        match storage.load_cache(&path) {
+            Ok(loaded) => info!(
+                "💾 Loaded {} cache entries from {}",
+                loaded,
+                path.display()
+            ),
+            Err(CacheFileError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warning!("💾 Ignoring the cache file {}: {}", path.display(), e),
+        }
+        if let Err(e) = storage.spawn_cache_saver(path.clone(), config.cache_save_interval) {
+            warning!(
+                "💾 Could not start saving the cache periodically, it is only saved on shutdown: {}",
+                e
+            );
+        }
+        (path, storage.cache())
+    });
+
+    let mut server = Server::new(MessageDecoder {}, MessageEncoder {}, storage)
+        .with_worker_pool_config(config.worker_pool.clone())
+        .with_tcp_config(config.tcp.clone())
+        .with_mode(config.mode)
+        .with_acl(config.acl.clone())
+        .with_proxy_protocol(config.proxy_protocol.clone())
+        .with_shutdown_timeout(config.shutdown_timeout)
+        .with_privileges(config.privileges.clone());
+    if let Some(tls) = config.tls() {
+        server = server.with_tls(tls);
+    }
+
+    // socket activated, the configured listeners are left to systemd
+    server = match activated_sockets {
+        Some(sockets) => {
+            info!(
+                "📦 Serving {} UDP and {} TCP sockets passed by systemd",
+                sockets.udp_sockets.len(),
+                sockets.tcp_listeners.len()
+            );
+            server.with_listeners(vec![]).with_sockets(sockets)
+        }
+        None => server.with_listeners(config.listeners.clone()),
+    };
+
+    let running = server.start().unwrap_or_else(|e| {
+        error!("💣🔥 {}", e);
+        process::exit(1);
+    });
+
+    for address in running.udp_addresses() {
+        info!("🚀💨 UDP DNS server running on {}", address);
+    }
+    for address in running.tcp_addresses() {
+        info!("🚀🔗 TCP DNS server running on {}", address);
+    }
+    for address in running.tls_addresses() {
+        info!("🚀🔒 DNS over TLS server running on {}", address);
+    }
+    for address in running.http_addresses() {
+        info!(
+            "🚀🌐 DNS over HTTP server running on http://{}/dns-query",
+            address
+        );
+    }
+    for address in running.https_addresses() {
+        info!(
+            "🚀🌐 DNS over HTTPS server running on https://{}/dns-query",
+            address
+        );
+    }
+
+    let handle = running.handle();
+    thread::spawn(move || handle_signals(options, config, handle, authoritative_records));
+
+    running.join();
+    if let Some((path, cache)) = cache_file {
+        match persistence::save(&cache, &path) {
+            Ok(saved) => info!("💾 Saved {} cache entries to {}", saved, path.display()),
+            Err(e) => warning!("💾 Could not save the cache to {}: {}", path.display(), e),
+        }
+    }
+    log::flush();
+}
+
+const HANDLED_SIGNALS: [i32; 3] = [signals::SIGTERM, signals::SIGINT, signals::SIGHUP];
+
+fn handle_signals(
+    options: Options,
+    mut config: Config,
+    handle: ServerHandle,
+    authoritative_records: AuthoritativeRecords,
+) {
+    loop {
+        match signals::wait(&HANDLED_SIGNALS) {
+            Ok(signals::SIGHUP) => {
+                info!("🔄 SIGHUP received, reloading the configuration");
+                reload(&options, &mut config, &authoritative_records);
+            }
+            // a second stop signal means the in-flight queries are not worth waiting for
+            Ok(_) if handle.is_shutting_down() => {
+                warning!("🚧 Stopping right away");
+                log::flush();
+                process::exit(1);
+            }
+            Ok(signal) => {
+                info!("🛑 {} received, shutting down", signals::name(signal));
+                handle.shutdown();
+            }
+            Err(e) => {
+                error!("💣🔥 Could not wait for signals: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Swaps the zones and logging settings for the ones read again from the configuration,
+/// the running ones are kept when the new configuration is invalid
+fn reload(options: &Options, config: &mut Config, authoritative_records: &AuthoritativeRecords) {
+    let reloaded = match options.load_config() {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            error!(
+                "💣🔥 Invalid configuration, still serving the previous one: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    if let Err(e) = log::init(reloaded.log_level, reloaded.log_file.as_deref()) {
+        error!(
+            "💣🔥 Could not open the log file, logging settings unchanged: {}",
+            e
+        );
+    }
+
+    authoritative_records.replace(load_zones(&reloaded.zones));
+
+    let changes_requiring_restart = config.changes_requiring_restart(&reloaded);
+    if !changes_requiring_restart.is_empty() {
+        warning!(
+            "🚧 Changes to [{}] only apply after a restart",
+            changes_requiring_restart.join("], [")
+        );
+    }
+
+    *config = Config {
+        zones: reloaded.zones,
+        log_level: reloaded.log_level,
+        log_file: reloaded.log_file,
+        ..config.clone()
+    };
+    info!("✅ Configuration reloaded");
+}
+
+fn load_zones(zones: &[Zone]) -> InMemoryResourceRecordRepository {
+    let mut records = InMemoryResourceRecordRepository::new();
+
+    for zone in zones {
+        info!(
+            "📚 Serving {} records for {}",
+            zone.records.len(),
+            zone.origin
+        );
+        for record in zone.records.iter() {
+            records.save(record.clone());
+        }
+    }
+
+    records
+}
+
+/// The `--reload` admin command, signals the server running with the given pidfile
+fn request_reload(options: &Options) {
+    let Some(pidfile) = options.pidfile.as_deref() else {
+        eprintln!("💣🔥 --reload needs the --pidfile of the running server");
+        process::exit(2);
     };
-    let storage =
-        storage::combined::CombinedRepository::new(in_memory_repository, fallback_repository);
 
-    Server::new(MessageDecoder {}, MessageEncoder {}, storage).run();
+    let pid = daemon::Pidfile::read(pidfile).unwrap_or_else(|e| {
+        eprintln!("💣🔥 Could not read {}: {}", pidfile.display(), e);
+        process::exit(1);
+    });
+
+    if let Err(e) = signals::send(pid, signals::SIGHUP) {
+        eprintln!("💣🔥 Could not signal process {}: {}", pid, e);
+        process::exit(1);
+    }
+
+    println!("🔄 Reload requested to process {}", pid);
 }
@@ -0,0 +1,209 @@
+// Binding port 53 requires root, serving queries does not: once the sockets are bound the
+// process switches to an unprivileged user, optionally jailed in a chroot
+
+use std::{
+    env,
+    ffi::CString,
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    mem,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    ptr,
+};
+
+/// User, group and directory the process is confined to before serving any query
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Privileges {
+    /// User name or id, its primary group is used when no group is given
+    pub user: Option<String>,
+    /// Group name or id
+    pub group: Option<String>,
+    /// Root directory of the process, zone and configuration files must be reachable from
+    /// inside it for reloads to work
+    pub chroot: Option<PathBuf>,
+}
+
+impl Privileges {
+    /// Reads DNS_USER, DNS_GROUP and DNS_CHROOT
+    pub fn from_env() -> Self {
+        Self {
+            user: env::var("DNS_USER").ok().filter(|u| !u.is_empty()),
+            group: env::var("DNS_GROUP").ok().filter(|g| !g.is_empty()),
+            chroot: env::var_os("DNS_CHROOT")
+                .filter(|c| !c.is_empty())
+                .map(PathBuf::from),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Chroots then switches to the group and user, for good: root can not be regained
+    /// Names are resolved first, the user and group databases are usually out of the chroot
+    pub fn apply(&self) -> Result<()> {
+        let user = self.user.as_deref().map(lookup_user).transpose()?;
+        let gid = match self.group.as_deref() {
+            Some(group) => Some(lookup_group(group)?),
+            None => user.map(|(_, gid)| gid),
+        };
+
+        if let Some(chroot) = &self.chroot {
+            let path = CString::new(chroot.as_os_str().as_bytes())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid chroot path"))?;
+
+            // SAFETY: path is a valid NUL terminated string
+            check(unsafe { libc::chroot(path.as_ptr()) }).map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("could not chroot to {}: {}", chroot.display(), e),
+                )
+            })?;
+            // SAFETY: the string literal is NUL terminated
+            check(unsafe { libc::chdir(c"/".as_ptr()) })?;
+        }
+
+        // the group goes first, changing it is not allowed anymore once the user is switched
+        if let Some(gid) = gid {
+            // SAFETY: gid outlives the call, the supplementary groups of root are dropped
+            check(unsafe { libc::setgroups(1, &gid) })?;
+            // SAFETY: no pointer involved
+            check(unsafe { libc::setgid(gid) })?;
+        }
+
+        if let Some((uid, _)) = user {
+            // SAFETY: no pointer involved, glibc applies it to every thread
+            check(unsafe { libc::setuid(uid) })?;
+
+            // SAFETY: no pointer involved
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(Error::other("root privileges could be regained"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Privileges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(user) = &self.user {
+            parts.push(format!("user {}", user));
+        }
+        if let Some(group) = &self.group {
+            parts.push(format!("group {}", group));
+        }
+        if let Some(chroot) = &self.chroot {
+            parts.push(format!("chroot {}", chroot.display()));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// User id and primary group of a user name, or of a numeric user id
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = c_string(user)?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: passwd is plain data, filled by getpwnam_r
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+
+    // SAFETY: every pointer is valid for the call, the buffer length is the one allocated
+    let errno = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if errno != 0 {
+        return Err(Error::from_raw_os_error(errno));
+    }
+
+    if found.is_null() {
+        // a numeric id does not need to be in the user database, its group then stays the same
+        return match user.parse() {
+            // SAFETY: no pointer involved
+            Ok(uid) => Ok((uid, unsafe { libc::getgid() })),
+            Err(_) => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("unknown user {}", user),
+            )),
+        };
+    }
+
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Group id of a group name, or a numeric group id
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = c_string(group)?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: group is plain data, filled by getgrnam_r
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+
+    // SAFETY: every pointer is valid for the call, the buffer length is the one allocated
+    let errno = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if errno != 0 {
+        return Err(Error::from_raw_os_error(errno));
+    }
+
+    if found.is_null() {
+        return group
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("unknown group {}", group)));
+    }
+
+    Ok(entry.gr_gid)
+}
+
+fn c_string(name: &str) -> Result<CString> {
+    CString::new(name)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid name {:?}", name)))
+}
+
+fn check(result: libc::c_int) -> Result<()> {
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_users_and_groups() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert_eq!(lookup_group("4242").unwrap(), 4242);
+        assert_eq!(lookup_user("4242").unwrap().0, 4242);
+
+        let error = lookup_user("no-such-user-here").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(error.to_string(), "unknown user no-such-user-here");
+        assert!(lookup_group("no-such-group-here").is_err());
+    }
+
+    #[test]
+    fn does_nothing_without_user_group_or_chroot() {
+        assert!(Privileges::default().is_empty());
+        Privileges::default().apply().unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::{net::IpAddr, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+impl FromStr for AclAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "deny" => Ok(Self::Deny),
+            _ => Err(format!("Unknown ACL action: {}", s)),
+        }
+    }
+}
+
+/// An IP address with a prefix length, e.g. `192.168.0.0/16` or `2001:db8::/32`
+/// An address without prefix length is a network of a single host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket show up as IPv4-mapped IPv6 addresses
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_length)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_length)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_length: u8) -> bool {
+    let full_bytes = prefix_length as usize / 8;
+    let remaining_bits = prefix_length % 8;
+
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = u8::MAX << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = match s.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (s, None),
+        };
+
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid network address: {}", s))?;
+        let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix_length)
+                .ok_or_else(|| format!("Invalid network prefix length: {}", s))?,
+            None => max_prefix_length,
+        };
+
+        Ok(Self {
+            address,
+            prefix_length,
+        })
+    }
+}
+
+/// Which clients may query the server, rules are checked in order and the first matching one wins
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    rules: Vec<(AclAction, Network)>,
+    default_action: AclAction,
+}
+
+impl Acl {
+    pub fn new(default_action: AclAction) -> Self {
+        Self {
+            rules: vec![],
+            default_action,
+        }
+    }
+
+    /// What to do with clients matching no rule
+    pub fn set_default_action(&mut self, default_action: AclAction) {
+        self.default_action = default_action;
+    }
+
+    pub fn add_rule(&mut self, action: AclAction, network: Network) {
+        self.rules.push((action, network));
+    }
+
+    pub fn is_allowed(&self, client: IpAddr) -> bool {
+        let action = self
+            .rules
+            .iter()
+            .find(|(_, network)| network.contains(client))
+            .map(|(action, _)| *action)
+            .unwrap_or(self.default_action);
+
+        action == AclAction::Allow
+    }
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new(AclAction::Allow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_networks() {
+        let network = "192.168.0.0/20".parse::<Network>().unwrap();
+        assert!(network.contains(ip("192.168.15.1")));
+        assert!(!network.contains(ip("192.168.16.1")));
+        assert!(network.contains(ip("::ffff:192.168.1.1")));
+        assert!(!network.contains(ip("2001:db8::1")));
+
+        let network = "2001:db8::/32".parse::<Network>().unwrap();
+        assert!(network.contains(ip("2001:db8:ffff::1")));
+        assert!(!network.contains(ip("2001:db9::1")));
+
+        let host = "10.0.0.1".parse::<Network>().unwrap();
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.2")));
+
+        assert!(
+            "0.0.0.0/0"
+                .parse::<Network>()
+                .unwrap()
+                .contains(ip("1.2.3.4"))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("10.0.0/8".parse::<Network>().is_err());
+        assert!("::/129".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let mut acl = Acl::new(AclAction::Deny);
+        acl.add_rule(AclAction::Deny, "10.0.0.13".parse().unwrap());
+        acl.add_rule(AclAction::Allow, "10.0.0.0/8".parse().unwrap());
+
+        assert!(acl.is_allowed(ip("10.1.2.3")));
+        assert!(!acl.is_allowed(ip("10.0.0.13")));
+        assert!(!acl.is_allowed(ip("192.0.2.1")));
+        assert!(Acl::default().is_allowed(ip("192.0.2.1")));
+    }
+}
@@ -0,0 +1,147 @@
+// systemd socket activation: the sockets are bound by systemd and handed over from fd 3 onwards,
+// LISTEN_FDS tells how many and LISTEN_PID which process they are meant for
+// https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+
+use std::{
+    env,
+    io::{Error, Result},
+    mem,
+    net::{TcpListener, UdpSocket},
+    os::fd::{FromRawFd, RawFd},
+    process,
+};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets bound by the service manager, served instead of the configured listeners
+#[derive(Debug)]
+pub struct ActivatedSockets {
+    pub udp_sockets: Vec<UdpSocket>,
+    pub tcp_listeners: Vec<TcpListener>,
+}
+
+impl ActivatedSockets {
+    /// Takes the sockets passed through LISTEN_FDS, None when the process was not socket activated
+    /// The variables are removed so that children do not pick the sockets up, which is why it must
+    /// be called before spawning any thread
+    pub fn from_env() -> Result<Option<Self>> {
+        let listen_pid = env::var("LISTEN_PID").ok();
+        let listen_fds = env::var("LISTEN_FDS").ok();
+
+        // SAFETY: no other thread is running yet to read the environment concurrently
+        unsafe {
+            env::remove_var("LISTEN_PID");
+            env::remove_var("LISTEN_FDS");
+            env::remove_var("LISTEN_FDNAMES");
+        }
+
+        if listen_pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(process::id()) {
+            return Ok(None);
+        }
+
+        let count = match listen_fds.map(|fds| fds.parse::<RawFd>()) {
+            Some(Ok(count)) if count > 0 => count,
+            Some(Ok(_)) | None => return Ok(None),
+            Some(Err(_)) => return Err(Error::other("LISTEN_FDS is not a number")),
+        };
+
+        // SAFETY: systemd hands these file descriptors over to this process, nothing else owns them
+        unsafe { Self::from_raw_fds(LISTEN_FDS_START..LISTEN_FDS_START + count) }.map(Some)
+    }
+
+    /// Sorts the sockets by transport, datagram ones are UDP and stream ones TCP
+    ///
+    /// # Safety
+    /// The file descriptors must be open sockets owned by nobody else
+    unsafe fn from_raw_fds<I: IntoIterator<Item = RawFd>>(fds: I) -> Result<Self> {
+        let mut sockets = Self {
+            udp_sockets: vec![],
+            tcp_listeners: vec![],
+        };
+
+        for fd in fds {
+            // not inherited by the processes spawned later on
+            check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+
+            match socket_type(fd)? {
+                libc::SOCK_DGRAM => {
+                    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+                    // the threaded mode expects blocking sockets, the event loops switch them over
+                    socket.set_nonblocking(false)?;
+                    sockets.udp_sockets.push(socket);
+                }
+                libc::SOCK_STREAM => {
+                    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+                    listener.set_nonblocking(false)?;
+                    sockets.tcp_listeners.push(listener);
+                }
+                _ => {
+                    return Err(Error::other(format!(
+                        "file descriptor {} is neither a datagram nor a stream socket",
+                        fd
+                    )));
+                }
+            }
+        }
+
+        Ok(sockets)
+    }
+}
+
+fn socket_type(fd: RawFd) -> Result<libc::c_int> {
+    let mut socket_type: libc::c_int = 0;
+    let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    check(unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            (&mut socket_type as *mut libc::c_int).cast(),
+            &mut length,
+        )
+    })
+    .map_err(|e| Error::new(e.kind(), format!("file descriptor {}: {}", fd, e)))?;
+
+    Ok(socket_type)
+}
+
+fn check(result: i32) -> Result<i32> {
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::fd::IntoRawFd};
+
+    use super::*;
+
+    #[test]
+    fn sorts_sockets_by_transport() {
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp_address = udp_socket.local_addr().unwrap();
+        let tcp_address = tcp_listener.local_addr().unwrap();
+
+        let sockets = unsafe {
+            ActivatedSockets::from_raw_fds([tcp_listener.into_raw_fd(), udp_socket.into_raw_fd()])
+        }
+        .unwrap();
+
+        assert_eq!(sockets.udp_sockets.len(), 1);
+        assert_eq!(sockets.udp_sockets[0].local_addr().unwrap(), udp_address);
+        assert_eq!(sockets.tcp_listeners.len(), 1);
+        assert_eq!(sockets.tcp_listeners[0].local_addr().unwrap(), tcp_address);
+    }
+
+    #[test]
+    fn rejects_file_descriptors_other_than_sockets() {
+        let file = File::open("/dev/null").unwrap();
+
+        assert!(unsafe { ActivatedSockets::from_raw_fds([file.into_raw_fd()]) }.is_err());
+    }
+}
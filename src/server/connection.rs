@@ -0,0 +1,661 @@
+// Connections of the TCP, TLS and HTTP listeners, served by a pool of workers as they become
+// readable: a connection waiting for its next query does not hold a thread, a single one polls
+// them all and hands the readable ones over to the pool, which serves what they sent then parks
+// them again
+
+use std::{
+    io::{BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, SendError, Sender, channel},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    decoder::Decoder,
+    encoder::Encoder,
+    log::{debug, warning},
+    server::{
+        ConnectionPermit, OverflowPolicy, PendingQueries, ProxyProtocol, QueryReader, QueryStream,
+        QueryWriter, SHUTDOWN_CHECK_INTERVAL, ServerHandle, StreamProtocol, TcpConfig,
+        handler::Handler, http, json, proxy::ProxyHeader, take_tcp_messages, tls::TlsContext,
+        write_tcp_message,
+    },
+    storage::ResourceRecordRepository,
+    transport::Transport,
+    worker_pool::WorkerPool,
+};
+
+const TCP_READ_CHUNK_SIZE: usize = 4096;
+
+/// A connection between two of its messages
+pub(super) trait Connection: Send {
+    fn fd(&self) -> RawFd;
+
+    /// The client, which is not the connection peer behind a proxy
+    fn peer(&self) -> SocketAddr;
+
+    /// Whether bytes already received can be served without the socket becoming readable
+    fn has_buffered_data(&self) -> bool {
+        false
+    }
+
+    /// Whether queries are being answered, a busy connection is never idle
+    fn is_busy(&self) -> bool {
+        false
+    }
+
+    /// Serves what the connection sent, None once it is closed
+    fn serve(self: Box<Self>) -> Option<Box<dyn Connection>>;
+
+    /// Closes the connection once its pending queries are answered
+    fn close(self: Box<Self>);
+}
+
+/// Parks the connections until they become readable, then serves them on the connection pool
+#[derive(Clone)]
+pub(super) struct ConnectionWatcher {
+    parked: Sender<Box<dyn Connection>>,
+    /// Written to when a connection is parked, so that the watching thread polls it too
+    waker: Arc<UnixStream>,
+}
+
+impl ConnectionWatcher {
+    /// Starts the watching thread, which closes the parked connections and returns once the
+    /// shutdown is requested
+    pub(super) fn start(
+        pool: Arc<WorkerPool>,
+        idle_timeout: Duration,
+        shutdown: ServerHandle,
+    ) -> std::io::Result<(Self, thread::JoinHandle<()>)> {
+        let (parked, receiver) = channel();
+        let (waker, wake_up) = UnixStream::pair()?;
+        waker.set_nonblocking(true)?;
+        wake_up.set_nonblocking(true)?;
+
+        let watcher = Self {
+            parked,
+            waker: Arc::new(waker),
+        };
+        let thread_watcher = watcher.clone();
+        let thread = thread::Builder::new()
+            .name("tcp-connections".to_string())
+            .spawn(move || thread_watcher.watch(receiver, wake_up, pool, idle_timeout, shutdown))?;
+
+        Ok((watcher, thread))
+    }
+
+    /// Waits for the connection to become readable, it is closed right away once the server stopped
+    pub(super) fn park(&self, connection: Box<dyn Connection>) {
+        match self.parked.send(connection) {
+            // a full socket already wakes the watching thread up
+            Ok(()) => {
+                let _ = (&*self.waker).write(&[1]);
+            }
+            Err(SendError(connection)) => connection.close(),
+        }
+    }
+
+    fn watch(
+        self,
+        receiver: Receiver<Box<dyn Connection>>,
+        mut wake_up: UnixStream,
+        pool: Arc<WorkerPool>,
+        idle_timeout: Duration,
+        shutdown: ServerHandle,
+    ) {
+        let mut connections: Vec<(Box<dyn Connection>, Instant)> = vec![];
+
+        while !shutdown.is_shutting_down() {
+            connections.extend(receiver.try_iter().map(|c| (c, Instant::now())));
+
+            let mut poll_fds = vec![poll_fd(wake_up.as_raw_fd())];
+            poll_fds.extend(connections.iter().map(|(c, _)| poll_fd(c.fd())));
+            // TLS may have decrypted the next message along with the previous one
+            let timeout = match connections.iter().any(|(c, _)| c.has_buffered_data()) {
+                true => Duration::ZERO,
+                false => SHUTDOWN_CHECK_INTERVAL,
+            };
+
+            // SAFETY: the pollfds are passed along with their count
+            let ready = unsafe {
+                libc::poll(
+                    poll_fds.as_mut_ptr(),
+                    poll_fds.len() as libc::nfds_t,
+                    timeout.as_millis() as libc::c_int,
+                )
+            };
+            if ready == -1 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != ErrorKind::Interrupted {
+                    warning!("couldn't wait for the TCP connections: {}", e);
+                }
+                continue;
+            }
+            while wake_up.read(&mut [0; 64]).is_ok_and(|amt| amt > 0) {}
+
+            // from the last one, so that swapping the removed ones does not skip any
+            for i in (0..connections.len()).rev() {
+                let (connection, idle_since) = &mut connections[i];
+                if poll_fds[i + 1].revents != 0 || connection.has_buffered_data() {
+                    let (connection, _) = connections.swap_remove(i);
+                    self.dispatch(connection, &pool);
+                } else if connection.is_busy() {
+                    *idle_since = Instant::now();
+                } else if idle_since.elapsed() >= idle_timeout {
+                    let (connection, _) = connections.swap_remove(i);
+                    debug!("⏱️ Closing idle TCP connection from {}", connection.peer());
+                    connection.close();
+                }
+            }
+        }
+
+        // the connections parked from now on are closed by the pool workers
+        connections.extend(receiver.try_iter().map(|c| (c, Instant::now())));
+        drop(receiver);
+        for (connection, _) in connections {
+            connection.close();
+        }
+    }
+
+    /// Serves the connection on the pool, it is closed when the queue is full
+    fn dispatch(&self, connection: Box<dyn Connection>, pool: &WorkerPool) {
+        let peer = connection.peer();
+        let watcher = self.clone();
+
+        let job = move || {
+            if let Some(connection) = connection.serve() {
+                watcher.park(connection);
+            }
+        };
+
+        if let Err(e) = pool.execute(job) {
+            warning!(
+                "🚧 Could not queue TCP connection from {} ({:?}), closing it, {}",
+                peer,
+                e,
+                pool.metrics()
+            );
+        }
+    }
+}
+
+fn poll_fd(fd: RawFd) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }
+}
+
+/// What the connections of a listener share
+pub(super) struct ConnectionContext<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    pub(super) handler: Arc<Handler<D, E, R>>,
+    pub(super) query_pool: Arc<WorkerPool>,
+    pub(super) overflow_policy: OverflowPolicy,
+    pub(super) tcp_config: TcpConfig,
+    pub(super) proxy_protocol: ProxyProtocol,
+    /// Context of the TLS handshake each connection starts with
+    pub(super) tls_context: Option<Arc<TlsContext>>,
+    pub(super) protocol: StreamProtocol,
+    pub(super) shutdown: ServerHandle,
+}
+
+/// A connection which has not sent anything yet, its PROXY header and TLS handshake come first
+pub(super) struct AcceptedConnection<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    context: Arc<ConnectionContext<D, E, R>>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    permit: ConnectionPermit,
+}
+
+impl<D, E, R> AcceptedConnection<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    pub(super) fn new(
+        context: Arc<ConnectionContext<D, E, R>>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        permit: ConnectionPermit,
+    ) -> Self {
+        Self {
+            context,
+            stream,
+            peer,
+            permit,
+        }
+    }
+
+    /// Serves the stream, past its PROXY header and TLS handshake, as its listener speaks
+    fn start<S: QueryStream>(
+        context: Arc<ConnectionContext<D, E, R>>,
+        stream: S,
+        peer: SocketAddr,
+        permit: ConnectionPermit,
+    ) -> Option<Box<dyn Connection>> {
+        match context.protocol {
+            StreamProtocol::Dns => match stream.split() {
+                Ok((reader, writer)) => Some(Box::new(DnsConnection::<D, E, R, S> {
+                    context,
+                    reader,
+                    writer: Arc::new(Mutex::new(writer)),
+                    pending_queries: Arc::new(PendingQueries::default()),
+                    read_buffer: vec![],
+                    queries: 0,
+                    peer,
+                    _permit: permit,
+                })),
+                Err(e) => {
+                    warning!("couldn't set TCP connection up: {}", e);
+                    None
+                }
+            },
+            StreamProtocol::Http => Some(Box::new(HttpConnection {
+                context,
+                reader: BufReader::new(stream),
+                requests: 0,
+                peer,
+                _permit: permit,
+            })),
+        }
+    }
+}
+
+impl<D, E, R> Connection for AcceptedConnection<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    fn fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn serve(self: Box<Self>) -> Option<Box<dyn Connection>> {
+        let Self {
+            context,
+            mut stream,
+            mut peer,
+            permit,
+        } = *self;
+
+        let idle_timeout = context.tcp_config.idle_timeout;
+        if let Err(e) = stream
+            .set_read_timeout(Some(idle_timeout))
+            .and_then(|_| stream.set_write_timeout(Some(idle_timeout)))
+        {
+            warning!("couldn't set TCP connection up: {}", e);
+            return None;
+        }
+
+        // the header comes first, within the idle timeout like any query
+        if context.proxy_protocol.is_trusted(peer.ip()) {
+            match ProxyHeader::read(&mut stream) {
+                Ok(header) => peer = header.source.unwrap_or(peer),
+                Err(e) => {
+                    warning!("🚧 Closing connection from proxy {}: {}", peer, e);
+                    return None;
+                }
+            }
+        }
+
+        match context.tls_context.clone() {
+            Some(tls_context) => match tls_context.accept(stream) {
+                Ok(stream) => Self::start(context, stream, peer, permit),
+                Err(e) => {
+                    debug!("🔒 TLS handshake with {} failed: {}", peer, e);
+                    None
+                }
+            },
+            None => Self::start(context, stream, peer, permit),
+        }
+    }
+
+    // nothing was read yet, dropping the stream closes it
+    fn close(self: Box<Self>) {}
+}
+
+/// Reads the queries of a connection until it is closed, idle or over its query limit
+/// Its write half is shared with the query pool which writes the responses as they come,
+/// while the next query is being read
+struct DnsConnection<D, E, R, S>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+    S: QueryStream,
+{
+    context: Arc<ConnectionContext<D, E, R>>,
+    reader: S::Reader,
+    writer: Arc<Mutex<S::Writer>>,
+    pending_queries: Arc<PendingQueries>,
+    /// Start of the next query, read along with the previous ones
+    read_buffer: Vec<u8>,
+    queries: usize,
+    peer: SocketAddr,
+    _permit: ConnectionPermit,
+}
+
+impl<D, E, R, S> DnsConnection<D, E, R, S>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+    S: QueryStream,
+{
+    /// Hands the query over to the query pool, or answers it with the overflow policy
+    fn submit(&self, query: Vec<u8>) {
+        let handler = Arc::clone(&self.context.handler);
+        let writer = Arc::clone(&self.writer);
+        let pending_queries = Arc::clone(&self.pending_queries);
+        let peer = self.peer;
+        let query_clone = query.clone();
+
+        self.pending_queries.add();
+        let job = move || {
+            if let Some(response) = handler.handle(&query_clone, Transport::Tcp, peer.ip()) {
+                write_tcp_message(&writer, &response, peer);
+            }
+            pending_queries.done();
+        };
+
+        let query_pool = &self.context.query_pool;
+        if let Err(e) = query_pool.execute(job) {
+            self.pending_queries.done();
+            warning!(
+                "🚧 Could not queue TCP query from {} ({:?}), {}",
+                peer,
+                e,
+                query_pool.metrics()
+            );
+
+            if let Some(rejection) = self
+                .context
+                .overflow_policy
+                .response_code()
+                .and_then(|code| self.context.handler.reject(&query, code))
+            {
+                write_tcp_message(&self.writer, &rejection, peer);
+            }
+        }
+    }
+}
+
+impl<D, E, R, S> Connection for DnsConnection<D, E, R, S>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+    S: QueryStream,
+{
+    fn fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn has_buffered_data(&self) -> bool {
+        self.reader.has_buffered_data()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.pending_queries.any()
+    }
+
+    /// Reads once, the socket stays readable when there is more
+    fn serve(mut self: Box<Self>) -> Option<Box<dyn Connection>> {
+        if self.context.shutdown.is_shutting_down() {
+            self.close();
+            return None;
+        }
+
+        let mut chunk = [0u8; TCP_READ_CHUNK_SIZE];
+        let read_closed = match self.reader.read(&mut chunk) {
+            Ok(0) => true,
+            Ok(amt) => {
+                self.read_buffer.extend_from_slice(&chunk[..amt]);
+                false
+            }
+            // TLS waits for the rest of a record without holding the stream
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => false,
+            Err(e) => {
+                warning!("couldn't read from {}: {}", self.peer, e);
+                true
+            }
+        };
+
+        let max_queries = self.context.tcp_config.max_queries_per_connection;
+        for query in take_tcp_messages(&mut self.read_buffer) {
+            if self.queries >= max_queries {
+                break;
+            }
+            self.queries += 1;
+            self.submit(query);
+        }
+
+        if read_closed || self.queries >= max_queries {
+            self.close();
+            return None;
+        }
+
+        Some(self)
+    }
+
+    fn close(self: Box<Self>) {
+        self.pending_queries.wait();
+        self.writer
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .close();
+    }
+}
+
+/// Answers the DNS over HTTP requests of a connection one after the other, as HTTP/1.1 does
+/// not allow responses out of order
+struct HttpConnection<D, E, R, S>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+    S: QueryStream,
+{
+    context: Arc<ConnectionContext<D, E, R>>,
+    reader: BufReader<S>,
+    requests: usize,
+    peer: SocketAddr,
+    _permit: ConnectionPermit,
+}
+
+impl<D, E, R, S> Connection for HttpConnection<D, E, R, S>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+    S: QueryStream,
+{
+    fn fd(&self) -> RawFd {
+        self.reader.get_ref().as_raw_fd()
+    }
+
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn has_buffered_data(&self) -> bool {
+        !self.reader.buffer().is_empty() || self.reader.get_ref().has_buffered_data()
+    }
+
+    /// Reads and answers a single request, waiting for the rest of it with the idle timeout
+    fn serve(mut self: Box<Self>) -> Option<Box<dyn Connection>> {
+        let peer = self.peer;
+        let request = match http::Request::read(&mut self.reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                self.close();
+                return None;
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                debug!("🚫 Invalid HTTP request from {}: {}", peer, e);
+                let _ = http::Response::error(400).write(self.reader.get_mut(), false);
+                self.close();
+                return None;
+            }
+            Err(e) => {
+                warning!("couldn't read from {}: {}", peer, e);
+                self.close();
+                return None;
+            }
+        };
+        self.requests += 1;
+        let keep_alive = request.keep_alive()
+            && self.requests < self.context.tcp_config.max_queries_per_connection
+            && !self.context.shutdown.is_shutting_down();
+
+        let handler = &self.context.handler;
+        let response = if request.path() == json::RESOLVE_PATH {
+            match json::query(&request) {
+                Ok(query) => match handler.resolve(query, Transport::Http, peer.ip()) {
+                    Some(message) => {
+                        http::Response::json(json::render(&message), message.min_ttl().unwrap_or(0))
+                    }
+                    None => http::Response::error(400),
+                },
+                Err(response) => response,
+            }
+        } else {
+            match request.dns_query() {
+                Ok(query) => match handler.handle(&query, Transport::Http, peer.ip()) {
+                    Some(message) => {
+                        let max_age = handler.response_ttl(&message).unwrap_or(0);
+                        http::Response::dns_message(message, max_age)
+                    }
+                    None => http::Response::error(400),
+                },
+                Err(response) => response,
+            }
+        };
+
+        if let Err(e) = response.write(self.reader.get_mut(), keep_alive) {
+            warning!("couldn't write to {}: {}", peer, e);
+            self.close();
+            return None;
+        }
+        if !keep_alive {
+            self.close();
+            return None;
+        }
+
+        Some(self)
+    }
+
+    fn close(self: Box<Self>) {
+        self.reader.into_inner().close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reports what the watcher does with it
+    struct MockConnection {
+        stream: UnixStream,
+        events: Sender<&'static str>,
+    }
+
+    impl Connection for MockConnection {
+        fn fd(&self) -> RawFd {
+            self.stream.as_raw_fd()
+        }
+
+        fn peer(&self) -> SocketAddr {
+            SocketAddr::from(([127, 0, 0, 1], 53))
+        }
+
+        fn serve(self: Box<Self>) -> Option<Box<dyn Connection>> {
+            self.events.send("served").unwrap();
+            None
+        }
+
+        fn close(self: Box<Self>) {
+            self.events.send("closed").unwrap();
+        }
+    }
+
+    fn park_connection(idle_timeout: Duration) -> (UnixStream, Receiver<&'static str>) {
+        let pool = Arc::new(WorkerPool::new("test-connection", 1, 4));
+        let (watcher, _) =
+            ConnectionWatcher::start(pool, idle_timeout, ServerHandle::default()).unwrap();
+        let (stream, client) = UnixStream::pair().unwrap();
+        let (events, received) = channel();
+
+        watcher.park(Box::new(MockConnection { stream, events }));
+
+        (client, received)
+    }
+
+    #[test]
+    fn serves_parked_connections_once_readable() {
+        let (mut client, events) = park_connection(Duration::from_secs(10));
+        assert!(events.recv_timeout(Duration::from_millis(50)).is_err());
+
+        client.write_all(&[0]).unwrap();
+
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)).unwrap(),
+            "served"
+        );
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let (_client, events) = park_connection(Duration::from_millis(50));
+
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)).unwrap(),
+            "closed"
+        );
+    }
+
+    #[test]
+    fn closes_parked_connections_on_shutdown() {
+        let pool = Arc::new(WorkerPool::new("test-connection", 1, 4));
+        let shutdown = ServerHandle::default();
+        let (watcher, thread) =
+            ConnectionWatcher::start(pool, Duration::from_secs(10), shutdown.clone()).unwrap();
+        let (stream, _client) = UnixStream::pair().unwrap();
+        let (events, received) = channel();
+        watcher.park(Box::new(MockConnection { stream, events }));
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+
+        assert_eq!(received.try_recv().unwrap(), "closed");
+    }
+}
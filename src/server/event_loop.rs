@@ -0,0 +1,1311 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Result, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::fd::AsRawFd,
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, Sender, channel},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    decoder::Decoder,
+    encoder::Encoder,
+    log::{debug, warning},
+    server::{
+        ConnectionLimiter, ConnectionPermit, OverflowPolicy, ProxyProtocol, ServerHandle,
+        TcpConfig,
+        handler::{Handled, Handler, PendingResponse},
+        poller::{Interest, Poller, Token, Waker},
+        proxy::ProxyHeader,
+        take_tcp_messages,
+    },
+    storage::{Answer, RepositoryError, ResourceRecordRepository, UpstreamQuery},
+    transport::{EDNS_STANDARD_UDP_PAYLOAD_SIZE, Transport, UDP_RECEIVE_BUFFER_SIZE},
+    worker_pool::{SubmitError, WorkerPool},
+};
+
+// the UDP sockets come right after the waker, then the TCP listeners, then the connections
+const WAKER_TOKEN: Token = 0;
+const FIRST_SOCKET_TOKEN: Token = 1;
+
+// bounds the work done for a single readiness event so that other sockets are not starved
+const MAX_DATAGRAMS_PER_EVENT: usize = 64;
+const MAX_ACCEPTS_PER_EVENT: usize = 64;
+
+const TCP_READ_CHUNK_SIZE: usize = 4096;
+
+// idle connections are looked for at least this often
+const MAX_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+enum Destination {
+    /// Index of the UDP socket the query came through, and its sender
+    Udp(usize, SocketAddr),
+    Tcp(Token),
+}
+
+/// Result of a query handled by the worker pool, either its response or a question for the
+/// fallback servers
+struct Completion {
+    destination: Destination,
+    handled: Handled,
+}
+
+/// A question sent to one of the fallback servers, through a socket of its own
+struct UpstreamExchange {
+    socket: UdpSocket,
+    destination: Destination,
+    pending: Box<PendingResponse>,
+    upstream: UpstreamQuery,
+    /// Index of the server asked among the ones of the query
+    server: usize,
+    id: u16,
+    deadline: Instant,
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// The client, which is not the connection peer behind a proxy
+    peer: SocketAddr,
+    /// Queries are only read once the PROXY header of a trusted proxy is
+    awaiting_proxy_header: bool,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    pending_queries: usize,
+    queries_received: usize,
+    read_closed: bool,
+    interest: Interest,
+    last_activity: Instant,
+    _permit: ConnectionPermit,
+}
+
+impl Connection {
+    fn new(
+        stream: TcpStream,
+        peer: SocketAddr,
+        awaiting_proxy_header: bool,
+        permit: ConnectionPermit,
+    ) -> Self {
+        Self {
+            stream,
+            peer,
+            awaiting_proxy_header,
+            read_buffer: vec![],
+            write_buffer: vec![],
+            pending_queries: 0,
+            queries_received: 0,
+            read_closed: false,
+            interest: Interest::READABLE,
+            last_activity: Instant::now(),
+            _permit: permit,
+        }
+    }
+
+    /// Reads everything available, returns false when the connection failed
+    fn fill_read_buffer(&mut self) -> bool {
+        let mut chunk = [0u8; TCP_READ_CHUNK_SIZE];
+
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    return true;
+                }
+                Ok(amt) => self.read_buffer.extend_from_slice(&chunk[..amt]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warning!("couldn't read from {}: {}", self.peer, e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Takes the PROXY header out of the read buffer once complete, false when it is invalid
+    fn take_proxy_header(&mut self) -> bool {
+        match ProxyHeader::parse(&self.read_buffer) {
+            Ok(Some(header)) => {
+                self.read_buffer.drain(..header.length);
+                self.peer = header.source.unwrap_or(self.peer);
+                self.awaiting_proxy_header = false;
+                true
+            }
+            Ok(None) => true,
+            Err(e) => {
+                warning!("🚧 Closing connection from proxy {}: {}", self.peer, e);
+                false
+            }
+        }
+    }
+
+    /// Extracts the complete length prefixed messages out of the read buffer
+    fn take_messages(&mut self) -> Vec<Vec<u8>> {
+        take_tcp_messages(&mut self.read_buffer)
+    }
+
+    fn queue_response(&mut self, response: &[u8]) {
+        self.write_buffer
+            .extend_from_slice(&(response.len() as u16).to_be_bytes());
+        self.write_buffer.extend_from_slice(response);
+    }
+
+    /// Writes as much as the socket accepts, returns false when the connection failed
+    fn flush(&mut self) -> bool {
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                Ok(written) => {
+                    self.write_buffer.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warning!("couldn't write to {}: {}", self.peer, e);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pending_queries == 0 && self.write_buffer.is_empty()
+    }
+
+    fn is_done(&self) -> bool {
+        self.read_closed && self.is_idle()
+    }
+
+    /// Only waits for what the connection can still do, a closed read side must not be polled
+    /// or the level triggered poller would keep reporting it
+    fn interest(&self) -> Interest {
+        Interest {
+            readable: !self.read_closed,
+            writable: !self.write_buffer.is_empty(),
+        }
+    }
+}
+
+/// Single threaded reactor multiplexing the UDP socket, every TCP connection it accepted and
+/// the questions sent to the fallback servers
+/// Lookups go through the repository so they run on the worker pool, their results are sent
+/// back to the reactor which wakes up to write them, or to ask the fallback servers without
+/// blocking a worker until they answer
+pub struct EventLoop<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    poller: Poller,
+    waker: Arc<Waker>,
+    udp_sockets: Vec<UdpSocket>,
+    tcp_listeners: Vec<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    upstreams: HashMap<Token, UpstreamExchange>,
+    next_token: Token,
+    next_upstream_id: u16,
+    completion_sender: Sender<Completion>,
+    completions: Receiver<Completion>,
+    handler: Arc<Handler<D, E, R>>,
+    pool: Arc<WorkerPool>,
+    overflow_policy: OverflowPolicy,
+    tcp_config: TcpConfig,
+    connection_limiter: ConnectionLimiter,
+    proxy_protocol: ProxyProtocol,
+    /// UDP queries handed over to the worker pool and not answered yet
+    pending_datagrams: usize,
+    shutdown: ServerHandle,
+    shutdown_timeout: Duration,
+}
+
+impl<D, E, R> EventLoop<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    /// The sockets can be shared with other event loops, only one of them is woken up per event
+    /// The connection limiter is shared with the other event loops so that the cap is global
+    pub fn new(
+        handler: Arc<Handler<D, E, R>>,
+        udp_sockets: Vec<UdpSocket>,
+        tcp_listeners: Vec<TcpListener>,
+        pool: Arc<WorkerPool>,
+        overflow_policy: OverflowPolicy,
+        tcp_config: TcpConfig,
+        connection_limiter: ConnectionLimiter,
+    ) -> Result<Self> {
+        let poller = Poller::new()?;
+        let waker = Arc::new(Waker::new()?);
+        poller.register(waker.as_raw_fd(), WAKER_TOKEN, Interest::READABLE)?;
+
+        let mut token = FIRST_SOCKET_TOKEN;
+        for udp_socket in udp_sockets.iter() {
+            udp_socket.set_nonblocking(true)?;
+            poller.register_exclusive(udp_socket.as_raw_fd(), token)?;
+            token += 1;
+        }
+        for tcp_listener in tcp_listeners.iter() {
+            tcp_listener.set_nonblocking(true)?;
+            poller.register_exclusive(tcp_listener.as_raw_fd(), token)?;
+            token += 1;
+        }
+
+        let (completion_sender, completions) = channel();
+
+        Ok(Self {
+            poller,
+            waker,
+            udp_sockets,
+            tcp_listeners,
+            connections: HashMap::new(),
+            upstreams: HashMap::new(),
+            next_token: token,
+            next_upstream_id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.subsec_nanos() as u16)
+                .unwrap_or_default(),
+            completion_sender,
+            completions,
+            handler,
+            pool,
+            overflow_policy,
+            tcp_config,
+            connection_limiter,
+            proxy_protocol: ProxyProtocol::default(),
+            pending_datagrams: 0,
+            shutdown: ServerHandle::default(),
+            shutdown_timeout: Duration::ZERO,
+        })
+    }
+
+    /// Proxies whose datagrams and connections start with a PROXY header
+    pub fn with_proxy_protocol(mut self, proxy_protocol: ProxyProtocol) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Makes run return once the shutdown is requested and the in-flight queries are answered,
+    /// or once the timeout elapsed
+    pub fn with_shutdown(mut self, shutdown: ServerHandle, shutdown_timeout: Duration) -> Self {
+        self.shutdown = shutdown;
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        let waker = Arc::clone(&self.waker);
+        self.shutdown.on_shutdown(move || {
+            if let Err(e) = waker.wake() {
+                warning!("couldn't wake the event loop up: {}", e);
+            }
+        });
+
+        let idle_check_interval = self.tcp_config.idle_timeout.min(MAX_IDLE_CHECK_INTERVAL);
+        let mut deadline = None;
+
+        loop {
+            if deadline.is_none()
+                && let Some(shutdown_deadline) = self.shutdown.deadline(self.shutdown_timeout)
+            {
+                self.stop_accepting();
+                deadline = Some(shutdown_deadline);
+            }
+
+            let mut timeout = idle_check_interval;
+            if let Some(deadline) = deadline {
+                if self.is_drained() {
+                    return Ok(());
+                }
+
+                let now = Instant::now();
+                if now >= deadline {
+                    warning!(
+                        "🚧 Shutdown deadline reached with {} TCP connections and {} UDP queries pending",
+                        self.connections.len(),
+                        self.pending_datagrams
+                    );
+                    return Ok(());
+                }
+                timeout = timeout.min(deadline - now);
+            }
+            if let Some(upstream_deadline) = self.upstreams.values().map(|u| u.deadline).min() {
+                timeout = timeout.min(upstream_deadline.saturating_duration_since(Instant::now()));
+            }
+
+            for event in self.poller.wait(Some(timeout))? {
+                let udp_sockets = self.udp_sockets.len();
+                let sockets = udp_sockets + self.tcp_listeners.len();
+
+                match event.token.checked_sub(FIRST_SOCKET_TOKEN) {
+                    None => self.waker.reset(),
+                    Some(index) if (index as usize) < udp_sockets => {
+                        self.receive_datagrams(index as usize)
+                    }
+                    Some(index) if (index as usize) < sockets => {
+                        self.accept_connections(index as usize - udp_sockets)
+                    }
+                    Some(_) if self.upstreams.contains_key(&event.token) => {
+                        self.receive_upstream(event.token)
+                    }
+                    Some(_) => {
+                        let token = event.token;
+                        if event.closed && !event.readable {
+                            self.close_connection(token);
+                            continue;
+                        }
+
+                        if event.readable {
+                            self.read_connection(token);
+                        }
+
+                        if event.writable {
+                            self.write_connection(token);
+                        }
+                    }
+                }
+            }
+
+            self.process_completions();
+            self.expire_upstreams();
+            self.close_idle_connections();
+        }
+    }
+
+    /// Stops receiving queries and accepting connections, the connections are closed once their
+    /// pending queries are answered
+    fn stop_accepting(&mut self) {
+        let sockets = self
+            .udp_sockets
+            .iter()
+            .map(|s| s.as_raw_fd())
+            .chain(self.tcp_listeners.iter().map(|l| l.as_raw_fd()));
+        for fd in sockets {
+            let _ = self.poller.deregister(fd);
+        }
+
+        let tokens = self.connections.keys().copied().collect::<Vec<_>>();
+        for token in tokens {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                connection.read_closed = true;
+                connection.read_buffer.clear();
+            }
+            self.update_connection(token);
+        }
+    }
+
+    fn is_drained(&self) -> bool {
+        self.connections.is_empty() && self.pending_datagrams == 0
+    }
+
+    fn receive_datagrams(&mut self, socket: usize) {
+        let mut buf = [0u8; UDP_RECEIVE_BUFFER_SIZE];
+
+        for _ in 0..MAX_DATAGRAMS_PER_EVENT {
+            match self.udp_sockets[socket].recv_from(&mut buf) {
+                Ok((amt, src)) => {
+                    // responses go back to the proxy, which relays them to the client
+                    let (client, query) = match self.proxy_protocol.strip_datagram(&buf[..amt], src)
+                    {
+                        Ok(stripped) => stripped,
+                        Err(e) => {
+                            warning!("🚧 Dropping datagram from proxy {}: {}", src, e);
+                            continue;
+                        }
+                    };
+
+                    match self.submit(query, Transport::Udp, client, Destination::Udp(socket, src))
+                    {
+                        Ok(()) => self.pending_datagrams += 1,
+                        Err(Some(rejection)) => self.send_datagram(socket, &rejection, src),
+                        Err(None) => {}
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warning!("couldn't receive a datagram: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Answers through the socket the query came from, so that the source address matches
+    fn send_datagram(&self, socket: usize, response: &[u8], destination: SocketAddr) {
+        // UDP offers no delivery guarantee, a full socket buffer means the response is lost
+        if let Err(e) = self.udp_sockets[socket].send_to(response, destination) {
+            warning!("couldn't send response to {}: {}", destination, e);
+        }
+    }
+
+    fn accept_connections(&mut self, listener: usize) {
+        for _ in 0..MAX_ACCEPTS_PER_EVENT {
+            match self.tcp_listeners[listener].accept() {
+                Ok((stream, peer)) => {
+                    let Some(permit) = self.connection_limiter.try_acquire() else {
+                        warning!(
+                            "🚧 Too many TCP connections ({}), closing the one from {}",
+                            self.tcp_config.max_connections,
+                            peer
+                        );
+                        continue;
+                    };
+
+                    if let Err(e) = self.add_connection(stream, peer, permit) {
+                        warning!("couldn't register TCP connection from {}: {}", peer, e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warning!("Failed to accept TCP connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn add_connection(
+        &mut self,
+        stream: TcpStream,
+        peer: SocketAddr,
+        permit: ConnectionPermit,
+    ) -> Result<()> {
+        stream.set_nonblocking(true)?;
+
+        // tokens are never reused so that late completions cannot reach another connection
+        let token = self.next_token;
+        self.next_token += 1;
+
+        self.poller
+            .register(stream.as_raw_fd(), token, Interest::READABLE)?;
+        let awaiting_proxy_header = self.proxy_protocol.is_trusted(peer.ip());
+        self.connections.insert(
+            token,
+            Connection::new(stream, peer, awaiting_proxy_header, permit),
+        );
+
+        Ok(())
+    }
+
+    fn read_connection(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        if !connection.fill_read_buffer() {
+            self.close_connection(token);
+            return;
+        }
+
+        connection.last_activity = Instant::now();
+
+        if connection.awaiting_proxy_header {
+            if !connection.take_proxy_header() {
+                self.close_connection(token);
+                return;
+            }
+
+            // the rest of the header is yet to come, unless the proxy left before sending it
+            if connection.awaiting_proxy_header {
+                self.update_connection(token);
+                return;
+            }
+        }
+
+        let mut messages = connection.take_messages();
+        let remaining_queries =
+            self.tcp_config.max_queries_per_connection - connection.queries_received;
+        if messages.len() >= remaining_queries {
+            // answers what is allowed then closes, anything sent past the limit is ignored
+            messages.truncate(remaining_queries);
+            connection.read_closed = true;
+            connection.read_buffer.clear();
+        }
+        connection.queries_received += messages.len();
+
+        let peer = connection.peer;
+        for message in messages {
+            let submitted = self.submit(&message, Transport::Tcp, peer, Destination::Tcp(token));
+
+            let Some(connection) = self.connections.get_mut(&token) else {
+                return;
+            };
+            match submitted {
+                Ok(()) => connection.pending_queries += 1,
+                Err(Some(rejection)) => connection.queue_response(&rejection),
+                Err(None) => {}
+            }
+        }
+
+        self.update_connection(token);
+    }
+
+    fn write_connection(&mut self, token: Token) {
+        if let Some(connection) = self.connections.get_mut(&token)
+            && !connection.flush()
+        {
+            self.close_connection(token);
+            return;
+        }
+
+        self.update_connection(token);
+    }
+
+    /// Closes finished connections and only asks for write readiness when there is something to write
+    fn update_connection(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        if !connection.flush() || connection.is_done() {
+            self.close_connection(token);
+            return;
+        }
+
+        let interest = connection.interest();
+        if interest != connection.interest {
+            connection.interest = interest;
+            if let Err(e) = self
+                .poller
+                .reregister(connection.stream.as_raw_fd(), token, interest)
+            {
+                warning!(
+                    "couldn't update TCP connection from {}: {}",
+                    connection.peer,
+                    e
+                );
+                self.close_connection(token);
+            }
+        }
+    }
+
+    fn close_connection(&mut self, token: Token) {
+        if let Some(connection) = self.connections.remove(&token) {
+            let _ = self.poller.deregister(connection.stream.as_raw_fd());
+        }
+    }
+
+    /// Connections without outstanding query which have been quiet for too long are closed
+    /// see: https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.3
+    fn close_idle_connections(&mut self) {
+        let idle_timeout = self.tcp_config.idle_timeout;
+        let idle_tokens = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.is_idle() && c.last_activity.elapsed() >= idle_timeout)
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+
+        for token in idle_tokens {
+            if let Some(connection) = self.connections.get(&token) {
+                debug!("⏱️ Closing idle TCP connection from {}", connection.peer);
+            }
+            self.close_connection(token);
+        }
+    }
+
+    /// Hands the query over to the worker pool, its response comes back as a completion
+    /// When the pool rejected the query, returns the response to send right away if any
+    fn submit(
+        &self,
+        buffer: &[u8],
+        transport: Transport,
+        client: SocketAddr,
+        destination: Destination,
+    ) -> std::result::Result<(), Option<Vec<u8>>> {
+        let query = buffer.to_vec();
+
+        if let Err(e) = self.execute(destination, move |handler| {
+            handler.start(&query, transport, client.ip())
+        }) {
+            warning!(
+                "🚧 Could not queue {:?} query ({:?}), {}",
+                transport,
+                e,
+                self.pool.metrics()
+            );
+
+            return Err(self
+                .overflow_policy
+                .response_code()
+                .and_then(|code| self.handler.reject(buffer, code)));
+        }
+
+        Ok(())
+    }
+
+    /// Runs the job on the worker pool and sends what it came to back to the event loop
+    fn execute<F>(&self, destination: Destination, job: F) -> std::result::Result<(), SubmitError>
+    where
+        F: FnOnce(&Handler<D, E, R>) -> Handled + Send + 'static,
+    {
+        let handler = Arc::clone(&self.handler);
+        let completion_sender = self.completion_sender.clone();
+        let waker = Arc::clone(&self.waker);
+
+        self.pool.execute(move || {
+            let handled = job(&handler);
+
+            // the event loop is gone, nobody is waiting for this response anymore
+            if completion_sender
+                .send(Completion {
+                    destination,
+                    handled,
+                })
+                .is_ok()
+                && let Err(e) = waker.wake()
+            {
+                warning!("couldn't wake the event loop up: {}", e);
+            }
+        })
+    }
+
+    fn process_completions(&mut self) {
+        while let Ok(completion) = self.completions.try_recv() {
+            match completion.handled {
+                Handled::Response(response) => self.deliver(completion.destination, response),
+                Handled::Upstream(pending, upstream) => self.ask_upstream(
+                    completion.destination,
+                    pending,
+                    upstream,
+                    0,
+                    RepositoryError::ContactingFallbackServerError(
+                        "no fallback server configured".to_string(),
+                    ),
+                ),
+            }
+        }
+    }
+
+    /// Sends the response of a query, or nothing if it was dropped
+    fn deliver(&mut self, destination: Destination, response: Option<Vec<u8>>) {
+        match destination {
+            Destination::Udp(socket, src) => {
+                self.pending_datagrams -= 1;
+                if let Some(response) = response {
+                    self.send_datagram(socket, &response, src);
+                }
+            }
+            Destination::Tcp(token) => {
+                // the connection may have been closed in the meantime
+                let Some(connection) = self.connections.get_mut(&token) else {
+                    return;
+                };
+
+                connection.pending_queries -= 1;
+                connection.last_activity = Instant::now();
+                if let Some(response) = response {
+                    connection.queue_response(&response);
+                }
+
+                self.update_connection(token);
+            }
+        }
+    }
+
+    /// Asks the fallback servers in order from the given one, until the question could be sent
+    /// to one of them, the query resumes with the last error once none is left
+    fn ask_upstream(
+        &mut self,
+        destination: Destination,
+        pending: Box<PendingResponse>,
+        upstream: UpstreamQuery,
+        first_server: usize,
+        mut last_error: RepositoryError,
+    ) {
+        for server in first_server..upstream.servers.len() {
+            let address = upstream.servers[server];
+            let id = self.next_upstream_id;
+            self.next_upstream_id = self.next_upstream_id.wrapping_add(1);
+
+            match self.send_upstream(address, &self.handler.upstream_query(&upstream, id)) {
+                Ok((token, socket)) => {
+                    self.upstreams.insert(
+                        token,
+                        UpstreamExchange {
+                            socket,
+                            destination,
+                            pending,
+                            deadline: Instant::now() + upstream.timeout,
+                            upstream,
+                            server,
+                            id,
+                        },
+                    );
+                    return;
+                }
+                Err(e) => {
+                    debug!("🔍 Fallback server {} failed: {}", address, e);
+                    last_error = RepositoryError::ContactingFallbackServerError(e.to_string());
+                }
+            }
+        }
+
+        self.resume(destination, pending, upstream, Err(last_error));
+    }
+
+    /// Sends the query through a socket of its own so that only the server can answer it
+    fn send_upstream(&mut self, address: SocketAddr, query: &[u8]) -> Result<(Token, UdpSocket)> {
+        let local_address = if address.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local_address)?;
+        socket.set_nonblocking(true)?;
+        socket.connect(address)?;
+        socket.send(query)?;
+
+        let token = self.next_token;
+        self.next_token += 1;
+        self.poller
+            .register(socket.as_raw_fd(), token, Interest::READABLE)?;
+
+        Ok((token, socket))
+    }
+
+    fn receive_upstream(&mut self, token: Token) {
+        let mut buf = [0u8; EDNS_STANDARD_UDP_PAYLOAD_SIZE];
+
+        loop {
+            let Some(exchange) = self.upstreams.get(&token) else {
+                return;
+            };
+
+            let answer = match exchange.socket.recv(&mut buf) {
+                Ok(amt) => {
+                    match self
+                        .handler
+                        .upstream_answer(&exchange.upstream, exchange.id, &buf[..amt])
+                    {
+                        Some(answer) => answer,
+                        None => continue,
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Err(RepositoryError::ContactingFallbackServerError(
+                    e.to_string(),
+                )),
+            };
+
+            self.finish_upstream(token, answer);
+            return;
+        }
+    }
+
+    /// Fallback servers which did not answer in time are given up on
+    fn expire_upstreams(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .upstreams
+            .iter()
+            .filter(|(_, exchange)| exchange.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+
+        for token in expired {
+            self.finish_upstream(
+                token,
+                Err(RepositoryError::ContactingFallbackServerError(
+                    "timed out".to_string(),
+                )),
+            );
+        }
+    }
+
+    /// The query resumes with the answer, or the next server is asked
+    fn finish_upstream(
+        &mut self,
+        token: Token,
+        answer: std::result::Result<Answer, RepositoryError>,
+    ) {
+        let Some(exchange) = self.upstreams.remove(&token) else {
+            return;
+        };
+        let _ = self.poller.deregister(exchange.socket.as_raw_fd());
+
+        match answer {
+            Ok(answer) => self.resume(
+                exchange.destination,
+                exchange.pending,
+                exchange.upstream,
+                Ok(answer),
+            ),
+            Err(e) => {
+                debug!(
+                    "🔍 Fallback server {} failed: {}",
+                    exchange.upstream.servers[exchange.server], e
+                );
+                self.ask_upstream(
+                    exchange.destination,
+                    exchange.pending,
+                    exchange.upstream,
+                    exchange.server + 1,
+                    e,
+                );
+            }
+        }
+    }
+
+    /// Hands the response of the fallback servers over to the worker pool, which carries on
+    /// with the query
+    fn resume(
+        &mut self,
+        destination: Destination,
+        pending: Box<PendingResponse>,
+        upstream: UpstreamQuery,
+        answer: std::result::Result<Answer, RepositoryError>,
+    ) {
+        // the query is taken back to be rejected if the pool does not accept the job
+        let query = Arc::new(Mutex::new(Some((pending, upstream, answer))));
+        let job_query = Arc::clone(&query);
+        let submitted = self.execute(destination, move |handler| {
+            match job_query.lock().unwrap_or_else(|p| p.into_inner()).take() {
+                Some((pending, upstream, answer)) => handler.resume(pending, upstream, answer),
+                None => Handled::Response(None),
+            }
+        });
+
+        if let Err(e) = submitted {
+            warning!(
+                "🚧 Could not queue the fallback servers response ({:?}), {}",
+                e,
+                self.pool.metrics()
+            );
+
+            let Some((pending, _, _)) = query.lock().unwrap_or_else(|p| p.into_inner()).take()
+            else {
+                return;
+            };
+            let rejection = self
+                .overflow_policy
+                .response_code()
+                .map(|code| self.handler.reject_pending(&pending, code));
+            self.deliver(destination, rejection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{
+        common::{
+            Message,
+            domain_name::DomainName,
+            header::{Header, MessageType, QueryType, ResponseCode},
+            question::{Class, Question, Type},
+            resource_record::{ResourceRecord, Type as RRType},
+        },
+        decoder::MessageDecoder,
+        encoder::MessageEncoder,
+        storage::{
+            AuthoritativeRecords, InMemoryResourceRecordRepository, combined::CombinedRepository,
+            fallback::FallbackRepository,
+        },
+        transport::UDP_MAX_MESSAGE_SIZE,
+    };
+
+    use super::*;
+
+    fn start_event_loop() -> (SocketAddr, SocketAddr) {
+        start_event_loop_with_tcp_config(TcpConfig::default())
+    }
+
+    fn start_event_loop_with_tcp_config(tcp_config: TcpConfig) -> (SocketAddr, SocketAddr) {
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addresses = (
+            udp_socket.local_addr().unwrap(),
+            tcp_listener.local_addr().unwrap(),
+        );
+
+        start_event_loop_on(vec![udp_socket], vec![tcp_listener], tcp_config);
+
+        addresses
+    }
+
+    fn start_event_loop_on(
+        udp_sockets: Vec<UdpSocket>,
+        tcp_listeners: Vec<TcpListener>,
+        tcp_config: TcpConfig,
+    ) {
+        let mut storage = InMemoryResourceRecordRepository::new();
+        storage.save(ResourceRecord::new(
+            DomainName::from("example.com."),
+            RRType::A,
+            Class::IN,
+            300,
+            vec![192, 0, 2, 1],
+        ));
+
+        let connection_limiter = ConnectionLimiter::new(tcp_config.max_connections);
+        let event_loop = EventLoop::new(
+            Arc::new(Handler::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                storage,
+                tcp_config.idle_timeout,
+            )),
+            udp_sockets,
+            tcp_listeners,
+            Arc::new(WorkerPool::new("test", 2, 64)),
+            OverflowPolicy::Drop,
+            tcp_config,
+            connection_limiter,
+        )
+        .unwrap();
+        thread::spawn(move || event_loop.run());
+    }
+
+    fn connect_tcp_client(tcp_address: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(tcp_address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream
+    }
+
+    fn assert_closed_by_server(stream: &mut TcpStream) {
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    fn build_query(id: u16) -> Vec<u8> {
+        build_query_for(id, "example.com.")
+    }
+
+    fn build_query_for(id: u16, name: &str) -> Vec<u8> {
+        MessageEncoder {}.encode(Message::new(
+            Header {
+                id,
+                qr: MessageType::Query,
+                opcode: QueryType::Standard,
+                authoritative_answer: false,
+                truncated: false,
+                recursion_desired: true,
+                recursion_available: false,
+                reserved: false,
+                response_code: ResponseCode::NoError,
+                questions_count: 1,
+                answers_count: 0,
+                authority_count: 0,
+                additional_count: 0,
+            },
+            vec![Question {
+                name: DomainName::from(name),
+                type_: Type::RRType(RRType::A),
+                class: Class::IN,
+            }],
+            vec![],
+            vec![],
+            vec![],
+            None,
+        ))
+    }
+
+    fn frame(message: &[u8]) -> Vec<u8> {
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(message);
+        framed
+    }
+
+    fn read_framed_response(stream: &mut TcpStream) -> Message {
+        let mut size_buf = [0u8; 2];
+        stream.read_exact(&mut size_buf).unwrap();
+        let mut buffer = vec![0u8; u16::from_be_bytes(size_buf) as usize];
+        stream.read_exact(&mut buffer).unwrap();
+        MessageDecoder {}.decode(&buffer).unwrap()
+    }
+
+    #[test]
+    fn answers_udp_queries() {
+        let (udp_address, _) = start_event_loop();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        client.send_to(&build_query(42), udp_address).unwrap();
+
+        let mut buf = [0u8; UDP_MAX_MESSAGE_SIZE];
+        let amt = client.recv(&mut buf).unwrap();
+        let response = MessageDecoder {}.decode(&buf[..amt]).unwrap();
+
+        assert_eq!(response.header.id, 42);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn answers_on_every_socket() {
+        let udp_sockets = vec![
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            UdpSocket::bind("[::1]:0").unwrap(),
+        ];
+        let tcp_listeners = vec![
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            TcpListener::bind("[::1]:0").unwrap(),
+        ];
+        let udp_addresses = udp_sockets
+            .iter()
+            .map(|s| s.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        let tcp_addresses = tcp_listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap())
+            .collect::<Vec<_>>();
+
+        start_event_loop_on(udp_sockets, tcp_listeners, TcpConfig::default());
+
+        for (id, udp_address) in udp_addresses.into_iter().enumerate() {
+            let client = UdpSocket::bind((udp_address.ip(), 0)).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            client
+                .send_to(&build_query(id as u16), udp_address)
+                .unwrap();
+
+            let mut buf = [0u8; UDP_MAX_MESSAGE_SIZE];
+            let (amt, src) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(src, udp_address);
+            assert_eq!(
+                MessageDecoder {}.decode(&buf[..amt]).unwrap().header.id,
+                id as u16
+            );
+        }
+
+        for (id, tcp_address) in tcp_addresses.into_iter().enumerate() {
+            let mut stream = connect_tcp_client(tcp_address);
+            stream.write_all(&frame(&build_query(id as u16))).unwrap();
+            assert_eq!(read_framed_response(&mut stream).header.id, id as u16);
+        }
+    }
+
+    #[test]
+    fn answers_pipelined_tcp_queries() {
+        let (_, tcp_address) = start_event_loop();
+
+        let mut stream = TcpStream::connect(tcp_address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        // both queries in a single write, the second one split across two writes
+        let mut queries = frame(&build_query(1));
+        queries.extend(frame(&build_query(2)));
+        let (first_part, second_part) = queries.split_at(queries.len() - 5);
+        stream.write_all(first_part).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        stream.write_all(second_part).unwrap();
+
+        let mut ids = vec![
+            read_framed_response(&mut stream).header.id,
+            read_framed_response(&mut stream).header.id,
+        ];
+        ids.sort();
+
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn multiplexes_many_tcp_connections() {
+        let (_, tcp_address) = start_event_loop();
+
+        let mut streams = (0..50)
+            .map(|_| {
+                let stream = TcpStream::connect(tcp_address).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+                stream
+            })
+            .collect::<Vec<_>>();
+
+        for (i, stream) in streams.iter_mut().enumerate() {
+            stream.write_all(&frame(&build_query(i as u16))).unwrap();
+        }
+
+        for (i, stream) in streams.iter_mut().enumerate() {
+            let response = read_framed_response(stream);
+            assert_eq!(response.header.id, i as u16);
+            assert_eq!(response.answers.len(), 1);
+        }
+    }
+
+    #[test]
+    fn closes_connection_once_answered_after_client_shutdown() {
+        let (_, tcp_address) = start_event_loop();
+
+        let mut stream = TcpStream::connect(tcp_address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream.write_all(&frame(&build_query(7))).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        assert_eq!(read_framed_response(&mut stream).header.id, 7);
+        assert_closed_by_server(&mut stream);
+    }
+
+    #[test]
+    fn keeps_tcp_connection_open_between_queries() {
+        let (_, tcp_address) = start_event_loop();
+        let mut stream = connect_tcp_client(tcp_address);
+
+        for id in 0..3 {
+            stream.write_all(&frame(&build_query(id))).unwrap();
+            assert_eq!(read_framed_response(&mut stream).header.id, id);
+        }
+    }
+
+    #[test]
+    fn closes_tcp_connection_after_max_queries() {
+        let (_, tcp_address) = start_event_loop_with_tcp_config(TcpConfig {
+            max_queries_per_connection: 2,
+            ..Default::default()
+        });
+        let mut stream = connect_tcp_client(tcp_address);
+
+        let mut queries = frame(&build_query(1));
+        queries.extend(frame(&build_query(2)));
+        queries.extend(frame(&build_query(3)));
+        stream.write_all(&queries).unwrap();
+
+        let mut ids = vec![
+            read_framed_response(&mut stream).header.id,
+            read_framed_response(&mut stream).header.id,
+        ];
+        ids.sort();
+
+        assert_eq!(ids, vec![1, 2]);
+        assert_closed_by_server(&mut stream);
+    }
+
+    #[test]
+    fn closes_idle_tcp_connection() {
+        let (_, tcp_address) = start_event_loop_with_tcp_config(TcpConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let mut stream = connect_tcp_client(tcp_address);
+
+        stream.write_all(&frame(&build_query(1))).unwrap();
+        assert_eq!(read_framed_response(&mut stream).header.id, 1);
+
+        assert_closed_by_server(&mut stream);
+    }
+
+    #[test]
+    fn closes_tcp_connections_over_the_limit() {
+        let (_, tcp_address) = start_event_loop_with_tcp_config(TcpConfig {
+            max_connections: 1,
+            ..Default::default()
+        });
+
+        let mut first = connect_tcp_client(tcp_address);
+        first.write_all(&frame(&build_query(1))).unwrap();
+        assert_eq!(read_framed_response(&mut first).header.id, 1);
+
+        let mut second = connect_tcp_client(tcp_address);
+        assert_closed_by_server(&mut second);
+
+        // the first connection is still served
+        first.write_all(&frame(&build_query(2))).unwrap();
+        assert_eq!(read_framed_response(&mut first).header.id, 2);
+    }
+
+    #[test]
+    fn waits_for_the_fallback_servers_without_blocking_a_worker() {
+        // the upstream only answers once it received all the queries, which a single worker
+        // waiting for each answer in turn would never send
+        let queries = 3;
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_address = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut received = vec![];
+            let mut buf = [0u8; UDP_MAX_MESSAGE_SIZE];
+            while received.len() < queries {
+                let (amt, src) = upstream.recv_from(&mut buf).unwrap();
+                received.push((MessageDecoder {}.decode(&buf[..amt]).unwrap(), src));
+            }
+            for (query, src) in received {
+                let mut response = query.into_response();
+                let name = response.questions[0].name.clone();
+                response.set_answers(vec![ResourceRecord::new(
+                    name,
+                    RRType::A,
+                    Class::IN,
+                    300,
+                    vec![192, 0, 2, 1],
+                )]);
+                upstream
+                    .send_to(&MessageEncoder {}.encode(response), src)
+                    .unwrap();
+            }
+        });
+
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_address = udp_socket.local_addr().unwrap();
+        let tcp_config = TcpConfig::default();
+        let storage = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(FallbackRepository {
+                fallback_server_addresses: vec![upstream_address],
+                timeout: Duration::from_secs(5),
+                decoder: MessageDecoder {},
+                encoder: MessageEncoder {},
+            }),
+        );
+        let connection_limiter = ConnectionLimiter::new(tcp_config.max_connections);
+        let event_loop = EventLoop::new(
+            Arc::new(Handler::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                storage,
+                tcp_config.idle_timeout,
+            )),
+            vec![udp_socket],
+            vec![],
+            Arc::new(WorkerPool::new("test", 1, 64)),
+            OverflowPolicy::Drop,
+            tcp_config,
+            connection_limiter,
+        )
+        .unwrap();
+        thread::spawn(move || event_loop.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        for id in 0..queries {
+            let name = format!("host{}.example.com.", id);
+            client
+                .send_to(&build_query_for(id as u16, &name), udp_address)
+                .unwrap();
+        }
+
+        let mut ids = (0..queries)
+            .map(|_| {
+                let mut buf = [0u8; UDP_MAX_MESSAGE_SIZE];
+                let amt = client.recv(&mut buf).unwrap();
+                let response = MessageDecoder {}.decode(&buf[..amt]).unwrap();
+                assert_eq!(response.answers.len(), 1);
+                assert_eq!(response.answers[0].name, response.questions[0].name);
+                response.header.id
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        assert_eq!(ids, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,659 @@
+use std::{net::IpAddr, time::Duration};
+
+use crate::{
+    common::{
+        Message,
+        header::{MessageType, QueryType, ResponseCode},
+        opt_record::{
+            EDNS_EXTENDED_ERROR_OPTION_CODE, EDNS_TCP_KEEPALIVE_OPTION_CODE,
+            EXTENDED_ERROR_STALE_ANSWER, EdnsOption,
+        },
+        question::{Question, Type as QuestionType},
+        resource_record::ResourceRecord,
+    },
+    decoder::{Decoder, DecodingError},
+    encoder::Encoder,
+    log::{debug, error},
+    server::acl::Acl,
+    storage::{Answer, Lookup, RepositoryError, ResourceRecordRepository, UpstreamQuery, fallback},
+    transport::Transport,
+    utils::concat_two_u8s,
+};
+
+/// Turns a raw query into a raw response, shared by every transport and server mode
+pub struct Handler<D, E, R>
+where
+    D: Decoder,
+    E: Encoder,
+    R: ResourceRecordRepository,
+{
+    decoder: D,
+    encoder: E,
+    storage: R,
+    tcp_idle_timeout: Duration,
+    acl: Acl,
+}
+
+impl<D, E, R> Handler<D, E, R>
+where
+    D: Decoder,
+    E: Encoder,
+    R: ResourceRecordRepository,
+{
+    pub fn new(decoder: D, encoder: E, storage: R, tcp_idle_timeout: Duration) -> Self {
+        Self {
+            decoder,
+            encoder,
+            storage,
+            tcp_idle_timeout,
+            acl: Acl::default(),
+        }
+    }
+
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Returns the encoded response to send back, or None if the message must be dropped
+    pub fn handle(&self, buffer: &[u8], transport: Transport, client: IpAddr) -> Option<Vec<u8>> {
+        let mut pending = match self.prepare(buffer, transport, client) {
+            Ok(pending) => pending,
+            Err(response) => return response,
+        };
+
+        while let Some(question) = pending.next_question() {
+            let answer = self.storage.get_answer(question);
+            if let Err(response) = self.add_answer(&mut pending, answer) {
+                return Some(response);
+            }
+        }
+
+        Some(self.respond(*pending))
+    }
+
+    /// Same as handle, except that the questions for the fallback servers are handed over
+    /// instead of waited for, see resume
+    pub fn start(&self, buffer: &[u8], transport: Transport, client: IpAddr) -> Handled {
+        match self.prepare(buffer, transport, client) {
+            Ok(pending) => self.proceed(pending),
+            Err(response) => Handled::Response(response),
+        }
+    }
+
+    /// Carries on with a query once the fallback servers answered (or failed to)
+    pub fn resume(
+        &self,
+        mut pending: Box<PendingResponse>,
+        upstream: UpstreamQuery,
+        response: Result<Answer, RepositoryError>,
+    ) -> Handled {
+        let answer = self.storage.complete_answer(upstream, response);
+        match self.add_answer(&mut pending, answer) {
+            Ok(()) => self.proceed(pending),
+            Err(response) => Handled::Response(Some(response)),
+        }
+    }
+
+    /// Encoded query asking the fallback servers the question
+    pub fn upstream_query(&self, upstream: &UpstreamQuery, id: u16) -> Vec<u8> {
+        self.encoder
+            .encode(fallback::query_message(upstream.question.clone(), id))
+    }
+
+    /// Answer out of a datagram of a fallback server, or None if it does not answer the query
+    /// with this id, as anyone could send it
+    pub fn upstream_answer(
+        &self,
+        upstream: &UpstreamQuery,
+        id: u16,
+        buffer: &[u8],
+    ) -> Option<Result<Answer, RepositoryError>> {
+        let response = match self.decoder.decode(buffer) {
+            Ok(response) => response,
+            Err(e) => {
+                if buffer.len() < 2 || concat_two_u8s(buffer[0], buffer[1]) != id {
+                    return None;
+                }
+                return Some(Err(RepositoryError::DecodingFallbackServerResponseError(e)));
+            }
+        };
+
+        if response.header.id != id
+            || response.header.qr != MessageType::Response
+            || response.questions.first() != Some(&upstream.question)
+        {
+            debug!("🙈 Dropping response which does not match the query");
+            return None;
+        }
+
+        Some(fallback::answer(response))
+    }
+
+    /// Answers a query waiting for the fallback servers with the given error
+    pub fn reject_pending(
+        &self,
+        pending: &PendingResponse,
+        response_code: ResponseCode,
+    ) -> Vec<u8> {
+        self.encoder
+            .encode(pending.message.clone().into_error_response(response_code))
+    }
+
+    /// Decodes and checks a query, or returns the response it gets without any lookup
+    fn prepare(
+        &self,
+        buffer: &[u8],
+        transport: Transport,
+        client: IpAddr,
+    ) -> Result<Box<PendingResponse>, Option<Vec<u8>>> {
+        let message = match self.decoder.decode(buffer) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("💣🔥 Could not decode message: {:?}", e);
+                return Err(self.handle_decoding_error(buffer, e));
+            }
+        };
+
+        // never answer responses, it could start a loop between two servers
+        if message.header.qr == MessageType::Response {
+            debug!("🙈 Dropping message which is not a query");
+            return Err(None);
+        }
+
+        debug!(
+            "👾 Received message over {:?}: questions {:?}; EDNS: {}",
+            transport,
+            message.questions,
+            message.opt_record.is_some()
+        );
+
+        if !self.acl.is_allowed(client) {
+            debug!("⛔ Refusing query from {}", client);
+            return Err(Some(
+                self.encoder
+                    .encode(message.into_error_response(ResponseCode::Refused)),
+            ));
+        }
+
+        if message.header.opcode != QueryType::Standard {
+            debug!("🚫 Unsupported opcode {:?}", message.header.opcode);
+            return Err(Some(
+                self.encoder
+                    .encode(message.into_error_response(ResponseCode::NotImplemented)),
+            ));
+        }
+
+        // zone transfers are not supported, see https://datatracker.ietf.org/doc/html/rfc5936
+        if message
+            .questions
+            .iter()
+            .any(|question| question.type_ == QuestionType::AXFR)
+        {
+            debug!("🚫 Unsupported zone transfer");
+            return Err(Some(
+                self.encoder
+                    .encode(message.into_error_response(ResponseCode::NotImplemented)),
+            ));
+        }
+
+        Ok(Box::new(PendingResponse {
+            transport,
+            next_question: 0,
+            response_code: ResponseCode::NoError,
+            stale: false,
+            // only when every question was answered from our zones
+            authoritative: !message.questions.is_empty(),
+            answers: vec![],
+            authorities: vec![],
+            message,
+        }))
+    }
+
+    /// Looks up the remaining questions until one of them has to be asked to the fallback servers
+    fn proceed(&self, mut pending: Box<PendingResponse>) -> Handled {
+        while let Some(question) = pending.next_question() {
+            match self.storage.start_answer(question) {
+                Lookup::Answered(answer) => {
+                    if let Err(response) = self.add_answer(&mut pending, answer) {
+                        return Handled::Response(Some(response));
+                    }
+                }
+                Lookup::Upstream(upstream) => return Handled::Upstream(pending, upstream),
+            }
+        }
+
+        Handled::Response(Some(self.respond(*pending)))
+    }
+
+    /// Returns the error response if the question could not be answered
+    fn add_answer(
+        &self,
+        pending: &mut PendingResponse,
+        answer: Result<Answer, RepositoryError>,
+    ) -> Result<(), Vec<u8>> {
+        match answer {
+            Ok(answer) => {
+                if answer.response_code != ResponseCode::NoError {
+                    pending.response_code = answer.response_code;
+                }
+                pending.stale |= answer.stale;
+                pending.authoritative &= answer.authoritative;
+                pending.answers.extend(answer.records);
+                pending.authorities.extend(answer.authorities);
+                Ok(())
+            }
+            Err(e) => {
+                error!("💣🔥 Error retrieving records from storage: {}", e);
+                Err(self.reject_pending(pending, ResponseCode::ServerFailure))
+            }
+        }
+    }
+
+    fn respond(&self, pending: PendingResponse) -> Vec<u8> {
+        let max_message_size = pending.message.max_message_size(pending.transport);
+
+        let mut response = pending.message.into_response();
+        response.header.response_code = pending.response_code;
+        response.header.authoritative_answer = pending.authoritative;
+        response.set_answers(pending.answers);
+        response.set_authorities(pending.authorities);
+        self.set_tcp_keepalive(&mut response, pending.transport);
+        self.set_stale_answer(&mut response, pending.stale);
+
+        let mut encoded_response = self.encoder.encode(response.clone());
+        let encoded_response_len = encoded_response.len();
+
+        if encoded_response_len > max_message_size {
+            debug!(
+                "⚠️ Encoded message size ({}) exceeds max message size ({}). Truncating.",
+                encoded_response_len, max_message_size
+            );
+            response = response.truncate();
+            encoded_response = self.encoder.encode(response);
+        } else {
+            debug!("✅ Encoded message size {}", encoded_response_len);
+        }
+
+        encoded_response
+    }
+
+    /// Runs an already decoded query through the same pipeline as encoded ones
+    pub fn resolve(&self, query: Message, transport: Transport, client: IpAddr) -> Option<Message> {
+        let response = self.handle(&self.encoder.encode(query), transport, client)?;
+        self.decoder.decode(&response).ok()
+    }
+
+    /// Shortest TTL of the answer and authority records of an encoded response, the time it may
+    /// be cached for as a whole
+    pub fn response_ttl(&self, response: &[u8]) -> Option<u32> {
+        self.decoder.decode(response).ok()?.min_ttl()
+    }
+
+    /// Answers a query with the given error without looking it up
+    pub fn reject(&self, buffer: &[u8], response_code: ResponseCode) -> Option<Vec<u8>> {
+        match self.decoder.decode(buffer) {
+            Ok(message) if message.header.qr == MessageType::Query => Some(
+                self.encoder
+                    .encode(message.into_error_response(response_code)),
+            ),
+            Ok(_) => None,
+            Err(e) => self.handle_decoding_error(buffer, e),
+        }
+    }
+
+    /// Answers FORMERR (or NOTIMP for unknown opcodes) as long as the message id can be recovered
+    fn handle_decoding_error(&self, buffer: &[u8], error: DecodingError) -> Option<Vec<u8>> {
+        let id = concat_two_u8s(*buffer.first()?, *buffer.get(1)?);
+
+        if let (Some(a), Some(b)) = (buffer.get(2), buffer.get(3))
+            && MessageType::from(concat_two_u8s(*a, *b)) == MessageType::Response
+        {
+            debug!("🙈 Dropping undecodable message which is not a query");
+            return None;
+        }
+
+        let response_code = match error {
+            DecodingError::InvalidHeaderQueryType(_) => ResponseCode::NotImplemented,
+            _ => ResponseCode::FormatError,
+        };
+
+        Some(
+            self.encoder
+                .encode(Message::error_response(id, response_code)),
+        )
+    }
+
+    /// Tells EDNS clients the answer comes from expired records (RFC 8767 and RFC 8914)
+    fn set_stale_answer(&self, response: &mut Message, stale: bool) {
+        let Some(opt_record) = response.opt_record.as_mut() else {
+            return;
+        };
+
+        opt_record
+            .options
+            .retain(|option| option.code != EDNS_EXTENDED_ERROR_OPTION_CODE);
+        if stale {
+            opt_record
+                .options
+                .push(EdnsOption::extended_error(EXTENDED_ERROR_STALE_ANSWER));
+        }
+    }
+
+    /// The keepalive option is only answered over TCP, and only if the client asked for it
+    /// see: https://datatracker.ietf.org/doc/html/rfc7828#section-3.3
+    fn set_tcp_keepalive(&self, response: &mut Message, transport: Transport) {
+        let Some(opt_record) = response.opt_record.as_mut() else {
+            return;
+        };
+
+        let requested = opt_record
+            .options
+            .iter()
+            .any(|option| option.code == EDNS_TCP_KEEPALIVE_OPTION_CODE);
+        opt_record
+            .options
+            .retain(|option| option.code != EDNS_TCP_KEEPALIVE_OPTION_CODE);
+
+        if requested && transport == Transport::Tcp {
+            opt_record
+                .options
+                .push(EdnsOption::tcp_keepalive(self.tcp_idle_timeout));
+        }
+    }
+}
+
+/// What start and resume came to
+pub enum Handled {
+    /// The encoded response to send back, or None if the message must be dropped
+    Response(Option<Vec<u8>>),
+    /// The query waits for the fallback servers to answer this question
+    Upstream(Box<PendingResponse>, UpstreamQuery),
+}
+
+/// A query partly answered, waiting for the fallback servers
+pub struct PendingResponse {
+    message: Message,
+    transport: Transport,
+    next_question: usize,
+    response_code: ResponseCode,
+    stale: bool,
+    authoritative: bool,
+    answers: Vec<ResourceRecord>,
+    authorities: Vec<ResourceRecord>,
+}
+
+impl PendingResponse {
+    fn next_question(&mut self) -> Option<Question> {
+        let question = self.message.questions.get(self.next_question)?.clone();
+        self.next_question += 1;
+        Some(question)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        common::{
+            domain_name::DomainName,
+            header::Header,
+            opt_record::OptRecord,
+            question::{Class, Question, Type},
+            resource_record::{ResourceRecord, Type as RRType},
+        },
+        decoder::MessageDecoder,
+        encoder::MessageEncoder,
+        server::acl::AclAction,
+        storage::{
+            Answer, AuthoritativeRecords, InMemoryResourceRecordRepository, RepositoryError,
+            combined::CombinedRepository,
+        },
+    };
+
+    use super::*;
+
+    fn build_query_with_options(options: Vec<EdnsOption>) -> Vec<u8> {
+        MessageEncoder {}.encode(Message::new(
+            Header {
+                id: 1,
+                qr: MessageType::Query,
+                opcode: QueryType::Standard,
+                authoritative_answer: false,
+                truncated: false,
+                recursion_desired: true,
+                recursion_available: false,
+                reserved: false,
+                response_code: ResponseCode::NoError,
+                questions_count: 1,
+                answers_count: 0,
+                authority_count: 0,
+                additional_count: 1,
+            },
+            vec![Question {
+                name: DomainName::from("example.com."),
+                type_: Type::RRType(RRType::A),
+                class: Class::IN,
+            }],
+            vec![],
+            vec![],
+            vec![],
+            Some(OptRecord::new(4096, 0, 0, false, options)),
+        ))
+    }
+
+    fn handle(query: &[u8], transport: Transport) -> Message {
+        let handler = Handler::new(
+            MessageDecoder {},
+            MessageEncoder {},
+            InMemoryResourceRecordRepository::new(),
+            Duration::from_secs(10),
+        );
+
+        let response = handler
+            .handle(query, transport, IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .unwrap();
+        MessageDecoder {}.decode(&response).unwrap()
+    }
+
+    #[test]
+    fn answers_tcp_keepalive_over_tcp() {
+        let query = build_query_with_options(vec![EdnsOption::new(
+            EDNS_TCP_KEEPALIVE_OPTION_CODE,
+            vec![],
+        )]);
+
+        let response = handle(&query, Transport::Tcp);
+
+        assert_eq!(
+            response.opt_record.unwrap().options,
+            vec![EdnsOption::tcp_keepalive(Duration::from_secs(10))]
+        );
+    }
+
+    #[test]
+    fn ignores_tcp_keepalive_over_udp() {
+        let query = build_query_with_options(vec![EdnsOption::new(
+            EDNS_TCP_KEEPALIVE_OPTION_CODE,
+            vec![],
+        )]);
+
+        let response = handle(&query, Transport::Udp);
+
+        assert!(response.opt_record.unwrap().options.is_empty());
+    }
+
+    #[test]
+    fn does_not_send_unrequested_tcp_keepalive() {
+        let query = build_query_with_options(vec![]);
+
+        let response = handle(&query, Transport::Tcp);
+
+        assert!(response.opt_record.unwrap().options.is_empty());
+    }
+
+    #[test]
+    fn answers_negative_answers_with_their_soa() {
+        let soa = crate::storage::zone::parse_record(
+            "@ 3600 IN SOA ns1 hostmaster 1 7200 3600 1209600 300",
+            "example.com",
+            crate::storage::zone::DEFAULT_TTL,
+        )
+        .unwrap();
+        let mut storage = InMemoryResourceRecordRepository::new();
+        storage.cache_negative_answer(
+            &Question {
+                name: DomainName::from("example.com."),
+                type_: Type::RRType(RRType::A),
+                class: Class::IN,
+            },
+            &Answer::negative(ResponseCode::NameError, soa),
+            std::time::Instant::now(),
+        );
+        let handler = Handler::new(
+            MessageDecoder {},
+            MessageEncoder {},
+            storage,
+            Duration::from_secs(10),
+        );
+
+        let response = handler
+            .handle(
+                &build_query_with_options(vec![]),
+                Transport::Udp,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+            )
+            .unwrap();
+        let response = MessageDecoder {}.decode(&response).unwrap();
+
+        assert_eq!(response.header.response_code, ResponseCode::NameError);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].type_, RRType::SOA);
+        assert_eq!(response.authorities[0].ttl, 300);
+    }
+
+    #[test]
+    fn flags_stale_answers_with_an_extended_error() {
+        struct StaleStorage;
+        impl ResourceRecordRepository for StaleStorage {
+            fn get_resource_records(
+                &self,
+                _question: Question,
+            ) -> Result<Vec<ResourceRecord>, RepositoryError> {
+                unreachable!()
+            }
+
+            fn get_answer(&self, _question: Question) -> Result<Answer, RepositoryError> {
+                Ok(Answer::stale(vec![]))
+            }
+        }
+        let handler = Handler::new(
+            MessageDecoder {},
+            MessageEncoder {},
+            StaleStorage,
+            Duration::from_secs(10),
+        );
+        let handle = |query: &[u8]| {
+            let response = handler
+                .handle(query, Transport::Udp, IpAddr::V4(Ipv4Addr::LOCALHOST))
+                .unwrap();
+            MessageDecoder {}.decode(&response).unwrap()
+        };
+
+        let response = handle(&build_query_with_options(vec![]));
+        assert_eq!(
+            response.opt_record.unwrap().options,
+            vec![EdnsOption::extended_error(EXTENDED_ERROR_STALE_ANSWER)]
+        );
+
+        // without EDNS there is nowhere to put it
+        let mut query = MessageDecoder {}
+            .decode(&build_query_with_options(vec![]))
+            .unwrap();
+        query.opt_record = None;
+        query.header.additional_count = 0;
+        let response = handle(&MessageEncoder {}.encode(query));
+        assert_eq!(response.opt_record, None);
+        assert_eq!(response.header.response_code, ResponseCode::NoError);
+    }
+
+    #[test]
+    fn sets_authoritative_answer_for_zone_records_only() {
+        let zone = InMemoryResourceRecordRepository::new();
+        let authoritative_records = AuthoritativeRecords::new(zone.clone());
+        let handler = Handler::new(
+            MessageDecoder {},
+            MessageEncoder {},
+            CombinedRepository::<MessageDecoder, MessageEncoder>::new(
+                authoritative_records.clone(),
+                None,
+            ),
+            Duration::from_secs(10),
+        );
+        let handle = || {
+            let response = handler
+                .handle(
+                    &build_query_with_options(vec![]),
+                    Transport::Udp,
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                )
+                .unwrap();
+            MessageDecoder {}.decode(&response).unwrap()
+        };
+
+        assert!(!handle().header.authoritative_answer);
+
+        let mut zone = zone;
+        zone.save(ResourceRecord::new(
+            DomainName::from("example.com."),
+            RRType::A,
+            Class::IN,
+            300,
+            vec![192, 0, 2, 1],
+        ));
+        authoritative_records.replace(zone);
+        let response = handle();
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn refuses_clients_denied_by_acl() {
+        let mut acl = Acl::new(AclAction::Allow);
+        acl.add_rule(AclAction::Deny, "192.0.2.0/24".parse().unwrap());
+        let handler = Handler::new(
+            MessageDecoder {},
+            MessageEncoder {},
+            InMemoryResourceRecordRepository::new(),
+            Duration::from_secs(10),
+        )
+        .with_acl(acl);
+        let query = build_query_with_options(vec![]);
+
+        let refused = handler
+            .handle(&query, Transport::Udp, "192.0.2.7".parse().unwrap())
+            .unwrap();
+        let allowed = handler
+            .handle(&query, Transport::Udp, "198.51.100.7".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(
+            MessageDecoder {}
+                .decode(&refused)
+                .unwrap()
+                .header
+                .response_code,
+            ResponseCode::Refused
+        );
+        assert_eq!(
+            MessageDecoder {}
+                .decode(&allowed)
+                .unwrap()
+                .header
+                .response_code,
+            ResponseCode::NoError
+        );
+    }
+}
@@ -0,0 +1,453 @@
+// DNS over HTTPS: queries are sent to /dns-query, base64url encoded in the `dns` parameter of a GET
+// or as the body of a POST, over HTTP/1.1 with or without TLS in front
+// see: https://datatracker.ietf.org/doc/html/rfc8484
+
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+
+use crate::transport::TCP_MAX_MESSAGE_SIZE;
+
+pub const DNS_QUERY_PATH: &str = "/dns-query";
+const DNS_MESSAGE_TYPE: &str = "application/dns-message";
+// request line and headers together
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// An HTTP/1.x request
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path along with the query string
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads the next request of a connection, None when the client closed it between requests
+    /// Malformed requests are reported as InvalidData errors
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Self>> {
+        let mut head_size = 0;
+
+        // empty lines are allowed before the request line
+        let request_line = loop {
+            match read_line(reader, &mut head_size)? {
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+                None => return Ok(None),
+            }
+        };
+
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid(format!("invalid request line {:?}", request_line)));
+        };
+        if !matches!(version, "HTTP/1.0" | "HTTP/1.1") {
+            return Err(invalid(format!("unsupported HTTP version {}", version)));
+        }
+
+        let mut request = Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers: vec![],
+            body: vec![],
+        };
+
+        loop {
+            let line = read_line(reader, &mut head_size)?
+                .ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("invalid header {:?}", line)))?;
+            request
+                .headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        if request.header("transfer-encoding").is_some() {
+            return Err(invalid(
+                "chunked request bodies are not supported".to_string(),
+            ));
+        }
+
+        if let Some(length) = request.header("content-length") {
+            let length = length
+                .parse::<usize>()
+                .ok()
+                .filter(|length| *length <= TCP_MAX_MESSAGE_SIZE)
+                .ok_or_else(|| invalid(format!("invalid content length {}", length)))?;
+
+            request.body = vec![0; length];
+            reader.read_exact(&mut request.body)?;
+        }
+
+        Ok(Some(request))
+    }
+
+    /// Value of the first header with this name, names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// HTTP/1.1 connections stay open unless the client asks otherwise, HTTP/1.0 ones are closed
+    /// unless it asks for the opposite
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("connection").unwrap_or_default();
+
+        if self.version == "HTTP/1.0" {
+            connection.eq_ignore_ascii_case("keep-alive")
+        } else {
+            !connection.eq_ignore_ascii_case("close")
+        }
+    }
+
+    /// Target without its query string
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+    /// Percent decoded value of the first query string parameter with this name
+    pub fn query_parameter(&self, name: &str) -> Option<String> {
+        let (_, query_string) = self.target.split_once('?')?;
+        query_string
+            .split('&')
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|(parameter, _)| *parameter == name)
+            .and_then(|(_, value)| decode_percent(value))
+    }
+
+    /// The DNS query carried by the request, or the error response to send instead
+    pub fn dns_query(&self) -> std::result::Result<Vec<u8>, Response> {
+        if self.path() != DNS_QUERY_PATH {
+            return Err(Response::error(404));
+        }
+
+        match self.method.as_str() {
+            "GET" => self
+                .query_parameter("dns")
+                .as_deref()
+                .and_then(decode_base64url)
+                .filter(|query| !query.is_empty())
+                .ok_or_else(|| Response::error(400)),
+            "POST" => {
+                let content_type = self.header("content-type").unwrap_or_default();
+                let media_type = content_type.split(';').next().unwrap_or_default().trim();
+                if !media_type.eq_ignore_ascii_case(DNS_MESSAGE_TYPE) {
+                    return Err(Response::error(415));
+                }
+                if self.body.is_empty() {
+                    return Err(Response::error(400));
+                }
+
+                Ok(self.body.clone())
+            }
+            _ => Err(Response::error(405).with_header("Allow", "GET, POST")),
+        }
+    }
+}
+
+/// An HTTP/1.1 response
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// A DNS response, which HTTP caches may keep as long as its shortest TTL
+    pub fn dns_message(message: Vec<u8>, max_age: u32) -> Self {
+        Self {
+            status: 200,
+            headers: vec![
+                ("Content-Type", DNS_MESSAGE_TYPE.to_string()),
+                ("Cache-Control", format!("max-age={}", max_age)),
+            ],
+            body: message,
+        }
+    }
+
+    /// A DNS response rendered as JSON, cached like its binary form
+    pub fn json(body: String, max_age: u32) -> Self {
+        Self {
+            status: 200,
+            headers: vec![
+                ("Content-Type", "application/dns-json".to_string()),
+                ("Cache-Control", format!("max-age={}", max_age)),
+            ],
+            body: body.into_bytes(),
+        }
+    }
+
+    /// An error of the JSON API, explained in an `error` field
+    pub fn json_error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "application/dns-json".to_string())],
+            body: format!("{{\"error\":\"{}\"}}", message).into_bytes(),
+        }
+    }
+
+    /// An error with its reason phrase as body
+    pub fn error(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "text/plain".to_string())],
+            body: format!("{}\n", reason_phrase(status)).into_bytes(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Writes the whole response at once, telling the client whether the connection stays open
+    pub fn write(&self, writer: &mut impl Write, keep_alive: bool) -> Result<()> {
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in self.headers.iter() {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        response.push_str(if keep_alive {
+            "Connection: keep-alive\r\n\r\n"
+        } else {
+            "Connection: close\r\n\r\n"
+        });
+
+        let mut response = response.into_bytes();
+        response.extend_from_slice(&self.body);
+        writer.write_all(&response)?;
+        writer.flush()
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Error",
+    }
+}
+
+/// Reads a line without its CRLF, None at the end of the stream
+fn read_line(reader: &mut impl BufRead, head_size: &mut usize) -> Result<Option<String>> {
+    let mut line = vec![];
+    let limit = (MAX_HEAD_SIZE - *head_size) as u64;
+    reader.take(limit).read_until(b'\n', &mut line)?;
+    *head_size += line.len();
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("request head too long or truncated".to_string()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("request head is not valid UTF-8".to_string()))
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Decodes %XX escapes, None if one is malformed or the result is not UTF-8
+fn decode_percent(input: &str) -> Option<String> {
+    let mut decoded = vec![];
+    let mut bytes = input.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Decodes base64url without padding, as RFC 8484 sends it, padding is tolerated anyway
+fn decode_base64url(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim_end_matches('=');
+    // a lone character does not make a byte
+    if input.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(input.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+
+        buffer = buffer << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(output)
+}
+
+/// Encodes base64url without padding, as a client would
+#[cfg(test)]
+pub fn encode_base64url(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut output = String::new();
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..=chunk.len() {
+            output.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(request: &str) -> Result<Option<Request>> {
+        Request::read(&mut request.as_bytes())
+    }
+
+    #[test]
+    fn round_trips_base64url() {
+        for input in [&b""[..], b"a", b"ab", b"abc", b"\xfb\xff\xfe\x00dns"] {
+            let encoded = encode_base64url(input);
+            assert!(!encoded.contains(['+', '/', '=']));
+            assert_eq!(decode_base64url(&encoded).unwrap(), input);
+        }
+
+        // the example query of RFC 8484 section 4.1.1
+        assert_eq!(
+            decode_base64url("AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB").unwrap()[12..16],
+            *b"\x03www"
+        );
+        assert_eq!(decode_base64url("YWI=").unwrap(), b"ab");
+        assert!(decode_base64url("a").is_none());
+        assert!(decode_base64url("ab+/").is_none());
+    }
+
+    #[test]
+    fn reads_get_and_post_requests() {
+        let mut stream = "GET /dns-query?ct&dns=AAABAAAB HTTP/1.1\r\nHost: dns.example\r\n\r\n\
+                          POST /dns-query HTTP/1.1\r\ncontent-type: application/dns-message\r\n\
+                          Content-Length: 3\r\nConnection: close\r\n\r\nabc"
+            .as_bytes();
+
+        let get = Request::read(&mut stream).unwrap().unwrap();
+        assert_eq!(get.method, "GET");
+        assert_eq!(get.header("HOST"), Some("dns.example"));
+        assert!(get.keep_alive());
+        assert_eq!(get.dns_query(), Ok(vec![0, 0, 1, 0, 0, 1]));
+        assert_eq!(get.path(), "/dns-query");
+        assert_eq!(get.query_parameter("ct"), None);
+
+        let post = Request::read(&mut stream).unwrap().unwrap();
+        assert!(!post.keep_alive());
+        assert_eq!(post.dns_query(), Ok(b"abc".to_vec()));
+
+        assert_eq!(Request::read(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_requests_without_dns_query() {
+        let status_of = |request: &str| {
+            parse(request)
+                .unwrap()
+                .unwrap()
+                .dns_query()
+                .unwrap_err()
+                .status
+        };
+
+        assert_eq!(status_of("GET / HTTP/1.1\r\n\r\n"), 404);
+        assert_eq!(status_of("GET /dns-query HTTP/1.1\r\n\r\n"), 400);
+        assert_eq!(status_of("GET /dns-query?dns=a HTTP/1.1\r\n\r\n"), 400);
+        assert_eq!(status_of("DELETE /dns-query HTTP/1.1\r\n\r\n"), 405);
+        assert_eq!(
+            status_of(
+                "POST /dns-query HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 1\r\n\r\na"
+            ),
+            415
+        );
+
+        assert!(parse("GET /dns-query\r\n\r\n").is_err());
+        assert!(parse("GET /dns-query HTTP/2\r\n\r\n").is_err());
+        assert!(parse("POST /dns-query HTTP/1.1\r\nContent-Length: 70000\r\n\r\n").is_err());
+        assert!(parse("POST /dns-query HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+        assert!(
+            parse(&format!(
+                "GET /{} HTTP/1.1\r\n\r\n",
+                "a".repeat(MAX_HEAD_SIZE)
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn writes_responses() {
+        let mut written = vec![];
+        Response::dns_message(vec![1, 2], 300)
+            .write(&mut written, true)
+            .unwrap();
+        assert_eq!(
+            written,
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n\
+              Cache-Control: max-age=300\r\nContent-Length: 2\r\nConnection: keep-alive\r\n\r\n\x01\x02"
+        );
+
+        let mut written = vec![];
+        Response::error(405)
+            .with_header("Allow", "GET, POST")
+            .write(&mut written, false)
+            .unwrap();
+        assert!(written.starts_with(b"HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(
+            String::from_utf8(written)
+                .unwrap()
+                .contains("Allow: GET, POST\r\nContent-Length: 19\r\nConnection: close\r\n")
+        );
+    }
+}
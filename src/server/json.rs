@@ -0,0 +1,256 @@
+// JSON API for web clients: GET /resolve?name=example.com&type=AAAA is answered with the response
+// rendered as application/dns-json, in the format popularized by public resolvers
+// see: https://developers.google.com/speed/public-dns/docs/doh/json
+
+use crate::{
+    common::{
+        Message,
+        domain_name::DomainName,
+        header::{Header, MessageType, QueryType, ResponseCode},
+        question::{Class, Question, Type as QuestionType},
+        resource_record::{ResourceRecord, Type},
+    },
+    server::http::{Request, Response},
+    storage::zone::format_resource_data,
+};
+
+pub const RESOLVE_PATH: &str = "/resolve";
+
+/// The query described by the `name` and `type` parameters, or the error response to send instead
+/// The type is a mnemonic or a number and defaults to A, the types no record can answer are
+/// rejected
+pub fn query(request: &Request) -> Result<Message, Response> {
+    if request.method != "GET" {
+        return Err(Response::error(405).with_header("Allow", "GET"));
+    }
+
+    let name = request
+        .query_parameter("name")
+        .filter(|name| !name.is_empty() && name.len() <= 253)
+        .ok_or_else(|| Response::error(400))?;
+    let type_ = match request.query_parameter("type") {
+        Some(type_) => parse_type(&type_).ok_or_else(|| Response::error(400))?,
+        None => QuestionType::RRType(Type::A),
+    };
+    if !is_supported(&type_) {
+        return Err(Response::json_error(400, "unsupported type"));
+    }
+
+    let name = match name.as_str() {
+        "." => DomainName {
+            labels: vec![String::new()],
+        },
+        name => DomainName::from(name),
+    };
+
+    Ok(Message::new(
+        Header {
+            id: 0,
+            qr: MessageType::Query,
+            opcode: QueryType::Standard,
+            authoritative_answer: false,
+            truncated: false,
+            recursion_desired: true,
+            recursion_available: false,
+            reserved: false,
+            response_code: ResponseCode::NoError,
+            questions_count: 1,
+            answers_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        },
+        vec![Question {
+            name,
+            type_,
+            class: Class::IN,
+        }],
+        vec![],
+        vec![],
+        vec![],
+        None,
+    ))
+}
+
+/// Renders a response, record data in presentation format
+pub fn render(response: &Message) -> String {
+    let header = &response.header;
+    let mut json = format!(
+        "{{\"Status\":{},\"TC\":{},\"RD\":{},\"RA\":{},\"AD\":false,\"CD\":false",
+        header.response_code.value(),
+        header.truncated,
+        header.recursion_desired,
+        header.recursion_available
+    );
+
+    let questions = response
+        .questions
+        .iter()
+        .map(|question| {
+            format!(
+                "{{\"name\":\"{}\",\"type\":{}}}",
+                escape(&format_name(&question.name)),
+                u16::from(question.type_.clone())
+            )
+        })
+        .collect::<Vec<_>>();
+    json.push_str(&format!(",\"Question\":[{}]", questions.join(",")));
+
+    for (section, records) in [
+        ("Answer", &response.answers),
+        ("Authority", &response.authorities),
+        ("Additional", &response.additionnals),
+    ] {
+        if !records.is_empty() {
+            let records = records.iter().map(render_record).collect::<Vec<_>>();
+            json.push_str(&format!(",\"{}\":[{}]", section, records.join(",")));
+        }
+    }
+
+    json.push('}');
+    json
+}
+
+fn render_record(record: &ResourceRecord) -> String {
+    format!(
+        "{{\"name\":\"{}\",\"type\":{},\"TTL\":{},\"data\":\"{}\"}}",
+        escape(&format_name(&record.name)),
+        u16::from(record.type_),
+        record.ttl,
+        escape(&format_resource_data(record.type_, &record.resource_data))
+    )
+}
+
+fn format_name(name: &DomainName) -> String {
+    match name.to_string().as_str() {
+        "" => ".".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Mnemonics of the known types, ANY, or any number
+fn parse_type(value: &str) -> Option<QuestionType> {
+    if let Ok(number) = value.parse::<u16>() {
+        return QuestionType::try_from(number).ok();
+    }
+
+    match value.to_ascii_uppercase().as_str() {
+        "ANY" => Some(QuestionType::ALL),
+        "AXFR" => Some(QuestionType::AXFR),
+        mnemonic => (1..=u16::MAX)
+            .filter_map(|number| Type::try_from(number).ok())
+            .find(|type_| format!("{:?}", type_) == mnemonic)
+            .map(QuestionType::RRType),
+    }
+}
+
+/// Zone transfers are refused, and OPT is a pseudo-record of EDNS rather than a record type
+fn is_supported(type_: &QuestionType) -> bool {
+    !matches!(type_, QuestionType::AXFR | QuestionType::RRType(Type::OPT))
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target: &str) -> Request {
+        Request::read(&mut format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn builds_queries_from_parameters() {
+        let query = query(&request("/resolve?name=example.com&type=aaaa")).unwrap();
+        assert!(query.header.recursion_desired);
+        assert_eq!(
+            query.questions,
+            vec![Question {
+                name: DomainName::from("example.com"),
+                type_: QuestionType::RRType(Type::AAAA),
+                class: Class::IN,
+            }]
+        );
+
+        let type_of = |target: &str| super::query(&request(target)).unwrap().questions[0].clone();
+        assert_eq!(
+            type_of("/resolve?name=example.com").type_,
+            QuestionType::RRType(Type::A)
+        );
+        assert_eq!(
+            type_of("/resolve?name=example.com&type=15").type_,
+            QuestionType::RRType(Type::MX)
+        );
+        assert_eq!(type_of("/resolve?type=ANY&name=.").type_, QuestionType::ALL);
+        assert_eq!(
+            type_of("/resolve?name=%65xample.com.").name,
+            DomainName::from("example.com.")
+        );
+
+        let status_of = |target: &str| super::query(&request(target)).unwrap_err().status;
+        assert_eq!(status_of("/resolve"), 400);
+        assert_eq!(status_of("/resolve?name="), 400);
+        assert_eq!(status_of("/resolve?name=example.com&type=BOGUS"), 400);
+        assert_eq!(status_of("/resolve?name=example.com&type=1000"), 400);
+        assert_eq!(status_of("/resolve?name=%zz"), 400);
+    }
+
+    #[test]
+    fn rejects_unsupported_types() {
+        for target in [
+            "/resolve?name=example.com&type=AXFR",
+            "/resolve?name=example.com&type=252",
+            "/resolve?name=example.com&type=OPT",
+        ] {
+            let mut response = vec![];
+            query(&request(target))
+                .unwrap_err()
+                .write(&mut response, false)
+                .unwrap();
+            let response = String::from_utf8(response).unwrap();
+
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", target);
+            assert!(response.contains("application/dns-json"));
+            assert!(response.ends_with("{\"error\":\"unsupported type\"}"));
+        }
+    }
+
+    #[test]
+    fn renders_responses() {
+        let mut response = query(&request("/resolve?name=example.com&type=TXT")).unwrap();
+        response.header.qr = MessageType::Response;
+        response.header.recursion_available = true;
+        response.header.answers_count = 1;
+        response.answers = vec![ResourceRecord {
+            name: DomainName::from("example.com"),
+            type_: Type::TXT,
+            class: Class::IN,
+            ttl: 300,
+            resource_data: b"say \"hi\"".to_vec(),
+        }];
+
+        assert_eq!(
+            render(&response),
+            "{\"Status\":0,\"TC\":false,\"RD\":true,\"RA\":true,\"AD\":false,\"CD\":false,\
+             \"Question\":[{\"name\":\"example.com.\",\"type\":16}],\
+             \"Answer\":[{\"name\":\"example.com.\",\"type\":16,\"TTL\":300,\
+             \"data\":\"\\\"say \\\\\\\"hi\\\\\\\"\\\"\"}]}"
+        );
+
+        let error = response.into_error_response(ResponseCode::NameError);
+        assert!(render(&error).starts_with("{\"Status\":3,"));
+        assert!(!render(&error).contains("Answer"));
+    }
+}
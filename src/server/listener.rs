@@ -0,0 +1,479 @@
+// Listening sockets set up by hand so that IPV6_V6ONLY, SO_REUSEPORT and SO_BINDTODEVICE
+// can be applied before binding, which std does not allow
+
+use std::{
+    env,
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    mem,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    str::FromStr,
+};
+
+use crate::transport::DNS_PORT;
+
+const TCP_BACKLOG: i32 = 1024;
+
+// ports picked for UDP which are tried over TCP before giving up, when binding port 0
+const MAX_PORT_ATTEMPTS: usize = 16;
+
+/// An address the server listens on, over UDP, TCP or both, or over TLS or HTTP
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub udp: bool,
+    pub tcp: bool,
+    /// The TCP connections start with a TLS handshake: DNS over TLS, see RFC 7858
+    pub tls: bool,
+    /// DNS over HTTP(S) at /dns-query instead of length prefixed messages, see RFC 8484
+    pub http: bool,
+    /// Only accept traffic coming through this network interface (Linux only)
+    pub interface: Option<String>,
+    /// Amount of sockets bound to the address per transport, with SO_REUSEPORT when more than one
+    /// so that the kernel spreads the traffic across them
+    pub sockets: usize,
+}
+
+impl ListenerConfig {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            udp: true,
+            tcp: true,
+            tls: false,
+            http: false,
+            interface: None,
+            sockets: 1,
+        }
+    }
+
+    /// Reads DNS_LISTEN, a comma separated list of listeners (see FromStr), and DNS_LISTEN_SOCKETS
+    /// Falls back to both transports on 0.0.0.0 and the port from DNS_PORT
+    pub fn from_env() -> Result<Vec<Self>> {
+        let sockets = env::var("DNS_LISTEN_SOCKETS")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|s| *s > 0)
+            .unwrap_or(1);
+
+        let listeners = match env::var("DNS_LISTEN") {
+            Ok(listeners) => listeners
+                .split(',')
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(|l| l.parse::<Self>().map_err(Error::other))
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => {
+                let port = env::var("DNS_PORT")
+                    .ok()
+                    .and_then(|p| p.parse::<u16>().ok())
+                    .unwrap_or(DNS_PORT);
+                vec![Self::new(SocketAddr::from(([0, 0, 0, 0], port)))]
+            }
+        };
+
+        Ok(listeners
+            .into_iter()
+            .map(|listener| Self {
+                sockets,
+                ..listener
+            })
+            .collect())
+    }
+
+    pub fn bind_udp(&self) -> Result<Vec<UdpSocket>> {
+        self.bind(libc::SOCK_DGRAM)
+            .map(|fds| fds.into_iter().map(UdpSocket::from).collect())
+    }
+
+    pub fn bind_tcp(&self) -> Result<Vec<TcpListener>> {
+        self.bind(libc::SOCK_STREAM)
+            .map(|fds| fds.into_iter().map(TcpListener::from).collect())
+    }
+
+    /// Binds the sockets of every transport the listener uses, with port 0 UDP and TCP share
+    /// the port picked for UDP
+    pub fn bind_all(&self) -> Result<(Vec<UdpSocket>, Vec<TcpListener>)> {
+        let mut attempts = 0;
+
+        loop {
+            let udp_sockets = if self.udp {
+                self.bind_udp().map_err(|e| self.error("UDP", e))?
+            } else {
+                vec![]
+            };
+
+            let tcp_listener = match udp_sockets.first() {
+                Some(udp_socket) => Self {
+                    address: udp_socket.local_addr()?,
+                    ..self.clone()
+                },
+                None => self.clone(),
+            };
+
+            match self.tcp.then(|| tcp_listener.bind_tcp()).transpose() {
+                Ok(tcp_listeners) => return Ok((udp_sockets, tcp_listeners.unwrap_or_default())),
+                // the port picked for UDP may already be taken over TCP
+                Err(e)
+                    if e.kind() == ErrorKind::AddrInUse
+                        && self.address.port() == 0
+                        && attempts < MAX_PORT_ATTEMPTS =>
+                {
+                    attempts += 1;
+                }
+                Err(e) => return Err(self.error("TCP", e)),
+            }
+        }
+    }
+
+    fn error(&self, transport: &str, e: Error) -> Error {
+        Error::new(
+            e.kind(),
+            format!("could not listen on {} over {}: {}", self, transport, e),
+        )
+    }
+
+    fn bind(&self, socket_type: i32) -> Result<Vec<OwnedFd>> {
+        let mut address = self.address;
+        let mut fds = Vec::with_capacity(self.sockets);
+
+        for _ in 0..self.sockets.max(1) {
+            let fd = self.bind_socket(socket_type, address)?;
+
+            // with port 0 the next sockets must share the port picked for the first one
+            if address.port() == 0 {
+                address = local_addr(&fd, socket_type)?;
+            }
+
+            fds.push(fd);
+        }
+
+        Ok(fds)
+    }
+
+    fn bind_socket(&self, socket_type: i32, address: SocketAddr) -> Result<OwnedFd> {
+        let domain = match address {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+
+        let fd = check(unsafe { libc::socket(domain, socket_type | libc::SOCK_CLOEXEC, 0) })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let raw_fd = fd.as_raw_fd();
+
+        // an IPv6 wildcard listener must not grab the IPv4 traffic, so that both can be configured
+        if address.is_ipv6() {
+            set_option(raw_fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+        }
+
+        if socket_type == libc::SOCK_STREAM {
+            set_option(raw_fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        }
+
+        if self.sockets > 1 {
+            set_option(raw_fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+
+        if let Some(interface) = &self.interface {
+            bind_to_device(raw_fd, interface)?;
+        }
+
+        let (storage, length) = to_sockaddr(address);
+        check(unsafe {
+            libc::bind(
+                raw_fd,
+                (&storage as *const libc::sockaddr_storage).cast(),
+                length,
+            )
+        })
+        .map_err(|e| Error::new(e.kind(), format!("could not bind {}: {}", address, e)))?;
+
+        if socket_type == libc::SOCK_STREAM {
+            check(unsafe { libc::listen(raw_fd, TCP_BACKLOG) })?;
+        }
+
+        Ok(fd)
+    }
+}
+
+/// Format: `[udp/|tcp/|tls/|http/|https/]address:port[@interface]`, IPv6 addresses are written
+/// between brackets, e.g. `0.0.0.0:53`, `udp/[::1]:5353`, `tcp/192.168.1.10:53@eth0`,
+/// `tls/0.0.0.0:853` or `https/[::]:443`
+impl FromStr for ListenerConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (transports, rest) = match s.split_once('/') {
+            Some((transports, rest)) => (Some(transports), rest),
+            None => (None, s),
+        };
+
+        let (address, interface) = match rest.split_once('@') {
+            Some((address, interface)) if !interface.is_empty() => {
+                (address, Some(interface.to_string()))
+            }
+            Some(_) => return Err(format!("Missing interface name in listener: {}", s)),
+            None => (rest, None),
+        };
+
+        let address = address
+            .parse::<SocketAddr>()
+            .map_err(|_| format!("Invalid listen address: {}", address))?;
+
+        let (udp, tcp, tls, http) = match transports.map(|t| t.to_ascii_lowercase()).as_deref() {
+            None => (true, true, false, false),
+            Some("udp") => (true, false, false, false),
+            Some("tcp") => (false, true, false, false),
+            Some("tls") => (false, true, true, false),
+            Some("http") => (false, true, false, true),
+            Some("https") => (false, true, true, true),
+            Some(transports) => return Err(format!("Unknown listener transport: {}", transports)),
+        };
+
+        Ok(Self {
+            address,
+            udp,
+            tcp,
+            tls,
+            http,
+            interface,
+            sockets: 1,
+        })
+    }
+}
+
+impl Display for ListenerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.udp, self.tcp, self.tls, self.http) {
+            (false, true, true, true) => write!(f, "https/")?,
+            (false, true, false, true) => write!(f, "http/")?,
+            (false, true, true, false) => write!(f, "tls/")?,
+            (true, false, _, _) => write!(f, "udp/")?,
+            (false, true, _, _) => write!(f, "tcp/")?,
+            _ => {}
+        }
+
+        write!(f, "{}", self.address)?;
+
+        if let Some(interface) = &self.interface {
+            write!(f, "@{}", interface)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(fd: RawFd, interface: &str) -> Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr().cast(),
+            interface.len() as libc::socklen_t,
+        )
+    })
+    .map(|_| ())
+    .map_err(|e| Error::new(e.kind(), format!("could not bind to {}: {}", interface, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_fd: RawFd, interface: &str) -> Result<()> {
+    Err(Error::other(format!(
+        "binding to interface {} is only supported on Linux",
+        interface
+    )))
+}
+
+fn set_option(fd: RawFd, level: i32, name: i32, value: i32) -> Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const i32).cast(),
+            mem::size_of::<i32>() as libc::socklen_t,
+        )
+    })
+    .map(|_| ())
+}
+
+fn to_sockaddr(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let length = match address {
+        SocketAddr::V4(address) => {
+            let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = address.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from_ne_bytes(address.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = address.port().to_be();
+            sockaddr.sin6_flowinfo = address.flowinfo();
+            sockaddr.sin6_addr.s6_addr = address.ip().octets();
+            sockaddr.sin6_scope_id = address.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, length as libc::socklen_t)
+}
+
+/// Reads the address the kernel picked, going through std to decode it
+fn local_addr(fd: &OwnedFd, socket_type: i32) -> Result<SocketAddr> {
+    let fd = fd.try_clone()?;
+
+    if socket_type == libc::SOCK_DGRAM {
+        UdpSocket::from(fd).local_addr()
+    } else {
+        TcpListener::from(fd).local_addr()
+    }
+}
+
+fn check(result: i32) -> Result<i32> {
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn parses_listener() {
+        assert_eq!(
+            "0.0.0.0:53".parse::<ListenerConfig>(),
+            Ok(ListenerConfig::new("0.0.0.0:53".parse().unwrap()))
+        );
+
+        assert_eq!(
+            "udp/[::1]:5353@lo".parse::<ListenerConfig>(),
+            Ok(ListenerConfig {
+                address: SocketAddr::from((Ipv6Addr::LOCALHOST, 5353)),
+                udp: true,
+                tcp: false,
+                tls: false,
+                http: false,
+                interface: Some("lo".to_string()),
+                sockets: 1,
+            })
+        );
+
+        let tcp_only = "TCP/127.0.0.1:53".parse::<ListenerConfig>().unwrap();
+        assert!(tcp_only.tcp && !tcp_only.udp && !tcp_only.tls);
+
+        let tls = "tls/0.0.0.0:853".parse::<ListenerConfig>().unwrap();
+        assert!(tls.tcp && tls.tls && !tls.udp && !tls.http);
+
+        let https = "https/0.0.0.0:443".parse::<ListenerConfig>().unwrap();
+        assert!(https.tcp && https.tls && https.http && !https.udp);
+        let http = "http/127.0.0.1:8053".parse::<ListenerConfig>().unwrap();
+        assert!(http.tcp && http.http && !http.tls);
+    }
+
+    #[test]
+    fn rejects_invalid_listener() {
+        assert!("sctp/127.0.0.1:53".parse::<ListenerConfig>().is_err());
+        assert!("127.0.0.1".parse::<ListenerConfig>().is_err());
+        assert!("::1:53".parse::<ListenerConfig>().is_err());
+        assert!("127.0.0.1:53@".parse::<ListenerConfig>().is_err());
+    }
+
+    #[test]
+    fn displays_listener_as_parsed() {
+        for listener in [
+            "0.0.0.0:53",
+            "udp/[::1]:5353@lo",
+            "tcp/127.0.0.1:53",
+            "tls/[::]:853",
+            "https/[::]:443",
+            "http/127.0.0.1:8053",
+        ] {
+            assert_eq!(
+                listener.parse::<ListenerConfig>().unwrap().to_string(),
+                listener
+            );
+        }
+    }
+
+    #[test]
+    fn binds_ipv4_and_ipv6_on_the_same_port() {
+        let ipv4 = ListenerConfig::new("127.0.0.1:0".parse().unwrap())
+            .bind_udp()
+            .unwrap();
+        let port = ipv4[0].local_addr().unwrap().port();
+
+        let ipv6 = ListenerConfig::new(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+            .bind_udp()
+            .unwrap();
+
+        assert_eq!(ipv6[0].local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn binds_both_transports_on_the_same_ephemeral_port() {
+        let listener = ListenerConfig::new("127.0.0.1:0".parse().unwrap());
+
+        let (udp_sockets, tcp_listeners) = listener.bind_all().unwrap();
+
+        let port = udp_sockets[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(tcp_listeners[0].local_addr().unwrap().port(), port);
+
+        let udp_only = ListenerConfig {
+            tcp: false,
+            ..listener
+        };
+        let (udp_sockets, tcp_listeners) = udp_only.bind_all().unwrap();
+        assert_eq!(udp_sockets.len(), 1);
+        assert!(tcp_listeners.is_empty());
+    }
+
+    #[test]
+    fn binds_several_sockets_with_reuse_port() {
+        let listener = ListenerConfig {
+            sockets: 3,
+            ..ListenerConfig::new("127.0.0.1:0".parse().unwrap())
+        };
+
+        let udp_sockets = listener.bind_udp().unwrap();
+        let tcp_listeners = listener.bind_tcp().unwrap();
+
+        let port = udp_sockets[0].local_addr().unwrap().port();
+        assert_eq!(udp_sockets.len(), 3);
+        assert!(
+            udp_sockets
+                .iter()
+                .all(|s| s.local_addr().unwrap().port() == port)
+        );
+        assert_eq!(tcp_listeners.len(), 3);
+    }
+
+    #[test]
+    fn exchanges_datagrams_over_ipv6() {
+        let server = ListenerConfig::new("[::1]:0".parse().unwrap())
+            .bind_udp()
+            .unwrap()
+            .remove(0);
+
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        client
+            .send_to(&[1, 2, 3], server.local_addr().unwrap())
+            .unwrap();
+
+        let mut buf = [0u8; 3];
+        let (amt, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], &[1, 2, 3]);
+    }
+}
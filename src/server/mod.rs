@@ -0,0 +1,2499 @@
+use std::{
+    env,
+    io::{Error, ErrorKind, Read, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::fd::AsRawFd,
+    str::FromStr,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{RecvTimeoutError, SendError, Sender, channel},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::header::ResponseCode,
+    decoder::Decoder,
+    encoder::Encoder,
+    log::{error, info, warning},
+    privileges::Privileges,
+    storage::ResourceRecordRepository,
+    transport::{DNS_PORT, Transport, UDP_RECEIVE_BUFFER_SIZE},
+    worker_pool::{WorkerPool, WorkerPoolMetrics},
+};
+
+pub use self::{
+    acl::{Acl, AclAction, Network},
+    activation::ActivatedSockets,
+    listener::ListenerConfig,
+    proxy::ProxyProtocol,
+    running::RunningServer,
+    shutdown::{DEFAULT_SHUTDOWN_TIMEOUT, ServerHandle},
+    tls::TlsConfig,
+};
+use self::{
+    connection::{AcceptedConnection, ConnectionContext, ConnectionWatcher},
+    handler::Handler,
+    tls::{TlsContext, TlsReader, TlsStream, TlsWriter},
+};
+
+mod acl;
+mod activation;
+mod connection;
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod handler;
+mod http;
+mod json;
+mod listener;
+#[cfg(target_os = "linux")]
+mod poller;
+mod proxy;
+mod running;
+mod shutdown;
+mod tls;
+
+const METRICS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+// blocking threads look for a shutdown request at least this often
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the connections still open are counted while stopping
+const CONNECTIONS_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What to do with a query received while the worker pool queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    Drop,
+    ServerFailure,
+    Refused,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(Self::Drop),
+            "servfail" => Ok(Self::ServerFailure),
+            "refused" => Ok(Self::Refused),
+            _ => Err(format!("Unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+impl OverflowPolicy {
+    fn response_code(&self) -> Option<ResponseCode> {
+        match self {
+            Self::Drop => None,
+            Self::ServerFailure => Some(ResponseCode::ServerFailure),
+            Self::Refused => Some(ResponseCode::Refused),
+        }
+    }
+}
+
+/// Sizing of the worker pools: one running queries, one serving the TCP connections as they
+/// become readable (the TLS and HTTP ones only in event loop mode)
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerPoolConfig {
+    pub workers: usize,
+    pub queue_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl WorkerPoolConfig {
+    /// Reads DNS_WORKERS, DNS_QUEUE_CAPACITY and DNS_OVERFLOW_POLICY, falling back to defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            workers: env::var("DNS_WORKERS")
+                .ok()
+                .and_then(|w| w.parse::<usize>().ok())
+                .filter(|w| *w > 0)
+                .unwrap_or(default.workers),
+            queue_capacity: env::var("DNS_QUEUE_CAPACITY")
+                .ok()
+                .and_then(|c| c.parse::<usize>().ok())
+                .filter(|c| *c > 0)
+                .unwrap_or(default.queue_capacity),
+            overflow_policy: env::var("DNS_OVERFLOW_POLICY")
+                .ok()
+                .and_then(|p| p.parse::<OverflowPolicy>().ok())
+                .unwrap_or(default.overflow_policy),
+        }
+    }
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: thread::available_parallelism()
+                .map(|n| n.get() * 2)
+                .unwrap_or(8),
+            queue_capacity: 1024,
+            overflow_policy: OverflowPolicy::Drop,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServerMode {
+    /// A thread blocks on each socket and hands queries (UDP) or connections (TCP) to worker pools
+    Threaded,
+    /// Non-blocking sockets multiplexed by epoll based reactors, Linux only
+    EventLoop { reactors: usize },
+}
+
+impl ServerMode {
+    /// Reads DNS_SERVER_MODE (threaded or event-loop) and DNS_REACTORS, falling back to threaded
+    pub fn from_env() -> Self {
+        match env::var("DNS_SERVER_MODE").ok().as_deref() {
+            Some("event-loop") => Self::EventLoop {
+                reactors: env::var("DNS_REACTORS")
+                    .ok()
+                    .and_then(|r| r.parse::<usize>().ok())
+                    .filter(|r| *r > 0)
+                    .unwrap_or(1),
+            },
+            _ => Self::Threaded,
+        }
+    }
+}
+
+/// Persistent TCP connections settings, see: https://datatracker.ietf.org/doc/html/rfc7766#section-6.2
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpConfig {
+    /// Connections without outstanding query are closed after this long without receiving anything
+    pub idle_timeout: Duration,
+    /// Connections are closed once this many queries have been answered
+    pub max_queries_per_connection: usize,
+    /// Connections accepted while this many are open are closed right away
+    pub max_connections: usize,
+}
+
+impl TcpConfig {
+    /// Reads DNS_TCP_IDLE_TIMEOUT (in seconds), DNS_TCP_MAX_QUERIES and DNS_TCP_MAX_CONNECTIONS,
+    /// falling back to defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            idle_timeout: env::var("DNS_TCP_IDLE_TIMEOUT")
+                .ok()
+                .and_then(|t| t.parse::<u64>().ok())
+                .filter(|t| *t > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default.idle_timeout),
+            max_queries_per_connection: env::var("DNS_TCP_MAX_QUERIES")
+                .ok()
+                .and_then(|q| q.parse::<usize>().ok())
+                .filter(|q| *q > 0)
+                .unwrap_or(default.max_queries_per_connection),
+            max_connections: env::var("DNS_TCP_MAX_CONNECTIONS")
+                .ok()
+                .and_then(|c| c.parse::<usize>().ok())
+                .filter(|c| *c > 0)
+                .unwrap_or(default.max_connections),
+        }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(10),
+            max_queries_per_connection: 100,
+            max_connections: 512,
+        }
+    }
+}
+
+/// Caps the amount of open TCP connections across every listener and event loop
+#[derive(Clone)]
+struct ConnectionLimiter {
+    active_connections: Arc<AtomicUsize>,
+    max_connections: usize,
+}
+
+impl ConnectionLimiter {
+    fn new(max_connections: usize) -> Self {
+        Self {
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            max_connections,
+        }
+    }
+
+    fn try_acquire(&self) -> Option<ConnectionPermit> {
+        let previous = self.active_connections.fetch_add(1, Ordering::Relaxed);
+
+        if previous >= self.max_connections {
+            self.active_connections.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+
+        Some(ConnectionPermit {
+            active_connections: Arc::clone(&self.active_connections),
+        })
+    }
+
+    /// Waits for every connection to be closed, false when some are still open at the deadline
+    fn wait_closed(&self, deadline: Instant) -> bool {
+        while self.active_connections.load(Ordering::Relaxed) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(CONNECTIONS_CHECK_INTERVAL);
+        }
+
+        true
+    }
+}
+
+/// Held for as long as the connection is open
+struct ConnectionPermit {
+    active_connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Shutdown request and how long the in-flight queries are given once it comes
+#[derive(Clone)]
+struct ShutdownControl {
+    handle: ServerHandle,
+    timeout: Duration,
+}
+
+impl ShutdownControl {
+    /// Once the server threads returned, which they only do after the shutdown was requested
+    fn deadline(&self) -> Instant {
+        self.handle
+            .deadline(self.timeout)
+            .unwrap_or_else(|| Instant::now() + self.timeout)
+    }
+}
+
+/// Counts the queries of a connection which have not been answered yet
+#[derive(Default)]
+struct PendingQueries {
+    count: Mutex<usize>,
+    all_answered: Condvar,
+}
+
+impl PendingQueries {
+    fn add(&self) {
+        *self.count.lock().unwrap_or_else(|p| p.into_inner()) += 1;
+    }
+
+    fn done(&self) {
+        let mut count = self.count.lock().unwrap_or_else(|p| p.into_inner());
+        *count -= 1;
+        if *count == 0 {
+            self.all_answered.notify_all();
+        }
+    }
+
+    fn any(&self) -> bool {
+        *self.count.lock().unwrap_or_else(|p| p.into_inner()) > 0
+    }
+
+    fn wait(&self) {
+        let count = self.count.lock().unwrap_or_else(|p| p.into_inner());
+        drop(
+            self.all_answered
+                .wait_while(count, |count| *count > 0)
+                .unwrap_or_else(|p| p.into_inner()),
+        );
+    }
+}
+
+/// Sockets bound by Server::start, handed over to the serving thread
+struct BoundSockets {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+    /// TLS and HTTP listeners
+    streams: Vec<StreamListener>,
+}
+
+/// A listener whose connections are served by the connection pool, whatever the server mode
+struct StreamListener {
+    listener: TcpListener,
+    /// Context of the TLS handshake each connection starts with
+    tls_context: Option<Arc<TlsContext>>,
+    protocol: StreamProtocol,
+}
+
+impl StreamListener {
+    fn tcp(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            tls_context: None,
+            protocol: StreamProtocol::Dns,
+        }
+    }
+}
+
+/// What the connections of a stream listener speak, once past TLS
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StreamProtocol {
+    /// Length prefixed DNS messages
+    Dns,
+    /// HTTP/1.1 requests to /dns-query
+    Http,
+}
+
+pub struct Server<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    decoder: D,
+    encoder: E,
+    storage: R,
+    listeners: Vec<ListenerConfig>,
+    /// Sockets bound beforehand, e.g. by systemd, served along with the listeners
+    udp_sockets: Vec<UdpSocket>,
+    tcp_listeners: Vec<TcpListener>,
+    worker_pool_config: WorkerPoolConfig,
+    tcp_config: TcpConfig,
+    mode: ServerMode,
+    acl: Acl,
+    proxy_protocol: ProxyProtocol,
+    tls: Option<TlsConfig>,
+    handle: ServerHandle,
+    shutdown_timeout: Duration,
+    privileges: Privileges,
+}
+
+impl<D, E, R> Server<D, E, R>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+    R: ResourceRecordRepository + Send + Sync + 'static,
+{
+    pub fn new(decoder: D, encoder: E, storage: R) -> Self {
+        Server {
+            decoder,
+            encoder,
+            storage,
+            listeners: vec![ListenerConfig::new(SocketAddr::from((
+                [0, 0, 0, 0],
+                DNS_PORT,
+            )))],
+            udp_sockets: vec![],
+            tcp_listeners: vec![],
+            worker_pool_config: WorkerPoolConfig::default(),
+            tcp_config: TcpConfig::default(),
+            mode: ServerMode::Threaded,
+            acl: Acl::default(),
+            proxy_protocol: ProxyProtocol::default(),
+            tls: None,
+            handle: ServerHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            privileges: Privileges::default(),
+        }
+    }
+
+    pub fn with_listeners(mut self, listeners: Vec<ListenerConfig>) -> Self {
+        self.listeners = listeners;
+        self
+    }
+
+    /// Serves sockets which are already bound, on top of the listeners
+    pub fn with_sockets(mut self, sockets: ActivatedSockets) -> Self {
+        self.udp_sockets.extend(sockets.udp_sockets);
+        self.tcp_listeners.extend(sockets.tcp_listeners);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: ServerMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_worker_pool_config(mut self, worker_pool_config: WorkerPoolConfig) -> Self {
+        self.worker_pool_config = worker_pool_config;
+        self
+    }
+
+    pub fn with_tcp_config(mut self, tcp_config: TcpConfig) -> Self {
+        self.tcp_config = tcp_config;
+        self
+    }
+
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Proxies whose queries carry the address of the client they come from
+    pub fn with_proxy_protocol(mut self, proxy_protocol: ProxyProtocol) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// How long in-flight queries are waited for once the shutdown is requested
+    pub fn with_shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Certificate and key of the TLS listeners
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// User, group and chroot switched to once the sockets are bound
+    pub fn with_privileges(mut self, privileges: Privileges) -> Self {
+        self.privileges = privileges;
+        self
+    }
+
+    /// Binds every listener then serves queries in the background, once the threads serving them
+    /// are started, the errors met until then are returned
+    /// Listening on port 0 lets the system pick a free port, see RunningServer::udp_addresses
+    pub fn start(mut self) -> std::io::Result<RunningServer> {
+        // loaded before dropping privileges, the key is usually only readable by root
+        let tls_context = match self.listeners.iter().any(|listener| listener.tls) {
+            true => {
+                let tls = self.tls.as_ref().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "TLS listeners need a certificate and a key",
+                    )
+                })?;
+                Some(Arc::new(TlsContext::server(&tls.certificate, &tls.key)?))
+            }
+            false => None,
+        };
+
+        let mut sockets = BoundSockets {
+            udp: mem::take(&mut self.udp_sockets),
+            tcp: mem::take(&mut self.tcp_listeners),
+            streams: vec![],
+        };
+        for listener in self.listeners.iter() {
+            let (udp, tcp) = listener.bind_all()?;
+            sockets.udp.extend(udp);
+            if listener.tls || listener.http {
+                sockets
+                    .streams
+                    .extend(tcp.into_iter().map(|tcp| StreamListener {
+                        listener: tcp,
+                        tls_context: tls_context.clone().filter(|_| listener.tls),
+                        protocol: match listener.http {
+                            true => StreamProtocol::Http,
+                            false => StreamProtocol::Dns,
+                        },
+                    }));
+            } else {
+                sockets.tcp.extend(tcp);
+            }
+        }
+
+        let udp_addresses = local_addresses(sockets.udp.iter().map(UdpSocket::local_addr))?;
+        let tcp_addresses = local_addresses(sockets.tcp.iter().map(TcpListener::local_addr))?;
+        let stream_addresses = |tls: bool, protocol: StreamProtocol| {
+            local_addresses(
+                sockets
+                    .streams
+                    .iter()
+                    .filter(|stream| {
+                        stream.tls_context.is_some() == tls && stream.protocol == protocol
+                    })
+                    .map(|stream| stream.listener.local_addr()),
+            )
+        };
+        let tls_addresses = stream_addresses(true, StreamProtocol::Dns)?;
+        let http_addresses = stream_addresses(false, StreamProtocol::Http)?;
+        let https_addresses = stream_addresses(true, StreamProtocol::Http)?;
+
+        // before any query is received
+        if !self.privileges.is_empty() {
+            self.privileges
+                .apply()
+                .map_err(|e| Error::new(e.kind(), format!("could not drop privileges: {}", e)))?;
+            info!("🔒 Privileges dropped to {}", self.privileges);
+        }
+        let handle = self.handle.clone();
+        let (ready, started) = channel();
+        let thread = thread::Builder::new()
+            .name("server".to_string())
+            .spawn(move || self.serve(sockets, ready))?;
+        let started = started
+            .recv()
+            .unwrap_or_else(|_| Err(Error::other("the server thread stopped while starting")));
+        if let Err(e) = started {
+            let _ = thread.join();
+            return Err(e);
+        }
+
+        Ok(RunningServer::new(
+            udp_addresses,
+            tcp_addresses,
+            tls_addresses,
+            http_addresses,
+            https_addresses,
+            handle,
+            thread,
+        ))
+    }
+
+    /// Reports on `ready` once the serving threads are started, or the error which stopped them
+    fn serve(self, sockets: BoundSockets, ready: Sender<std::io::Result<()>>) {
+        let handler = Arc::new(
+            Handler::new(
+                self.decoder,
+                self.encoder,
+                self.storage,
+                self.tcp_config.idle_timeout,
+            )
+            .with_acl(self.acl),
+        );
+
+        let pool_config = self.worker_pool_config;
+        let tcp_config = self.tcp_config;
+        let proxy_protocol = self.proxy_protocol;
+        let handle = self.handle.clone();
+        let shutdown = ShutdownControl {
+            handle: self.handle,
+            timeout: self.shutdown_timeout,
+        };
+
+        let served = match self.mode {
+            ServerMode::Threaded => Self::run_threaded(
+                handler,
+                sockets,
+                pool_config,
+                tcp_config,
+                proxy_protocol,
+                shutdown,
+                &ready,
+            ),
+            #[cfg(target_os = "linux")]
+            ServerMode::EventLoop { reactors } => Self::run_event_loops(
+                handler,
+                sockets,
+                pool_config,
+                tcp_config,
+                proxy_protocol,
+                reactors,
+                shutdown,
+                &ready,
+            ),
+            #[cfg(not(target_os = "linux"))]
+            ServerMode::EventLoop { .. } => Err(Error::new(
+                ErrorKind::Unsupported,
+                "the event loop mode is only supported on Linux",
+            )),
+        };
+
+        if let Err(e) = served {
+            // the threads started so far stop along with the server
+            handle.shutdown();
+            if let Err(SendError(Err(e))) = ready.send(Err(e)) {
+                error!("💣🔥 Server stopped: {}", e);
+            }
+            return;
+        }
+
+        info!("🛑 Server stopped");
+    }
+
+    /// Each socket gets its own receiving thread, sockets sharing a port through SO_REUSEPORT
+    /// are thus read in parallel
+    fn run_threaded(
+        handler: Arc<Handler<D, E, R>>,
+        sockets: BoundSockets,
+        pool_config: WorkerPoolConfig,
+        tcp_config: TcpConfig,
+        proxy_protocol: ProxyProtocol,
+        shutdown: ShutdownControl,
+        ready: &Sender<std::io::Result<()>>,
+    ) -> std::io::Result<()> {
+        let query_pool = Arc::new(WorkerPool::new(
+            "query",
+            pool_config.workers,
+            pool_config.queue_capacity,
+        ));
+        let (connection_pool, watcher) =
+            start_connection_pool(&pool_config, &tcp_config, &shutdown.handle)?;
+        info!(
+            "🧵 {} query workers and {} connection workers, queue of {} jobs each, overflow policy {:?}, up to {} TCP connections",
+            pool_config.workers,
+            pool_config.workers,
+            pool_config.queue_capacity,
+            pool_config.overflow_policy,
+            tcp_config.max_connections
+        );
+
+        Self::report_metrics(
+            vec![
+                ("Query", query_pool.metrics()),
+                ("Connection", connection_pool.metrics()),
+            ],
+            &shutdown.handle,
+        )?;
+
+        let mut handles = vec![];
+        for udp_socket in sockets.udp {
+            handles.push(Self::run_udp(
+                Arc::clone(&handler),
+                udp_socket,
+                Arc::clone(&query_pool),
+                pool_config.overflow_policy,
+                proxy_protocol.clone(),
+                shutdown.handle.clone(),
+            )?);
+        }
+
+        // TLS and HTTP connections are served like the other TCP connections
+        let connection_limiter = ConnectionLimiter::new(tcp_config.max_connections);
+        let listeners = sockets.tcp.into_iter().map(StreamListener::tcp);
+        for listener in listeners.chain(sockets.streams) {
+            handles.push(Self::run_tcp(
+                Arc::clone(&handler),
+                listener,
+                Arc::clone(&query_pool),
+                pool_config.overflow_policy,
+                tcp_config.clone(),
+                proxy_protocol.clone(),
+                connection_limiter.clone(),
+                watcher.clone(),
+                shutdown.handle.clone(),
+            )?);
+        }
+        let _ = ready.send(Ok(()));
+
+        // the receiving threads stop as soon as the shutdown is requested
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // connections answer their pending queries before closing, they need the pools meanwhile
+        let deadline = shutdown.deadline();
+        drain_connections(&connection_limiter, deadline);
+        drain_pool("Connection", connection_pool, deadline);
+        drain_pool("Query", query_pool, deadline);
+
+        Ok(())
+    }
+
+    /// Every event loop polls all the UDP sockets and TCP listeners, a TCP connection stays
+    /// on the event loop which accepted it
+    /// TLS and HTTP connections are served by the connection pool, as in the threaded mode
+    #[cfg(target_os = "linux")]
+    #[allow(clippy::too_many_arguments)]
+    fn run_event_loops(
+        handler: Arc<Handler<D, E, R>>,
+        sockets: BoundSockets,
+        pool_config: WorkerPoolConfig,
+        tcp_config: TcpConfig,
+        proxy_protocol: ProxyProtocol,
+        reactors: usize,
+        shutdown: ShutdownControl,
+        ready: &Sender<std::io::Result<()>>,
+    ) -> std::io::Result<()> {
+        let BoundSockets {
+            udp: udp_sockets,
+            tcp: tcp_listeners,
+            streams: stream_listeners,
+        } = sockets;
+
+        let pool = Arc::new(WorkerPool::new(
+            "query",
+            pool_config.workers,
+            pool_config.queue_capacity,
+        ));
+        info!(
+            "🌀 {} event loops, {} query workers, queue of {} jobs, overflow policy {:?}",
+            reactors, pool_config.workers, pool_config.queue_capacity, pool_config.overflow_policy
+        );
+
+        let mut pools = vec![("Query", pool.metrics())];
+        let connections = match stream_listeners.is_empty() {
+            true => None,
+            false => {
+                let connections =
+                    start_connection_pool(&pool_config, &tcp_config, &shutdown.handle)?;
+                pools.push(("Connection", connections.0.metrics()));
+                Some(connections)
+            }
+        };
+        Self::report_metrics(pools, &shutdown.handle)?;
+
+        let connection_limiter = ConnectionLimiter::new(tcp_config.max_connections);
+        let event_loops = (0..reactors)
+            .map(|_| -> std::io::Result<_> {
+                Ok(event_loop::EventLoop::new(
+                    Arc::clone(&handler),
+                    udp_sockets
+                        .iter()
+                        .map(UdpSocket::try_clone)
+                        .collect::<std::io::Result<_>>()?,
+                    tcp_listeners
+                        .iter()
+                        .map(TcpListener::try_clone)
+                        .collect::<std::io::Result<_>>()?,
+                    Arc::clone(&pool),
+                    pool_config.overflow_policy,
+                    tcp_config.clone(),
+                    connection_limiter.clone(),
+                )?
+                .with_proxy_protocol(proxy_protocol.clone())
+                .with_shutdown(shutdown.handle.clone(), shutdown.timeout))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut handles = vec![];
+        for (i, event_loop) in event_loops.into_iter().enumerate() {
+            handles.push(
+                thread::Builder::new()
+                    .name(format!("event-loop-{}", i))
+                    .spawn(move || {
+                        if let Err(e) = event_loop.run() {
+                            error!("💣🔥 Event loop stopped: {}", e);
+                        }
+                    })?,
+            );
+        }
+
+        let connection_pool = match connections {
+            Some((connection_pool, watcher)) => {
+                for listener in stream_listeners {
+                    handles.push(Self::run_tcp(
+                        Arc::clone(&handler),
+                        listener,
+                        Arc::clone(&pool),
+                        pool_config.overflow_policy,
+                        tcp_config.clone(),
+                        proxy_protocol.clone(),
+                        connection_limiter.clone(),
+                        watcher.clone(),
+                        shutdown.handle.clone(),
+                    )?);
+                }
+                Some(connection_pool)
+            }
+            None => None,
+        };
+        let _ = ready.send(Ok(()));
+
+        // each event loop drains its own connections before returning
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let deadline = shutdown.deadline();
+        drain_connections(&connection_limiter, deadline);
+        if let Some(connection_pool) = connection_pool {
+            drain_pool("Connection", connection_pool, deadline);
+        }
+        drain_pool("Query", pool, deadline);
+
+        Ok(())
+    }
+
+    fn report_metrics(
+        pools: Vec<(&'static str, Arc<WorkerPoolMetrics>)>,
+        shutdown: &ServerHandle,
+    ) -> std::io::Result<()> {
+        let (stop_sender, stop) = channel();
+        shutdown.on_shutdown(move || {
+            let _ = stop_sender.send(());
+        });
+
+        thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) =
+                    stop.recv_timeout(METRICS_REPORT_INTERVAL)
+                {
+                    for (name, metrics) in pools.iter() {
+                        info!("📊 {} pool: {}", name, metrics);
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn run_udp(
+        handler: Arc<Handler<D, E, R>>,
+        socket: UdpSocket,
+        pool: Arc<WorkerPool>,
+        overflow_policy: OverflowPolicy,
+        proxy_protocol: ProxyProtocol,
+        shutdown: ServerHandle,
+    ) -> std::io::Result<thread::JoinHandle<()>> {
+        thread::Builder::new()
+            .name("udp".to_string())
+            .spawn(move || {
+                // shared by the jobs sending the responses
+                let socket = Arc::new(socket);
+                let mut buf = vec![0; UDP_RECEIVE_BUFFER_SIZE];
+                while !shutdown.is_shutting_down() {
+                    match wait_readable(&*socket, SHUTDOWN_CHECK_INTERVAL) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(e) => {
+                            warning!("couldn't wait for a datagram: {}", e);
+                            continue;
+                        }
+                    }
+
+                    match socket.recv_from(&mut buf) {
+                        Ok((amt, src)) => {
+                            // responses go back to the proxy, which relays them to the client
+                            let (client, query) =
+                                match proxy_protocol.strip_datagram(&buf[..amt], src) {
+                                    Ok(stripped) => stripped,
+                                    Err(e) => {
+                                        warning!("🚧 Dropping datagram from proxy {}: {}", src, e);
+                                        continue;
+                                    }
+                                };
+                            let buffer = query.to_vec();
+                            let handler_clone = Arc::clone(&handler);
+                            let socket_clone = Arc::clone(&socket);
+
+                            let job = move || {
+                                let Some(encoded_response) =
+                                    handler_clone.handle(&buffer, Transport::Udp, client.ip())
+                                else {
+                                    return;
+                                };
+
+                                if let Err(e) = socket_clone.send_to(&encoded_response, src) {
+                                    warning!("couldn't send response to {}: {}", src, e);
+                                }
+                            };
+
+                            if let Err(e) = pool.execute(job) {
+                                warning!(
+                                    "🚧 Could not queue UDP query from {} ({:?}), {}",
+                                    client,
+                                    e,
+                                    pool.metrics()
+                                );
+
+                                let rejection = overflow_policy
+                                    .response_code()
+                                    .and_then(|code| handler.reject(query, code));
+                                if let Some(rejection) = rejection
+                                    && let Err(e) = socket.send_to(&rejection, src)
+                                {
+                                    warning!("couldn't send response to {}: {}", src, e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warning!("couldn't receive a datagram: {}", e);
+                        }
+                    }
+                }
+            })
+    }
+
+    /// Connections are parked until they send something, then served on the connection pool,
+    /// which hands their queries over to the query pool so that pipelined queries are answered as
+    /// they complete, and waiting connections hold no thread
+    /// Connections over the limit are closed right away
+    #[allow(clippy::too_many_arguments)]
+    fn run_tcp(
+        handler: Arc<Handler<D, E, R>>,
+        listener: StreamListener,
+        query_pool: Arc<WorkerPool>,
+        overflow_policy: OverflowPolicy,
+        tcp_config: TcpConfig,
+        proxy_protocol: ProxyProtocol,
+        connection_limiter: ConnectionLimiter,
+        watcher: ConnectionWatcher,
+        shutdown: ServerHandle,
+    ) -> std::io::Result<thread::JoinHandle<()>> {
+        let context = Arc::new(ConnectionContext {
+            handler,
+            query_pool,
+            overflow_policy,
+            tcp_config,
+            proxy_protocol,
+            tls_context: listener.tls_context,
+            protocol: listener.protocol,
+            shutdown: shutdown.clone(),
+        });
+        let listener = listener.listener;
+
+        thread::Builder::new()
+            .name("tcp-listener".to_string())
+            .spawn(move || {
+                while !shutdown.is_shutting_down() {
+                    match wait_readable(&listener, SHUTDOWN_CHECK_INTERVAL) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(e) => {
+                            warning!("couldn't wait for a TCP connection: {}", e);
+                            continue;
+                        }
+                    }
+
+                    let (stream, peer) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warning!("Failed to accept TCP connection: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let Some(permit) = connection_limiter.try_acquire() else {
+                        warning!(
+                            "🚧 Too many TCP connections ({}), closing new one",
+                            context.tcp_config.max_connections
+                        );
+                        continue;
+                    };
+
+                    watcher.park(Box::new(AcceptedConnection::new(
+                        Arc::clone(&context),
+                        stream,
+                        peer,
+                        permit,
+                    )));
+                }
+            })
+    }
+}
+
+/// Stream of a TCP connection, in the clear or over TLS
+trait QueryStream: Read + Write + AsRawFd + Send + 'static {
+    type Reader: QueryReader;
+    type Writer: QueryWriter;
+
+    /// Whether bytes already received can be read without the socket becoming readable
+    fn has_buffered_data(&self) -> bool;
+
+    fn close(&mut self);
+
+    /// Halves reading the queries and writing the responses, which do not wait for each other
+    fn split(self) -> std::io::Result<(Self::Reader, Self::Writer)>;
+}
+
+/// Read half of a connection
+trait QueryReader: Read + AsRawFd + Send + 'static {
+    /// Whether bytes already received can be read without the socket becoming readable
+    fn has_buffered_data(&self) -> bool;
+}
+
+/// Write half of a connection, which closes it
+trait QueryWriter: Write + Send + 'static {
+    fn close(&mut self);
+}
+
+impl QueryStream for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+
+    fn has_buffered_data(&self) -> bool {
+        false
+    }
+
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn split(self) -> std::io::Result<(TcpStream, TcpStream)> {
+        Ok((self.try_clone()?, self))
+    }
+}
+
+impl QueryReader for TcpStream {
+    fn has_buffered_data(&self) -> bool {
+        false
+    }
+}
+
+impl QueryWriter for TcpStream {
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl QueryStream for TlsStream {
+    type Reader = TlsReader;
+    type Writer = TlsWriter;
+
+    fn has_buffered_data(&self) -> bool {
+        TlsStream::has_buffered_data(self)
+    }
+
+    fn close(&mut self) {
+        TlsStream::shutdown(self);
+    }
+
+    fn split(self) -> std::io::Result<(TlsReader, TlsWriter)> {
+        TlsStream::split(self)
+    }
+}
+
+impl QueryReader for TlsReader {
+    fn has_buffered_data(&self) -> bool {
+        TlsReader::has_buffered_data(self)
+    }
+}
+
+impl QueryWriter for TlsWriter {
+    fn close(&mut self) {
+        TlsWriter::shutdown(self);
+    }
+}
+
+/// Bound addresses without the duplicates of sockets sharing a port
+fn local_addresses(
+    addresses: impl Iterator<Item = std::io::Result<SocketAddr>>,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let mut unique_addresses = vec![];
+    for address in addresses {
+        let address = address?;
+        if !unique_addresses.contains(&address) {
+            unique_addresses.push(address);
+        }
+    }
+
+    Ok(unique_addresses)
+}
+
+/// Pool serving the TCP connections as they become readable, and the thread watching them until then
+/// The watching thread closes the connections left once the shutdown is requested, which
+/// drain_connections waits for up to the deadline rather than joining it
+fn start_connection_pool(
+    pool_config: &WorkerPoolConfig,
+    tcp_config: &TcpConfig,
+    shutdown: &ServerHandle,
+) -> std::io::Result<(Arc<WorkerPool>, ConnectionWatcher)> {
+    let pool = Arc::new(WorkerPool::new(
+        "connection",
+        pool_config.workers,
+        pool_config.queue_capacity,
+    ));
+    let (watcher, _) =
+        ConnectionWatcher::start(Arc::clone(&pool), tcp_config.idle_timeout, shutdown.clone())?;
+
+    Ok((pool, watcher))
+}
+
+/// Waits for the socket to become readable, false when the timeout elapsed first
+fn wait_readable(socket: &impl AsRawFd, timeout: Duration) -> std::io::Result<bool> {
+    wait_ready(socket, libc::POLLIN, timeout)
+}
+
+/// Waits for the socket to accept more, false when the timeout elapsed first
+fn wait_writable(socket: &impl AsRawFd, timeout: Duration) -> std::io::Result<bool> {
+    wait_ready(socket, libc::POLLOUT, timeout)
+}
+
+fn wait_ready(
+    socket: &impl AsRawFd,
+    events: libc::c_short,
+    timeout: Duration,
+) -> std::io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events,
+        revents: 0,
+    };
+
+    // SAFETY: a single pollfd is passed along with its count
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) } {
+        -1 => match Error::last_os_error() {
+            e if e.kind() == ErrorKind::Interrupted => Ok(false),
+            e => Err(e),
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Waits for the queued and running jobs of a pool, a pool still busy at the deadline is left behind
+/// as dropping it would wait for its workers
+fn drain_pool(name: &str, pool: Arc<WorkerPool>, deadline: Instant) {
+    if pool.wait_idle(deadline) {
+        return;
+    }
+
+    warning!(
+        "🚧 {} pool still busy at the shutdown deadline, {}",
+        name,
+        pool.metrics()
+    );
+    mem::forget(pool);
+}
+
+/// Waits for the connection threads to close their connections, the ones still open at the
+/// deadline are left behind
+fn drain_connections(connection_limiter: &ConnectionLimiter, deadline: Instant) {
+    if !connection_limiter.wait_closed(deadline) {
+        warning!(
+            "🚧 {} TCP connections still open at the shutdown deadline",
+            connection_limiter
+                .active_connections
+                .load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Extracts the complete length prefixed messages out of the bytes read so far
+fn take_tcp_messages(buffer: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut consumed = 0;
+
+    while let [a, b, ..] = buffer[consumed..] {
+        let message_size = u16::from_be_bytes([a, b]) as usize;
+        let message_end = consumed + 2 + message_size;
+
+        if buffer.len() < message_end {
+            break;
+        }
+
+        messages.push(buffer[consumed + 2..message_end].to_vec());
+        consumed = message_end;
+    }
+
+    buffer.drain(..consumed);
+    messages
+}
+
+/// Writes a length prefixed message, responses to pipelined queries share the same stream
+fn write_tcp_message(stream: &Mutex<impl Write>, message: &[u8], peer: SocketAddr) {
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+
+    let mut stream = stream.lock().unwrap_or_else(|p| p.into_inner());
+    if let Err(e) = stream.write_all(&framed) {
+        warning!("couldn't write to {}: {}", peer, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{
+            Message,
+            domain_name::DomainName,
+            header::{Header, MessageType, QueryType, ResponseCode},
+            opt_record::{EdnsOption, OptRecord},
+            question::{Class, Question, Type},
+            resource_record::{ResourceRecord, Type as RRType},
+        },
+        decoder::{Decoder, DecodingError, MessageDecoder},
+        encoder::{Encoder, MessageEncoder},
+        storage::{InMemoryResourceRecordRepository, RepositoryError, ResourceRecordRepository},
+        transport::UDP_MAX_MESSAGE_SIZE,
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::AtomicBool,
+            mpsc::{Receiver, Sender, channel},
+        },
+    };
+
+    const MOCKED_HEADER_SIZE: usize = 20;
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const MOCKED_QUESTIONS_SIZE: usize = 20;
+
+    struct MockEncoder {
+        bytes_per_record: usize,
+    }
+
+    impl Encoder for MockEncoder {
+        fn encode(&self, message: Message) -> Vec<u8> {
+            let mocked_answer_size = MOCKED_QUESTIONS_SIZE
+                + message.answers.len() * self.bytes_per_record
+                + message.authorities.len() * self.bytes_per_record
+                + message.additionnals.len() * self.bytes_per_record;
+            vec![0u8; MOCKED_HEADER_SIZE + mocked_answer_size]
+        }
+    }
+
+    struct MockDecoder;
+
+    impl Decoder for MockDecoder {
+        fn decode(&self, _buffer: &[u8]) -> Result<Message, DecodingError> {
+            Ok(Message::new(
+                Header {
+                    id: 1234,
+                    qr: MessageType::Query,
+                    opcode: QueryType::Standard,
+                    authoritative_answer: false,
+                    truncated: false,
+                    recursion_desired: true,
+                    recursion_available: false,
+                    reserved: false,
+                    response_code: ResponseCode::NoError,
+                    questions_count: 1,
+                    answers_count: 0,
+                    authority_count: 0,
+                    additional_count: 0,
+                },
+                vec![Question {
+                    name: DomainName::from("example.com."),
+                    type_: Type::RRType(RRType::A),
+                    class: Class::IN,
+                }],
+                vec![],
+                vec![],
+                vec![],
+                None,
+            ))
+        }
+    }
+
+    struct MockDecoderWithEDNS;
+
+    impl Decoder for MockDecoderWithEDNS {
+        fn decode(&self, _buffer: &[u8]) -> Result<Message, DecodingError> {
+            Ok(Message::new(
+                Header {
+                    id: 1234,
+                    qr: MessageType::Query,
+                    opcode: QueryType::Standard,
+                    authoritative_answer: false,
+                    truncated: false,
+                    recursion_desired: true,
+                    recursion_available: false,
+                    reserved: false,
+                    response_code: ResponseCode::NoError,
+                    questions_count: 1,
+                    answers_count: 0,
+                    authority_count: 0,
+                    additional_count: 1,
+                },
+                vec![Question {
+                    name: DomainName::from("example.com."),
+                    type_: Type::RRType(RRType::A),
+                    class: Class::IN,
+                }],
+                vec![],
+                vec![],
+                vec![],
+                Some(OptRecord {
+                    udp_payload_size: 4096,
+                    extended_rcode: 0,
+                    version: 0,
+                    dnssec_ok: false,
+                    options: vec![],
+                }),
+            ))
+        }
+    }
+
+    struct MockStorage {
+        records_to_return: Vec<ResourceRecord>,
+    }
+
+    impl ResourceRecordRepository for MockStorage {
+        fn get_resource_records(
+            &self,
+            _question: Question,
+        ) -> Result<Vec<ResourceRecord>, RepositoryError> {
+            Ok(self.records_to_return.clone())
+        }
+    }
+
+    struct SlowStorage {
+        delay: Duration,
+    }
+
+    impl ResourceRecordRepository for SlowStorage {
+        fn get_resource_records(
+            &self,
+            _question: Question,
+        ) -> Result<Vec<ResourceRecord>, RepositoryError> {
+            thread::sleep(self.delay);
+            Ok(vec![build_type_a_record("example.com.", "192.0.2.1")])
+        }
+    }
+
+    struct FailingStorage;
+
+    impl ResourceRecordRepository for FailingStorage {
+        fn get_resource_records(
+            &self,
+            _question: Question,
+        ) -> Result<Vec<ResourceRecord>, RepositoryError> {
+            Err(RepositoryError::ContactingFallbackServerError(
+                "unreachable".to_string(),
+            ))
+        }
+    }
+
+    #[test]
+    fn handle_regular_request() {
+        let decoder = MockDecoder;
+
+        const MOCKED_ANSWER_SIZE: usize = 10;
+        let encoder = MockEncoder {
+            bytes_per_record: MOCKED_ANSWER_SIZE, // Small size per answer
+        };
+
+        let mocked_answers = vec![
+            build_type_a_record("example.com.", "192.0.2.1"),
+            build_type_a_record("example.com.", "192.0.2.2"),
+        ];
+        let mocked_answers_len = mocked_answers.len();
+        let storage = MockStorage {
+            records_to_return: mocked_answers,
+        };
+
+        let response = Handler::new(decoder, encoder, storage, Duration::from_secs(10))
+            .handle(&[0u8; UDP_MAX_MESSAGE_SIZE / 8], Transport::Udp, LOCALHOST)
+            .unwrap();
+
+        assert_eq!(
+            response.len(),
+            MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + mocked_answers_len * MOCKED_ANSWER_SIZE
+        );
+    }
+
+    #[test]
+    fn handle_truncated_request() {
+        let decoder = MockDecoder;
+
+        let encoder = MockEncoder {
+            bytes_per_record: 300,
+        };
+
+        // 2 records size = MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + 2 * 300 = 640 bytes
+        // 640 bytes > 512 bytes (DNS default UDP limit) - should truncate
+        let mocked_answers = vec![
+            build_type_a_record("example.com.", "192.0.2.1"),
+            build_type_a_record("example.com.", "192.0.2.2"),
+        ];
+        let storage = MockStorage {
+            records_to_return: mocked_answers,
+        };
+
+        let response = Handler::new(decoder, encoder, storage, Duration::from_secs(10))
+            .handle(&[0u8; UDP_MAX_MESSAGE_SIZE / 8], Transport::Udp, LOCALHOST)
+            .unwrap();
+
+        assert_eq!(response.len(), MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE);
+    }
+
+    #[test]
+    fn handle_with_edns_larger_limit() {
+        let decoder = MockDecoderWithEDNS;
+
+        const MOCKED_ANSWER_SIZE: usize = 50;
+        let encoder = MockEncoder {
+            bytes_per_record: MOCKED_ANSWER_SIZE,
+        };
+
+        // 3 records size = MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + 3 * MOCKED_ANSWER_SIZE = 190 bytes
+        // 190 bytes < 4096 bytes (EDNS limit) - should NOT truncate
+        let mocked_answers = vec![
+            build_type_a_record("example.com.", "192.0.2.1"),
+            build_type_a_record("example.com.", "192.0.2.2"),
+            build_type_a_record("example.com.", "192.0.2.3"),
+        ];
+        let mocked_answers_len = mocked_answers.len();
+
+        let storage = MockStorage {
+            records_to_return: mocked_answers,
+        };
+
+        let response = Handler::new(decoder, encoder, storage, Duration::from_secs(10))
+            .handle(&[0u8; UDP_MAX_MESSAGE_SIZE / 8], Transport::Udp, LOCALHOST)
+            .unwrap();
+
+        assert_eq!(
+            response.len(),
+            MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + mocked_answers_len * MOCKED_ANSWER_SIZE
+        );
+    }
+
+    #[test]
+    fn handle_with_edns_still_truncated() {
+        let decoder = MockDecoderWithEDNS;
+
+        const MOCKED_ANSWER_SIZE: usize = 1500;
+        let encoder = MockEncoder {
+            bytes_per_record: MOCKED_ANSWER_SIZE,
+        };
+
+        // 3 records size = MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + 3 * 1500 = 4540 bytes
+        // 4540 bytes > 4096 bytes (EDNS limit) - should truncate
+        let mocked_answers = vec![
+            build_type_a_record("example.com.", "192.0.2.1"),
+            build_type_a_record("example.com.", "192.0.2.2"),
+            build_type_a_record("example.com.", "192.0.2.3"),
+        ];
+
+        let storage = MockStorage {
+            records_to_return: mocked_answers,
+        };
+
+        let response = Handler::new(decoder, encoder, storage, Duration::from_secs(10))
+            .handle(&[0u8; UDP_MAX_MESSAGE_SIZE / 8], Transport::Udp, LOCALHOST)
+            .unwrap();
+
+        assert_eq!(response.len(), MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE);
+    }
+
+    #[test]
+    fn handle_tcp_request_not_truncated() {
+        let decoder = MockDecoder;
+
+        const MOCKED_ANSWER_SIZE: usize = 300;
+        let encoder = MockEncoder {
+            bytes_per_record: MOCKED_ANSWER_SIZE,
+        };
+
+        // 2 records size = MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + 2 * 300 = 640 bytes
+        // 640 bytes > 512 bytes but TCP is only limited by its 2 bytes length prefix - should NOT truncate
+        let mocked_answers = vec![
+            build_type_a_record("example.com.", "192.0.2.1"),
+            build_type_a_record("example.com.", "192.0.2.2"),
+        ];
+        let mocked_answers_len = mocked_answers.len();
+        let storage = MockStorage {
+            records_to_return: mocked_answers,
+        };
+
+        let response = Handler::new(decoder, encoder, storage, Duration::from_secs(10))
+            .handle(&[0u8; UDP_MAX_MESSAGE_SIZE / 8], Transport::Tcp, LOCALHOST)
+            .unwrap();
+
+        assert_eq!(
+            response.len(),
+            MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + mocked_answers_len * MOCKED_ANSWER_SIZE
+        );
+    }
+
+    #[test]
+    fn handle_tcp_request_ignores_edns_payload_size() {
+        let decoder = MockDecoderWithEDNS;
+
+        const MOCKED_ANSWER_SIZE: usize = 1500;
+        let encoder = MockEncoder {
+            bytes_per_record: MOCKED_ANSWER_SIZE,
+        };
+
+        // 3 records size = MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + 3 * 1500 = 4540 bytes
+        // 4540 bytes > 4096 bytes (EDNS limit) but the EDNS limit only applies to UDP - should NOT truncate
+        let mocked_answers = vec![
+            build_type_a_record("example.com.", "192.0.2.1"),
+            build_type_a_record("example.com.", "192.0.2.2"),
+            build_type_a_record("example.com.", "192.0.2.3"),
+        ];
+        let mocked_answers_len = mocked_answers.len();
+        let storage = MockStorage {
+            records_to_return: mocked_answers,
+        };
+
+        let response = Handler::new(decoder, encoder, storage, Duration::from_secs(10))
+            .handle(&[0u8; UDP_MAX_MESSAGE_SIZE / 8], Transport::Tcp, LOCALHOST)
+            .unwrap();
+
+        assert_eq!(
+            response.len(),
+            MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + mocked_answers_len * MOCKED_ANSWER_SIZE
+        );
+    }
+
+    #[test]
+    fn handle_tcp_request_exceeding_frame_limit() {
+        let decoder = MockDecoder;
+
+        let encoder = MockEncoder {
+            bytes_per_record: 40_000,
+        };
+
+        // 2 records size = MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE + 2 * 40000 = 80040 bytes
+        // 80040 bytes > 65535 bytes (TCP length prefix limit) - should truncate
+        let mocked_answers = vec![
+            build_type_a_record("example.com.", "192.0.2.1"),
+            build_type_a_record("example.com.", "192.0.2.2"),
+        ];
+        let storage = MockStorage {
+            records_to_return: mocked_answers,
+        };
+
+        let response = Handler::new(decoder, encoder, storage, Duration::from_secs(10))
+            .handle(&[0u8; UDP_MAX_MESSAGE_SIZE / 8], Transport::Tcp, LOCALHOST)
+            .unwrap();
+
+        assert_eq!(response.len(), MOCKED_HEADER_SIZE + MOCKED_QUESTIONS_SIZE);
+    }
+
+    fn build_type_a_record(name: &str, ip: &str) -> ResourceRecord {
+        ResourceRecord::new(
+            DomainName::from(name),
+            RRType::A,
+            Class::IN,
+            300,
+            ip.parse::<std::net::Ipv4Addr>().unwrap().octets().to_vec(),
+        )
+    }
+
+    /// Blocks on every lookup until released, to keep workers busy
+    struct BlockingStorage {
+        started: Sender<()>,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl ResourceRecordRepository for BlockingStorage {
+        fn get_resource_records(
+            &self,
+            _question: Question,
+        ) -> Result<Vec<ResourceRecord>, RepositoryError> {
+            self.started.send(()).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+            Ok(vec![])
+        }
+    }
+
+    fn start_udp_server<R: ResourceRecordRepository + Send + Sync + 'static>(
+        storage: R,
+    ) -> UdpSocket {
+        start_udp_server_with_pool(
+            storage,
+            WorkerPool::new("test", 2, 16),
+            OverflowPolicy::Drop,
+        )
+    }
+
+    fn start_udp_server_with_pool<R: ResourceRecordRepository + Send + Sync + 'static>(
+        storage: R,
+        pool: WorkerPool,
+        overflow_policy: OverflowPolicy,
+    ) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = socket.local_addr().unwrap();
+
+        Server::<MessageDecoder, MessageEncoder, R>::run_udp(
+            Arc::new(Handler::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                storage,
+                Duration::from_secs(10),
+            )),
+            socket,
+            Arc::new(pool),
+            overflow_policy,
+            ProxyProtocol::default(),
+            ServerHandle::default(),
+        )
+        .unwrap();
+
+        connect_udp_client(server_address)
+    }
+
+    fn connect_udp_client(server_address: SocketAddr) -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        client.connect(server_address).unwrap();
+        client
+    }
+
+    fn exchange(client: &UdpSocket, query: &[u8]) -> Option<Message> {
+        client.send(query).unwrap();
+
+        let mut buf = [0u8; UDP_RECEIVE_BUFFER_SIZE];
+        let amt = client.recv(&mut buf).ok()?;
+        Some(MessageDecoder {}.decode(&buf[..amt]).unwrap())
+    }
+
+    fn build_query(id: u16, opcode: QueryType) -> Vec<u8> {
+        MessageEncoder {}.encode(Message::new(
+            Header {
+                id,
+                qr: MessageType::Query,
+                opcode,
+                authoritative_answer: false,
+                truncated: false,
+                recursion_desired: true,
+                recursion_available: false,
+                reserved: false,
+                response_code: ResponseCode::NoError,
+                questions_count: 1,
+                answers_count: 0,
+                authority_count: 0,
+                additional_count: 0,
+            },
+            vec![Question {
+                name: DomainName::from("example.com."),
+                type_: Type::RRType(RRType::A),
+                class: Class::IN,
+            }],
+            vec![],
+            vec![],
+            vec![],
+            None,
+        ))
+    }
+
+    #[test]
+    fn udp_server_answers_regular_query() {
+        let client = start_udp_server(MockStorage {
+            records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+        });
+
+        let response = exchange(&client, &build_query(4242, QueryType::Standard)).unwrap();
+
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.header.qr, MessageType::Response);
+        assert_eq!(response.header.response_code, ResponseCode::NoError);
+        assert_eq!(
+            response.answers,
+            vec![build_type_a_record("example.com.", "192.0.2.1")]
+        );
+    }
+
+    #[test]
+    fn udp_server_answers_format_error_to_undecodable_query() {
+        let client = start_udp_server(MockStorage {
+            records_to_return: vec![],
+        });
+
+        let query = [
+            0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, // header announcing one question
+            3, b'c', b'o', // truncated question
+        ];
+        let response = exchange(&client, &query).unwrap();
+
+        assert_eq!(
+            response,
+            Message::error_response(0x1234, ResponseCode::FormatError)
+        );
+    }
+
+    #[test]
+    fn udp_server_answers_not_implemented_to_inverse_query() {
+        let client = start_udp_server(MockStorage {
+            records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+        });
+
+        let response = exchange(&client, &build_query(4242, QueryType::Inverse)).unwrap();
+
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.header.opcode, QueryType::Inverse);
+        assert_eq!(response.header.response_code, ResponseCode::NotImplemented);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn udp_server_answers_not_implemented_to_unknown_opcode() {
+        let client = start_udp_server(MockStorage {
+            records_to_return: vec![],
+        });
+
+        // opcode 5 (UPDATE) is not supported by the decoder
+        let query = [0x12, 0x34, 0b00101000, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let response = exchange(&client, &query).unwrap();
+
+        assert_eq!(
+            response,
+            Message::error_response(0x1234, ResponseCode::NotImplemented)
+        );
+    }
+
+    #[test]
+    fn udp_server_answers_not_implemented_to_zone_transfer() {
+        let mut storage = InMemoryResourceRecordRepository::new();
+        storage.save(build_type_a_record("example.com.", "192.0.2.1"));
+        let client = start_udp_server(storage);
+
+        let mut query = build_query(4242, QueryType::Standard);
+        // QTYPE of the single question, right before its QCLASS
+        let type_offset = query.len() - 4;
+        query[type_offset..type_offset + 2].copy_from_slice(&252u16.to_be_bytes());
+        let response = exchange(&client, &query).unwrap();
+
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.header.response_code, ResponseCode::NotImplemented);
+        assert_eq!(response.questions[0].type_, Type::AXFR);
+        assert!(response.answers.is_empty());
+
+        // the server is still alive
+        assert!(exchange(&client, &build_query(4243, QueryType::Standard)).is_some());
+    }
+
+    #[test]
+    fn udp_server_answers_server_failure_on_storage_error() {
+        let client = start_udp_server(FailingStorage);
+
+        let response = exchange(&client, &build_query(4242, QueryType::Standard)).unwrap();
+
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.header.response_code, ResponseCode::ServerFailure);
+        assert_eq!(response.questions.len(), 1);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn udp_server_drops_responses() {
+        let client = start_udp_server(MockStorage {
+            records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+        });
+
+        let mut response = build_query(4242, QueryType::Standard);
+        response[2] |= 0b10000000; // QR bit
+        assert!(exchange(&client, &response).is_none());
+
+        // undecodable responses are dropped as well
+        assert!(exchange(&client, &[0x12, 0x34, 0b10000000, 0, 0, 1]).is_none());
+
+        // the server is still alive
+        assert!(exchange(&client, &build_query(4243, QueryType::Standard)).is_some());
+    }
+
+    #[test]
+    fn udp_server_applies_overflow_policy_when_queue_is_full() {
+        let (started_sender, started_receiver) = channel();
+        let (release_sender, release_receiver) = channel();
+        let client = start_udp_server_with_pool(
+            BlockingStorage {
+                started: started_sender,
+                release: Mutex::new(release_receiver),
+            },
+            WorkerPool::new("test", 1, 1),
+            OverflowPolicy::Refused,
+        );
+
+        // occupies the only worker
+        client.send(&build_query(1, QueryType::Standard)).unwrap();
+        started_receiver
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap();
+
+        // fills the queue
+        client.send(&build_query(2, QueryType::Standard)).unwrap();
+
+        // rejected straight away
+        let rejected = exchange(&client, &build_query(3, QueryType::Standard)).unwrap();
+        assert_eq!(rejected.header.id, 3);
+        assert_eq!(rejected.header.response_code, ResponseCode::Refused);
+        assert_eq!(rejected.questions.len(), 1);
+
+        release_sender.send(()).unwrap();
+        release_sender.send(()).unwrap();
+
+        let mut buf = [0u8; UDP_MAX_MESSAGE_SIZE];
+        let mut answered_ids = (0..2)
+            .map(|_| {
+                let amt = client.recv(&mut buf).unwrap();
+                MessageDecoder {}.decode(&buf[..amt]).unwrap().header.id
+            })
+            .collect::<Vec<_>>();
+        answered_ids.sort();
+
+        assert_eq!(answered_ids, vec![1, 2]);
+    }
+
+    fn start_tcp_server(tcp_config: TcpConfig) -> SocketAddr {
+        start_tcp_server_with_storage(
+            MockStorage {
+                records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+            },
+            tcp_config,
+        )
+    }
+
+    fn start_tcp_server_with_storage<R: ResourceRecordRepository + Send + Sync + 'static>(
+        storage: R,
+        tcp_config: TcpConfig,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_address = listener.local_addr().unwrap();
+
+        let shutdown = ServerHandle::default();
+        let (_, watcher) = start_connection_pool(
+            &WorkerPoolConfig {
+                workers: 2,
+                queue_capacity: 16,
+                overflow_policy: OverflowPolicy::Drop,
+            },
+            &tcp_config,
+            &shutdown,
+        )
+        .unwrap();
+        Server::<MessageDecoder, MessageEncoder, R>::run_tcp(
+            Arc::new(Handler::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                storage,
+                tcp_config.idle_timeout,
+            )),
+            StreamListener::tcp(listener),
+            Arc::new(WorkerPool::new("test-query", 2, 16)),
+            OverflowPolicy::Drop,
+            tcp_config.clone(),
+            ProxyProtocol::default(),
+            ConnectionLimiter::new(tcp_config.max_connections),
+            watcher,
+            shutdown,
+        )
+        .unwrap();
+
+        server_address
+    }
+
+    /// Reads a length prefixed message, None means the server closed the connection
+    fn read_tcp_message(stream: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+        let mut size_buf = [0; 2];
+        match stream.read_exact(&mut size_buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut buffer = vec![0u8; u16::from_be_bytes(size_buf) as usize];
+        stream.read_exact(&mut buffer)?;
+
+        Ok(Some(buffer))
+    }
+
+    fn connect_tcp_client(server_address: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(server_address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream
+    }
+
+    fn send_tcp_queries(stream: &mut impl Write, ids: &[u16]) {
+        let mut framed = vec![];
+        for id in ids {
+            let query = build_query(*id, QueryType::Standard);
+            framed.extend((query.len() as u16).to_be_bytes());
+            framed.extend(query);
+        }
+        stream.write_all(&framed).unwrap();
+    }
+
+    fn receive_tcp_response(stream: &mut impl Read) -> Message {
+        let response = read_tcp_message(stream).unwrap().unwrap();
+        MessageDecoder {}.decode(&response).unwrap()
+    }
+
+    fn assert_closed_by_server(stream: &mut impl Read) {
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn tcp_server_keeps_connection_open_between_queries() {
+        let mut stream = connect_tcp_client(start_tcp_server(TcpConfig::default()));
+
+        for id in 0..3 {
+            send_tcp_queries(&mut stream, &[id]);
+            let response = receive_tcp_response(&mut stream);
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.answers.len(), 1);
+        }
+    }
+
+    #[test]
+    fn tcp_server_serves_more_idle_connections_than_workers() {
+        let server_address = start_tcp_server(TcpConfig::default());
+
+        // more than the 2 connection workers, all waiting for a query
+        let idle_streams = (0..4)
+            .map(|_| connect_tcp_client(server_address))
+            .collect::<Vec<_>>();
+
+        let mut stream = connect_tcp_client(server_address);
+        send_tcp_queries(&mut stream, &[1]);
+        assert_eq!(receive_tcp_response(&mut stream).header.id, 1);
+        drop(idle_streams);
+    }
+
+    #[test]
+    fn tcp_server_answers_pipelined_queries() {
+        let mut stream = connect_tcp_client(start_tcp_server(TcpConfig::default()));
+
+        send_tcp_queries(&mut stream, &[1, 2, 3]);
+
+        let mut ids = (0..3)
+            .map(|_| receive_tcp_response(&mut stream).header.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn tcp_server_answers_while_next_query_is_incomplete() {
+        // answered once the server waits for the rest of the next query
+        let mut stream = connect_tcp_client(start_tcp_server_with_storage(
+            SlowStorage {
+                delay: Duration::from_millis(100),
+            },
+            TcpConfig::default(),
+        ));
+
+        let query = build_query(1, QueryType::Standard);
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend(query);
+        // the length and first byte of the next query
+        framed.extend([0, 12, 0]);
+        stream.write_all(&framed).unwrap();
+
+        assert_eq!(receive_tcp_response(&mut stream).header.id, 1);
+    }
+
+    #[test]
+    fn tcp_server_closes_connection_after_max_queries() {
+        let mut stream = connect_tcp_client(start_tcp_server(TcpConfig {
+            max_queries_per_connection: 2,
+            ..Default::default()
+        }));
+
+        send_tcp_queries(&mut stream, &[1, 2]);
+
+        let mut ids = (0..2)
+            .map(|_| receive_tcp_response(&mut stream).header.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        assert_eq!(ids, vec![1, 2]);
+        assert_closed_by_server(&mut stream);
+    }
+
+    #[test]
+    fn tcp_server_closes_idle_connection() {
+        let mut stream = connect_tcp_client(start_tcp_server(TcpConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..Default::default()
+        }));
+
+        send_tcp_queries(&mut stream, &[1]);
+        assert_eq!(receive_tcp_response(&mut stream).header.id, 1);
+
+        assert_closed_by_server(&mut stream);
+    }
+
+    #[test]
+    fn tcp_server_closes_connections_over_the_limit() {
+        let server_address = start_tcp_server(TcpConfig {
+            max_connections: 1,
+            ..Default::default()
+        });
+
+        let mut first = connect_tcp_client(server_address);
+        send_tcp_queries(&mut first, &[1]);
+        assert_eq!(receive_tcp_response(&mut first).header.id, 1);
+
+        let mut second = connect_tcp_client(server_address);
+        assert_closed_by_server(&mut second);
+
+        send_tcp_queries(&mut first, &[2]);
+        assert_eq!(receive_tcp_response(&mut first).header.id, 2);
+    }
+
+    /// Runs a whole server on an ephemeral port
+    fn start_server<R: ResourceRecordRepository + Send + Sync + 'static>(
+        storage: R,
+        mode: ServerMode,
+        shutdown_timeout: Duration,
+    ) -> (RunningServer, SocketAddr) {
+        let running = Server::new(MessageDecoder {}, MessageEncoder {}, storage)
+            .with_listeners(vec![ListenerConfig::new("127.0.0.1:0".parse().unwrap())])
+            .with_worker_pool_config(WorkerPoolConfig {
+                workers: 2,
+                queue_capacity: 16,
+                overflow_policy: OverflowPolicy::Drop,
+            })
+            .with_mode(mode)
+            .with_shutdown_timeout(shutdown_timeout)
+            .start()
+            .unwrap();
+
+        let address = running.udp_addresses()[0];
+        assert_eq!(running.tcp_addresses(), &[address]);
+
+        (running, address)
+    }
+
+    fn blocking_storage() -> (BlockingStorage, Receiver<()>, Sender<()>) {
+        let (started_sender, started_receiver) = channel();
+        let (release_sender, release_receiver) = channel();
+        let storage = BlockingStorage {
+            started: started_sender,
+            release: Mutex::new(release_receiver),
+        };
+
+        (storage, started_receiver, release_sender)
+    }
+
+    fn modes() -> Vec<ServerMode> {
+        vec![
+            ServerMode::Threaded,
+            #[cfg(target_os = "linux")]
+            ServerMode::EventLoop { reactors: 2 },
+        ]
+    }
+
+    #[test]
+    fn server_answers_in_flight_queries_on_shutdown() {
+        for mode in modes() {
+            let (storage, started, release) = blocking_storage();
+            let (running, address) = start_server(storage, mode, Duration::from_secs(5));
+            let handle = running.handle();
+
+            let udp_client = connect_udp_client(address);
+            udp_client
+                .send(&build_query(1, QueryType::Standard))
+                .unwrap();
+            started.recv_timeout(Duration::from_secs(1)).unwrap();
+
+            // waits for the storage lock held by the first query
+            let mut tcp_client = connect_tcp_client(address);
+            send_tcp_queries(&mut tcp_client, &[2]);
+            thread::sleep(Duration::from_millis(50));
+
+            handle.shutdown();
+            release.send(()).unwrap();
+            release.send(()).unwrap();
+
+            let mut buf = [0u8; UDP_MAX_MESSAGE_SIZE];
+            let amt = udp_client.recv(&mut buf).unwrap();
+            assert_eq!(MessageDecoder {}.decode(&buf[..amt]).unwrap().header.id, 1);
+            assert_eq!(receive_tcp_response(&mut tcp_client).header.id, 2);
+            assert_closed_by_server(&mut tcp_client);
+
+            running.join();
+            assert!(TcpStream::connect(address).is_err(), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn runs_isolated_servers_in_parallel() {
+        for mode in modes() {
+            let servers = (0..3)
+                .map(|i| {
+                    start_server(
+                        MockStorage {
+                            records_to_return: vec![build_type_a_record(
+                                "example.com.",
+                                &format!("192.0.2.{}", i),
+                            )],
+                        },
+                        mode,
+                        Duration::from_secs(1),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            for (i, (_, address)) in servers.iter().enumerate() {
+                let response = exchange(
+                    &connect_udp_client(*address),
+                    &build_query(1, QueryType::Standard),
+                )
+                .unwrap();
+                assert_eq!(response.answers[0].resource_data, vec![192, 0, 2, i as u8]);
+
+                let mut tcp_client = connect_tcp_client(*address);
+                send_tcp_queries(&mut tcp_client, &[2]);
+                let response = receive_tcp_response(&mut tcp_client);
+                assert_eq!(response.answers[0].resource_data, vec![192, 0, 2, i as u8]);
+            }
+
+            for (running, _) in servers {
+                running.shutdown();
+            }
+        }
+    }
+
+    #[test]
+    fn server_stops_at_shutdown_deadline() {
+        for mode in modes() {
+            let (storage, started, release) = blocking_storage();
+            let (running, address) = start_server(storage, mode, Duration::from_millis(200));
+
+            let udp_client = connect_udp_client(address);
+            udp_client
+                .send(&build_query(1, QueryType::Standard))
+                .unwrap();
+            started.recv_timeout(Duration::from_secs(1)).unwrap();
+
+            let stopping = Instant::now();
+            running.shutdown();
+            assert!(stopping.elapsed() < Duration::from_secs(2), "{:?}", mode);
+
+            // lets the abandoned worker finish
+            release.send(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn server_sees_clients_behind_proxy() {
+        let client = "192.0.2.10:40000".parse().unwrap();
+        let denied_client = "192.0.2.99:40000".parse().unwrap();
+
+        for mode in modes() {
+            let mut acl = Acl::new(AclAction::Deny);
+            acl.add_rule(AclAction::Allow, "192.0.2.10".parse().unwrap());
+            let mut proxy_protocol = ProxyProtocol::default();
+            proxy_protocol.add_trusted_proxy("127.0.0.1".parse().unwrap());
+
+            let running = Server::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                MockStorage {
+                    records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+                },
+            )
+            .with_listeners(vec![ListenerConfig::new("127.0.0.1:0".parse().unwrap())])
+            .with_mode(mode)
+            .with_acl(acl)
+            .with_proxy_protocol(proxy_protocol)
+            .start()
+            .unwrap();
+            let address = running.udp_addresses()[0];
+
+            let proxied_query = |client, id| {
+                let mut datagram = proxy::build_header(client, address);
+                datagram.extend(build_query(id, QueryType::Standard));
+                datagram
+            };
+
+            let udp_client = connect_udp_client(address);
+            let response = exchange(&udp_client, &proxied_query(client, 1)).unwrap();
+            assert_eq!(response.header.response_code, ResponseCode::NoError);
+            assert_eq!(response.answers.len(), 1);
+            let response = exchange(&udp_client, &proxied_query(denied_client, 2)).unwrap();
+            assert_eq!(response.header.response_code, ResponseCode::Refused);
+            // the proxy itself must send a header
+            assert!(exchange(&udp_client, &build_query(3, QueryType::Standard)).is_none());
+
+            let mut tcp_client = connect_tcp_client(address);
+            tcp_client
+                .write_all(&proxy::build_header(client, address))
+                .unwrap();
+            send_tcp_queries(&mut tcp_client, &[4]);
+            let response = receive_tcp_response(&mut tcp_client);
+            assert_eq!(
+                response.header.response_code,
+                ResponseCode::NoError,
+                "{:?}",
+                mode
+            );
+
+            // closed with the query unread, which resets the connection
+            let mut tcp_client = connect_tcp_client(address);
+            send_tcp_queries(&mut tcp_client, &[5]);
+            let mut rest = vec![];
+            match tcp_client.read_to_end(&mut rest) {
+                Ok(_) => assert!(rest.is_empty()),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+            }
+
+            running.shutdown();
+        }
+    }
+
+    #[test]
+    fn server_reads_large_queries_behind_proxy() {
+        let client = "192.0.2.10:40000".parse().unwrap();
+
+        for mode in modes() {
+            let mut proxy_protocol = ProxyProtocol::default();
+            proxy_protocol.add_trusted_proxy("127.0.0.1".parse().unwrap());
+            let running = Server::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                MockStorage {
+                    records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+                },
+            )
+            .with_listeners(vec![ListenerConfig::new("127.0.0.1:0".parse().unwrap())])
+            .with_mode(mode)
+            .with_proxy_protocol(proxy_protocol)
+            .start()
+            .unwrap();
+            let address = running.udp_addresses()[0];
+
+            // padded past 512 bytes (RFC 7830), as EDNS clients may send
+            let mut query = MessageDecoder {}
+                .decode(&build_query(1, QueryType::Standard))
+                .unwrap();
+            query.header.additional_count = 1;
+            query.opt_record = Some(OptRecord::new(
+                4096,
+                0,
+                0,
+                false,
+                vec![EdnsOption::new(12, vec![0; 600])],
+            ));
+            let mut datagram = proxy::build_header(client, address);
+            datagram.extend(MessageEncoder {}.encode(query));
+            assert!(datagram.len() > UDP_MAX_MESSAGE_SIZE + 100);
+
+            let response = exchange(&connect_udp_client(address), &datagram).unwrap();
+            assert_eq!(
+                response.header.response_code,
+                ResponseCode::NoError,
+                "{:?}",
+                mode
+            );
+            assert_eq!(response.answers.len(), 1);
+
+            running.shutdown();
+        }
+    }
+
+    #[test]
+    fn server_answers_over_tls() {
+        let (certificate, key) = tls::tests::test_certificate();
+        let client = TlsContext::insecure_client().unwrap();
+
+        for mode in modes() {
+            let running = Server::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                MockStorage {
+                    records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+                },
+            )
+            .with_listeners(vec!["tls/127.0.0.1:0".parse().unwrap()])
+            .with_mode(mode)
+            .with_tls(TlsConfig {
+                certificate: certificate.clone(),
+                key: key.clone(),
+            })
+            .start()
+            .unwrap();
+            assert!(running.udp_addresses().is_empty());
+            assert!(running.tcp_addresses().is_empty());
+            let address = running.tls_addresses()[0];
+
+            let mut stream = client.connect(connect_tcp_client(address)).unwrap();
+
+            // pipelined queries and a later one share the connection
+            send_tcp_queries(&mut stream, &[1, 2]);
+            let mut ids = vec![
+                receive_tcp_response(&mut stream).header.id,
+                receive_tcp_response(&mut stream).header.id,
+            ];
+            ids.sort();
+            assert_eq!(ids, vec![1, 2], "{:?}", mode);
+
+            send_tcp_queries(&mut stream, &[3]);
+            let response = receive_tcp_response(&mut stream);
+            assert_eq!(response.header.id, 3);
+            assert_eq!(response.answers[0].resource_data, vec![192, 0, 2, 1]);
+
+            // plain DNS is not spoken on a TLS listener
+            let mut plain_client = connect_tcp_client(address);
+            send_tcp_queries(&mut plain_client, &[4]);
+            assert!(!matches!(read_tcp_message(&mut plain_client), Ok(Some(_))));
+
+            running.shutdown();
+        }
+    }
+
+    /// Relays a connection to the server, once truncate is set only the header of the next TLS
+    /// record is relayed, the rest of the connection is held back
+    fn start_truncating_relay(server: SocketAddr, truncate: Arc<AtomicBool>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut server = TcpStream::connect(server).unwrap();
+            let mut client_reader = client.try_clone().unwrap();
+            let mut server_writer = server.try_clone().unwrap();
+            thread::spawn(move || {
+                let _ = std::io::copy(&mut server, &mut client);
+            });
+
+            let mut buffer = [0u8; 4096];
+            while let Ok(amt @ 1..) = client_reader.read(&mut buffer) {
+                if truncate.load(Ordering::Relaxed) {
+                    server_writer.write_all(&buffer[..5]).unwrap();
+                    return;
+                }
+                server_writer.write_all(&buffer[..amt]).unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn tls_server_answers_while_next_record_is_incomplete() {
+        let (certificate, key) = tls::tests::test_certificate();
+        let running = Server::new(
+            MessageDecoder {},
+            MessageEncoder {},
+            SlowStorage {
+                delay: Duration::from_millis(300),
+            },
+        )
+        .with_listeners(vec!["tls/127.0.0.1:0".parse().unwrap()])
+        .with_tls(TlsConfig { certificate, key })
+        .start()
+        .unwrap();
+        let truncate = Arc::new(AtomicBool::new(false));
+        let relay = start_truncating_relay(running.tls_addresses()[0], Arc::clone(&truncate));
+
+        let client = TlsContext::insecure_client().unwrap();
+        let mut stream = client.connect(connect_tcp_client(relay)).unwrap();
+        send_tcp_queries(&mut stream, &[1]);
+        thread::sleep(Duration::from_millis(100));
+
+        // the server reads the start of the record then waits for the rest
+        truncate.store(true, Ordering::Relaxed);
+        send_tcp_queries(&mut stream, &[2]);
+
+        assert_eq!(receive_tcp_response(&mut stream).header.id, 1);
+        running.shutdown();
+    }
+
+    #[test]
+    fn refuses_tls_listeners_without_certificate() {
+        let error = Server::new(
+            MessageDecoder {},
+            MessageEncoder {},
+            MockStorage {
+                records_to_return: vec![],
+            },
+        )
+        .with_listeners(vec!["tls/127.0.0.1:0".parse().unwrap()])
+        .start()
+        .err()
+        .unwrap();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    /// Sends an HTTP request and reads the response, its status line, headers and body
+    /// Client side of a connection, in the clear or over TLS
+    trait ClientStream: Read + Write {}
+
+    impl<T: Read + Write> ClientStream for T {}
+
+    fn http_exchange(stream: &mut (impl Read + Write), request: &[u8]) -> (String, Vec<u8>) {
+        stream.write_all(request).unwrap();
+
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (head, body)
+    }
+
+    #[test]
+    fn server_answers_over_http_and_https() {
+        let (certificate, key) = tls::tests::test_certificate();
+        let client = TlsContext::insecure_client().unwrap();
+
+        for mode in modes() {
+            let running = Server::new(
+                MessageDecoder {},
+                MessageEncoder {},
+                MockStorage {
+                    records_to_return: vec![build_type_a_record("example.com.", "192.0.2.1")],
+                },
+            )
+            .with_listeners(vec![
+                "http/127.0.0.1:0".parse().unwrap(),
+                "https/127.0.0.1:0".parse().unwrap(),
+            ])
+            .with_mode(mode)
+            .with_tls(TlsConfig {
+                certificate: certificate.clone(),
+                key: key.clone(),
+            })
+            .start()
+            .unwrap();
+            let http_address = running.http_addresses()[0];
+            let https_address = running.https_addresses()[0];
+
+            let mut http_stream = connect_tcp_client(http_address);
+            let mut https_stream = client.connect(connect_tcp_client(https_address)).unwrap();
+            let streams: [&mut dyn ClientStream; 2] = [&mut http_stream, &mut https_stream];
+
+            for mut stream in streams {
+                let query = build_query(1, QueryType::Standard);
+                let get = format!(
+                    "GET /dns-query?dns={} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                    http::encode_base64url(&query)
+                );
+                let (head, body) = http_exchange(&mut stream, get.as_bytes());
+                assert!(
+                    head.starts_with("HTTP/1.1 200 OK\r\n"),
+                    "{:?}: {}",
+                    mode,
+                    head
+                );
+                assert!(head.contains("Content-Type: application/dns-message\r\n"));
+                assert!(head.contains("Cache-Control: max-age=300\r\n"));
+                let response = MessageDecoder {}.decode(&body).unwrap();
+                assert_eq!(response.header.id, 1);
+                assert_eq!(response.answers[0].resource_data, vec![192, 0, 2, 1]);
+
+                // on the same connection
+                let query = build_query(2, QueryType::Standard);
+                let mut post = format!(
+                    "POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\n\
+                     Content-Length: {}\r\n\r\n",
+                    query.len()
+                )
+                .into_bytes();
+                post.extend(query);
+                let (head, body) = http_exchange(&mut stream, &post);
+                assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+                assert_eq!(MessageDecoder {}.decode(&body).unwrap().header.id, 2);
+
+                let (head, body) = http_exchange(
+                    &mut stream,
+                    b"GET /resolve?name=example.com&type=A HTTP/1.1\r\n\r\n",
+                );
+                assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+                assert!(head.contains("Content-Type: application/dns-json\r\n"));
+                assert!(head.contains("Cache-Control: max-age=300\r\n"));
+                let body = String::from_utf8(body).unwrap();
+                assert!(body.starts_with("{\"Status\":0,"), "{}", body);
+                assert!(body.contains(
+                    "\"Answer\":[{\"name\":\"example.com.\",\"type\":1,\"TTL\":300,\"data\":\"192.0.2.1\"}]"
+                ));
+
+                let (head, _) = http_exchange(
+                    &mut stream,
+                    b"GET /status HTTP/1.1\r\nConnection: close\r\n\r\n",
+                );
+                assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+                assert!(head.contains("Connection: close\r\n"));
+                assert_closed_by_server(&mut stream);
+            }
+
+            running.shutdown();
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// Thin wrapper around epoll, see: https://man7.org/linux/man-pages/man7/epoll.7.html
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+pub type Token = u64;
+
+const MAX_EVENTS_PER_WAIT: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Interest {
+    pub const READABLE: Self = Self {
+        readable: true,
+        writable: false,
+    };
+
+    fn flags(&self) -> u32 {
+        let mut flags = 0;
+
+        if self.readable {
+            flags |= libc::EPOLLIN | libc::EPOLLRDHUP;
+        }
+
+        if self.writable {
+            flags |= libc::EPOLLOUT;
+        }
+
+        flags as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    pub token: Token,
+    pub readable: bool,
+    pub writable: bool,
+    pub closed: bool,
+}
+
+impl From<&libc::epoll_event> for Event {
+    fn from(event: &libc::epoll_event) -> Self {
+        let flags = event.events as i32;
+
+        Self {
+            token: event.u64,
+            readable: flags & (libc::EPOLLIN | libc::EPOLLRDHUP) != 0,
+            writable: flags & libc::EPOLLOUT != 0,
+            closed: flags & (libc::EPOLLHUP | libc::EPOLLERR) != 0,
+        }
+    }
+}
+
+pub struct Poller {
+    epoll_fd: OwnedFd,
+    events: Vec<libc::epoll_event>,
+}
+
+impl Poller {
+    pub fn new() -> Result<Self> {
+        let fd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+
+        Ok(Self {
+            epoll_fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            events: Vec::with_capacity(MAX_EVENTS_PER_WAIT),
+        })
+    }
+
+    pub fn register(&self, fd: RawFd, token: Token, interest: Interest) -> Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, interest.flags())
+    }
+
+    /// Only one of the pollers sharing the file descriptor is woken up when it is readable,
+    /// used for sockets shared between event loops
+    pub fn register_exclusive(&self, fd: RawFd, token: Token) -> Result<()> {
+        // EPOLLRDHUP cannot be combined with EPOLLEXCLUSIVE
+        self.control(
+            libc::EPOLL_CTL_ADD,
+            fd,
+            token,
+            (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32,
+        )
+    }
+
+    pub fn reregister(&self, fd: RawFd, token: Token, interest: Interest) -> Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, interest.flags())
+    }
+
+    pub fn deregister(&self, fd: RawFd) -> Result<()> {
+        check(unsafe {
+            libc::epoll_ctl(
+                self.epoll_fd.as_raw_fd(),
+                libc::EPOLL_CTL_DEL,
+                fd,
+                std::ptr::null_mut(),
+            )
+        })
+        .map(|_| ())
+    }
+
+    /// Waits for events, an interrupted wait returns no event
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Vec<Event>> {
+        let timeout = timeout
+            .map(|t| t.as_millis().min(i32::MAX as u128) as i32)
+            .unwrap_or(-1);
+
+        self.events.clear();
+        let ready = unsafe {
+            libc::epoll_wait(
+                self.epoll_fd.as_raw_fd(),
+                self.events.as_mut_ptr(),
+                MAX_EVENTS_PER_WAIT as i32,
+                timeout,
+            )
+        };
+
+        match check(ready) {
+            Ok(ready) => {
+                unsafe { self.events.set_len(ready as usize) };
+                Ok(self.events.iter().map(Event::from).collect())
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn control(&self, operation: i32, fd: RawFd, token: Token, flags: u32) -> Result<()> {
+        let mut event = libc::epoll_event {
+            events: flags,
+            u64: token,
+        };
+
+        check(unsafe { libc::epoll_ctl(self.epoll_fd.as_raw_fd(), operation, fd, &mut event) })
+            .map(|_| ())
+    }
+}
+
+/// Wakes a poller up from another thread, backed by an eventfd
+pub struct Waker {
+    fd: OwnedFd,
+}
+
+impl Waker {
+    pub fn new() -> Result<Self> {
+        let fd = check(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn wake(&self) -> Result<()> {
+        let value = 1u64.to_ne_bytes();
+        let written = unsafe { libc::write(self.fd.as_raw_fd(), value.as_ptr().cast(), 8) };
+
+        match check(written as i32) {
+            // the counter is saturated, the poller will wake up anyway
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Resets the counter so that the poller stops reporting the waker as readable
+    pub fn reset(&self) {
+        let mut value = [0u8; 8];
+        unsafe { libc::read(self.fd.as_raw_fd(), value.as_mut_ptr().cast(), 8) };
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+fn check(result: i32) -> Result<i32> {
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn reports_readable_socket() {
+        let mut poller = Poller::new().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        poller
+            .register(socket.as_raw_fd(), 42, Interest::READABLE)
+            .unwrap();
+
+        let events = poller.wait(Some(Duration::from_millis(10))).unwrap();
+        assert!(events.is_empty());
+
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .send_to(&[1, 2, 3], socket.local_addr().unwrap())
+            .unwrap();
+
+        let events = poller.wait(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token, 42);
+        assert!(events[0].readable);
+        assert!(!events[0].writable);
+    }
+
+    #[test]
+    fn waker_wakes_poller_up() {
+        let mut poller = Poller::new().unwrap();
+        let waker = Arc::new(Waker::new().unwrap());
+        poller
+            .register(waker.as_raw_fd(), 7, Interest::READABLE)
+            .unwrap();
+
+        let remote_waker = Arc::clone(&waker);
+        thread::spawn(move || remote_waker.wake().unwrap());
+
+        let events = poller.wait(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token, 7);
+
+        waker.reset();
+        let events = poller.wait(Some(Duration::from_millis(10))).unwrap();
+        assert!(events.is_empty());
+    }
+}
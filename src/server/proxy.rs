@@ -0,0 +1,270 @@
+// PROXY protocol v2: a load balancer in front of the server prepends the address of the client
+// to each TCP stream and each UDP datagram, so that the client is not mistaken for the proxy
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::{
+    io::{Error, ErrorKind, Read, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::server::Network;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// signature, version and command, address family and protocol, length of the addresses
+const FIXED_HEADER_SIZE: usize = 16;
+
+const VERSION: u8 = 0x2;
+const COMMAND_LOCAL: u8 = 0x0;
+const COMMAND_PROXY: u8 = 0x1;
+const FAMILY_INET: u8 = 0x1;
+const FAMILY_INET6: u8 = 0x2;
+
+/// Peers allowed to speak the PROXY protocol, whatever they send must start with a v2 header
+/// Anybody else is a client talking to the server directly
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProxyProtocol {
+    trusted_proxies: Vec<Network>,
+}
+
+impl ProxyProtocol {
+    pub fn add_trusted_proxy(&mut self, network: Network) {
+        self.trusted_proxies.push(network);
+    }
+
+    pub fn is_trusted(&self, peer: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(peer))
+    }
+
+    /// Client address and query of a datagram, an error when a trusted proxy sent no valid header
+    pub fn strip_datagram<'a>(
+        &self,
+        datagram: &'a [u8],
+        peer: SocketAddr,
+    ) -> std::result::Result<(SocketAddr, &'a [u8]), String> {
+        if !self.is_trusted(peer.ip()) {
+            return Ok((peer, datagram));
+        }
+
+        match ProxyHeader::parse(datagram)? {
+            Some(header) => Ok((header.source.unwrap_or(peer), &datagram[header.length..])),
+            None => Err("truncated PROXY header".to_string()),
+        }
+    }
+}
+
+/// What matters of a PROXY v2 header
+#[derive(Debug, PartialEq)]
+pub struct ProxyHeader {
+    /// Address of the client, None for the proxy's own health checks (LOCAL command)
+    /// or a transport other than TCP and UDP over IP
+    pub source: Option<SocketAddr>,
+    /// Bytes taken by the header, the DNS message starts right after
+    pub length: usize,
+}
+
+impl ProxyHeader {
+    /// Parses the header at the start of the buffer, None when more bytes are needed
+    pub fn parse(buffer: &[u8]) -> std::result::Result<Option<Self>, String> {
+        let signature_length = buffer.len().min(SIGNATURE.len());
+        if buffer[..signature_length] != SIGNATURE[..signature_length] {
+            return Err("missing PROXY v2 signature".to_string());
+        }
+
+        let [.., version_command, family_protocol, a, b] = match buffer.get(..FIXED_HEADER_SIZE) {
+            Some(fixed_header) => <[u8; FIXED_HEADER_SIZE]>::try_from(fixed_header).unwrap(),
+            None => return Ok(None),
+        };
+
+        if version_command >> 4 != VERSION {
+            return Err(format!(
+                "unsupported PROXY protocol version {}",
+                version_command >> 4
+            ));
+        }
+
+        let length = FIXED_HEADER_SIZE + u16::from_be_bytes([a, b]) as usize;
+        let Some(addresses) = buffer.get(FIXED_HEADER_SIZE..length) else {
+            return Ok(None);
+        };
+
+        let source = match version_command & 0x0f {
+            COMMAND_LOCAL => None,
+            COMMAND_PROXY => parse_source(family_protocol >> 4, addresses)?,
+            command => return Err(format!("unknown PROXY command {}", command)),
+        };
+
+        Ok(Some(Self { source, length }))
+    }
+
+    /// Reads the header off a blocking stream, before anything else is read from it
+    pub fn read(stream: &mut impl Read) -> Result<Self> {
+        let mut buffer = vec![0; FIXED_HEADER_SIZE];
+        stream.read_exact(&mut buffer)?;
+
+        // no point in reading the addresses when the fixed part is already wrong
+        if let Err(e) = Self::parse(&buffer) {
+            return Err(Error::new(ErrorKind::InvalidData, e));
+        }
+
+        let remaining = u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+        buffer.resize(FIXED_HEADER_SIZE + remaining, 0);
+        stream.read_exact(&mut buffer[FIXED_HEADER_SIZE..])?;
+
+        match Self::parse(&buffer) {
+            Ok(Some(header)) => Ok(header),
+            Ok(None) => unreachable!("the whole header has been read"),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Source address out of the address block, its destination address and the TLVs are ignored
+fn parse_source(family: u8, addresses: &[u8]) -> std::result::Result<Option<SocketAddr>, String> {
+    let (ip, port) = match family {
+        FAMILY_INET if addresses.len() >= 12 => (
+            IpAddr::from(Ipv4Addr::from(
+                <[u8; 4]>::try_from(&addresses[..4]).unwrap(),
+            )),
+            &addresses[8..10],
+        ),
+        FAMILY_INET6 if addresses.len() >= 36 => (
+            IpAddr::from(Ipv6Addr::from(
+                <[u8; 16]>::try_from(&addresses[..16]).unwrap(),
+            )),
+            &addresses[32..34],
+        ),
+        FAMILY_INET | FAMILY_INET6 => return Err("truncated PROXY addresses".to_string()),
+        // unspecified or UNIX sockets, the proxy connection is all there is to know
+        _ => return Ok(None),
+    };
+
+    Ok(Some(SocketAddr::new(
+        ip,
+        u16::from_be_bytes([port[0], port[1]]),
+    )))
+}
+
+/// Builds a PROXY v2 header, as a proxy would
+#[cfg(test)]
+pub fn build_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION << 4 | COMMAND_PROXY);
+
+    let (family, mut addresses) = match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => (
+            FAMILY_INET,
+            [source.ip().octets(), destination.ip().octets()].concat(),
+        ),
+        (SocketAddr::V6(source), SocketAddr::V6(destination)) => (
+            FAMILY_INET6,
+            [source.ip().octets(), destination.ip().octets()].concat(),
+        ),
+        _ => panic!("source and destination must be of the same family"),
+    };
+    addresses.extend_from_slice(&source.port().to_be_bytes());
+    addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+    // the protocol does not matter to the server, stream is as good as datagram
+    header.push(family << 4 | 0x1);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusting(network: &str) -> ProxyProtocol {
+        let mut proxy_protocol = ProxyProtocol::default();
+        proxy_protocol.add_trusted_proxy(network.parse().unwrap());
+        proxy_protocol
+    }
+
+    #[test]
+    fn parses_ipv4_and_ipv6_headers() {
+        for (source, destination) in [
+            ("192.0.2.10:40000", "198.51.100.1:53"),
+            ("[2001:db8::10]:40000", "[2001:db8::1]:53"),
+        ] {
+            let source = source.parse().unwrap();
+            let mut buffer = build_header(source, destination.parse().unwrap());
+            let length = buffer.len();
+            buffer.extend_from_slice(b"query");
+
+            assert_eq!(
+                ProxyHeader::parse(&buffer),
+                Ok(Some(ProxyHeader {
+                    source: Some(source),
+                    length
+                }))
+            );
+            assert_eq!(ProxyHeader::parse(&buffer[..length - 1]), Ok(None));
+            assert_eq!(ProxyHeader::parse(&buffer[..10]), Ok(None));
+        }
+    }
+
+    #[test]
+    fn keeps_proxy_address_for_local_command() {
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[VERSION << 4 | COMMAND_LOCAL, 0, 0, 0]);
+
+        assert_eq!(
+            ProxyHeader::parse(&header),
+            Ok(Some(ProxyHeader {
+                source: None,
+                length: 16
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(ProxyHeader::parse(b"\x12\x34\x01\x00").is_err());
+        assert!(ProxyHeader::parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 1 2\r\n").is_err());
+
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert!(ProxyHeader::parse(&header).is_err());
+
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[VERSION << 4 | COMMAND_PROXY, FAMILY_INET << 4 | 0x2, 0, 4]);
+        header.extend_from_slice(&[192, 0, 2, 1]);
+        assert!(ProxyHeader::parse(&header).is_err());
+    }
+
+    #[test]
+    fn only_strips_headers_of_trusted_proxies() {
+        let proxy_protocol = trusting("10.0.0.0/8");
+        let client = "192.0.2.10:40000".parse().unwrap();
+        let proxy = "10.0.0.1:50000".parse().unwrap();
+        let mut datagram = build_header(client, "10.0.0.2:53".parse().unwrap());
+        datagram.extend_from_slice(b"query");
+
+        assert_eq!(
+            proxy_protocol.strip_datagram(&datagram, proxy),
+            Ok((client, &b"query"[..]))
+        );
+        assert!(proxy_protocol.strip_datagram(b"query", proxy).is_err());
+
+        // a client can not pretend to be another one
+        let direct = "192.0.2.20:40000".parse().unwrap();
+        assert_eq!(
+            proxy_protocol.strip_datagram(&datagram, direct),
+            Ok((direct, &datagram[..]))
+        );
+    }
+
+    #[test]
+    fn reads_header_off_stream() {
+        let client = "192.0.2.10:40000".parse().unwrap();
+        let mut stream = build_header(client, "10.0.0.2:53".parse().unwrap());
+        stream.extend_from_slice(&[0, 5]);
+        let mut stream = &stream[..];
+
+        assert_eq!(ProxyHeader::read(&mut stream).unwrap().source, Some(client));
+        assert_eq!(stream, &[0, 5]);
+    }
+}
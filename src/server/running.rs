@@ -0,0 +1,81 @@
+use std::{net::SocketAddr, thread};
+
+use crate::server::ServerHandle;
+
+/// A server serving queries in the background, see Server::start
+pub struct RunningServer {
+    udp_addresses: Vec<SocketAddr>,
+    tcp_addresses: Vec<SocketAddr>,
+    tls_addresses: Vec<SocketAddr>,
+    http_addresses: Vec<SocketAddr>,
+    https_addresses: Vec<SocketAddr>,
+    handle: ServerHandle,
+    thread: thread::JoinHandle<()>,
+}
+
+impl RunningServer {
+    pub(crate) fn new(
+        udp_addresses: Vec<SocketAddr>,
+        tcp_addresses: Vec<SocketAddr>,
+        tls_addresses: Vec<SocketAddr>,
+        http_addresses: Vec<SocketAddr>,
+        https_addresses: Vec<SocketAddr>,
+        handle: ServerHandle,
+        thread: thread::JoinHandle<()>,
+    ) -> Self {
+        Self {
+            udp_addresses,
+            tcp_addresses,
+            tls_addresses,
+            http_addresses,
+            https_addresses,
+            handle,
+            thread,
+        }
+    }
+
+    /// Addresses the UDP sockets are bound to, with the port picked by the system for port 0
+    pub fn udp_addresses(&self) -> &[SocketAddr] {
+        &self.udp_addresses
+    }
+
+    /// Addresses the TCP listeners are bound to, with the port picked by the system for port 0
+    pub fn tcp_addresses(&self) -> &[SocketAddr] {
+        &self.tcp_addresses
+    }
+
+    /// Addresses the DNS over TLS listeners are bound to
+    pub fn tls_addresses(&self) -> &[SocketAddr] {
+        &self.tls_addresses
+    }
+
+    /// Addresses the plain DNS over HTTP listeners are bound to
+    pub fn http_addresses(&self) -> &[SocketAddr] {
+        &self.http_addresses
+    }
+
+    /// Addresses the DNS over HTTPS listeners are bound to
+    pub fn https_addresses(&self) -> &[SocketAddr] {
+        &self.https_addresses
+    }
+
+    /// Stops the server from another thread, e.g. a signal handling one
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Stops the server and waits for the in-flight queries, see ServerHandle::shutdown
+    /// The binary stops through a handle from its signal thread instead
+    #[allow(dead_code)]
+    pub fn shutdown(self) {
+        self.handle.shutdown();
+        self.join();
+    }
+
+    /// Waits for the server to stop, which only happens once the shutdown is requested
+    pub fn join(self) {
+        if self.thread.join().is_err() {
+            crate::log::error!("💣🔥 Server thread panicked");
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Time given to in-flight queries to be answered once the server is asked to stop
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Callback = Box<dyn Fn() + Send>;
+
+#[derive(Default)]
+struct Shared {
+    requested_at: OnceLock<Instant>,
+    callbacks: Mutex<Vec<Callback>>,
+}
+
+/// Stops a running server from any thread, see RunningServer::handle
+/// Every clone controls the same server
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    shared: Arc<Shared>,
+}
+
+impl ServerHandle {
+    /// Stops accepting queries and connections, the in-flight ones are still answered
+    /// RunningServer::join returns once they are, or once the shutdown timeout elapsed
+    pub fn shutdown(&self) {
+        if self.shared.requested_at.set(Instant::now()).is_err() {
+            return;
+        }
+
+        let callbacks = self
+            .shared
+            .callbacks
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+        for callback in callbacks.iter() {
+            callback();
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shared.requested_at.get().is_some()
+    }
+
+    /// When the in-flight queries stop being waited for
+    pub(crate) fn deadline(&self, shutdown_timeout: Duration) -> Option<Instant> {
+        self.shared
+            .requested_at
+            .get()
+            .map(|requested_at| *requested_at + shutdown_timeout)
+    }
+
+    /// Registers a callback run once the shutdown is requested, e.g. to wake a thread up
+    /// It runs right away when the shutdown has already been requested
+    pub(crate) fn on_shutdown<F: Fn() + Send + 'static>(&self, callback: F) {
+        let mut callbacks = self
+            .shared
+            .callbacks
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+
+        if self.is_shutting_down() {
+            callback();
+            return;
+        }
+
+        callbacks.push(Box::new(callback));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn runs_callbacks_once() {
+        let handle = ServerHandle::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let calls_clone = Arc::clone(&calls);
+        handle.on_shutdown(move || {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(!handle.is_shutting_down());
+
+        handle.clone().shutdown();
+        handle.shutdown();
+        assert!(handle.is_shutting_down());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // registered too late, called straight away
+        let calls_clone = Arc::clone(&calls);
+        handle.on_shutdown(move || {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,507 @@
+// Thin wrapper around the system OpenSSL library, just what DNS over TLS needs:
+// a server context loaded from PEM files and streams on top of TCP, blocking until they are
+// split into non-blocking halves
+// see: https://docs.openssl.org/3.0/man7/ssl/
+
+use std::{
+    ffi::{CString, c_char, c_int, c_long, c_ulong, c_void},
+    io::{Error, ErrorKind, Read, Result, Write},
+    net::{Shutdown, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    ptr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+#[allow(non_camel_case_types)]
+type SSL_METHOD = c_void;
+#[allow(non_camel_case_types)]
+type SSL_CTX = c_void;
+#[allow(clippy::upper_case_acronyms)]
+type SSL = c_void;
+
+const SSL_FILETYPE_PEM: c_int = 1;
+const SSL_CTRL_SET_MIN_PROTO_VERSION: c_int = 123;
+const TLS1_2_VERSION: c_long = 0x0303;
+const SSL_OP_IGNORE_UNEXPECTED_EOF: u64 = 1 << 7;
+
+const SSL_ERROR_SSL: c_int = 1;
+const SSL_ERROR_WANT_READ: c_int = 2;
+const SSL_ERROR_WANT_WRITE: c_int = 3;
+const SSL_ERROR_SYSCALL: c_int = 5;
+const SSL_ERROR_ZERO_RETURN: c_int = 6;
+
+#[link(name = "ssl")]
+unsafe extern "C" {
+    fn TLS_server_method() -> *const SSL_METHOD;
+    #[cfg(test)]
+    fn TLS_client_method() -> *const SSL_METHOD;
+    fn SSL_CTX_new(method: *const SSL_METHOD) -> *mut SSL_CTX;
+    fn SSL_CTX_free(ctx: *mut SSL_CTX);
+    fn SSL_CTX_set_options(ctx: *mut SSL_CTX, options: u64) -> u64;
+    fn SSL_CTX_ctrl(ctx: *mut SSL_CTX, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_CTX_use_certificate_chain_file(ctx: *mut SSL_CTX, file: *const c_char) -> c_int;
+    fn SSL_CTX_use_PrivateKey_file(ctx: *mut SSL_CTX, file: *const c_char, type_: c_int) -> c_int;
+    fn SSL_CTX_check_private_key(ctx: *const SSL_CTX) -> c_int;
+    fn SSL_new(ctx: *mut SSL_CTX) -> *mut SSL;
+    fn SSL_free(ssl: *mut SSL);
+    fn SSL_set_fd(ssl: *mut SSL, fd: c_int) -> c_int;
+    fn SSL_accept(ssl: *mut SSL) -> c_int;
+    #[cfg(test)]
+    fn SSL_connect(ssl: *mut SSL) -> c_int;
+    fn SSL_read(ssl: *mut SSL, buf: *mut c_void, num: c_int) -> c_int;
+    fn SSL_write(ssl: *mut SSL, buf: *const c_void, num: c_int) -> c_int;
+    fn SSL_pending(ssl: *const SSL) -> c_int;
+    fn SSL_shutdown(ssl: *mut SSL) -> c_int;
+    fn SSL_get_error(ssl: *const SSL, ret: c_int) -> c_int;
+}
+
+#[link(name = "crypto")]
+unsafe extern "C" {
+    fn ERR_get_error() -> c_ulong;
+    fn ERR_clear_error();
+    fn ERR_error_string_n(e: c_ulong, buf: *mut c_char, len: usize);
+}
+
+/// PEM files of the TLS listeners
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+/// TLS context shared by every connection of a listener
+pub struct TlsContext {
+    ctx: *mut SSL_CTX,
+}
+
+// SAFETY: an SSL_CTX is reference counted and safe to share once set up, it is never modified after
+unsafe impl Send for TlsContext {}
+unsafe impl Sync for TlsContext {}
+
+impl TlsContext {
+    /// Server side context, the certificate file may hold the whole chain, leaf first
+    pub fn server(certificate: &Path, key: &Path) -> Result<Self> {
+        // SAFETY: the method is static, a null context is reported as an error
+        let context = Self::new(unsafe { TLS_server_method() })?;
+
+        let certificate_path = c_path(certificate)?;
+        let key_path = c_path(key)?;
+
+        // SAFETY: the context is valid and the paths are NUL terminated
+        if unsafe { SSL_CTX_use_certificate_chain_file(context.ctx, certificate_path.as_ptr()) }
+            != 1
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "could not load certificate {}: {}",
+                    certificate.display(),
+                    last_error()
+                ),
+            ));
+        }
+        if unsafe { SSL_CTX_use_PrivateKey_file(context.ctx, key_path.as_ptr(), SSL_FILETYPE_PEM) }
+            != 1
+            || unsafe { SSL_CTX_check_private_key(context.ctx) } != 1
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("could not load key {}: {}", key.display(), last_error()),
+            ));
+        }
+
+        Ok(context)
+    }
+
+    /// Client side context which does not verify the server, only good for tests
+    #[cfg(test)]
+    pub fn insecure_client() -> Result<Self> {
+        // SAFETY: the method is static, a null context is reported as an error
+        Self::new(unsafe { TLS_client_method() })
+    }
+
+    fn new(method: *const SSL_METHOD) -> Result<Self> {
+        // SAFETY: a null context is reported as an error
+        let ctx = unsafe { SSL_CTX_new(method) };
+        if ctx.is_null() {
+            return Err(Error::other(last_error()));
+        }
+
+        // SAFETY: the context is valid, older versions are not allowed by RFC 8310
+        unsafe {
+            SSL_CTX_ctrl(
+                ctx,
+                SSL_CTRL_SET_MIN_PROTO_VERSION,
+                TLS1_2_VERSION,
+                ptr::null_mut(),
+            );
+            // clients often close the connection without a close_notify alert, the DNS framing
+            // already tells a truncated message apart
+            SSL_CTX_set_options(ctx, SSL_OP_IGNORE_UNEXPECTED_EOF);
+        };
+
+        Ok(Self { ctx })
+    }
+
+    /// Runs the server side handshake over a connected stream, bounded by its timeouts
+    pub fn accept(&self, stream: TcpStream) -> Result<TlsStream> {
+        self.handshake(stream, SSL_accept)
+    }
+
+    #[cfg(test)]
+    pub fn connect(&self, stream: TcpStream) -> Result<TlsStream> {
+        self.handshake(stream, SSL_connect)
+    }
+
+    fn handshake(
+        &self,
+        stream: TcpStream,
+        handshake: unsafe extern "C" fn(*mut SSL) -> c_int,
+    ) -> Result<TlsStream> {
+        // SAFETY: the context is valid, a null SSL is reported as an error
+        let ssl = unsafe { SSL_new(self.ctx) };
+        if ssl.is_null() {
+            return Err(Error::other(last_error()));
+        }
+        // freed along with the stream from now on
+        let stream = TlsStream { ssl, stream };
+
+        // SAFETY: the SSL is valid and the file descriptor outlives it, both belong to the stream
+        if unsafe { SSL_set_fd(ssl, stream.stream.as_raw_fd()) } != 1 {
+            return Err(Error::other(last_error()));
+        }
+
+        // SAFETY: the SSL is valid and set up
+        let ret = unsafe { handshake(ssl) };
+        if ret != 1 {
+            return Err(stream.error(ret));
+        }
+
+        Ok(stream)
+    }
+}
+
+impl Drop for TlsContext {
+    fn drop(&mut self) {
+        // SAFETY: the context is valid, the connections hold their own reference
+        unsafe { SSL_CTX_free(self.ctx) };
+    }
+}
+
+/// A TLS connection over a blocking TCP stream, the stream timeouts apply, until it is split
+pub struct TlsStream {
+    ssl: *mut SSL,
+    stream: TcpStream,
+}
+
+// SAFETY: the SSL is only used through &mut self, so by one thread at a time
+unsafe impl Send for TlsStream {}
+
+impl TlsStream {
+    /// Data already decrypted, which polling the socket does not report
+    pub fn has_buffered_data(&self) -> bool {
+        // SAFETY: the SSL is valid
+        unsafe { SSL_pending(self.ssl) > 0 }
+    }
+
+    /// Sends the close_notify alert then closes the TCP stream
+    pub fn shutdown(&mut self) {
+        // SAFETY: the SSL is valid, the peer's alert is not waited for
+        unsafe { SSL_shutdown(self.ssl) };
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    /// Turns the result of an SSL call into an error, clearing the OpenSSL error queue
+    fn error(&self, ret: c_int) -> Error {
+        // SAFETY: the SSL is valid and ret is the result of the last call made with it
+        match unsafe { SSL_get_error(self.ssl, ret) } {
+            SSL_ERROR_WANT_READ | SSL_ERROR_WANT_WRITE => {
+                // SAFETY: no pointer involved
+                unsafe { ERR_clear_error() };
+                Error::from(ErrorKind::WouldBlock)
+            }
+            SSL_ERROR_ZERO_RETURN => {
+                unsafe { ERR_clear_error() };
+                Error::from(ErrorKind::UnexpectedEof)
+            }
+            SSL_ERROR_SYSCALL => match Error::last_os_error() {
+                e if e.raw_os_error() == Some(0) => {
+                    unsafe { ERR_clear_error() };
+                    Error::from(ErrorKind::UnexpectedEof)
+                }
+                e => {
+                    unsafe { ERR_clear_error() };
+                    e
+                }
+            },
+            SSL_ERROR_SSL => Error::new(ErrorKind::InvalidData, last_error()),
+            code => {
+                unsafe { ERR_clear_error() };
+                Error::other(format!("TLS error {}", code))
+            }
+        }
+    }
+}
+
+impl TlsStream {
+    /// Halves reading and writing the stream from different threads
+    /// OpenSSL does not allow it at the same time, the halves take turns: the stream is made
+    /// non-blocking so that each only holds it for one OpenSSL call, never while waiting for
+    /// the peer
+    pub fn split(self) -> Result<(TlsReader, TlsWriter)> {
+        let fd = self.as_raw_fd();
+        let timeout = self.stream.write_timeout()?;
+        self.stream.set_nonblocking(true)?;
+        let stream = Arc::new(Mutex::new(self));
+
+        Ok((
+            TlsReader {
+                stream: Arc::clone(&stream),
+                fd,
+            },
+            TlsWriter {
+                stream,
+                fd,
+                timeout,
+            },
+        ))
+    }
+}
+
+fn lock(stream: &Mutex<TlsStream>) -> MutexGuard<'_, TlsStream> {
+    stream.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+/// Read half of a TLS stream, which fails with WouldBlock until a whole record is received
+pub struct TlsReader {
+    stream: Arc<Mutex<TlsStream>>,
+    fd: RawFd,
+}
+
+impl TlsReader {
+    pub fn has_buffered_data(&self) -> bool {
+        lock(&self.stream).has_buffered_data()
+    }
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        lock(&self.stream).read(buf)
+    }
+}
+
+impl AsRawFd for TlsReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// How long a writer without write timeout waits for the socket at once
+const WRITE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Write half of a TLS stream, which waits for the socket to accept more with the stream write
+/// timeout
+pub struct TlsWriter {
+    stream: Arc<Mutex<TlsStream>>,
+    fd: RawFd,
+    timeout: Option<Duration>,
+}
+
+impl TlsWriter {
+    pub fn shutdown(&mut self) {
+        lock(&self.stream).shutdown();
+    }
+}
+
+impl Write for TlsWriter {
+    /// OpenSSL wants the same buffer again once the socket accepts more
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let started = Instant::now();
+        loop {
+            match lock(&self.stream).write(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            let waited = started.elapsed();
+            let timeout = match self.timeout {
+                Some(timeout) if waited >= timeout => return Err(Error::from(ErrorKind::TimedOut)),
+                Some(timeout) => timeout - waited,
+                None => WRITE_POLL_INTERVAL,
+            };
+            super::wait_writable(&self.fd, timeout)?;
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+
+        // SAFETY: the SSL is valid and the buffer holds at least len bytes
+        let ret = unsafe { SSL_read(self.ssl, buf.as_mut_ptr().cast(), len) };
+        if ret > 0 {
+            return Ok(ret as usize);
+        }
+
+        match self.error(ret) {
+            // the client closed the TLS session
+            e if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            e => Err(e),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+
+        // SAFETY: the SSL is valid and the buffer holds at least len bytes
+        let ret = unsafe { SSL_write(self.ssl, buf.as_ptr().cast(), len) };
+        if ret > 0 {
+            return Ok(ret as usize);
+        }
+
+        Err(self.error(ret))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for TlsStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // SAFETY: the SSL is valid and not used after this
+        unsafe { SSL_free(self.ssl) };
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid path {}", path.display()),
+        )
+    })
+}
+
+/// Oldest error of the OpenSSL error queue, the queue is emptied
+fn last_error() -> String {
+    // SAFETY: no pointer involved
+    let code = unsafe { ERR_get_error() };
+    if code == 0 {
+        return "unknown TLS error".to_string();
+    }
+
+    let mut buffer = [0 as c_char; 256];
+    // SAFETY: the buffer length is the one passed, the string is NUL terminated
+    let message = unsafe {
+        ERR_error_string_n(code, buffer.as_mut_ptr(), buffer.len());
+        std::ffi::CStr::from_ptr(buffer.as_ptr())
+    }
+    .to_string_lossy()
+    .into_owned();
+
+    unsafe { ERR_clear_error() };
+    message
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// Self-signed certificate for localhost, see testdata/README.md
+    pub fn test_certificate() -> (PathBuf, PathBuf) {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        (
+            testdata.join("localhost.crt"),
+            testdata.join("localhost.key"),
+        )
+    }
+
+    pub fn test_context() -> TlsContext {
+        let (certificate, key) = test_certificate();
+        TlsContext::server(&certificate, &key).unwrap()
+    }
+
+    #[test]
+    fn exchanges_data_over_tls() {
+        let context = test_context();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = context.accept(stream).unwrap();
+
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).unwrap();
+            stream.write_all(&buffer.map(|b| b + 1)).unwrap();
+            stream.shutdown();
+        });
+
+        let client = TlsContext::insecure_client().unwrap();
+        let mut stream = client
+            .connect(TcpStream::connect(address).unwrap())
+            .unwrap();
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        assert_eq!(response, vec![2, 3, 4, 5]);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reports_invalid_certificate_files() {
+        let (certificate, key) = test_certificate();
+
+        let error = TlsContext::server(Path::new("/nonexistent/cert.pem"), &key)
+            .err()
+            .unwrap();
+        assert!(
+            error
+                .to_string()
+                .starts_with("could not load certificate /nonexistent/cert.pem")
+        );
+
+        // a certificate is not a key
+        assert!(TlsContext::server(&certificate, &certificate).is_err());
+    }
+
+    #[test]
+    fn fails_handshake_with_plain_client() {
+        let context = test_context();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"\x00\x1cnot a TLS client hello").unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        assert!(context.accept(stream).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+// Signals are blocked in every thread and picked up by a dedicated one with sigwait, so that
+// handling them is not restricted to async-signal-safe code
+
+use std::{
+    io::{Error, Result},
+    mem, ptr,
+};
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM};
+
+/// Blocks the signals in the calling thread and the threads it spawns afterwards,
+/// to be called before spawning any thread
+pub fn block(signals: &[libc::c_int]) -> Result<()> {
+    let set = signal_set(signals)?;
+
+    // SAFETY: the set is initialized, the previous mask is not asked for
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) } {
+        0 => Ok(()),
+        errno => Err(Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Waits for one of the signals, which must have been blocked beforehand
+pub fn wait(signals: &[libc::c_int]) -> Result<libc::c_int> {
+    let set = signal_set(signals)?;
+    let mut signal = 0;
+
+    // SAFETY: the set is initialized and signal points to a valid c_int
+    match unsafe { libc::sigwait(&set, &mut signal) } {
+        0 => Ok(signal),
+        errno => Err(Error::from_raw_os_error(errno)),
+    }
+}
+
+pub fn name(signal: libc::c_int) -> &'static str {
+    match signal {
+        SIGHUP => "SIGHUP",
+        SIGINT => "SIGINT",
+        SIGTERM => "SIGTERM",
+        _ => "signal",
+    }
+}
+
+/// Sends the signal to another process
+pub fn send(pid: libc::pid_t, signal: libc::c_int) -> Result<()> {
+    // SAFETY: no pointer involved, an unknown pid is reported through errno
+    if unsafe { libc::kill(pid, signal) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn signal_set(signals: &[libc::c_int]) -> Result<libc::sigset_t> {
+    // SAFETY: sigemptyset initializes the zeroed set before it is used
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    if unsafe { libc::sigemptyset(&mut set) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    for signal in signals {
+        // SAFETY: the set is initialized
+        if unsafe { libc::sigaddset(&mut set, *signal) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn waits_for_blocked_signal() {
+        // SIGUSR2 is not used by the test harness, blocked in this thread only
+        let waiter = thread::spawn(|| {
+            block(&[libc::SIGUSR2]).unwrap();
+            // SAFETY: sends a signal to the current thread
+            unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR2) };
+            wait(&[libc::SIGUSR2]).unwrap()
+        });
+
+        assert_eq!(waiter.join().unwrap(), libc::SIGUSR2);
+    }
+}
@@ -1,63 +1,1077 @@
-use std::net::ToSocketAddrs;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    common::resource_record::ResourceRecord,
+    common::{domain_name::DomainName, question::Question, resource_record::ResourceRecord},
     decoder::Decoder,
     encoder::Encoder,
+    log::{debug, info, warning},
     storage::{
-        InMemoryResourceRecordRepository, RepositoryError, ResourceRecordRepository,
+        Answer, AuthoritativeRecords, InMemoryResourceRecordRepository, Lookup, Prefetch,
+        RepositoryError, ResourceRecordRepository, UpstreamQuery,
         fallback::FallbackRepository,
+        metrics::CacheMetrics,
+        persistence::{self, CacheFileError},
+        sharded::{CACHE_SHARDS, ShardedCache},
     },
+    worker_pool::WorkerPool,
+};
+
+pub const DEFAULT_CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// RFC 8767 section 5 suggests between 1 and 3 days
+pub const DEFAULT_STALE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// Popular records are refreshed once served 3 times, in the last 10% of their TTL
+pub const DEFAULT_PREFETCH: Prefetch = Prefetch {
+    min_hits: 3,
+    percent: 10,
 };
+/// Waited before each attempt to refresh stale records in the background
+const STALE_REFRESH_DELAYS: [Duration; 5] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+    Duration::from_secs(8),
+    Duration::from_secs(16),
+];
+/// Refreshes run at the same time, the other ones wait in a queue, beyond which they are skipped
+const REFRESH_WORKERS: usize = 4;
+const REFRESH_QUEUE_CAPACITY: usize = 256;
+/// How often a refresh waiting for its next attempt checks whether the repository was dropped
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
-pub struct CombinedRepository<T: ToSocketAddrs + Clone, D: Decoder, E: Encoder> {
-    in_memory_repository: InMemoryResourceRecordRepository,
-    fallback_repository: FallbackRepository<T, D, E>,
+/// Serves the records of two distinct stores, and caches what the fallback servers answer
+/// Lookups go through them in this order, the first one with an answer wins: the authoritative
+/// records of the zones, which never expire and are answered with AA set, then the cache of what
+/// the fallback servers answered, records and negative answers alike, then the fallback servers
+/// The cache never shadows the zones, nor outlives them as configured data: it is kept when the
+/// authoritative records are replaced, and only ever holds what the fallback servers answered
+/// Without fallback repository only the authoritative records are served
+/// Cached records are served with the TTL they have left, and no longer once it reaches zero,
+/// they are dropped when their name gets a new answer, and by the sweeper
+/// Within the stale window expired records are still kept, and served when the fallback servers
+/// can not be reached while they are refreshed in the background (RFC 8767)
+/// The cache can be bounded, the least recently used names are then evicted to make room
+/// Popular records are prefetched: refreshed in the background shortly before they expire, while
+/// they are still served
+/// The cache is sharded by name behind read-write locks, lookups share them, and no lock is held
+/// while the fallback servers are asked
+/// The cache can be saved to a file and loaded back, to start warm after a restart
+pub struct CombinedRepository<D: Decoder, E: Encoder> {
+    authoritative_records: AuthoritativeRecords,
+    cache: Arc<ShardedCache>,
+    fallback_repository: Option<Arc<FallbackRepository<D, E>>>,
+    /// Name and type of the questions whose records are being refreshed, stale or prefetched
+    refreshing: Arc<Mutex<HashSet<(DomainName, u16)>>>,
+    /// Runs the refreshes, with a fallback repository only; dropped last so that its workers
+    /// see the repository gone and stop waiting for their next attempt
+    refresh_pool: Option<WorkerPool>,
 }
 
-impl<T: ToSocketAddrs + Clone, D: Decoder, E: Encoder> CombinedRepository<T, D, E> {
+impl<D: Decoder, E: Encoder> CombinedRepository<D, E> {
     pub fn new(
-        in_memory_repository: InMemoryResourceRecordRepository,
-        fallback_repository: FallbackRepository<T, D, E>,
+        authoritative_records: AuthoritativeRecords,
+        fallback_repository: Option<FallbackRepository<D, E>>,
     ) -> Self {
         Self {
-            in_memory_repository,
-            fallback_repository,
+            authoritative_records,
+            cache: Arc::new(ShardedCache::new(CACHE_SHARDS)),
+            refresh_pool: fallback_repository
+                .is_some()
+                .then(|| WorkerPool::new("cache-refresh", REFRESH_WORKERS, REFRESH_QUEUE_CAPACITY)),
+            fallback_repository: fallback_repository.map(Arc::new),
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Expired records are kept this long, zero to drop them right away
+    pub fn with_stale_window(self, stale_window: Duration) -> Self {
+        self.with_cache(|cache| cache.with_stale_window(stale_window))
+    }
+
+    pub fn with_prefetch(self, prefetch: Prefetch) -> Self {
+        self.with_cache(|cache| cache.with_prefetch(prefetch))
+    }
+
+    /// Records and negative answers cached beyond this many evict the least recently used names
+    /// Each shard of the cache keeps to its share of the limit
+    pub fn with_max_cached_records(self, max_cached_records: usize) -> Self {
+        self.cache
+            .configure_limit(max_cached_records, |cache, max_entries| {
+                cache.with_max_entries(max_entries)
+            });
+        self
+    }
+
+    /// Same as with_max_cached_records, for the approximate memory used by the cache
+    pub fn with_max_cache_bytes(self, max_cache_bytes: usize) -> Self {
+        self.cache
+            .configure_limit(max_cache_bytes, |cache, max_bytes| {
+                cache.with_max_bytes(max_bytes)
+            });
+        self
+    }
+
+    fn with_cache(
+        self,
+        configure: impl Fn(InMemoryResourceRecordRepository) -> InMemoryResourceRecordRepository,
+    ) -> Self {
+        self.cache.configure(configure);
+        self
+    }
+
+    pub fn cache_metrics(&self) -> Arc<CacheMetrics> {
+        self.cache.metrics()
+    }
+
+    /// The cache, for it to be saved once the repository is handed over to the server
+    pub fn cache(&self) -> Arc<ShardedCache> {
+        self.cache.clone()
+    }
+
+    /// Caches the entries of a file saved by a previous run, returns how many did not expire yet
+    pub fn load_cache(&self, path: &Path) -> Result<usize, CacheFileError> {
+        persistence::load(&self.cache, path)
+    }
+
+    /// Saves the cache to a file every `interval`, until the repository is dropped
+    pub fn spawn_cache_saver(&self, path: PathBuf, interval: Duration) -> io::Result<()> {
+        let cache = Arc::downgrade(&self.cache);
+
+        thread::Builder::new()
+            .name("cache-saver".to_string())
+            .spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    let Some(cache) = cache.upgrade() else {
+                        break;
+                    };
+
+                    match persistence::save(&cache, &path) {
+                        Ok(saved) => {
+                            debug!("💾 Saved {} cache entries to {}", saved, path.display())
+                        }
+                        Err(e) => {
+                            warning!("💾 Could not save the cache to {}: {}", path.display(), e)
+                        }
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+
+    /// Removes the expired records from the cache every `interval`, until the repository is dropped
+    /// The cache metrics are reported along the way
+    pub fn spawn_cache_sweeper(&self, interval: Duration) -> io::Result<()> {
+        let cache = Arc::downgrade(&self.cache);
+        let metrics = self.cache_metrics();
+
+        thread::Builder::new()
+            .name("cache-sweeper".to_string())
+            .spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    let Some(cache) = cache.upgrade() else {
+                        break;
+                    };
+
+                    let removed = cache.remove_expired(Instant::now());
+                    if removed > 0 {
+                        debug!("🧹 Removed {} expired records from the cache", removed);
+                    }
+                    info!("📊 Cache: {}", metrics);
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+impl<D, E> CombinedRepository<D, E>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
+{
+    /// Asks the fallback servers again after each delay, until they answer, meanwhile the cached
+    /// records are served right away
+    /// A question is refreshed once at a time, false when it already is or the pool is full
+    fn refresh_in_background(
+        &self,
+        fallback_repository: Arc<FallbackRepository<D, E>>,
+        question: Question,
+        delays: &'static [Duration],
+    ) -> bool {
+        let Some(refresh_pool) = &self.refresh_pool else {
+            return false;
+        };
+        let key = (question.name.clone(), u16::from(question.type_.clone()));
+        if !self
+            .refreshing
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .insert(key.clone())
+        {
+            return false;
+        }
+
+        let cache = self.cache.clone();
+        let refreshing = Arc::downgrade(&self.refreshing);
+        let refreshed = key.clone();
+        let queued = refresh_pool.execute(move || {
+            for delay in delays {
+                if !wait_for_refresh(&refreshing, *delay) {
+                    return;
+                }
+                match fallback_repository.fetch(&question) {
+                    Ok(answer) => {
+                        debug!("🔄 Refreshed the records of {:?}", question.name);
+                        cache_answer(&cache, &question, &answer);
+                        break;
+                    }
+                    Err(e) => debug!("🔄 Could not refresh {:?}: {}", question.name, e),
+                }
+            }
+
+            if let Some(refreshing) = refreshing.upgrade() {
+                refreshing
+                    .lock()
+                    .unwrap_or_else(|p| p.into_inner())
+                    .remove(&refreshed);
+            }
+        });
+
+        if let Err(e) = queued {
+            warning!("🔄 Could not refresh records in the background: {:?}", e);
+            self.refreshing
+                .lock()
+                .unwrap_or_else(|p| p.into_inner())
+                .remove(&key);
+            return false;
+        }
+        true
+    }
+}
+
+/// Waits before an attempt to refresh records, false once the repository is dropped meanwhile
+fn wait_for_refresh(refreshing: &Weak<Mutex<HashSet<(DomainName, u16)>>>, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        if refreshing.strong_count() == 0 {
+            return false;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(REFRESH_CHECK_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Caches an answer of the fallback servers in place of the records it had, along the way the
+/// expired entries of the name are dropped
+/// Records of other names, like the target of a CNAME, are cached in the shards of their names
+fn cache_answer(cache: &ShardedCache, question: &Question, answer: &Answer) {
+    let now = Instant::now();
+    let mut records_by_name: HashMap<&DomainName, Vec<ResourceRecord>> = HashMap::new();
+    for record in answer.records.iter() {
+        records_by_name
+            .entry(&record.name)
+            .or_default()
+            .push(record.clone());
+    }
+
+    {
+        let mut shard = cache.write(&question.name);
+        let expired = shard.remove_expired_records_of(&question.name, now);
+        if expired > 0 {
+            debug!("⌛ Removed expired entries from cache: {}", expired);
+        }
+        let records = records_by_name.remove(&question.name).unwrap_or_default();
+        shard.replace_rrsets(question, &records, now);
+        shard.cache_negative_answer(question, answer, now);
+    }
+
+    for (name, records) in records_by_name {
+        let mut shard = cache.write(name);
+        for record in records {
+            shard.cache(record, now);
         }
     }
 }
 
-impl<T: ToSocketAddrs + Clone, D: Decoder, E: Encoder> ResourceRecordRepository
-    for CombinedRepository<T, D, E>
+impl<D, E> ResourceRecordRepository for CombinedRepository<D, E>
+where
+    D: Decoder + Send + Sync + 'static,
+    E: Encoder + Send + Sync + 'static,
 {
     fn get_resource_records(
-        &mut self,
+        &self,
         question: crate::common::question::Question,
     ) -> Result<Vec<ResourceRecord>, RepositoryError> {
-        let in_memory_records = self
-            .in_memory_repository
-            .get_resource_records(question.clone())?;
-
-        if !in_memory_records.is_empty() {
-            println!(
-                "💾 Found records in in-memory repository: {:?}",
-                in_memory_records.len()
+        self.get_answer(question).map(|answer| answer.records)
+    }
+
+    /// Only the shard of the name is locked, and never while the fallback servers are asked
+    fn get_answer(
+        &self,
+        question: crate::common::question::Question,
+    ) -> Result<Answer, RepositoryError> {
+        match self.start_answer(question) {
+            Lookup::Answered(answer) => answer,
+            Lookup::Upstream(query) => {
+                let response = match &self.fallback_repository {
+                    Some(fallback_repository) => fallback_repository.fetch(&query.question),
+                    None => Ok(Answer::records(vec![])),
+                };
+                self.complete_answer(query, response)
+            }
+        }
+    }
+
+    fn start_answer(&self, question: Question) -> Lookup {
+        let authoritative_records = self.authoritative_records.lookup(&question);
+
+        if !authoritative_records.is_empty() {
+            debug!(
+                "📚 Found authoritative records: {:?}",
+                authoritative_records.len()
+            );
+            return Lookup::Answered(Ok(Answer::authoritative(authoritative_records)));
+        }
+
+        let now = Instant::now();
+        let (cached, prefetch, stale_records) = {
+            let cache = self.cache.read(&question.name);
+            match cache.lookup_cached(&question, now) {
+                Some(answer) => (Some(answer), cache.should_prefetch(&question, now), vec![]),
+                None => (None, false, cache.stale_lookup_at(&question, now)),
+            }
+        };
+
+        if let Some(answer) = cached {
+            debug!(
+                "💾 Found in cache: {:?} records, {:?}",
+                answer.records.len(),
+                answer.response_code
+            );
+            if prefetch
+                && let Some(fallback_repository) = self.fallback_repository.clone()
+                && self.refresh_in_background(fallback_repository, question, &[Duration::ZERO])
+            {
+                debug!("🔄 Prefetching popular records before they expire");
+                self.cache.metrics().prefetched();
+            }
+            return Lookup::Answered(Ok(answer));
+        }
+
+        let Some(fallback_repository) = &self.fallback_repository else {
+            return Lookup::Answered(Ok(Answer::records(vec![])));
+        };
+
+        let refreshing = self
+            .refreshing
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .contains(&(question.name.clone(), u16::from(question.type_.clone())));
+        if !stale_records.is_empty() && refreshing {
+            debug!(
+                "🥖 Serving stale records while they are refreshed: {:?}",
+                stale_records.len()
             );
-            return Ok(in_memory_records);
+            return Lookup::Answered(Ok(Answer::stale(stale_records)));
         }
 
-        let fallback_repository_records =
-            self.fallback_repository.get_resource_records(question)?;
+        Lookup::Upstream(UpstreamQuery {
+            question,
+            servers: fallback_repository.fallback_server_addresses.clone(),
+            timeout: fallback_repository.timeout,
+            stale_records,
+        })
+    }
+
+    /// Caches what the fallback servers answered, when they failed the stale records are served
+    /// while they are refreshed in the background
+    fn complete_answer(
+        &self,
+        query: UpstreamQuery,
+        response: Result<Answer, RepositoryError>,
+    ) -> Result<Answer, RepositoryError> {
+        let answer = match response {
+            Ok(answer) => answer,
+            Err(e) if !query.stale_records.is_empty() => {
+                debug!(
+                    "🥖 Serving stale records, the fallback servers failed: {}",
+                    e
+                );
+                if let Some(fallback_repository) = self.fallback_repository.clone() {
+                    self.refresh_in_background(
+                        fallback_repository,
+                        query.question,
+                        &STALE_REFRESH_DELAYS,
+                    );
+                }
+                return Ok(Answer::stale(query.stale_records));
+            }
+            Err(e) => return Err(e),
+        };
 
-        println!(
+        debug!(
             "🔍 Found records in fallback repository: {:?}",
-            fallback_repository_records.len()
+            answer.records.len()
+        );
+
+        cache_answer(&self.cache, &query.question, &answer);
+
+        Ok(answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::UdpSocket,
+        sync::atomic::{AtomicU8, Ordering},
+        thread,
+    };
+
+    use crate::{
+        common::{
+            Message,
+            domain_name::DomainName,
+            header::ResponseCode,
+            question::{Class, Question, Type as QuestionType},
+            resource_record::Type,
+        },
+        decoder::MessageDecoder,
+        encoder::MessageEncoder,
+        storage::{
+            STALE_TTL,
+            fallback::DEFAULT_UPSTREAM_TIMEOUT,
+            zone::{DEFAULT_TTL, parse_record},
+        },
+    };
+
+    use super::*;
+
+    /// Answers the given amount of queries with a single A record, returns its address
+    fn start_upstream(answers: usize) -> std::net::SocketAddr {
+        start_upstream_with_ttl(answers, 300)
+    }
+
+    fn start_upstream_with_ttl(answers: usize, ttl: u32) -> std::net::SocketAddr {
+        start_upstream_with(answers, move |response| {
+            response.set_answers(vec![ResourceRecord::new(
+                DomainName::from("example.com."),
+                Type::A,
+                Class::IN,
+                ttl,
+                vec![192, 0, 2, 1],
+            )]);
+        })
+    }
+
+    /// Answers the given amount of queries with NXDOMAIN and the SOA of example.com
+    fn start_nxdomain_upstream(answers: usize) -> std::net::SocketAddr {
+        start_upstream_with(answers, |response| {
+            response.header.response_code = ResponseCode::NameError;
+            response.set_authorities(vec![soa()]);
+        })
+    }
+
+    fn start_upstream_with(
+        answers: usize,
+        respond: impl Fn(&mut Message) + Send + 'static,
+    ) -> std::net::SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            for _ in 0..answers {
+                let (amt, src) = socket.recv_from(&mut buf).unwrap();
+                let query = MessageDecoder {}.decode(&buf[..amt]).unwrap();
+                let mut response = query.into_response();
+                respond(&mut response);
+                socket
+                    .send_to(&MessageEncoder {}.encode(response), src)
+                    .unwrap();
+            }
+        });
+
+        address
+    }
+
+    fn soa() -> ResourceRecord {
+        parse_record(
+            "@ 3600 IN SOA ns1 hostmaster 2024010101 7200 3600 1209600 300",
+            "example.com",
+            DEFAULT_TTL,
+        )
+        .unwrap()
+    }
+
+    fn question(name: &str) -> Question {
+        Question {
+            name: DomainName::from(name),
+            type_: QuestionType::RRType(Type::A),
+            class: Class::IN,
+        }
+    }
+
+    fn fallback_repository(
+        fallback_server_addresses: Vec<std::net::SocketAddr>,
+    ) -> FallbackRepository<MessageDecoder, MessageEncoder> {
+        FallbackRepository {
+            fallback_server_addresses,
+            timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            decoder: MessageDecoder {},
+            encoder: MessageEncoder {},
+        }
+    }
+
+    #[test]
+    fn serves_in_memory_records_only_without_fallback() {
+        let repository = CombinedRepository::<MessageDecoder, MessageEncoder>::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            None,
+        );
+
+        let records = repository
+            .get_resource_records(question("example.com."))
+            .unwrap();
+
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn hands_over_questions_for_the_fallback_servers() {
+        let upstream = "127.0.0.1:53".parse().unwrap();
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        );
+
+        let Lookup::Upstream(query) = repository.start_answer(question("example.com.")) else {
+            panic!("the question should be handed over");
+        };
+        assert_eq!(query.question, question("example.com."));
+        assert_eq!(query.servers, vec![upstream]);
+        assert_eq!(query.timeout, DEFAULT_UPSTREAM_TIMEOUT);
+
+        let record = ResourceRecord::new(
+            DomainName::from("example.com."),
+            Type::A,
+            Class::IN,
+            300,
+            vec![192, 0, 2, 1],
+        );
+        let answer = repository
+            .complete_answer(query, Ok(Answer::records(vec![record.clone()])))
+            .unwrap();
+        assert_eq!(answer.records, vec![record.clone()]);
+
+        // the answer is cached, the fallback servers are not needed anymore
+        let Lookup::Answered(Ok(answer)) = repository.start_answer(question("example.com.")) else {
+            panic!("the answer should be cached");
+        };
+        assert_eq!(answer.records.len(), 1);
+        assert_eq!(answer.records[0].resource_data, record.resource_data);
+    }
+
+    #[test]
+    fn caches_fallback_records() {
+        // a single answer, the second lookup must come from the cache
+        let upstream = start_upstream(1);
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        );
+
+        for _ in 0..2 {
+            let records = repository
+                .get_resource_records(question("example.com."))
+                .unwrap();
+            assert_eq!(records.len(), 1);
+        }
+    }
+
+    #[test]
+    fn caches_records_in_the_shards_of_their_names() {
+        let upstream = start_upstream_with(1, |response| {
+            response.set_answers(vec![
+                ResourceRecord::new(
+                    DomainName::from("example.com."),
+                    Type::CNAME,
+                    Class::IN,
+                    300,
+                    b"\x06target\x07example\x03net\x00".to_vec(),
+                ),
+                ResourceRecord::new(
+                    DomainName::from("target.example.net."),
+                    Type::A,
+                    Class::IN,
+                    300,
+                    vec![192, 0, 2, 1],
+                ),
+            ]);
+        });
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        );
+
+        let records = repository
+            .get_resource_records(question("example.com."))
+            .unwrap();
+        assert_eq!(records.len(), 2);
+
+        let target = question("target.example.net.");
+        let answer = repository
+            .cache
+            .read(&target.name)
+            .lookup_cached(&target, Instant::now())
+            .unwrap();
+        assert_eq!(answer.records[0].resource_data, vec![192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn fetches_records_again_once_expired() {
+        // the third lookup can only be answered if the cached record expired
+        let upstream = start_upstream_with_ttl(2, 1);
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        );
+
+        for _ in 0..2 {
+            let records = repository
+                .get_resource_records(question("example.com."))
+                .unwrap();
+            assert_eq!(records[0].ttl, 1);
+        }
+
+        thread::sleep(Duration::from_millis(1100));
+        let records = repository
+            .get_resource_records(question("example.com."))
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(repository.cache_metrics().entries(), 1);
+    }
+
+    #[test]
+    fn caches_negative_answers() {
+        // a single answer, the other lookups must come from the cache
+        let upstream = start_nxdomain_upstream(1);
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        );
+
+        let answer = repository
+            .get_answer(question("nowhere.example.com."))
+            .unwrap();
+        assert_eq!(answer.response_code, ResponseCode::NameError);
+        assert!(answer.records.is_empty());
+        assert_eq!(answer.authorities, vec![soa()]);
+
+        // NXDOMAIN holds for every type, with the SOA TTL capped to its MINIMUM
+        let answer = repository
+            .get_answer(Question {
+                type_: QuestionType::RRType(Type::AAAA),
+                ..question("nowhere.example.com.")
+            })
+            .unwrap();
+        assert_eq!(answer.response_code, ResponseCode::NameError);
+        assert_eq!(answer.authorities[0].ttl, 300);
+        assert_eq!(answer.authorities[0].resource_data, soa().resource_data);
+    }
+
+    #[test]
+    fn serves_stale_records_while_refreshing_them() {
+        // answers the first query, ignores the second and answers the third one
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            for i in 0..3 {
+                let (amt, src) = socket.recv_from(&mut buf).unwrap();
+                let mut response = MessageDecoder {}
+                    .decode(&buf[..amt])
+                    .unwrap()
+                    .into_response();
+                response.set_answers(vec![ResourceRecord::new(
+                    DomainName::from("example.com."),
+                    Type::A,
+                    Class::IN,
+                    1,
+                    vec![192, 0, 2, i],
+                )]);
+                if i != 1 {
+                    socket
+                        .send_to(&MessageEncoder {}.encode(response), src)
+                        .unwrap();
+                }
+            }
+        });
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(FallbackRepository {
+                timeout: Duration::from_millis(100),
+                ..fallback_repository(vec![upstream])
+            }),
+        )
+        .with_stale_window(Duration::from_secs(60));
+
+        let answer = repository.get_answer(question("example.com.")).unwrap();
+        assert!(!answer.stale);
+        thread::sleep(Duration::from_millis(1100));
+
+        // the upstream does not answer, then is not asked while the refresh is pending
+        for _ in 0..2 {
+            let answer = repository.get_answer(question("example.com.")).unwrap();
+            assert!(answer.stale);
+            assert_eq!(answer.records[0].ttl, STALE_TTL);
+            assert_eq!(answer.records[0].resource_data, vec![192, 0, 2, 0]);
+        }
+
+        thread::sleep(STALE_REFRESH_DELAYS[0] + Duration::from_millis(500));
+        let answer = repository.get_answer(question("example.com.")).unwrap();
+        assert!(!answer.stale);
+        assert_eq!(answer.records.len(), 1);
+        assert_eq!(answer.records[0].resource_data, vec![192, 0, 2, 2]);
+        assert!(repository.refreshing.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn prefetches_popular_records() {
+        let answered = Arc::new(AtomicU8::new(0));
+        let upstream = start_upstream_with(2, move |response| {
+            let address = answered.fetch_add(1, Ordering::Relaxed) + 1;
+            response.set_answers(vec![ResourceRecord::new(
+                DomainName::from("example.com."),
+                Type::A,
+                Class::IN,
+                300,
+                vec![192, 0, 2, address],
+            )]);
+        });
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        )
+        .with_prefetch(Prefetch {
+            min_hits: 1,
+            percent: 100,
+        });
+
+        let address = |repository: &CombinedRepository<_, _>| {
+            let records = repository
+                .get_resource_records(question("example.com."))
+                .unwrap();
+            assert_eq!(records.len(), 1);
+            records[0].resource_data[3]
+        };
+        assert_eq!(address(&repository), 1);
+        // served once from the cache, popular enough to be refreshed
+        assert_eq!(address(&repository), 1);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(repository.cache_metrics().prefetches(), 1);
+
+        assert_eq!(address(&repository), 2);
+    }
+
+    #[test]
+    fn serves_the_cache_while_the_fallback_servers_are_asked() {
+        let upstream = start_upstream_with(1, |response| {
+            thread::sleep(Duration::from_millis(500));
+            response.header.response_code = ResponseCode::NameError;
+            response.set_authorities(vec![soa()]);
+        });
+        let repository = Arc::new(CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        ));
+        repository
+            .cache
+            .write(&DomainName::from("example.com."))
+            .cache(
+                ResourceRecord::new(
+                    DomainName::from("example.com."),
+                    Type::A,
+                    Class::IN,
+                    300,
+                    vec![192, 0, 2, 1],
+                ),
+                Instant::now(),
+            );
+
+        let slow_lookup = {
+            let repository = repository.clone();
+            thread::spawn(move || repository.get_answer(question("slow.example.com.")))
+        };
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let answer = repository.get_answer(question("example.com.")).unwrap();
+        assert_eq!(answer.records.len(), 1);
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        let answer = slow_lookup.join().unwrap().unwrap();
+        assert_eq!(answer.response_code, ResponseCode::NameError);
+    }
+
+    #[test]
+    fn fails_without_stale_records() {
+        let unreachable = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(FallbackRepository {
+                timeout: Duration::from_millis(100),
+                ..fallback_repository(vec![unreachable])
+            }),
+        )
+        .with_stale_window(Duration::from_secs(60));
+
+        assert!(repository.get_answer(question("example.com.")).is_err());
+    }
+
+    #[test]
+    fn serves_stale_records_when_the_fallback_server_fails() {
+        // answers the first query, then fails the next ones
+        let answered = Arc::new(AtomicU8::new(0));
+        let upstream = start_upstream_with(3, move |response| {
+            if answered.fetch_add(1, Ordering::Relaxed) == 0 {
+                response.set_answers(vec![ResourceRecord::new(
+                    DomainName::from("example.com."),
+                    Type::A,
+                    Class::IN,
+                    1,
+                    vec![192, 0, 2, 1],
+                )]);
+            } else {
+                response.header.response_code = ResponseCode::ServerFailure;
+            }
+        });
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        )
+        .with_stale_window(Duration::from_secs(60));
+
+        assert!(
+            !repository
+                .get_answer(question("example.com."))
+                .unwrap()
+                .stale
+        );
+        assert!(
+            repository
+                .get_answer(question("other.example.com."))
+                .is_err()
+        );
+        thread::sleep(Duration::from_millis(1100));
+
+        // the failure is not cached in place of the expired records
+        let answer = repository.get_answer(question("example.com.")).unwrap();
+        assert!(answer.stale);
+        assert_eq!(answer.records[0].resource_data, vec![192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn stops_refreshing_once_dropped() {
+        let unreachable = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(FallbackRepository {
+                timeout: Duration::from_millis(100),
+                ..fallback_repository(vec![unreachable])
+            }),
+        )
+        .with_stale_window(Duration::from_secs(60));
+        repository
+            .cache
+            .write(&DomainName::from("example.com."))
+            .cache(
+                ResourceRecord::new(
+                    DomainName::from("example.com."),
+                    Type::A,
+                    Class::IN,
+                    1,
+                    vec![192, 0, 2, 1],
+                ),
+                Instant::now() - Duration::from_secs(2),
+            );
+
+        assert!(
+            repository
+                .get_answer(question("example.com."))
+                .unwrap()
+                .stale
+        );
+        assert!(!repository.refreshing.lock().unwrap().is_empty());
+
+        // the refresh waits for its next attempt on a pool worker, which is joined
+        let started = Instant::now();
+        drop(repository);
+        assert!(started.elapsed() < STALE_REFRESH_DELAYS[0]);
+    }
+
+    #[test]
+    fn sweeps_expired_records() {
+        let repository = CombinedRepository::<MessageDecoder, MessageEncoder>::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            None,
         );
+        repository
+            .cache
+            .write(&DomainName::from("example.com."))
+            .cache(
+                ResourceRecord::new(
+                    DomainName::from("example.com."),
+                    Type::A,
+                    Class::IN,
+                    0,
+                    vec![192, 0, 2, 1],
+                ),
+                Instant::now(),
+            );
+
+        repository
+            .spawn_cache_sweeper(Duration::from_millis(10))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(repository.cache_metrics().entries(), 0);
+    }
+
+    #[test]
+    fn stops_caching_when_full() {
+        let upstream = start_upstream(2);
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(fallback_repository(vec![upstream])),
+        )
+        .with_max_cached_records(0);
 
-        for record in fallback_repository_records.iter() {
-            self.in_memory_repository.save(record.clone());
+        for _ in 0..2 {
+            let records = repository
+                .get_resource_records(question("example.com."))
+                .unwrap();
+            assert_eq!(records.len(), 1);
         }
+        assert_eq!(repository.cache_metrics().entries(), 0);
+    }
+
+    #[test]
+    fn tries_next_fallback_server_on_failure() {
+        // nothing listens on the first one, sending there fails or times out
+        let unreachable = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = start_upstream(1);
+        let repository = CombinedRepository::new(
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new()),
+            Some(FallbackRepository {
+                timeout: std::time::Duration::from_millis(100),
+                ..fallback_repository(vec![unreachable, upstream])
+            }),
+        );
+
+        let records = repository
+            .get_resource_records(question("example.com."))
+            .unwrap();
+
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn keeps_cache_when_authoritative_records_are_replaced() {
+        let upstream = start_upstream(1);
+        let mut zone = InMemoryResourceRecordRepository::new();
+        zone.save(ResourceRecord::new(
+            DomainName::from("www.example.org."),
+            Type::A,
+            Class::IN,
+            3600,
+            vec![192, 0, 2, 80],
+        ));
+        let authoritative_records = AuthoritativeRecords::new(zone);
+        let repository = CombinedRepository::new(
+            authoritative_records.clone(),
+            Some(fallback_repository(vec![upstream])),
+        );
+
+        // cached, the upstream only answers once
+        repository
+            .get_resource_records(question("example.com."))
+            .unwrap();
+
+        let mut reloaded_zone = InMemoryResourceRecordRepository::new();
+        reloaded_zone.save(ResourceRecord::new(
+            DomainName::from("www.example.org."),
+            Type::A,
+            Class::IN,
+            3600,
+            vec![192, 0, 2, 81],
+        ));
+        authoritative_records.replace(reloaded_zone);
+
+        let records = repository
+            .get_resource_records(question("www.example.org."))
+            .unwrap();
+        assert_eq!(records[0].resource_data, vec![192, 0, 2, 81]);
+
+        let records = repository
+            .get_resource_records(question("example.com."))
+            .unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn serves_authoritative_records_before_the_cache() {
+        let upstream = start_upstream(1);
+        let authoritative_records =
+            AuthoritativeRecords::new(InMemoryResourceRecordRepository::new());
+        let repository = CombinedRepository::new(
+            authoritative_records.clone(),
+            Some(fallback_repository(vec![upstream])),
+        );
+
+        let answer = repository.get_answer(question("example.com.")).unwrap();
+        assert!(!answer.authoritative);
+        assert_eq!(answer.records[0].resource_data, vec![192, 0, 2, 1]);
+
+        // the zone now has the cached name, it wins over the cache
+        let mut zone = InMemoryResourceRecordRepository::new();
+        zone.save(ResourceRecord::new(
+            DomainName::from("example.com."),
+            Type::A,
+            Class::IN,
+            3600,
+            vec![192, 0, 2, 80],
+        ));
+        authoritative_records.replace(zone);
 
-        Ok(fallback_repository_records)
+        let answer = repository.get_answer(question("example.com.")).unwrap();
+        assert!(answer.authoritative);
+        assert_eq!(answer.records.len(), 1);
+        assert_eq!(answer.records[0].resource_data, vec![192, 0, 2, 80]);
+        assert_eq!(answer.records[0].ttl, 3600);
+        assert_eq!(repository.cache_metrics().entries(), 1);
     }
 }
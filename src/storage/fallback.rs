@@ -3,62 +3,131 @@ use crate::{
         Message,
         header::{Header, MessageType, QueryType, ResponseCode},
         question::Question,
-        resource_record::ResourceRecord,
+        resource_record::{ResourceRecord, Type},
     },
     decoder::Decoder,
     encoder::Encoder,
-    storage::{RepositoryError, ResourceRecordRepository},
+    log::debug,
+    storage::{Answer, RepositoryError, ResourceRecordRepository},
     transport::EDNS_STANDARD_UDP_PAYLOAD_SIZE,
 };
 
 use std::{
     io::Error,
-    net::{ToSocketAddrs, UdpSocket},
-    time,
+    net::{SocketAddr, UdpSocket},
+    time::{self, Duration},
 };
 
-pub struct FallbackRepository<T: ToSocketAddrs + Clone, D: Decoder, E: Encoder> {
-    pub fallback_server_address: T,
+pub const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct FallbackRepository<D: Decoder, E: Encoder> {
+    /// Tried in order until one of them answers
+    pub fallback_server_addresses: Vec<SocketAddr>,
+    pub timeout: Duration,
     pub decoder: D,
     pub encoder: E,
 }
 
-impl<T: ToSocketAddrs + Clone, D: Decoder, E: Encoder> ResourceRecordRepository
-    for FallbackRepository<T, D, E>
-{
+impl<D: Decoder, E: Encoder> ResourceRecordRepository for FallbackRepository<D, E> {
     fn get_resource_records(
-        &mut self,
+        &self,
         question: Question,
     ) -> Result<Vec<ResourceRecord>, RepositoryError> {
-        //println!("Question to fallback server: {:?}", question);
+        self.fetch(&question).map(|answer| answer.records)
+    }
+
+    fn get_answer(&self, question: Question) -> Result<Answer, RepositoryError> {
+        self.fetch(&question)
+    }
+}
 
-        let response_from_fallback_server = fetch_from_other_server(
-            &self.encoder,
-            &self.decoder,
-            self.fallback_server_address.clone(),
-            generate_message_with_question(question),
+impl<D: Decoder, E: Encoder> FallbackRepository<D, E> {
+    /// Asks the fallback servers in order, without any state, so that it can be done from any thread
+    /// NXDOMAIN is passed on, along with the SOA records of the authority section which make
+    /// NXDOMAIN and NODATA answers cacheable, any other error or a truncated response fails
+    pub fn fetch(&self, question: &Question) -> Result<Answer, RepositoryError> {
+        let mut last_error = RepositoryError::ContactingFallbackServerError(
+            "no fallback server configured".to_string(),
         );
 
-        //println!(
-        //    "Response from fallback server: {:?}",
-        //    response_from_fallback_server
-        //);
+        for fallback_server_address in self.fallback_server_addresses.iter() {
+            match fetch_from_other_server(
+                &self.encoder,
+                &self.decoder,
+                *fallback_server_address,
+                self.timeout,
+                query_message(question.clone(), time_based_id()),
+            )
+            .and_then(answer)
+            {
+                Ok(answer) => return Ok(answer),
+                Err(e) => {
+                    debug!(
+                        "🔍 Fallback server {} failed: {}",
+                        fallback_server_address, e
+                    );
+                    last_error = e;
+                }
+            }
+        }
 
-        response_from_fallback_server.map(|m| m.answers)
+        Err(last_error)
     }
 }
 
-fn fetch_from_other_server<T: ToSocketAddrs + Clone, D: Decoder, E: Encoder>(
+/// Answer out of a response of a fallback server
+/// Only NOERROR and NXDOMAIN responses carrying all their records answer the question
+pub fn answer(response: Message) -> Result<Answer, RepositoryError> {
+    check_response(&response)?;
+
+    Ok(Answer {
+        response_code: response.header.response_code,
+        records: response.answers,
+        authorities: response
+            .authorities
+            .into_iter()
+            .filter(|record| record.type_ == Type::SOA)
+            .collect(),
+        stale: false,
+        // even when the upstream is authoritative for them, we are not
+        authoritative: false,
+    })
+}
+
+fn check_response(response: &Message) -> Result<(), RepositoryError> {
+    if response.header.truncated {
+        return Err(RepositoryError::UnusableFallbackServerResponse(
+            "truncated".to_string(),
+        ));
+    }
+
+    match response.header.response_code {
+        ResponseCode::NoError | ResponseCode::NameError => Ok(()),
+        ref response_code => Err(RepositoryError::UnusableFallbackServerResponse(format!(
+            "{:?}",
+            response_code
+        ))),
+    }
+}
+
+fn fetch_from_other_server<D: Decoder, E: Encoder>(
     encoder: &E,
     decoder: &D,
-    fallback_server_address: T,
+    fallback_server_address: SocketAddr,
+    timeout: Duration,
     message: Message,
 ) -> Result<Message, RepositoryError> {
     let mut buf = [0; EDNS_STANDARD_UDP_PAYLOAD_SIZE]; // could be improved by only allocating based on if EDNS is enabled
     let encode_message = encoder.encode(message);
+    let local_address = if fallback_server_address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
 
-    UdpSocket::bind("0.0.0.0:0")
+    UdpSocket::bind(local_address)
         .and_then(|socket| {
+            socket.set_read_timeout(Some(timeout))?;
             socket.connect(fallback_server_address)?;
             socket.send(encode_message.as_slice())?;
             socket.recv_from(&mut buf)
@@ -71,14 +140,19 @@ fn fetch_from_other_server<T: ToSocketAddrs + Clone, D: Decoder, E: Encoder>(
         })
 }
 
-fn generate_message_with_question(question: Question) -> Message {
+fn time_based_id() -> u16 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u16
+}
+
+/// Query asking the fallback servers a question
+pub fn query_message(question: Question, id: u16) -> Message {
     // todo IMPROVEMENT: problem with building our own headers (and message) is that we potentially discard some of the original request properties
     Message::new(
         Header {
-            id: time::SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u16,
+            id,
             qr: MessageType::Query,
             opcode: QueryType::Standard,
             authoritative_answer: false,
@@ -0,0 +1,139 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// Size of a single repository, which its limits apply to when its metrics are shared
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct CacheSize {
+    pub(super) entries: usize,
+    pub(super) bytes: usize,
+}
+
+impl CacheSize {
+    pub(super) fn added(&mut self, bytes: usize) {
+        self.entries += 1;
+        self.bytes += bytes;
+    }
+
+    pub(super) fn removed(&mut self, bytes: usize) {
+        self.entries -= 1;
+        self.bytes -= bytes;
+    }
+
+    pub(super) fn resized(&mut self, from: usize, to: usize) {
+        self.bytes = self.bytes + to - from;
+    }
+}
+
+/// Counters of a cache, readable without locking it
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    prefetches: AtomicUsize,
+}
+
+impl CacheMetrics {
+    /// Cached RRsets and negative answers, stale or not
+    pub fn entries(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    /// Approximate memory used by the entries
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Entries dropped to make room, expired ones are not counted
+    pub fn evictions(&self) -> usize {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Popular entries refreshed before they expire
+    pub fn prefetches(&self) -> usize {
+        self.prefetches.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn added(&self, bytes: usize) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(super) fn removed(&self, bytes: usize) {
+        self.entries.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub(super) fn resized(&self, from: usize, to: usize) {
+        self.bytes.fetch_add(to, Ordering::Relaxed);
+        self.bytes.fetch_sub(from, Ordering::Relaxed);
+    }
+
+    pub(super) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn missed(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn evicted(&self, entries: usize) {
+        self.evictions.fetch_add(entries, Ordering::Relaxed);
+    }
+
+    pub(super) fn prefetched(&self) {
+        self.prefetches.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Display for CacheMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "entries {}, bytes {}, hits {}, misses {}, evictions {}, prefetches {}",
+            self.entries(),
+            self.bytes(),
+            self.hits(),
+            self.misses(),
+            self.evictions(),
+            self.prefetches()
+        )
+    }
+}
+
+/// A number updated through shared references, by the lookups made under a read lock
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Returns the incremented value
+    pub fn increment(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl Clone for Counter {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
@@ -1,81 +1,1025 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 
 use crate::{
     common::{
         domain_name::DomainName,
+        header::ResponseCode,
         question::{Class as QuestionClass, Question, Type as QuestionType},
         resource_record::{ResourceRecord, Type},
     },
     decoder::DecodingError,
+    storage::{
+        metrics::{CacheMetrics, CacheSize, Counter},
+        rrset::RRset,
+    },
 };
 
 pub mod combined;
 pub mod fallback;
+pub mod metrics;
+pub mod persistence;
+mod rrset;
+pub mod sharded;
+pub mod zone;
+
+/// TTL of the stale records served while the upstreams can not be reached, see RFC 8767 section 4
+pub const STALE_TTL: u32 = 30;
 
 #[derive(Debug)]
 pub enum RepositoryError {
     ContactingFallbackServerError(String),
     DecodingFallbackServerResponseError(DecodingError),
+    /// Answered with an error of its own or truncated, which is not an answer to pass on or cache
+    UnusableFallbackServerResponse(String),
+}
+
+impl Display for RepositoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ContactingFallbackServerError(e) => {
+                write!(f, "could not contact fallback server: {}", e)
+            }
+            Self::DecodingFallbackServerResponseError(e) => {
+                write!(f, "could not decode fallback server response: {:?}", e)
+            }
+            Self::UnusableFallbackServerResponse(e) => {
+                write!(f, "unusable fallback server response: {}", e)
+            }
+        }
+    }
 }
 
 pub trait ResourceRecordRepository {
     fn get_resource_records(
-        &mut self,
+        &self,
         question: Question,
     ) -> Result<Vec<ResourceRecord>, RepositoryError>;
+
+    /// The records along with the negative answers of the repositories which know about them
+    fn get_answer(&self, question: Question) -> Result<Answer, RepositoryError> {
+        self.get_resource_records(question).map(Answer::records)
+    }
+
+    /// Same as get_answer, without asking the fallback servers: the question to ask them is
+    /// handed over instead, for the caller to wait for their response without blocking, then
+    /// pass it to complete_answer
+    fn start_answer(&self, question: Question) -> Lookup {
+        Lookup::Answered(self.get_answer(question))
+    }
+
+    /// Answer to the question start_answer handed over, out of the fallback servers response
+    fn complete_answer(
+        &self,
+        _query: UpstreamQuery,
+        response: Result<Answer, RepositoryError>,
+    ) -> Result<Answer, RepositoryError> {
+        response
+    }
+}
+
+/// What start_answer found out
+pub enum Lookup {
+    Answered(Result<Answer, RepositoryError>),
+    Upstream(UpstreamQuery),
+}
+
+/// A question for the fallback servers, asked in order until one of them answers
+#[derive(Debug)]
+pub struct UpstreamQuery {
+    pub question: Question,
+    pub servers: Vec<SocketAddr>,
+    /// How long each server is waited for
+    pub timeout: Duration,
+    /// Served if none of the servers answers
+    stale_records: Vec<ResourceRecord>,
+}
+
+/// Records answering a question, or a negative answer (RFC 2308): the name does not exist
+/// (NXDOMAIN) or has no record of this type (NODATA), with the SOA of its zone as authority
+#[derive(Debug, PartialEq, Clone)]
+pub struct Answer {
+    pub response_code: ResponseCode,
+    pub records: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    /// Expired records served because they could not be refreshed (RFC 8767)
+    pub stale: bool,
+    /// Records of our zones rather than learnt from the upstreams, answered with AA set
+    pub authoritative: bool,
+}
+
+impl Answer {
+    pub fn records(records: Vec<ResourceRecord>) -> Self {
+        Self {
+            response_code: ResponseCode::NoError,
+            records,
+            authorities: vec![],
+            stale: false,
+            authoritative: false,
+        }
+    }
+
+    pub fn authoritative(records: Vec<ResourceRecord>) -> Self {
+        Self {
+            authoritative: true,
+            ..Self::records(records)
+        }
+    }
+
+    pub fn negative(response_code: ResponseCode, soa: ResourceRecord) -> Self {
+        Self {
+            response_code,
+            records: vec![],
+            authorities: vec![soa],
+            stale: false,
+            authoritative: false,
+        }
+    }
+
+    pub fn stale(records: Vec<ResourceRecord>) -> Self {
+        Self {
+            stale: true,
+            ..Self::records(records)
+        }
+    }
+
+    /// SOA of a negative answer, which can be cached for the smallest of its TTL and its MINIMUM
+    /// field (RFC 2308 section 5)
+    pub fn negative_soa(&self) -> Option<(&ResourceRecord, u32)> {
+        if !self.records.is_empty()
+            || !matches!(
+                self.response_code,
+                ResponseCode::NoError | ResponseCode::NameError
+            )
+        {
+            return None;
+        }
+
+        self.authorities.iter().find_map(|record| {
+            let minimum = record
+                .resource_data
+                .last_chunk::<4>()
+                .filter(|_| record.type_ == Type::SOA)?;
+            Some((record, record.ttl.min(u32::from_be_bytes(*minimum))))
+        })
+    }
+}
+
+/// TTL left at `now` of something cached at `cached_at`, None once expired
+fn remaining_ttl(ttl: u32, cached_at: Instant, now: Instant) -> Option<u32> {
+    let elapsed = now.saturating_duration_since(cached_at).as_secs();
+    (elapsed < ttl as u64).then(|| ttl - elapsed as u32)
+}
+
+/// A negative answer, kept for its negative TTL
+#[derive(Clone)]
+struct NegativeAnswer {
+    response_code: ResponseCode,
+    soa: ResourceRecord,
+    ttl: u32,
+    cached_at: Instant,
+    /// Tick of the cache clock it was last served at
+    last_used: Counter,
+}
+
+impl NegativeAnswer {
+    fn remaining_ttl(&self, now: Instant) -> Option<u32> {
+        remaining_ttl(self.ttl, self.cached_at, now)
+    }
+
+    /// The answer it stands for, its SOA with the given TTL
+    fn answer(&self, ttl: u32) -> Answer {
+        Answer::negative(
+            self.response_code.clone(),
+            ResourceRecord {
+                ttl,
+                ..self.soa.clone()
+            },
+        )
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>() + record_size(&self.soa)
+    }
+}
+
+/// Popular cached records are refreshed before they expire, so that their next query does not wait
+/// for the fallback servers: once served `min_hits` times, when queried in the last `percent` of
+/// their TTL
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Prefetch {
+    pub min_hits: u32,
+    pub percent: u8,
+}
+
+/// Heap memory of a record: its name labels and its data
+fn record_size(record: &ResourceRecord) -> usize {
+    name_size(&record.name) + record.resource_data.len()
+}
+
+fn name_size(name: &DomainName) -> usize {
+    name.labels
+        .iter()
+        .map(|label| size_of::<String>() + label.len())
+        .sum()
 }
 
 #[derive(Clone)]
 pub struct InMemoryResourceRecordRepository {
-    inner: HashMap<DomainName, Vec<ResourceRecord>>,
+    inner: HashMap<DomainName, Vec<RRset>>,
+    /// NXDOMAIN answers are kept for every type of a name (None), NODATA ones for a single type
+    negative_answers: HashMap<DomainName, HashMap<Option<u16>, NegativeAnswer>>,
+    /// How long records are kept once expired, in case they can not be refreshed
+    stale_window: Duration,
+    prefetch: Option<Prefetch>,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    /// Names with cached entries by last change, the least recently changed first, static RRsets
+    /// are never evicted
+    /// Lookups only stamp the entries they serve, so that they can share the repository: a name
+    /// served since it was put there is moved to the back of the queue instead of being evicted
+    lru: BTreeMap<u64, DomainName>,
+    queued_at: HashMap<DomainName, u64>,
+    clock: Counter,
+    size: CacheSize,
+    metrics: Arc<CacheMetrics>,
 }
 
 impl InMemoryResourceRecordRepository {
     pub fn new() -> Self {
         Self {
             inner: HashMap::new(),
+            negative_answers: HashMap::new(),
+            stale_window: Duration::ZERO,
+            prefetch: None,
+            max_entries: None,
+            max_bytes: None,
+            lru: BTreeMap::new(),
+            queued_at: HashMap::new(),
+            clock: Counter::default(),
+            size: CacheSize::default(),
+            metrics: Arc::new(CacheMetrics::default()),
         }
     }
+
+    pub fn with_stale_window(mut self, stale_window: Duration) -> Self {
+        self.stale_window = stale_window;
+        self
+    }
+
+    pub fn with_prefetch(mut self, prefetch: Prefetch) -> Self {
+        self.prefetch = Some(prefetch);
+        self
+    }
+
+    /// Cached RRsets and negative answers beyond this many evict the least recently used names
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Same as with_max_entries, for the approximate memory used by the entries
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Counts in shared metrics, like the ones of the other shards of a cache
+    /// The limits still apply to the entries of this repository only
+    pub fn with_metrics(mut self, metrics: Arc<CacheMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
 }
 
 impl InMemoryResourceRecordRepository {
-    // todo: what about authoritative answers and additional answers?
+    // todo: what about additional answers?
+    /// Saves a record which never expires, like the ones of the zones, in its RRset
+    /// A cached RRset it belongs to is replaced
     pub fn save(&mut self, resource_record: ResourceRecord) {
-        let entry = self.inner.entry(resource_record.name.clone()).or_default();
-        entry.push(resource_record);
+        let name = resource_record.name.clone();
+        self.retain_rrsets_of(&name, |rrset| {
+            rrset.cached_at.is_none() || !rrset.includes(&resource_record)
+        });
+
+        let rrsets = self.inner.entry(name).or_default();
+        match rrsets
+            .iter_mut()
+            .find(|rrset| rrset.includes(&resource_record))
+        {
+            Some(rrset) => rrset.add(resource_record),
+            None => rrsets.push(RRset::new(resource_record, None)),
+        }
+    }
+
+    /// Caches a record in its RRset, which expires once its TTL has elapsed since `cached_at`
+    /// Records only join the RRset they were cached along with, an RRset cached at another time
+    /// is replaced, a static one is kept as it is
+    pub fn cache(&mut self, resource_record: ResourceRecord, cached_at: Instant) {
+        let name = resource_record.name.clone();
+        let rrsets = self.inner.entry(name.clone()).or_default();
+
+        match rrsets
+            .iter_mut()
+            .find(|rrset| rrset.includes(&resource_record))
+        {
+            Some(rrset) if rrset.cached_at.is_none() => return,
+            Some(rrset) if rrset.cached_at == Some(cached_at) => {
+                let size = rrset.size();
+                rrset.add(resource_record);
+                self.size.resized(size, rrset.size());
+                self.metrics.resized(size, rrset.size());
+            }
+            Some(rrset) => {
+                let (size, hits) = (rrset.size(), rrset.hits.get());
+                *rrset = RRset::new(resource_record, Some(cached_at));
+                rrset.hits.set(hits);
+                self.size.resized(size, rrset.size());
+                self.metrics.resized(size, rrset.size());
+            }
+            None => {
+                let rrset = RRset::new(resource_record, Some(cached_at));
+                self.size.added(rrset.size());
+                self.metrics.added(rrset.size());
+                rrsets.push(rrset);
+            }
+        }
+
+        self.touch(&name);
+        self.evict_over_limits();
+    }
+
+    /// Caches the RRsets answering a question in place of the ones it had, expired or not
+    /// Their hits carry over, a popular name stays popular once refreshed
+    pub fn replace_rrsets(
+        &mut self,
+        question: &Question,
+        records: &[ResourceRecord],
+        cached_at: Instant,
+    ) {
+        let hits = self
+            .matching(question)
+            .filter(|rrset| rrset.cached_at.is_some())
+            .map(|rrset| rrset.hits.get())
+            .max()
+            .unwrap_or_default();
+        if let QuestionType::RRType(type_) = question.type_ {
+            self.delete_rrset(&question.name, &question.class, type_);
+        }
+
+        for record in records {
+            self.cache(record.clone(), cached_at);
+        }
+        if let Some(rrsets) = self.inner.get_mut(&question.name) {
+            rrsets
+                .iter_mut()
+                .filter(|rrset| rrset.cached_at == Some(cached_at) && rrset.answers(question))
+                .for_each(|rrset| rrset.hits.set(hits));
+        }
+    }
+
+    /// Drops a cached RRset, returns whether there was one
+    pub fn delete_rrset(&mut self, name: &DomainName, class: &QuestionClass, type_: Type) -> bool {
+        self.retain_rrsets_of(name, |rrset| {
+            rrset.cached_at.is_none() || !rrset.is(class, type_)
+        }) > 0
+    }
+
+    /// Caches a negative answer to the question for its negative TTL, ignores the other answers
+    pub fn cache_negative_answer(
+        &mut self,
+        question: &Question,
+        answer: &Answer,
+        cached_at: Instant,
+    ) {
+        let Some((soa, ttl)) = answer.negative_soa() else {
+            return;
+        };
+
+        let type_ = (answer.response_code != ResponseCode::NameError)
+            .then(|| u16::from(question.type_.clone()));
+        let negative_answer = NegativeAnswer {
+            response_code: answer.response_code.clone(),
+            soa: soa.clone(),
+            ttl,
+            cached_at,
+            last_used: Counter::default(),
+        };
+        self.insert_negative_answer(&question.name, type_, negative_answer);
+    }
+
+    /// Caches the NXDOMAIN answer of a name (no type) or the NODATA one of a type
+    fn insert_negative_answer(
+        &mut self,
+        name: &DomainName,
+        type_: Option<u16>,
+        negative_answer: NegativeAnswer,
+    ) {
+        self.size.added(negative_answer.size());
+        self.metrics.added(negative_answer.size());
+        if let Some(replaced) = self
+            .negative_answers
+            .entry(name.clone())
+            .or_default()
+            .insert(type_, negative_answer)
+        {
+            self.size.removed(replaced.size());
+            self.metrics.removed(replaced.size());
+        }
+        self.touch(name);
+        self.evict_over_limits();
+    }
+
+    /// Fresh records or negative answer to the question, counted as a hit or a miss
+    /// The entries served are stamped as used, which only takes a shared reference
+    pub fn lookup_cached(&self, question: &Question, now: Instant) -> Option<Answer> {
+        let tick = self.clock.increment();
+        let fresh_rrsets = self
+            .matching(question)
+            .filter_map(|rrset| Some((rrset, rrset.remaining_ttl(now)?)))
+            .collect::<Vec<_>>();
+
+        let answer = if !fresh_rrsets.is_empty() {
+            let mut records = vec![];
+            for (rrset, ttl) in fresh_rrsets {
+                rrset.hits.increment();
+                rrset.last_used.set(tick);
+                records.extend(rrset.records(ttl));
+            }
+            Some(Answer::records(records))
+        } else if let Some((negative_answer, ttl)) = self.negative_answer_to(question, now) {
+            negative_answer.last_used.set(tick);
+            Some(negative_answer.answer(ttl))
+        } else {
+            None
+        };
+
+        match answer {
+            Some(_) => self.metrics.hit(),
+            None => self.metrics.missed(),
+        }
+        answer
+    }
+
+    /// Cached negative answer to the question, with the TTL left on its SOA
+    pub fn negative_answer(&self, question: &Question, now: Instant) -> Option<Answer> {
+        let (negative_answer, ttl) = self.negative_answer_to(question, now)?;
+        Some(negative_answer.answer(ttl))
+    }
+
+    /// The NXDOMAIN answer of the name or the NODATA one of the type, if still alive at `now`,
+    /// with the TTL it has left
+    fn negative_answer_to(
+        &self,
+        question: &Question,
+        now: Instant,
+    ) -> Option<(&NegativeAnswer, u32)> {
+        let negative_answers = self.negative_answers.get(&question.name)?;
+        [None, Some(u16::from(question.type_.clone()))]
+            .iter()
+            .filter_map(|type_| negative_answers.get(type_))
+            .find_map(|negative_answer| {
+                Some((negative_answer, negative_answer.remaining_ttl(now)?))
+            })
+    }
+
+    /// Drops the RRsets and negative answers of a name which expired at `now`, past the stale
+    /// window for the RRsets, returns how many were
+    pub fn remove_expired_records_of(&mut self, name: &DomainName, now: Instant) -> usize {
+        let stale_window = self.stale_window;
+        self.retain_rrsets_of(name, |rrset| rrset.is_kept(now, stale_window))
+            + self.retain_negative_answers_of(name, |_, negative_answer| {
+                negative_answer.remaining_ttl(now).is_some()
+            })
+    }
+
+    /// Drops every RRset and negative answer which expired at `now`, past the stale window,
+    /// returns how many were
+    pub fn remove_expired(&mut self, now: Instant) -> usize {
+        let stale_window = self.stale_window;
+        let names = self.queued_at.keys().cloned().collect::<Vec<DomainName>>();
+
+        names
+            .iter()
+            .map(|name| {
+                self.retain_rrsets_of(name, |rrset| rrset.is_kept(now, stale_window))
+                    + self.retain_negative_answers_of(name, |_, negative_answer| {
+                        negative_answer.remaining_ttl(now).is_some()
+                    })
+            })
+            .sum()
+    }
+
+    /// Keeps the records of a name for which `keep` is true, returns how many cached ones were not
+    fn retain_rrsets_of(&mut self, name: &DomainName, keep: impl Fn(&RRset) -> bool) -> usize {
+        let Some(rrsets) = self.inner.get_mut(name) else {
+            return 0;
+        };
+
+        let mut removed = 0;
+        rrsets.retain(|rrset| {
+            let kept = keep(rrset);
+            if !kept {
+                self.size.removed(rrset.size());
+                self.metrics.removed(rrset.size());
+                removed += 1;
+            }
+            kept
+        });
+        if rrsets.is_empty() {
+            self.inner.remove(name);
+        }
+
+        self.forget_if_unused(name);
+        removed
+    }
+
+    /// Same as retain_rrsets_of, for the negative answers of a name
+    fn retain_negative_answers_of(
+        &mut self,
+        name: &DomainName,
+        keep: impl Fn(&Option<u16>, &NegativeAnswer) -> bool,
+    ) -> usize {
+        let Some(negative_answers) = self.negative_answers.get_mut(name) else {
+            return 0;
+        };
+
+        let mut removed = 0;
+        negative_answers.retain(|type_, negative_answer| {
+            let kept = keep(type_, negative_answer);
+            if !kept {
+                self.size.removed(negative_answer.size());
+                self.metrics.removed(negative_answer.size());
+                removed += 1;
+            }
+            kept
+        });
+        if negative_answers.is_empty() {
+            self.negative_answers.remove(name);
+        }
+
+        self.forget_if_unused(name);
+        removed
+    }
+
+    /// Moves a name to the back of the eviction queue
+    fn touch(&mut self, name: &DomainName) {
+        self.enqueue(name.clone(), self.clock.increment());
+    }
+
+    fn enqueue(&mut self, name: DomainName, tick: u64) {
+        if let Some(queued_at) = self.queued_at.get(&name) {
+            self.lru.remove(queued_at);
+        }
+
+        self.lru.insert(tick, name.clone());
+        self.queued_at.insert(name, tick);
+    }
+
+    /// Tick the entries of a name were last served at
+    fn last_served(&self, name: &DomainName) -> u64 {
+        let rrsets = self.inner.get(name).into_iter().flatten();
+        let negative_answers = self.negative_answers.get(name).into_iter().flatten();
+
+        rrsets
+            .map(|rrset| rrset.last_used.get())
+            .chain(negative_answers.map(|(_, negative_answer)| negative_answer.last_used.get()))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Stops tracking the use of a name once it has no cached entries left
+    fn forget_if_unused(&mut self, name: &DomainName) {
+        let cached = self.negative_answers.contains_key(name)
+            || self
+                .inner
+                .get(name)
+                .is_some_and(|rrsets| rrsets.iter().any(|rrset| rrset.cached_at.is_some()));
+
+        if !cached && let Some(queued_at) = self.queued_at.remove(name) {
+            self.lru.remove(&queued_at);
+        }
+    }
+
+    fn is_over_limits(&self) -> bool {
+        self.max_entries
+            .is_some_and(|max_entries| self.size.entries > max_entries)
+            || self
+                .max_bytes
+                .is_some_and(|max_bytes| self.size.bytes > max_bytes)
+    }
+
+    /// Evicts the least recently used names until the cache fits its limits again
+    fn evict_over_limits(&mut self) {
+        while self.is_over_limits() {
+            let Some((queued_at, name)) = self.lru.pop_first() else {
+                break;
+            };
+            let last_served = self.last_served(&name);
+            if last_served > queued_at {
+                self.enqueue(name, last_served);
+                continue;
+            }
+            self.queued_at.remove(&name);
+
+            let evicted = self.retain_rrsets_of(&name, |rrset| rrset.cached_at.is_none())
+                + self.retain_negative_answers_of(&name, |_, _| false);
+            self.metrics.evicted(evicted);
+        }
+    }
+}
+
+impl InMemoryResourceRecordRepository {
+    fn lookup(&self, question: &Question) -> Vec<ResourceRecord> {
+        self.lookup_at(question, Instant::now())
+    }
+
+    /// Records matching the question which are still alive at `now`, with the TTL they have left
+    fn lookup_at(&self, question: &Question, now: Instant) -> Vec<ResourceRecord> {
+        self.matching(question)
+            .filter_map(|rrset| Some(rrset.records(rrset.remaining_ttl(now)?)))
+            .flatten()
+            .collect()
+    }
+
+    /// Records matching the question which expired at `now` but are within the stale window,
+    /// with the stale TTL
+    pub fn stale_lookup_at(&self, question: &Question, now: Instant) -> Vec<ResourceRecord> {
+        self.matching(question)
+            .filter(|rrset| {
+                rrset.remaining_ttl(now).is_none() && rrset.is_kept(now, self.stale_window)
+            })
+            .flat_map(|rrset| rrset.records(STALE_TTL))
+            .collect()
+    }
+
+    /// Whether the popular records answering the question should be refreshed at `now`
+    pub fn should_prefetch(&self, question: &Question, now: Instant) -> bool {
+        self.prefetch.is_some_and(|prefetch| {
+            self.matching(question)
+                .any(|rrset| rrset.is_prefetched(now, prefetch))
+        })
+    }
+
+    fn matching(&self, question: &Question) -> impl Iterator<Item = &RRset> {
+        self.inner
+            .get(&question.name)
+            .map(|rrsets| rrsets.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(|rrset| rrset.answers(question))
     }
 }
 
 impl ResourceRecordRepository for InMemoryResourceRecordRepository {
-    // todo: deal with TTLs
     fn get_resource_records(
-        &mut self,
+        &self,
         question: Question,
     ) -> Result<Vec<ResourceRecord>, RepositoryError> {
-        let entries_for_domain_name = self.inner.get(&question.name);
+        Ok(self.lookup(&question))
+    }
 
-        Ok(entries_for_domain_name
-            .unwrap_or(&vec![])
-            .iter()
-            .filter(|record| {
-                question.name == record.name
-                    && match question.class {
-                        QuestionClass::ALL => true,
-                        _ => question.class == record.class,
-                    }
-                    && match question.type_ {
-                        QuestionType::ALL => true,
-                        QuestionType::MAILA => record.type_ == Type::MX,
-                        QuestionType::MAILB => {
-                            record.type_ == Type::MB_EXP
-                                || record.type_ == Type::MG_EXP
-                                || record.type_ == Type::MR_EXP
-                        }
-                        QuestionType::AXFR => unimplemented!("AXFR not implemented"), // no idea how to handle that
-                        QuestionType::RRType(t) => record.type_ == t,
-                    }
-            })
-            .cloned()
-            .collect())
+    fn get_answer(&self, question: Question) -> Result<Answer, RepositoryError> {
+        let now = Instant::now();
+        let records = self.lookup_at(&question, now);
+
+        if records.is_empty()
+            && let Some(answer) = self.negative_answer(&question, now)
+        {
+            return Ok(answer);
+        }
+        Ok(Answer::records(records))
+    }
+}
+
+/// Records loaded from the configuration, shared so that a reload can replace all of them at once
+/// while the server is running
+#[derive(Clone)]
+pub struct AuthoritativeRecords {
+    inner: Arc<RwLock<InMemoryResourceRecordRepository>>,
+}
+
+impl AuthoritativeRecords {
+    pub fn new(records: InMemoryResourceRecordRepository) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(records)),
+        }
+    }
+
+    /// Lookups see either all the previous records or all the new ones, never a mix of both
+    pub fn replace(&self, records: InMemoryResourceRecordRepository) {
+        *self.inner.write().unwrap_or_else(|p| p.into_inner()) = records;
+    }
+
+    fn lookup(&self, question: &Question) -> Vec<ResourceRecord> {
+        self.inner
+            .read()
+            .unwrap_or_else(|p| p.into_inner())
+            .lookup(question)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        zone::{DEFAULT_TTL, parse_record},
+        *,
+    };
+
+    fn record(address: u8, ttl: u32) -> ResourceRecord {
+        ResourceRecord::new(
+            DomainName::from("example.com."),
+            Type::A,
+            QuestionClass::IN,
+            ttl,
+            vec![192, 0, 2, address],
+        )
+    }
+
+    fn question() -> Question {
+        Question {
+            name: DomainName::from("example.com."),
+            type_: QuestionType::RRType(Type::A),
+            class: QuestionClass::IN,
+        }
+    }
+
+    #[test]
+    fn serves_remaining_ttl_until_expiry() {
+        let now = Instant::now();
+        let mut repository = InMemoryResourceRecordRepository::new();
+        repository.cache(record(1, 300), now);
+        repository.cache(record(2, 60), now);
+
+        let ttls = |at: Duration| {
+            repository
+                .lookup_at(&question(), now + at)
+                .iter()
+                .map(|record| record.ttl)
+                .collect::<Vec<_>>()
+        };
+        // a single TTL for the RRset, the lowest one
+        assert_eq!(ttls(Duration::ZERO), vec![60, 60]);
+        assert_eq!(ttls(Duration::from_millis(59_500)), vec![1, 1]);
+        assert!(ttls(Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn caches_negative_answers_for_their_negative_ttl() {
+        let now = Instant::now();
+        let soa = parse_record(
+            "example.com. 3600 IN SOA ns1 hostmaster 1 7200 3600 1209600 300",
+            "example.com",
+            DEFAULT_TTL,
+        )
+        .unwrap();
+        let question_for = |name: &str, type_: Type| Question {
+            name: DomainName::from(name),
+            type_: QuestionType::RRType(type_),
+            class: QuestionClass::IN,
+        };
+        let mut repository = InMemoryResourceRecordRepository::new();
+
+        let nxdomain = Answer::negative(ResponseCode::NameError, soa.clone());
+        assert_eq!(nxdomain.negative_soa(), Some((&soa, 300)));
+        repository.cache_negative_answer(&question_for("a.example.com.", Type::A), &nxdomain, now);
+        let nodata = Answer::negative(ResponseCode::NoError, soa.clone());
+        repository.cache_negative_answer(&question_for("b.example.com.", Type::A), &nodata, now);
+        // not negative answers
+        repository.cache_negative_answer(
+            &question_for("c.example.com.", Type::A),
+            &Answer::negative(ResponseCode::ServerFailure, soa.clone()),
+            now,
+        );
+        repository.cache_negative_answer(
+            &question_for("c.example.com.", Type::A),
+            &Answer::records(vec![]),
+            now,
+        );
+        assert_eq!(repository.metrics.entries(), 2);
+
+        let later = now + Duration::from_secs(100);
+        let answer = repository
+            .negative_answer(&question_for("a.example.com.", Type::MX), later)
+            .unwrap();
+        assert_eq!(answer.response_code, ResponseCode::NameError);
+        assert_eq!(answer.authorities[0].ttl, 200);
+        assert_eq!(
+            repository
+                .negative_answer(&question_for("b.example.com.", Type::A), later)
+                .unwrap()
+                .response_code,
+            ResponseCode::NoError
+        );
+        assert!(
+            repository
+                .negative_answer(&question_for("b.example.com.", Type::AAAA), later)
+                .is_none()
+        );
+
+        let expired = now + Duration::from_secs(300);
+        assert!(
+            repository
+                .negative_answer(&question_for("a.example.com.", Type::A), expired)
+                .is_none()
+        );
+        // looking them up leaves them in place
+        assert_eq!(repository.metrics.entries(), 2);
+        assert_eq!(
+            repository.remove_expired_records_of(&DomainName::from("a.example.com."), expired),
+            1
+        );
+        assert_eq!(repository.remove_expired(expired), 1);
+    }
+
+    #[test]
+    fn never_expires_static_records() {
+        let now = Instant::now();
+        let mut repository = InMemoryResourceRecordRepository::new();
+        repository.save(record(1, 60));
+
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(repository.lookup_at(&question(), later)[0].ttl, 60);
+        assert_eq!(repository.remove_expired(later), 0);
+        assert_eq!(repository.metrics.entries(), 0);
+    }
+
+    #[test]
+    fn removes_expired_records() {
+        let now = Instant::now();
+        let mut repository = InMemoryResourceRecordRepository::new();
+        repository.cache(record(1, 300), now);
+        repository.cache(
+            ResourceRecord {
+                type_: Type::AAAA,
+                ..record(2, 60)
+            },
+            now,
+        );
+        repository.cache(
+            ResourceRecord {
+                name: DomainName::from("example.org."),
+                ..record(3, 60)
+            },
+            now,
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            repository.remove_expired_records_of(&DomainName::from("example.com."), later),
+            1
+        );
+        assert_eq!(repository.metrics.entries(), 2);
+        assert_eq!(repository.remove_expired(later), 1);
+        assert_eq!(repository.metrics.entries(), 1);
+        assert_eq!(repository.remove_expired(now + Duration::from_secs(300)), 1);
+        assert_eq!(repository.metrics.entries(), 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_names() {
+        let now = Instant::now();
+        let named = |name: &str, address: u8| ResourceRecord {
+            name: DomainName::from(name),
+            ..record(address, 300)
+        };
+        let question_for = |name: &str| Question {
+            name: DomainName::from(name),
+            ..question()
+        };
+        let mut repository = InMemoryResourceRecordRepository::new().with_max_entries(2);
+        repository.save(named("static.example.com.", 1));
+        repository.cache(named("a.example.com.", 2), now);
+        repository.cache(named("a.example.com.", 3), now);
+        repository.cache(named("b.example.com.", 4), now);
+        assert!(
+            repository
+                .lookup_cached(&question_for("a.example.com."), now)
+                .is_some()
+        );
+
+        // b is the least recently used name now
+        repository.cache(named("c.example.com.", 5), now);
+        assert!(
+            repository
+                .lookup_cached(&question_for("b.example.com."), now)
+                .is_none()
+        );
+        assert!(
+            repository
+                .lookup_cached(&question_for("a.example.com."), now)
+                .is_some()
+        );
+        assert!(
+            repository
+                .lookup_cached(&question_for("c.example.com."), now)
+                .is_some()
+        );
+        assert_eq!(
+            repository
+                .lookup_at(&question_for("static.example.com."), now)
+                .len(),
+            1
+        );
+
+        let metrics = repository.metrics.clone();
+        assert_eq!(metrics.entries(), 2);
+        assert_eq!(metrics.evictions(), 1);
+        assert_eq!(metrics.hits(), 3);
+        assert_eq!(metrics.misses(), 1);
+        let mut rrset = RRset::new(named("a.example.com.", 2), Some(now));
+        rrset.add(named("a.example.com.", 3));
+        assert_eq!(
+            metrics.bytes(),
+            rrset.size() + RRset::new(named("c.example.com.", 5), Some(now)).size()
+        );
+
+        // evicting a name drops all of its entries at once
+        let size = metrics.bytes();
+        let mut repository = repository.with_max_bytes(size - 1);
+        repository.cache(named("c.example.com.", 6), now);
+        assert!(
+            repository
+                .lookup_cached(&question_for("a.example.com."), now)
+                .is_none()
+        );
+        assert_eq!(metrics.entries(), 1);
+        assert_eq!(metrics.evictions(), 2);
+    }
+
+    #[test]
+    fn prefetches_popular_records_at_the_end_of_their_ttl() {
+        let now = Instant::now();
+        let mut repository = InMemoryResourceRecordRepository::new().with_prefetch(Prefetch {
+            min_hits: 2,
+            percent: 10,
+        });
+        repository.cache(record(1, 100), now);
+
+        let late = now + Duration::from_secs(95);
+        assert!(repository.lookup_cached(&question(), now).is_some());
+        assert!(!repository.should_prefetch(&question(), late));
+        assert!(repository.lookup_cached(&question(), now).is_some());
+        assert!(!repository.should_prefetch(&question(), now));
+        assert!(repository.should_prefetch(&question(), late));
+
+        // refreshed records stay popular
+        repository.replace_rrsets(&question(), &[record(2, 100)], late);
+        assert_eq!(
+            repository.lookup_at(&question(), late)[0].resource_data[3],
+            2
+        );
+        assert!(repository.should_prefetch(&question(), late + Duration::from_secs(95)));
+        assert!(!repository.should_prefetch(&question(), late + Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn stores_records_by_rrset() {
+        let now = Instant::now();
+        let cached = |address: u8| ResourceRecord {
+            name: DomainName::from("example.org."),
+            ..record(address, 300)
+        };
+        let cached_question = Question {
+            name: DomainName::from("example.org."),
+            ..question()
+        };
+        let data = |repository: &InMemoryResourceRecordRepository, question: &Question| {
+            repository
+                .lookup_at(question, now)
+                .into_iter()
+                .map(|record| record.resource_data[3])
+                .collect::<Vec<_>>()
+        };
+        let mut repository = InMemoryResourceRecordRepository::new();
+
+        repository.save(record(1, 300));
+        repository.save(record(1, 300));
+        repository.save(record(2, 300));
+        repository.cache(record(3, 300), now);
+        assert_eq!(data(&repository, &question()), vec![1, 2]);
+
+        repository.cache(cached(1), now);
+        repository.cache(cached(1), now);
+        assert_eq!(data(&repository, &cached_question), vec![1]);
+        // cached later, a new answer
+        let later = now + Duration::from_secs(1);
+        repository.cache(cached(2), later);
+        assert_eq!(data(&repository, &cached_question), vec![2]);
+        assert_eq!(repository.metrics.entries(), 1);
+
+        assert!(repository.delete_rrset(&cached_question.name, &QuestionClass::IN, Type::A));
+        assert!(!repository.delete_rrset(&cached_question.name, &QuestionClass::IN, Type::A));
+        assert!(data(&repository, &cached_question).is_empty());
+        assert_eq!(repository.metrics.entries(), 0);
+        // static ones are not the cache's to delete
+        assert!(!repository.delete_rrset(&question().name, &QuestionClass::IN, Type::A));
     }
 }
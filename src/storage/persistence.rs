@@ -0,0 +1,576 @@
+// Cache file, written on shutdown and periodically so that a restarted server does not send every
+// query upstream: the cached RRsets and negative answers with their absolute expiry time
+// Layout, big endian: magic, version (u16), FNV-1a checksum of the entries (u64), then entries
+// made of a kind (u8), an expiry in seconds since the UNIX epoch (u64) and a name, followed by
+// - RRsets: type (u16), class (u16), count (u16) and data of their records
+// - negative answers: NODATA type if any (u8 flag, u16), response code (u16), SOA name, class and
+//   data
+// Names are written as their labels and data as stored, each preceded by its length
+
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    common::{
+        domain_name::DomainName,
+        header::ResponseCode,
+        question::Class,
+        resource_record::{ResourceRecord, Type},
+    },
+    storage::{InMemoryResourceRecordRepository, NegativeAnswer, sharded::ShardedCache},
+    utils::push_u16_to_u8_vec,
+};
+
+const MAGIC: &[u8; 4] = b"DNSC";
+/// Bumped whenever the layout changes, files of other versions are ignored
+const VERSION: u16 = 1;
+const HEADER_LENGTH: usize = MAGIC.len() + 2 + 8;
+
+const RRSET: u8 = 0;
+const NEGATIVE_ANSWER: u8 = 1;
+
+#[derive(Debug)]
+pub enum CacheFileError {
+    Io(io::Error),
+    Corrupt(String),
+    VersionMismatch(u16),
+}
+
+impl Display for CacheFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Corrupt(e) => write!(f, "corrupt cache file: {}", e),
+            Self::VersionMismatch(version) => {
+                write!(f, "cache file of version {}, expected {}", version, VERSION)
+            }
+        }
+    }
+}
+
+/// A cache entry as saved, with the TTL it has left
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    /// Records of an RRset, all with its TTL
+    RRset(Vec<ResourceRecord>),
+    /// The NXDOMAIN answer of a name (no type) or the NODATA one of a type, its SOA with its TTL
+    NegativeAnswer {
+        name: DomainName,
+        type_: Option<u16>,
+        response_code: ResponseCode,
+        soa: ResourceRecord,
+    },
+}
+
+impl Entry {
+    pub fn name(&self) -> &DomainName {
+        match self {
+            Self::RRset(records) => &records[0].name,
+            Self::NegativeAnswer { name, .. } => name,
+        }
+    }
+
+    fn ttl(&self) -> u32 {
+        match self {
+            Self::RRset(records) => records[0].ttl,
+            Self::NegativeAnswer { soa, .. } => soa.ttl,
+        }
+    }
+}
+
+impl InMemoryResourceRecordRepository {
+    /// Cached RRsets and negative answers still alive at `now`, with the TTL they have left
+    /// Static RRsets and stale ones are left out
+    pub fn entries(&self, now: Instant) -> Vec<Entry> {
+        let rrsets = self
+            .inner
+            .values()
+            .flatten()
+            .filter(|rrset| rrset.cached_at.is_some())
+            .filter_map(|rrset| {
+                Some(Entry::RRset(
+                    rrset.records(rrset.remaining_ttl(now)?).collect(),
+                ))
+            });
+        let negative_answers = self
+            .negative_answers
+            .iter()
+            .flat_map(|(name, negative_answers)| {
+                negative_answers
+                    .iter()
+                    .filter_map(move |(type_, negative_answer)| {
+                        let ttl = negative_answer.remaining_ttl(now)?;
+                        Some(Entry::NegativeAnswer {
+                            name: name.clone(),
+                            type_: *type_,
+                            response_code: negative_answer.response_code.clone(),
+                            soa: ResourceRecord {
+                                ttl,
+                                ..negative_answer.soa.clone()
+                            },
+                        })
+                    })
+            });
+
+        rrsets.chain(negative_answers).collect()
+    }
+
+    /// Caches an entry again, as if its answer was received at `now` with the TTL it has left
+    pub fn restore(&mut self, entry: Entry, now: Instant) {
+        match entry {
+            Entry::RRset(records) => {
+                for record in records {
+                    self.cache(record, now);
+                }
+            }
+            Entry::NegativeAnswer {
+                name,
+                type_,
+                response_code,
+                soa,
+            } => {
+                let negative_answer = NegativeAnswer {
+                    response_code,
+                    ttl: soa.ttl,
+                    soa,
+                    cached_at: now,
+                    last_used: Default::default(),
+                };
+                self.insert_negative_answer(&name, type_, negative_answer);
+            }
+        }
+    }
+}
+
+/// Held while a file is saved, the periodic saves and the one on shutdown share the temporary file
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// Writes the entries of the cache alive now to a file, returns how many there were
+/// The file is replaced at once and synced, a crash while writing leaves the previous one in place
+pub fn save(cache: &ShardedCache, path: &Path) -> io::Result<usize> {
+    let _saving = SAVING.lock().unwrap_or_else(|p| p.into_inner());
+    let entries = cache.entries(Instant::now());
+
+    let mut temporary_path = OsString::from(path);
+    temporary_path.push(".tmp");
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(&encode(&entries, unix_time()))?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+
+    // the rename itself is only durable once the directory is
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()?;
+
+    Ok(entries.len())
+}
+
+/// Caches the entries of a file which did not expire yet, returns how many there were
+/// A file which is corrupt or of another version is rejected as a whole
+pub fn load(cache: &ShardedCache, path: &Path) -> Result<usize, CacheFileError> {
+    let bytes = fs::read(path).map_err(CacheFileError::Io)?;
+    let entries = decode(&bytes, unix_time())?;
+
+    let count = entries.len();
+    cache.restore(entries, Instant::now());
+    Ok(count)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The file content of entries with the TTL they have left at `now`, in seconds since the epoch
+fn encode(entries: &[Entry], now: u64) -> Vec<u8> {
+    let mut content = vec![];
+
+    for entry in entries {
+        match entry {
+            Entry::RRset(records) => {
+                content.push(RRSET);
+                push_entry_start(&mut content, entry, now);
+                push_u16_to_u8_vec(&mut content, records[0].type_.into());
+                push_u16_to_u8_vec(&mut content, records[0].class.clone().into());
+                push_u16_to_u8_vec(&mut content, records.len() as u16);
+                for record in records {
+                    push_data(&mut content, &record.resource_data);
+                }
+            }
+            Entry::NegativeAnswer {
+                type_,
+                response_code,
+                soa,
+                ..
+            } => {
+                content.push(NEGATIVE_ANSWER);
+                push_entry_start(&mut content, entry, now);
+                content.push(type_.is_some() as u8);
+                push_u16_to_u8_vec(&mut content, type_.unwrap_or_default());
+                push_u16_to_u8_vec(&mut content, response_code.value());
+                push_name(&mut content, &soa.name);
+                push_u16_to_u8_vec(&mut content, soa.class.clone().into());
+                push_data(&mut content, &soa.resource_data);
+            }
+        }
+    }
+
+    let mut file = MAGIC.to_vec();
+    push_u16_to_u8_vec(&mut file, VERSION);
+    file.extend(checksum(&content).to_be_bytes());
+    file.extend(content);
+    file
+}
+
+fn push_entry_start(content: &mut Vec<u8>, entry: &Entry, now: u64) {
+    content.extend((now + entry.ttl() as u64).to_be_bytes());
+    push_name(content, entry.name());
+}
+
+fn push_name(content: &mut Vec<u8>, name: &DomainName) {
+    content.push(name.labels.len() as u8);
+    for label in name.labels.iter() {
+        content.push(label.len() as u8);
+        content.extend(label.as_bytes());
+    }
+}
+
+fn push_data(content: &mut Vec<u8>, data: &[u8]) {
+    push_u16_to_u8_vec(content, data.len() as u16);
+    content.extend(data);
+}
+
+/// The entries of a file which are still alive at `now`, in seconds since the epoch, with the TTL
+/// they have left
+fn decode(file: &[u8], now: u64) -> Result<Vec<Entry>, CacheFileError> {
+    if file.len() < HEADER_LENGTH || !file.starts_with(MAGIC) {
+        return Err(CacheFileError::Corrupt("not a cache file".to_string()));
+    }
+
+    let mut header = Reader(&file[MAGIC.len()..HEADER_LENGTH]);
+    let version = header.u16()?;
+    if version != VERSION {
+        return Err(CacheFileError::VersionMismatch(version));
+    }
+    let content = &file[HEADER_LENGTH..];
+    if header.u64()? != checksum(content) {
+        return Err(CacheFileError::Corrupt("checksum mismatch".to_string()));
+    }
+
+    let mut reader = Reader(content);
+    let mut entries = vec![];
+    while !reader.0.is_empty() {
+        let kind = reader.u8()?;
+        let ttl = reader.u64()?.saturating_sub(now).min(u32::MAX as u64) as u32;
+        let name = reader.name()?;
+
+        let entry = match kind {
+            RRSET => {
+                let type_ = reader.type_()?;
+                let class = reader.class()?;
+                let count = reader.u16()?;
+                let records = (0..count)
+                    .map(|_| {
+                        let data = reader.data()?;
+                        Ok(ResourceRecord::new(
+                            name.clone(),
+                            type_,
+                            class.clone(),
+                            ttl,
+                            data,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, CacheFileError>>()?;
+                if records.is_empty() {
+                    return Err(CacheFileError::Corrupt("empty RRset".to_string()));
+                }
+                Entry::RRset(records)
+            }
+            NEGATIVE_ANSWER => {
+                let has_type = reader.u8()? != 0;
+                let type_ = reader.u16()?;
+                let response_code =
+                    ResponseCode::try_from(reader.u16()?).map_err(CacheFileError::Corrupt)?;
+                let soa_name = reader.name()?;
+                let class = reader.class()?;
+                let data = reader.data()?;
+                Entry::NegativeAnswer {
+                    name,
+                    type_: has_type.then_some(type_),
+                    response_code,
+                    soa: ResourceRecord::new(soa_name, Type::SOA, class, ttl, data),
+                }
+            }
+            kind => {
+                return Err(CacheFileError::Corrupt(format!(
+                    "unknown entry kind {}",
+                    kind
+                )));
+            }
+        };
+
+        if ttl > 0 {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// 64-bit FNV-1a, enough to tell a truncated or damaged file
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], CacheFileError> {
+        if self.0.len() < length {
+            return Err(CacheFileError::Corrupt(
+                "unexpected end of file".to_string(),
+            ));
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, CacheFileError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CacheFileError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, CacheFileError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn name(&mut self) -> Result<DomainName, CacheFileError> {
+        let count = self.u8()?;
+        let labels = (0..count)
+            .map(|_| {
+                let length = self.u8()? as usize;
+                String::from_utf8(self.take(length)?.to_vec())
+                    .map_err(|_| CacheFileError::Corrupt("invalid label".to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DomainName { labels })
+    }
+
+    fn data(&mut self) -> Result<Vec<u8>, CacheFileError> {
+        let length = self.u16()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    fn type_(&mut self) -> Result<Type, CacheFileError> {
+        Type::try_from(self.u16()?).map_err(CacheFileError::Corrupt)
+    }
+
+    fn class(&mut self) -> Result<Class, CacheFileError> {
+        Class::try_from(self.u16()?).map_err(CacheFileError::Corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process, sync::Arc, thread, time::Duration};
+
+    use crate::{
+        common::question::{Question, Type as QuestionType},
+        storage::{
+            Answer,
+            zone::{DEFAULT_TTL, parse_record},
+        },
+    };
+
+    use super::*;
+
+    fn record(name: &str, address: u8, ttl: u32) -> ResourceRecord {
+        ResourceRecord::new(
+            DomainName::from(name),
+            Type::A,
+            Class::IN,
+            ttl,
+            vec![192, 0, 2, address],
+        )
+    }
+
+    fn question(name: &str, type_: Type) -> Question {
+        Question {
+            name: DomainName::from(name),
+            type_: QuestionType::RRType(type_),
+            class: Class::IN,
+        }
+    }
+
+    #[test]
+    fn saves_and_loads_the_entries_alive() {
+        let path = env::temp_dir().join(format!("dns-cache-test-{}.bin", process::id()));
+        let soa = parse_record(
+            "example.com. 3600 IN SOA ns1 hostmaster 1 7200 3600 1209600 300",
+            "example.com",
+            DEFAULT_TTL,
+        )
+        .unwrap();
+        let now = Instant::now();
+
+        let cache = ShardedCache::new(4);
+        cache
+            .write(&DomainName::from("a.example.com."))
+            .replace_rrsets(
+                &question("a.example.com.", Type::A),
+                &[
+                    record("a.example.com.", 1, 300),
+                    record("a.example.com.", 2, 300),
+                ],
+                now,
+            );
+        cache
+            .write(&DomainName::from("b.example.com."))
+            .cache(record("b.example.com.", 1, 1), now - Duration::from_secs(2));
+        cache
+            .write(&DomainName::from("c.example.com."))
+            .save(record("c.example.com.", 1, 300));
+        cache
+            .write(&DomainName::from("d.example.com."))
+            .cache_negative_answer(
+                &question("d.example.com.", Type::A),
+                &Answer::negative(ResponseCode::NameError, soa.clone()),
+                now,
+            );
+        cache
+            .write(&DomainName::from("e.example.com."))
+            .cache_negative_answer(
+                &question("e.example.com.", Type::AAAA),
+                &Answer::negative(ResponseCode::NoError, soa.clone()),
+                now,
+            );
+
+        // the expired RRset and the static one are left out
+        assert_eq!(save(&cache, &path).unwrap(), 3);
+
+        let loaded = ShardedCache::new(4);
+        assert_eq!(load(&loaded, &path).unwrap(), 3);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.metrics().entries(), 3);
+
+        let now = Instant::now();
+        let lookup = |name: &str, type_: Type| {
+            let question = question(name, type_);
+            loaded.read(&question.name).lookup_cached(&question, now)
+        };
+        let records = lookup("a.example.com.", Type::A).unwrap().records;
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.ttl > 290));
+        assert!(lookup("b.example.com.", Type::A).is_none());
+        assert!(lookup("c.example.com.", Type::A).is_none());
+        let nxdomain = lookup("d.example.com.", Type::MX).unwrap();
+        assert_eq!(nxdomain.response_code, ResponseCode::NameError);
+        assert_eq!(nxdomain.authorities[0].name, soa.name);
+        assert!(nxdomain.authorities[0].ttl > 290);
+        assert_eq!(
+            lookup("e.example.com.", Type::AAAA).unwrap().response_code,
+            ResponseCode::NoError
+        );
+        assert!(lookup("e.example.com.", Type::A).is_none());
+    }
+
+    #[test]
+    fn saves_one_file_at_a_time() {
+        let path = env::temp_dir().join(format!("dns-cache-saves-test-{}.bin", process::id()));
+        let cache = Arc::new(ShardedCache::new(4));
+        for i in 0..200 {
+            let name = format!("{}.example.com.", i);
+            cache
+                .write(&DomainName::from(name.as_str()))
+                .cache(record(&name, 1, 300), Instant::now());
+        }
+
+        // like the periodic saver and the save on shutdown
+        let savers = (0..4)
+            .map(|_| {
+                let (cache, path) = (cache.clone(), path.clone());
+                thread::spawn(move || (0..10).all(|_| save(&cache, &path).is_ok()))
+            })
+            .collect::<Vec<_>>();
+        for saver in savers {
+            assert!(saver.join().unwrap());
+        }
+
+        assert_eq!(load(&ShardedCache::new(4), &path).unwrap(), 200);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_the_entries_expired_since_they_were_saved() {
+        let entries = vec![
+            Entry::RRset(vec![record("a.example.com.", 1, 60)]),
+            Entry::RRset(vec![record("b.example.com.", 1, 600)]),
+        ];
+        let file = encode(&entries, 1_000);
+
+        assert_eq!(
+            decode(&file, 1_030).unwrap(),
+            vec![
+                Entry::RRset(vec![record("a.example.com.", 1, 30)]),
+                Entry::RRset(vec![record("b.example.com.", 1, 570)]),
+            ]
+        );
+        assert_eq!(
+            decode(&file, 1_060).unwrap(),
+            vec![Entry::RRset(vec![record("b.example.com.", 1, 540)])]
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_and_other_version_files() {
+        let file = encode(&[Entry::RRset(vec![record("example.com.", 1, 60)])], 1_000);
+        assert!(decode(&file, 1_000).is_ok());
+
+        let mut damaged = file.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode(&damaged, 1_000),
+            Err(CacheFileError::Corrupt(_))
+        ));
+        assert!(matches!(
+            decode(&file[..file.len() - 1], 1_000),
+            Err(CacheFileError::Corrupt(_))
+        ));
+        assert!(matches!(
+            decode(b"", 1_000),
+            Err(CacheFileError::Corrupt(_))
+        ));
+        assert!(matches!(
+            decode(b"not a cache file at all", 1_000),
+            Err(CacheFileError::Corrupt(_))
+        ));
+
+        let mut other_version = file.clone();
+        other_version[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        assert!(matches!(
+            decode(&other_version, 1_000),
+            Err(CacheFileError::VersionMismatch(version)) if version == VERSION + 1
+        ));
+    }
+}
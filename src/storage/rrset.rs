@@ -0,0 +1,177 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    common::{
+        domain_name::DomainName,
+        question::{Class, Question, Type as QuestionType},
+        resource_record::{ResourceRecord, Type},
+    },
+    storage::{Prefetch, metrics::Counter, name_size, remaining_ttl},
+};
+
+/// Records sharing a name, a class and a type, the unit they are stored, served and replaced by
+/// Their data is unique, and they have a single TTL, the lowest one of their records
+/// see: https://datatracker.ietf.org/doc/html/rfc2181#section-5
+/// Static RRsets, like the ones of the zones, have no caching time and never expire
+#[derive(Debug, Clone)]
+pub struct RRset {
+    name: DomainName,
+    class: Class,
+    type_: Type,
+    ttl: u32,
+    data: Vec<Vec<u8>>,
+    pub cached_at: Option<Instant>,
+    /// Times it was served from the cache
+    pub hits: Counter,
+    /// Tick of the cache clock it was last served at
+    pub last_used: Counter,
+}
+
+impl RRset {
+    pub fn new(record: ResourceRecord, cached_at: Option<Instant>) -> Self {
+        Self {
+            name: record.name,
+            class: record.class,
+            type_: record.type_,
+            ttl: record.ttl,
+            data: vec![record.resource_data],
+            cached_at,
+            hits: Counter::default(),
+            last_used: Counter::default(),
+        }
+    }
+
+    pub fn is(&self, class: &Class, type_: Type) -> bool {
+        self.class == *class && self.type_ == type_
+    }
+
+    /// Whether the record belongs to the RRset, whatever its data and TTL
+    pub fn includes(&self, record: &ResourceRecord) -> bool {
+        self.name == record.name && self.is(&record.class, record.type_)
+    }
+
+    /// Adds a record of the RRset unless it has the same data already, lowering the TTL of the
+    /// RRset to its own
+    pub fn add(&mut self, record: ResourceRecord) {
+        self.ttl = self.ttl.min(record.ttl);
+        if !self.data.contains(&record.resource_data) {
+            self.data.push(record.resource_data);
+        }
+    }
+
+    pub fn answers(&self, question: &Question) -> bool {
+        question.name == self.name
+            && match question.class {
+                Class::ALL => true,
+                _ => question.class == self.class,
+            }
+            && match question.type_ {
+                QuestionType::ALL => true,
+                QuestionType::MAILA => self.type_ == Type::MX,
+                QuestionType::MAILB => {
+                    self.type_ == Type::MB_EXP
+                        || self.type_ == Type::MG_EXP
+                        || self.type_ == Type::MR_EXP
+                }
+                // zone transfers are refused by the handler, no RRset answers them
+                QuestionType::AXFR => false,
+                QuestionType::RRType(t) => self.type_ == t,
+            }
+    }
+
+    /// Its records, all with the given TTL
+    pub fn records(&self, ttl: u32) -> impl Iterator<Item = ResourceRecord> {
+        self.data.iter().map(move |data| {
+            ResourceRecord::new(
+                self.name.clone(),
+                self.type_,
+                self.class.clone(),
+                ttl,
+                data.clone(),
+            )
+        })
+    }
+
+    /// TTL left at `now`, None once expired
+    pub fn remaining_ttl(&self, now: Instant) -> Option<u32> {
+        match self.cached_at {
+            Some(cached_at) => remaining_ttl(self.ttl, cached_at, now),
+            None => Some(self.ttl),
+        }
+    }
+
+    /// Approximate memory used, counted against the cache limits
+    pub fn size(&self) -> usize {
+        size_of::<Self>()
+            + name_size(&self.name)
+            + self
+                .data
+                .iter()
+                .map(|data| size_of::<Vec<u8>>() + data.len())
+                .sum::<usize>()
+    }
+
+    /// Whether the RRset was served often enough and is in the last part of its TTL at `now`
+    pub fn is_prefetched(&self, now: Instant, prefetch: Prefetch) -> bool {
+        let (Some(_), Some(remaining_ttl)) = (self.cached_at, self.remaining_ttl(now)) else {
+            return false;
+        };
+
+        self.hits.get() >= prefetch.min_hits as u64
+            && remaining_ttl as u64 * 100 <= self.ttl as u64 * prefetch.percent as u64
+    }
+
+    /// Whether the RRset is still alive at `now`, or expired less than `stale_window` ago
+    pub fn is_kept(&self, now: Instant, stale_window: Duration) -> bool {
+        match self.cached_at {
+            Some(cached_at) => {
+                now.saturating_duration_since(cached_at)
+                    < Duration::from_secs(self.ttl as u64) + stale_window
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(address: u8, ttl: u32) -> ResourceRecord {
+        ResourceRecord::new(
+            DomainName::from("example.com."),
+            Type::A,
+            Class::IN,
+            ttl,
+            vec![192, 0, 2, address],
+        )
+    }
+
+    #[test]
+    fn deduplicates_data_and_keeps_the_lowest_ttl() {
+        let mut rrset = RRset::new(record(1, 300), None);
+        rrset.add(record(2, 60));
+        rrset.add(record(1, 3600));
+
+        let records = rrset.records(60).collect::<Vec<_>>();
+        assert_eq!(records, vec![record(1, 60), record(2, 60)]);
+        assert_eq!(rrset.remaining_ttl(Instant::now()), Some(60));
+
+        assert!(rrset.answers(&Question {
+            name: DomainName::from("example.com."),
+            type_: QuestionType::ALL,
+            class: Class::IN,
+        }));
+        assert!(!rrset.answers(&Question {
+            name: DomainName::from("example.com."),
+            type_: QuestionType::AXFR,
+            class: Class::IN,
+        }));
+
+        assert!(rrset.includes(&record(3, 300)));
+        assert!(!rrset.includes(&ResourceRecord {
+            type_: Type::AAAA,
+            ..record(3, 300)
+        }));
+    }
+}
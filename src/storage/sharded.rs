@@ -0,0 +1,177 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    mem,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Instant,
+};
+
+use crate::{
+    common::domain_name::DomainName,
+    storage::{InMemoryResourceRecordRepository, metrics::CacheMetrics, persistence::Entry},
+};
+
+pub const CACHE_SHARDS: usize = 16;
+
+/// A cache split by name over shards locked on their own, so that lookups of different names
+/// never wait for each other, and lookups of the same one only wait for the writes
+/// The shards share their metrics, each one keeping to its share of the limits by evicting its own
+/// least recently used names
+pub struct ShardedCache {
+    shards: Vec<RwLock<InMemoryResourceRecordRepository>>,
+    metrics: Arc<CacheMetrics>,
+}
+
+impl ShardedCache {
+    pub fn new(shards: usize) -> Self {
+        let metrics = Arc::new(CacheMetrics::default());
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| {
+                    RwLock::new(
+                        InMemoryResourceRecordRepository::new().with_metrics(metrics.clone()),
+                    )
+                })
+                .collect(),
+            metrics,
+        }
+    }
+
+    /// Applies the same settings to every shard
+    pub fn configure(
+        &self,
+        configure: impl Fn(InMemoryResourceRecordRepository) -> InMemoryResourceRecordRepository,
+    ) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap_or_else(|p| p.into_inner());
+            *shard = configure(mem::replace(
+                &mut *shard,
+                InMemoryResourceRecordRepository::new(),
+            ));
+        }
+    }
+
+    /// Spreads a limit of the whole cache over the shards, their shares add up to it
+    pub fn configure_limit(
+        &self,
+        limit: usize,
+        configure: impl Fn(InMemoryResourceRecordRepository, usize) -> InMemoryResourceRecordRepository,
+    ) {
+        let shards = self.shards.len();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let share = limit / shards + usize::from(i < limit % shards);
+            let mut shard = shard.write().unwrap_or_else(|p| p.into_inner());
+            *shard = configure(
+                mem::replace(&mut *shard, InMemoryResourceRecordRepository::new()),
+                share,
+            );
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<CacheMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn read(&self, name: &DomainName) -> RwLockReadGuard<'_, InMemoryResourceRecordRepository> {
+        self.shard(name).read().unwrap_or_else(|p| p.into_inner())
+    }
+
+    pub fn write(
+        &self,
+        name: &DomainName,
+    ) -> RwLockWriteGuard<'_, InMemoryResourceRecordRepository> {
+        self.shard(name).write().unwrap_or_else(|p| p.into_inner())
+    }
+
+    /// Drops the expired entries of every shard in turn, returns how many were
+    pub fn remove_expired(&self, now: Instant) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .write()
+                    .unwrap_or_else(|p| p.into_inner())
+                    .remove_expired(now)
+            })
+            .sum()
+    }
+
+    /// Entries of every shard in turn, with the TTL they have left at `now`
+    pub fn entries(&self, now: Instant) -> Vec<Entry> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.read().unwrap_or_else(|p| p.into_inner()).entries(now))
+            .collect()
+    }
+
+    /// Caches entries again in the shards of their names, as of `now`
+    pub fn restore(&self, entries: Vec<Entry>, now: Instant) {
+        for entry in entries {
+            let name = entry.name().clone();
+            self.write(&name).restore(entry, now);
+        }
+    }
+
+    fn shard(&self, name: &DomainName) -> &RwLock<InMemoryResourceRecordRepository> {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::common::{
+        question::{Class, Question, Type as QuestionType},
+        resource_record::{ResourceRecord, Type},
+    };
+
+    use super::*;
+
+    #[test]
+    fn shares_settings_and_metrics_between_shards() {
+        let cache = ShardedCache::new(4);
+        cache.configure_limit(4, |shard, max_entries| shard.with_max_entries(max_entries));
+        let now = Instant::now();
+
+        for i in 0..8 {
+            let name = DomainName::from(format!("{}.example.com.", i).as_str());
+            cache.write(&name).cache(
+                ResourceRecord::new(name.clone(), Type::A, Class::IN, 60, vec![192, 0, 2, i]),
+                now,
+            );
+
+            // over its share of the limit, a shard evicts its own older names
+            let question = Question {
+                name: name.clone(),
+                type_: QuestionType::RRType(Type::A),
+                class: Class::IN,
+            };
+            assert_eq!(cache.read(&name).lookup_at(&question, now).len(), 1);
+        }
+
+        let entries = cache.metrics().entries();
+        assert!((1..=4).contains(&entries));
+        assert_eq!(cache.metrics().evictions(), 8 - entries);
+        assert_eq!(cache.remove_expired(now + Duration::from_secs(60)), entries);
+        assert_eq!(cache.metrics().entries(), 0);
+    }
+
+    #[test]
+    fn spreads_limits_over_shards() {
+        let cache = ShardedCache::new(4);
+        cache.configure_limit(3, |shard, max_entries| shard.with_max_entries(max_entries));
+        let now = Instant::now();
+
+        for i in 0..16 {
+            let name = DomainName::from(format!("{}.example.com.", i).as_str());
+            cache.write(&name).cache(
+                ResourceRecord::new(name.clone(), Type::A, Class::IN, 60, vec![192, 0, 2, i]),
+                now,
+            );
+        }
+
+        assert!(cache.metrics().entries() <= 3);
+    }
+}
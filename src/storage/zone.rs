@@ -0,0 +1,591 @@
+// Master file (zone file) parsing, see: https://datatracker.ietf.org/doc/html/rfc1035#section-5
+// Supports $ORIGIN, $TTL, @, relative names, omitted owners, comments and parentheses
+// for the record types the encoder knows about: A, AAAA, CNAME, NS, PTR, MX, TXT and SOA
+// Resource data of these types is also rendered back to the same presentation format
+
+use std::{
+    fmt::Display,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use crate::{
+    common::{
+        domain_name::DomainName,
+        question::Class,
+        resource_record::{ResourceRecord, Type},
+    },
+    decoder::domain_name::decode as decode_domain_name,
+    encoder::domain_name::encode as encode_domain_name,
+    utils::{push_u16_to_u8_vec, push_u32_to_u8_vec},
+};
+
+pub const DEFAULT_TTL: u32 = 3600;
+
+#[derive(Debug, PartialEq)]
+pub struct ZoneError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ZoneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Reads every record of a zone file, names are relative to the given origin until $ORIGIN says otherwise
+pub fn load_zone_file(path: &Path, origin: &str) -> Result<Vec<ResourceRecord>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("could not read zone file {}: {}", path.display(), e))?;
+
+    parse_zone(&content, origin).map_err(|e| format!("{}:{}", path.display(), e))
+}
+
+pub fn parse_zone(content: &str, origin: &str) -> Result<Vec<ResourceRecord>, ZoneError> {
+    let mut origin = absolute_origin(origin);
+    let mut default_ttl = DEFAULT_TTL;
+    let mut previous_owner: Option<String> = None;
+    let mut records = vec![];
+
+    for (line, entry) in logical_lines(content)? {
+        let error = |message: String| ZoneError { line, message };
+
+        let starts_with_owner = !entry.starts_with([' ', '\t']);
+        let tokens = tokenize(&entry).map_err(error)?;
+        let Some(first) = tokens.first() else {
+            continue;
+        };
+
+        match first.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => {
+                let name = tokens
+                    .get(1)
+                    .ok_or_else(|| error("missing $ORIGIN name".into()))?;
+                origin = absolute_name(name, &origin);
+                continue;
+            }
+            "$TTL" => {
+                let ttl = tokens
+                    .get(1)
+                    .ok_or_else(|| error("missing $TTL value".into()))?;
+                default_ttl = parse_ttl(ttl).map_err(error)?;
+                continue;
+            }
+            directive if directive.starts_with('$') => {
+                return Err(error(format!("unsupported directive {}", first)));
+            }
+            _ => {}
+        }
+
+        let (owner, fields) = if starts_with_owner {
+            (absolute_name(first, &origin), &tokens[1..])
+        } else {
+            let owner = previous_owner
+                .clone()
+                .ok_or_else(|| error("the first record must have an owner name".into()))?;
+            (owner, &tokens[..])
+        };
+
+        records.push(parse_fields(&owner, fields, &origin, default_ttl).map_err(error)?);
+        previous_owner = Some(owner);
+    }
+
+    Ok(records)
+}
+
+/// Parses a single record in presentation format, e.g. `www 300 IN A 192.0.2.1`
+pub fn parse_record(text: &str, origin: &str, default_ttl: u32) -> Result<ResourceRecord, String> {
+    let origin = absolute_origin(origin);
+    let tokens = tokenize(text)?;
+    let (owner, fields) = tokens
+        .split_first()
+        .ok_or_else(|| "empty record".to_string())?;
+
+    parse_fields(&absolute_name(owner, &origin), fields, &origin, default_ttl)
+}
+
+/// Owner name already resolved, fields are `[ttl] [class] type rdata...` with ttl and class in any order
+fn parse_fields(
+    owner: &str,
+    fields: &[String],
+    origin: &str,
+    default_ttl: u32,
+) -> Result<ResourceRecord, String> {
+    let mut ttl = None;
+    let mut class = None;
+    let mut fields = fields.iter();
+
+    let type_ = loop {
+        let field = fields
+            .next()
+            .ok_or_else(|| format!("missing record type for {}", owner))?;
+
+        if ttl.is_none() && field.starts_with(|c: char| c.is_ascii_digit()) {
+            ttl = Some(parse_ttl(field)?);
+        } else if class.is_none()
+            && let Some(c) = parse_class(field)
+        {
+            class = Some(c);
+        } else {
+            break parse_type(field)?;
+        }
+    };
+
+    let rdata = fields.map(String::as_str).collect::<Vec<_>>();
+    let resource_data = parse_resource_data(type_, &rdata, origin)?;
+
+    Ok(ResourceRecord::new(
+        to_domain_name(owner),
+        type_,
+        class.unwrap_or(Class::IN),
+        ttl.unwrap_or(default_ttl),
+        resource_data,
+    ))
+}
+
+/// Resource data in the form the encoder expects it
+fn parse_resource_data(type_: Type, rdata: &[&str], origin: &str) -> Result<Vec<u8>, String> {
+    let expect = |count: usize| {
+        if rdata.len() == count {
+            Ok(())
+        } else {
+            Err(format!(
+                "{:?} record expects {} field(s), got {}",
+                type_,
+                count,
+                rdata.len()
+            ))
+        }
+    };
+    let name = |name: &str| encode_domain_name(to_domain_name(&absolute_name(name, origin)));
+
+    match type_ {
+        Type::A => {
+            expect(1)?;
+            rdata[0]
+                .parse::<Ipv4Addr>()
+                .map(|ip| ip.octets().to_vec())
+                .map_err(|_| format!("invalid IPv4 address: {}", rdata[0]))
+        }
+        Type::AAAA => {
+            expect(1)?;
+            rdata[0]
+                .parse::<Ipv6Addr>()
+                .map(|ip| ip.octets().to_vec())
+                .map_err(|_| format!("invalid IPv6 address: {}", rdata[0]))
+        }
+        Type::CNAME | Type::NS | Type::PTR => {
+            expect(1)?;
+            Ok(name(rdata[0]))
+        }
+        Type::MX => {
+            expect(2)?;
+            let preference = rdata[0]
+                .parse::<u16>()
+                .map_err(|_| format!("invalid MX preference: {}", rdata[0]))?;
+            let mut data = vec![];
+            push_u16_to_u8_vec(&mut data, preference);
+            data.extend(name(rdata[1]));
+            Ok(data)
+        }
+        Type::TXT => {
+            expect(1)?;
+            if rdata[0].len() > u8::MAX as usize {
+                return Err("TXT record is limited to 255 characters".to_string());
+            }
+            Ok(rdata[0].as_bytes().to_vec())
+        }
+        Type::SOA => {
+            expect(7)?;
+            let mut data = name(rdata[0]);
+            data.extend(name(rdata[1]));
+            push_u32_to_u8_vec(
+                &mut data,
+                rdata[2]
+                    .parse::<u32>()
+                    .map_err(|_| format!("invalid SOA serial: {}", rdata[2]))?,
+            );
+            for value in &rdata[3..] {
+                push_u32_to_u8_vec(&mut data, parse_ttl(value)?);
+            }
+            Ok(data)
+        }
+        t => Err(format!("unsupported record type in zone data: {:?}", t)),
+    }
+}
+
+/// Presentation format of resource data as parse_record reads it, with absolute names
+/// Other types, or data which can not be read, use the generic `\# length hex` form of RFC 3597
+pub fn format_resource_data(type_: Type, data: &[u8]) -> String {
+    let formatted = match type_ {
+        Type::A => <[u8; 4]>::try_from(data)
+            .ok()
+            .map(|octets| Ipv4Addr::from(octets).to_string()),
+        Type::AAAA => <[u8; 16]>::try_from(data)
+            .ok()
+            .map(|octets| Ipv6Addr::from(octets).to_string()),
+        Type::CNAME | Type::NS | Type::PTR => format_names(data, 1, 0).map(|names| names.join(" ")),
+        Type::MX => data
+            .split_first_chunk::<2>()
+            .and_then(|(preference, name)| {
+                format_names(name, 1, 0)
+                    .map(|names| format!("{} {}", u16::from_be_bytes(*preference), names[0]))
+            }),
+        Type::TXT => Some(format!(
+            "\"{}\"",
+            String::from_utf8_lossy(data)
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+        )),
+        Type::SOA => format_names(data, 2, 20).map(|names| {
+            let numbers = data[data.len() - 20..]
+                .chunks(4)
+                .map(|n| u32::from_be_bytes([n[0], n[1], n[2], n[3]]).to_string())
+                .collect::<Vec<_>>();
+            format!("{} {}", names.join(" "), numbers.join(" "))
+        }),
+        _ => None,
+    };
+
+    formatted.unwrap_or_else(|| {
+        let hex = data
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("\\# {} {}", data.len(), hex).trim_end().to_string()
+    })
+}
+
+/// Reads `count` uncompressed names followed by exactly `trailing` bytes
+fn format_names(data: &[u8], count: usize, trailing: usize) -> Option<Vec<String>> {
+    let mut names = vec![];
+    let mut rest = data;
+    for _ in 0..count {
+        // compression pointers point into a message which is gone by now
+        let (name, remaining) = decode_domain_name(rest, &[]).ok()?;
+        names.push(match name.to_string().as_str() {
+            "" => ".".to_string(),
+            name => name.to_string(),
+        });
+        rest = remaining;
+    }
+
+    (rest.len() == trailing).then_some(names)
+}
+
+/// Seconds, optionally with a unit: 30, 5m, 1h, 2d or 1w
+pub fn parse_ttl(value: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid TTL: {}", value);
+
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let multiplier = match unit.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                'w' => 604800,
+                _ => return Err(invalid()),
+            };
+            (&value[..i], multiplier)
+        }
+        _ => (value, 1),
+    };
+
+    number
+        .parse::<u32>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
+fn parse_class(value: &str) -> Option<Class> {
+    match value.to_ascii_uppercase().as_str() {
+        "IN" => Some(Class::IN),
+        "CS" => Some(Class::CS),
+        "CH" => Some(Class::CH),
+        "HS" => Some(Class::HS),
+        _ => None,
+    }
+}
+
+fn parse_type(value: &str) -> Result<Type, String> {
+    match value.to_ascii_uppercase().as_str() {
+        "A" => Ok(Type::A),
+        "AAAA" => Ok(Type::AAAA),
+        "CNAME" => Ok(Type::CNAME),
+        "NS" => Ok(Type::NS),
+        "PTR" => Ok(Type::PTR),
+        "MX" => Ok(Type::MX),
+        "TXT" => Ok(Type::TXT),
+        "SOA" => Ok(Type::SOA),
+        _ => Err(format!("unknown record type: {}", value)),
+    }
+}
+
+fn absolute_origin(origin: &str) -> String {
+    if origin.ends_with('.') {
+        origin.to_string()
+    } else {
+        format!("{}.", origin)
+    }
+}
+
+/// Resolves `@` and names without trailing dot against the origin
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') {
+        name.to_string()
+    } else if origin == "." {
+        format!("{}.", name)
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+fn to_domain_name(absolute_name: &str) -> DomainName {
+    if absolute_name == "." {
+        DomainName {
+            labels: vec![String::new()],
+        }
+    } else {
+        DomainName::from(absolute_name)
+    }
+}
+
+/// Strips comments and joins the lines between parentheses, keeping the first line number
+fn logical_lines(content: &str) -> Result<Vec<(usize, String)>, ZoneError> {
+    let mut lines = vec![];
+    let mut current: Option<(usize, String)> = None;
+    let mut depth = 0;
+
+    for (i, raw_line) in content.lines().enumerate() {
+        let line_number = i + 1;
+        let mut line = String::new();
+        let mut quoted = false;
+
+        for c in raw_line.chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    line.push(c);
+                }
+                ';' if !quoted => break,
+                '(' if !quoted => {
+                    depth += 1;
+                    line.push(' ');
+                }
+                ')' if !quoted => {
+                    if depth == 0 {
+                        return Err(ZoneError {
+                            line: line_number,
+                            message: "unbalanced closing parenthesis".to_string(),
+                        });
+                    }
+                    depth -= 1;
+                    line.push(' ');
+                }
+                c => line.push(c),
+            }
+        }
+
+        match current.as_mut() {
+            Some((_, entry)) => {
+                entry.push(' ');
+                entry.push_str(&line);
+            }
+            None => current = Some((line_number, line)),
+        }
+
+        if depth == 0
+            && let Some((line_number, entry)) = current.take()
+            && !entry.trim().is_empty()
+        {
+            lines.push((line_number, entry.trim_end().to_string()));
+        }
+    }
+
+    if let Some((line, _)) = current
+        && depth > 0
+    {
+        return Err(ZoneError {
+            line,
+            message: "unbalanced opening parenthesis".to_string(),
+        });
+    }
+
+    Ok(lines)
+}
+
+/// Splits on whitespace, a quoted string is a single token without its quotes
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let mut token = String::new();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => token.extend(chars.next()),
+                    Some(c) => token.push(c),
+                    None => return Err("unterminated quoted string".to_string()),
+                }
+            }
+        } else {
+            token.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+        }
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_zone() {
+        let zone = r#"
+$TTL 1h
+$ORIGIN example.com.
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                2h 1h 1w 5m )
+        IN  NS  ns1
+ns1     300 IN  A   192.0.2.53
+www         A   192.0.2.1
+            AAAA 2001:db8::1
+mail.example.com. MX 10 mx.other.org.
+txt     TXT "hello; world"
+"#;
+
+        let records = parse_zone(zone, "ignored.org").unwrap();
+
+        assert_eq!(records.len(), 7);
+
+        assert_eq!(records[0].name, DomainName::from("example.com."));
+        assert_eq!(records[0].type_, Type::SOA);
+        assert_eq!(records[0].ttl, 3600);
+        let mut soa = encode_domain_name(DomainName::from("ns1.example.com."));
+        soa.extend(encode_domain_name(DomainName::from(
+            "hostmaster.example.com.",
+        )));
+        for value in [2024010101, 7200, 3600, 604800, 300] {
+            push_u32_to_u8_vec(&mut soa, value);
+        }
+        assert_eq!(records[0].resource_data, soa);
+
+        assert_eq!(records[1].name, DomainName::from("example.com."));
+        assert_eq!(
+            records[1].resource_data,
+            encode_domain_name(DomainName::from("ns1.example.com."))
+        );
+
+        assert_eq!(records[2].ttl, 300);
+        assert_eq!(records[2].resource_data, vec![192, 0, 2, 53]);
+
+        assert_eq!(records[4].name, DomainName::from("www.example.com."));
+        assert_eq!(records[4].type_, Type::AAAA);
+
+        let mut mx = vec![0, 10];
+        mx.extend(encode_domain_name(DomainName::from("mx.other.org.")));
+        assert_eq!(records[5].resource_data, mx);
+
+        assert_eq!(records[6].resource_data, b"hello; world".to_vec());
+    }
+
+    #[test]
+    fn parses_single_record() {
+        let record = parse_record("www 60 IN A 192.0.2.1", "example.com", DEFAULT_TTL).unwrap();
+
+        assert_eq!(
+            record,
+            ResourceRecord::new(
+                DomainName::from("www.example.com."),
+                Type::A,
+                Class::IN,
+                60,
+                vec![192, 0, 2, 1]
+            )
+        );
+    }
+
+    #[test]
+    fn reports_line_of_invalid_record() {
+        let zone = "$ORIGIN example.com.\nwww A 192.0.2.1\nbad A 999.0.2.1\n";
+
+        assert_eq!(
+            parse_zone(zone, "."),
+            Err(ZoneError {
+                line: 3,
+                message: "invalid IPv4 address: 999.0.2.1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_zone_data() {
+        assert!(parse_record("www A", "example.com", DEFAULT_TTL).is_err());
+        assert!(parse_record("www SRV 1 2 3 target", "example.com", DEFAULT_TTL).is_err());
+        assert!(parse_zone("  A 192.0.2.1", "example.com").is_err());
+        assert!(parse_zone("@ SOA ns1 hostmaster ( 1 2 3 4 5", "example.com").is_err());
+        assert!(parse_zone("$INCLUDE other.zone", "example.com").is_err());
+    }
+
+    #[test]
+    fn formats_resource_data_as_parsed() {
+        for (type_, rdata) in [
+            (Type::A, "192.0.2.1"),
+            (Type::AAAA, "2001:db8::1"),
+            (Type::CNAME, "www.example.com."),
+            (Type::NS, "."),
+            (Type::MX, "10 mail.example.com."),
+            (Type::TXT, "\"v=spf1 mx -all\""),
+            (
+                Type::SOA,
+                "ns1.example.com. hostmaster.example.com. 2024010101 7200 3600 1209600 300",
+            ),
+        ] {
+            let record = parse_record(
+                &format!("@ {:?} {}", type_, rdata),
+                "example.com",
+                DEFAULT_TTL,
+            )
+            .unwrap();
+            assert_eq!(format_resource_data(type_, &record.resource_data), rdata);
+        }
+
+        assert_eq!(
+            format_resource_data(Type::TXT, b"say \"hi\""),
+            "\"say \\\"hi\\\"\""
+        );
+        assert_eq!(
+            format_resource_data(Type::HTTPS, &[0, 1, 0]),
+            "\\# 3 000100"
+        );
+        // a compression pointer can not be followed anymore
+        assert_eq!(format_resource_data(Type::CNAME, &[0xc0, 12]), "\\# 2 c00c");
+    }
+
+    #[test]
+    fn parses_ttl_units() {
+        assert_eq!(parse_ttl("30"), Ok(30));
+        assert_eq!(parse_ttl("5m"), Ok(300));
+        assert_eq!(parse_ttl("1D"), Ok(86400));
+        assert!(parse_ttl("1y").is_err());
+        assert!(parse_ttl("h").is_err());
+    }
+}
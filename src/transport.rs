@@ -10,3 +10,17 @@ pub const DNS_PORT: u16 = 53;
 pub const UDP_MAX_MESSAGE_SIZE: usize = 512;
 
 pub const EDNS_STANDARD_UDP_PAYLOAD_SIZE: usize = 4096;
+
+// datagrams are read whole, whatever payload size the client uses and with a PROXY header first
+pub const UDP_RECEIVE_BUFFER_SIZE: usize = u16::MAX as usize;
+
+// TCP messages are only bounded by their two byte length prefix
+pub const TCP_MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS over HTTP(S), messages are bounded like over TCP but there is no EDNS TCP keepalive
+    Http,
+}
@@ -0,0 +1,279 @@
+use std::{
+    fmt::Display,
+    panic::{AssertUnwindSafe, catch_unwind},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError, sync_channel},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::log::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, PartialEq)]
+pub enum SubmitError {
+    QueueFull,
+    ShutDown,
+}
+
+#[derive(Debug, Default)]
+pub struct WorkerPoolMetrics {
+    queue_capacity: usize,
+    queue_depth: AtomicUsize,
+    in_flight: AtomicUsize,
+    completed: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+impl WorkerPoolMetrics {
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+impl Display for WorkerPoolMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "queue depth {}/{}, in flight {}, completed {}, rejected {}",
+            self.queue_depth(),
+            self.queue_capacity,
+            self.in_flight(),
+            self.completed(),
+            self.rejected()
+        )
+    }
+}
+
+/// Fixed amount of threads consuming jobs from a bounded queue
+/// Jobs submitted while the queue is full are rejected instead of piling up
+pub struct WorkerPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    metrics: Arc<WorkerPoolMetrics>,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, workers: usize, queue_capacity: usize) -> Self {
+        assert!(workers > 0, "a worker pool needs at least one worker");
+        assert!(queue_capacity > 0, "a worker pool needs a non empty queue");
+
+        let (sender, receiver) = sync_channel::<Job>(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let metrics = Arc::new(WorkerPoolMetrics {
+            queue_capacity,
+            ..Default::default()
+        });
+
+        let workers = (0..workers)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                let metrics = Arc::clone(&metrics);
+
+                thread::Builder::new()
+                    .name(format!("{}-worker-{}", name, i))
+                    .spawn(move || work(receiver, metrics))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+            metrics,
+        }
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) -> Result<(), SubmitError> {
+        let sender = self.sender.as_ref().ok_or(SubmitError::ShutDown)?;
+
+        // incremented before sending so that a worker never decrements a depth it did not see
+        self.metrics.queue_depth.fetch_add(1, Ordering::Relaxed);
+
+        sender.try_send(Box::new(job)).map_err(|e| {
+            self.metrics.queue_depth.fetch_sub(1, Ordering::Relaxed);
+            match e {
+                TrySendError::Full(_) => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    SubmitError::QueueFull
+                }
+                TrySendError::Disconnected(_) => SubmitError::ShutDown,
+            }
+        })
+    }
+
+    pub fn metrics(&self) -> Arc<WorkerPoolMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Waits for the queue to empty and the running jobs to finish, false when the deadline passed first
+    /// Jobs can still be submitted meanwhile, the caller is expected to have stopped doing so
+    pub fn wait_idle(&self, deadline: Instant) -> bool {
+        loop {
+            if self.metrics.queue_depth() == 0 && self.metrics.in_flight() == 0 {
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            thread::sleep(IDLE_CHECK_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    // closing the queue lets workers finish the queued jobs before exiting
+    fn drop(&mut self) {
+        self.sender.take();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(receiver: Arc<Mutex<Receiver<Job>>>, metrics: Arc<WorkerPoolMetrics>) {
+    loop {
+        let job = receiver
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .recv();
+
+        let Ok(job) = job else {
+            return; // the pool has been dropped
+        };
+
+        // in flight before leaving the queue so that the pool never looks idle in between
+        metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+        metrics.queue_depth.fetch_sub(1, Ordering::Relaxed);
+
+        // a panicking job must not take its worker down with it
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!(
+                "💣🔥 Job panicked in {}",
+                thread::current().name().unwrap_or("worker")
+            );
+        }
+
+        metrics.completed.fetch_add(1, Ordering::Relaxed);
+        metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn executes_jobs() {
+        let pool = WorkerPool::new("test", 2, 8);
+        let (sender, receiver) = channel();
+
+        for i in 0..8 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+
+        let mut results = (0..8)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect::<Vec<_>>();
+        results.sort();
+
+        assert_eq!(results, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rejects_jobs_when_queue_is_full() {
+        let pool = WorkerPool::new("test", 1, 1);
+        let (started_sender, started_receiver) = channel();
+        let (release_sender, release_receiver) = channel::<()>();
+
+        // occupies the only worker
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            release_receiver.recv().unwrap();
+        })
+        .unwrap();
+        started_receiver
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap();
+
+        // fills the queue
+        pool.execute(|| {}).unwrap();
+        assert_eq!(pool.metrics().queue_depth(), 1);
+        assert_eq!(pool.metrics().in_flight(), 1);
+
+        assert_eq!(pool.execute(|| {}), Err(SubmitError::QueueFull));
+        assert_eq!(pool.metrics().rejected(), 1);
+        assert_eq!(pool.metrics().queue_depth(), 1);
+
+        release_sender.send(()).unwrap();
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = WorkerPool::new("test", 1, 4);
+        let (sender, receiver) = channel();
+
+        pool.execute(|| panic!("job failure")).unwrap();
+        pool.execute(move || sender.send(()).unwrap()).unwrap();
+
+        assert!(receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn waits_for_jobs_to_finish() {
+        let pool = WorkerPool::new("test", 1, 4);
+        let (release_sender, release_receiver) = channel::<()>();
+
+        pool.execute(move || release_receiver.recv().unwrap())
+            .unwrap();
+        pool.execute(|| {}).unwrap();
+
+        assert!(!pool.wait_idle(Instant::now() + Duration::from_millis(50)));
+
+        release_sender.send(()).unwrap();
+        assert!(pool.wait_idle(Instant::now() + Duration::from_secs(1)));
+        assert_eq!(pool.metrics().completed(), 2);
+    }
+
+    #[test]
+    fn drains_queued_jobs_on_drop() {
+        let pool = WorkerPool::new("test", 1, 16);
+        let (sender, receiver) = channel();
+
+        for i in 0..16 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+
+        let metrics = pool.metrics();
+        drop(pool);
+
+        assert_eq!(receiver.try_iter().count(), 16);
+        assert_eq!(metrics.completed(), 16);
+        assert_eq!(metrics.queue_depth(), 0);
+    }
+}